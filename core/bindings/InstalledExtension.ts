@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Manifest } from "./Manifest";
+import type { Permission } from "./Permission";
+
+export interface InstalledExtension { manifest: Manifest, url: string, path: string, local: boolean, revision: string | null, pinned: string | null, previous_revision: string | null, approved_permission: Permission, install_time: bigint, update_time: bigint, }
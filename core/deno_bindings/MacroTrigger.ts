@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { InstanceState } from "./InstanceState.ts";
+
+export type MacroTrigger = { type: "PlayerJoin" } | { type: "ChatMessage", pattern: string, } | { type: "StateTransition", to: InstanceState, } | { type: "Crash" } | { type: "Cron", schedule: string, };
@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ClientEvent } from "./ClientEvent.ts";
+import type { MacroTrigger } from "./MacroTrigger.ts";
+
+export interface MacroTriggerFired { trigger: MacroTrigger, event: ClientEvent | null, time: bigint, }
@@ -0,0 +1,52 @@
+-- Every macro run, kept after the macro exits
+CREATE TABLE IF NOT EXISTS MacroRuns (
+    id                  BIGINT      PRIMARY KEY     NOT NULL,
+    pid                 BIGINT      NOT NULL,
+    instance_id         TEXT,
+    name                TEXT        NOT NULL,
+    args                TEXT        NOT NULL,
+    caused_by           TEXT        NOT NULL,
+    start_time          BIGINT      NOT NULL,
+    end_time            BIGINT,
+    exit_status         TEXT,
+    cpu_time_ms         BIGINT
+);
+
+-- Console output of macro runs, in the order it was emitted
+CREATE TABLE IF NOT EXISTS MacroRunLogs (
+    id                  INTEGER     PRIMARY KEY     AUTOINCREMENT,
+    run_id              BIGINT      NOT NULL,
+    time                BIGINT      NOT NULL,
+    line                TEXT        NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS MacroRunLogsRunId ON MacroRunLogs (run_id);
+
+-- Persistent key-value state of macros, namespaced by instance and macro name
+CREATE TABLE IF NOT EXISTS MacroKv (
+    instance_id         TEXT        NOT NULL,
+    macro_name          TEXT        NOT NULL,
+    key                 TEXT        NOT NULL,
+    value               TEXT        NOT NULL,
+    version             BIGINT      NOT NULL,
+    update_time         BIGINT      NOT NULL,
+    PRIMARY KEY (instance_id, macro_name, key)
+);
+
+-- Last version handed out in each key-value namespace, so versions are never reused
+CREATE TABLE IF NOT EXISTS MacroKvVersions (
+    instance_id         TEXT        NOT NULL,
+    macro_name          TEXT        NOT NULL,
+    last_version        BIGINT      NOT NULL,
+    PRIMARY KEY (instance_id, macro_name)
+);
+
+-- Triggers of macros and who set them, the macro runs on behalf of that user
+CREATE TABLE IF NOT EXISTS MacroTriggers (
+    instance_id         TEXT        NOT NULL,
+    macro_name          TEXT        NOT NULL,
+    triggers            TEXT        NOT NULL,
+    concurrency         TEXT        NOT NULL,
+    configured_by       TEXT        NOT NULL,
+    PRIMARY KEY (instance_id, macro_name)
+);
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ts_rs::TS;
+
+use crate::util::rand_alphanumeric;
+
+use super::permission::UserPermission;
+
+/// Prefix of every API token, used to tell them apart from JWTs
+pub const API_TOKEN_PREFIX: &str = "lst_";
+
+#[derive(Debug, Clone, Eq, Serialize, Deserialize, TS)]
+#[serde(transparent)]
+#[ts(export)]
+pub struct ApiTokenId(String);
+
+impl Default for ApiTokenId {
+    fn default() -> Self {
+        Self(rand_alphanumeric(12))
+    }
+}
+
+impl<T: AsRef<str>> PartialEq<T> for ApiTokenId {
+    fn eq(&self, other: &T) -> bool {
+        self.0 == other.as_ref()
+    }
+}
+
+impl AsRef<str> for ApiTokenId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ApiTokenId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A long-lived token that authenticates as its user, limited to `scopes`.
+///
+/// Only the hash of the secret is stored, the plaintext token is handed out once on creation.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiToken {
+    pub id: ApiTokenId,
+    pub name: String,
+    hashed_secret: String,
+    pub scopes: UserPermission,
+    pub creation_time: i64,
+    pub expiry: Option<i64>,
+}
+
+impl ApiToken {
+    /// Returns the new token and its plaintext form
+    pub fn new(name: String, scopes: UserPermission, expiry: Option<i64>) -> (Self, String) {
+        let id = ApiTokenId::default();
+        let secret = rand_alphanumeric(32);
+        let plaintext = format!("{API_TOKEN_PREFIX}{id}_{secret}");
+        (
+            Self {
+                id,
+                name,
+                hashed_secret: hash_secret(&secret),
+                scopes,
+                creation_time: chrono::Utc::now().timestamp(),
+                expiry,
+            },
+            plaintext,
+        )
+    }
+
+    pub fn verify(&self, secret: &str) -> bool {
+        self.hashed_secret == hash_secret(secret)
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry.map_or(false, |expiry| expiry <= now)
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Splits a plaintext API token into its id and secret.
+///
+/// Returns `None` if the token is not an API token.
+pub fn parse_api_token(token: &str) -> Option<(ApiTokenId, &str)> {
+    let (id, secret) = token.strip_prefix(API_TOKEN_PREFIX)?.split_once('_')?;
+    Some((ApiTokenId(id.to_string()), secret))
+}
+
+#[derive(Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct PublicApiToken {
+    pub id: ApiTokenId,
+    pub name: String,
+    pub scopes: UserPermission,
+    pub creation_time: i64,
+    pub expiry: Option<i64>,
+}
+
+impl From<&ApiToken> for PublicApiToken {
+    fn from(token: &ApiToken) -> Self {
+        PublicApiToken {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            scopes: token.scopes.clone(),
+            creation_time: token.creation_time,
+            expiry: token.expiry,
+        }
+    }
+}
+
+#[test]
+fn test_api_token() {
+    let (token, plaintext) = ApiToken::new("ci".to_string(), UserPermission::default(), Some(100));
+    let (id, secret) = parse_api_token(&plaintext).unwrap();
+    assert_eq!(id, token.id);
+    assert!(token.verify(secret));
+    assert!(!token.verify("not the secret"));
+    assert!(!token.is_expired(99));
+    assert!(token.is_expired(100));
+    assert!(parse_api_token("eyJhbGciOiJIUzUxMiJ9.e30.sig").is_none());
+}
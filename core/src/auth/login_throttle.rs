@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+/// Failures are counted within this window
+const FAILURE_WINDOW: i64 = 15 * 60;
+const LOCKOUT_DURATION: i64 = 15 * 60;
+const MAX_ACCOUNT_FAILURES: u32 = 5;
+/// Higher than the account limit so a shared address doesn't lock everyone out as quickly
+const MAX_IP_FAILURES: u32 = 20;
+/// From every address together, only a sustained guessing attempt locks the owner out
+const MAX_USERNAME_FAILURES: u32 = 50;
+/// Single sign-on logins an address can start within the window, each one fetches from the
+/// identity provider and is kept until it expires
+const MAX_OIDC_LOGINS_PER_IP: u32 = 20;
+
+#[derive(Clone, Debug, Default)]
+struct FailureRecord {
+    count: u32,
+    window_start: i64,
+    locked_until: Option<i64>,
+}
+
+impl FailureRecord {
+    fn locked_until(&self, now: i64) -> Option<i64> {
+        self.locked_until.filter(|until| *until > now)
+    }
+
+    fn record(&mut self, max_failures: u32, now: i64) {
+        if now - self.window_start >= FAILURE_WINDOW {
+            self.count = 0;
+            self.window_start = now;
+        }
+        self.count += 1;
+        if self.count >= max_failures {
+            self.locked_until = Some(now + LOCKOUT_DURATION);
+            self.count = 0;
+            self.window_start = now;
+        }
+    }
+}
+
+/// Tracks failed logins per account and IP, per IP and per account,
+/// locking any of them out after too many failures.
+///
+/// Accounts are first locked out for the address the failures came from, so anyone guessing
+/// a username can't easily lock its owner out, and only everywhere after many more failures,
+/// so guesses spread over many addresses are still limited.
+/// Kept in memory only, a restart clears every lockout.
+#[derive(Clone, Debug, Default)]
+pub struct LoginThrottle {
+    /// By username and IP, the IP is empty if unknown
+    accounts: HashMap<(String, String), FailureRecord>,
+    ips: HashMap<String, FailureRecord>,
+    usernames: HashMap<String, FailureRecord>,
+    /// Single sign-on logins started by IP, counted whether or not they are finished
+    oidc_logins: HashMap<String, FailureRecord>,
+}
+
+fn account_key(username: &str, ip: Option<&str>) -> (String, String) {
+    (username.to_string(), ip.unwrap_or_default().to_string())
+}
+
+impl LoginThrottle {
+    /// Returns when the lockout ends if the account or the IP is locked out
+    pub fn locked_until(&self, username: &str, ip: Option<&str>, now: i64) -> Option<i64> {
+        let account = self
+            .accounts
+            .get(&account_key(username, ip))
+            .and_then(|record| record.locked_until(now));
+        let ip = ip
+            .and_then(|ip| self.ips.get(ip))
+            .and_then(|record| record.locked_until(now));
+        let username = self
+            .usernames
+            .get(username)
+            .and_then(|record| record.locked_until(now));
+        account.max(ip).max(username)
+    }
+
+    pub fn record_failure(&mut self, username: &str, ip: Option<&str>, now: i64) {
+        self.accounts
+            .entry(account_key(username, ip))
+            .or_default()
+            .record(MAX_ACCOUNT_FAILURES, now);
+        self.usernames
+            .entry(username.to_string())
+            .or_default()
+            .record(MAX_USERNAME_FAILURES, now);
+        if let Some(ip) = ip {
+            self.ips
+                .entry(ip.to_string())
+                .or_default()
+                .record(MAX_IP_FAILURES, now);
+        }
+        self.prune(now);
+    }
+
+    /// Counts a single sign-on login started from `ip`,
+    /// returns when the lockout ends if it already started too many
+    pub fn record_oidc_login(&mut self, ip: &str, now: i64) -> Option<i64> {
+        let locked_until = self
+            .oidc_logins
+            .get(ip)
+            .and_then(|record| record.locked_until(now));
+        if locked_until.is_none() {
+            self.oidc_logins
+                .entry(ip.to_string())
+                .or_default()
+                .record(MAX_OIDC_LOGINS_PER_IP, now);
+            self.prune(now);
+        }
+        locked_until
+    }
+
+    pub fn record_success(&mut self, username: &str, ip: Option<&str>) {
+        self.accounts.remove(&account_key(username, ip));
+    }
+
+    /// Drops records that no longer affect anything, so the maps don't grow unbounded
+    fn prune(&mut self, now: i64) {
+        let is_stale = |record: &FailureRecord| {
+            record.locked_until(now).is_none() && now - record.window_start >= FAILURE_WINDOW
+        };
+        self.accounts.retain(|_, record| !is_stale(record));
+        self.ips.retain(|_, record| !is_stale(record));
+        self.usernames.retain(|_, record| !is_stale(record));
+        self.oidc_logins.retain(|_, record| !is_stale(record));
+    }
+}
+
+#[test]
+fn test_login_throttle() {
+    let mut throttle = LoginThrottle::default();
+    let now = 1_700_000_000;
+    for _ in 0..MAX_ACCOUNT_FAILURES - 1 {
+        throttle.record_failure("alice", Some("10.0.0.1"), now);
+    }
+    assert!(throttle
+        .locked_until("alice", Some("10.0.0.1"), now)
+        .is_none());
+    throttle.record_failure("alice", Some("10.0.0.1"), now);
+    assert_eq!(
+        throttle.locked_until("alice", Some("10.0.0.1"), now),
+        Some(now + LOCKOUT_DURATION)
+    );
+    assert!(throttle
+        .locked_until("alice", Some("10.0.0.1"), now + LOCKOUT_DURATION)
+        .is_none());
+    // failures from one address don't lock the account out everywhere
+    assert!(throttle
+        .locked_until("alice", Some("10.0.0.4"), now)
+        .is_none());
+    assert!(throttle.locked_until("alice", None, now).is_none());
+
+    // a successful login only clears the failures of its own address
+    throttle.record_failure("dave", Some("10.0.0.5"), now);
+    throttle.record_failure("dave", Some("10.0.0.6"), now);
+    throttle.record_success("dave", Some("10.0.0.5"));
+    assert!(!throttle
+        .accounts
+        .contains_key(&account_key("dave", Some("10.0.0.5"))));
+    assert!(throttle
+        .accounts
+        .contains_key(&account_key("dave", Some("10.0.0.6"))));
+
+    // the IP limit spans accounts
+    for i in 0..MAX_IP_FAILURES {
+        throttle.record_failure(&format!("user{i}"), Some("10.0.0.2"), now);
+    }
+    assert!(throttle
+        .locked_until("bob", Some("10.0.0.2"), now)
+        .is_some());
+    assert!(throttle
+        .locked_until("bob", Some("10.0.0.3"), now)
+        .is_none());
+
+    // guesses spread over many addresses still lock the account out everywhere
+    for i in 0..MAX_USERNAME_FAILURES {
+        throttle.record_failure("erin", Some(&format!("10.1.{}.{}", i / 4, i % 4)), now);
+    }
+    assert!(throttle
+        .locked_until("erin", Some("10.0.0.7"), now)
+        .is_some());
+
+    // single sign-on logins are limited per address
+    for _ in 0..MAX_OIDC_LOGINS_PER_IP {
+        assert!(throttle.record_oidc_login("10.0.0.8", now).is_none());
+    }
+    assert!(throttle.record_oidc_login("10.0.0.8", now).is_some());
+    assert!(throttle.record_oidc_login("10.0.0.9", now).is_none());
+
+    // failures outside the window don't add up
+    throttle.record_failure("carol", None, now);
+    for _ in 0..MAX_ACCOUNT_FAILURES - 1 {
+        throttle.record_failure("carol", None, now + FAILURE_WINDOW);
+    }
+    assert!(throttle
+        .locked_until("carol", None, now + FAILURE_WINDOW)
+        .is_none());
+}
@@ -1,6 +1,12 @@
+pub mod api_token;
 pub mod hashed_password;
 pub mod jwt_token;
+pub mod login_throttle;
+pub mod oidc;
 pub mod permission;
+pub mod role;
+pub mod session;
+pub mod totp;
 pub mod user;
 pub mod user_id;
 pub mod user_secrets;
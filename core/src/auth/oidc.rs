@@ -0,0 +1,453 @@
+use std::collections::{HashMap, HashSet};
+
+use color_eyre::eyre::{eyre, Context};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    util::rand_alphanumeric,
+};
+
+use super::role::RoleId;
+
+/// How long the user has to finish logging in at the identity provider
+pub const PENDING_LOGIN_TIMEOUT: i64 = 10 * 60;
+/// Logins started but not finished past this are refused until some expire
+pub const MAX_PENDING_LOGINS: usize = 1000;
+/// How long a discovery document is reused before it is fetched again
+const DISCOVERY_CACHE_DURATION: i64 = 10 * 60;
+
+/// By issuer URL, with when it was fetched
+static DISCOVERY_CACHE: Lazy<std::sync::Mutex<HashMap<String, (i64, ProviderMetadata)>>> =
+    Lazy::new(Default::default);
+
+fn default_scopes() -> Vec<String> {
+    vec![
+        "openid".to_string(),
+        "profile".to_string(),
+        "groups".to_string(),
+    ]
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_string()
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct OidcSettings {
+    pub enabled: bool,
+    /// The issuer, the discovery document is fetched from `<issuer_url>/.well-known/openid-configuration`
+    pub issuer_url: String,
+    pub client_id: String,
+    /// Only needed for confidential clients, never sent back to the dashboard
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Where the identity provider sends the user back to, the dashboard's callback page
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// The ID token claim used as the username of newly provisioned users
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    /// Members of any of these groups are admins
+    #[serde(default)]
+    pub admin_groups: HashSet<String>,
+    /// Roles given to the members of each group, replaced on every login
+    #[serde(default)]
+    pub group_roles: HashMap<String, HashSet<RoleId>>,
+    /// Lets users other than the owner keep logging in with a password
+    #[serde(default)]
+    pub allow_password_login: bool,
+}
+
+impl OidcSettings {
+    pub fn is_admin(&self, identity: &OidcIdentity) -> bool {
+        !self.admin_groups.is_disjoint(&identity.groups)
+    }
+
+    pub fn roles(&self, identity: &OidcIdentity) -> HashSet<RoleId> {
+        identity
+            .groups
+            .iter()
+            .filter_map(|group| self.group_roles.get(group))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+/// The part of the discovery document we use
+#[derive(Deserialize, Clone, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// A login started by `begin_login`, kept until the identity provider redirects back
+#[derive(Clone, Debug)]
+pub struct PendingOidcLogin {
+    code_verifier: String,
+    nonce: String,
+    pub creation_time: i64,
+}
+
+/// Who the identity provider says logged in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub username: Option<String>,
+    pub groups: HashSet<String>,
+}
+
+/// Links a user to their account at the identity provider
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct OidcSubject {
+    pub issuer: String,
+    pub subject: String,
+}
+
+fn idp_error(source: color_eyre::Report) -> Error {
+    Error {
+        kind: ErrorKind::External,
+        source,
+    }
+}
+
+fn invalid_id_token(reason: &str) -> Error {
+    Error {
+        kind: ErrorKind::Unauthorized,
+        source: eyre!("Invalid ID token: {reason}"),
+    }
+}
+
+/// RFC 7636 S256 code challenge
+fn pkce_challenge(code_verifier: &str) -> String {
+    data_encoding::BASE64URL_NOPAD.encode(&Sha256::digest(code_verifier.as_bytes()))
+}
+
+async fn discover(http: &reqwest::Client, issuer_url: &str) -> Result<ProviderMetadata, Error> {
+    let issuer_url = issuer_url.trim_end_matches('/');
+    let now = chrono::Utc::now().timestamp();
+    if let Some((fetched_at, metadata)) = DISCOVERY_CACHE.lock().unwrap().get(issuer_url) {
+        if now - fetched_at < DISCOVERY_CACHE_DURATION {
+            return Ok(metadata.clone());
+        }
+    }
+    let metadata: ProviderMetadata = http
+        .get(format!("{issuer_url}/.well-known/openid-configuration"))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("Failed to fetch the identity provider's discovery document")
+        .map_err(idp_error)?
+        .json()
+        .await
+        .context("Failed to parse the identity provider's discovery document")
+        .map_err(idp_error)?;
+    if metadata.issuer.trim_end_matches('/') != issuer_url {
+        return Err(idp_error(eyre!(
+            "Discovery document issuer {} does not match {}",
+            metadata.issuer,
+            issuer_url
+        )));
+    }
+    DISCOVERY_CACHE
+        .lock()
+        .unwrap()
+        .insert(issuer_url.to_string(), (now, metadata.clone()));
+    Ok(metadata)
+}
+
+/// Returns the `state` identifying the login, the URL to send the user to, and what is needed to
+/// finish the login
+pub async fn begin_login(
+    settings: &OidcSettings,
+    now: i64,
+) -> Result<(String, String, PendingOidcLogin), Error> {
+    let http = reqwest::Client::new();
+    let metadata = discover(&http, &settings.issuer_url).await?;
+    let state = rand_alphanumeric(32);
+    let pending = PendingOidcLogin {
+        code_verifier: rand_alphanumeric(64),
+        nonce: rand_alphanumeric(32),
+        creation_time: now,
+    };
+    let mut url = url::Url::parse(&metadata.authorization_endpoint)
+        .context("Invalid authorization endpoint")
+        .map_err(idp_error)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &settings.client_id)
+        .append_pair("redirect_uri", &settings.redirect_uri)
+        .append_pair("scope", &settings.scopes.join(" "))
+        .append_pair("state", &state)
+        .append_pair("nonce", &pending.nonce)
+        .append_pair("code_challenge", &pkce_challenge(&pending.code_verifier))
+        .append_pair("code_challenge_method", "S256");
+    Ok((state, url.into(), pending))
+}
+
+/// Exchanges the authorization code and validates the returned ID token
+pub async fn complete_login(
+    settings: &OidcSettings,
+    pending: PendingOidcLogin,
+    code: &str,
+) -> Result<OidcIdentity, Error> {
+    let http = reqwest::Client::new();
+    let metadata = discover(&http, &settings.issuer_url).await?;
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", settings.redirect_uri.as_str()),
+        ("client_id", settings.client_id.as_str()),
+        ("code_verifier", pending.code_verifier.as_str()),
+    ];
+    if let Some(client_secret) = &settings.client_secret {
+        params.push(("client_secret", client_secret.as_str()));
+    }
+    let token_response: TokenResponse = http
+        .post(&metadata.token_endpoint)
+        .form(&params)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("Failed to exchange the authorization code")
+        .map_err(idp_error)?
+        .json()
+        .await
+        .context("Failed to parse the token response")
+        .map_err(idp_error)?;
+
+    let header = jsonwebtoken::decode_header(&token_response.id_token)
+        .map_err(|_| invalid_id_token("malformed header"))?;
+    let key = match header.alg {
+        // symmetric signatures use the client secret as the key
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let client_secret = settings.client_secret.as_ref().ok_or_else(|| {
+                invalid_id_token("HMAC signed but no client secret is configured")
+            })?;
+            DecodingKey::from_secret(client_secret.as_bytes())
+        }
+        _ => {
+            let jwks_uri = metadata
+                .jwks_uri
+                .as_ref()
+                .ok_or_else(|| idp_error(eyre!("The identity provider has no jwks_uri")))?;
+            let jwks: JwkSet = http
+                .get(jwks_uri)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .context("Failed to fetch the identity provider's keys")
+                .map_err(idp_error)?
+                .json()
+                .await
+                .context("Failed to parse the identity provider's keys")
+                .map_err(idp_error)?;
+            let jwk = match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }
+            .ok_or_else(|| invalid_id_token("signing key not found"))?;
+            DecodingKey::from_jwk(jwk).map_err(|_| invalid_id_token("unsupported signing key"))?
+        }
+    };
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&settings.client_id]);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims =
+        jsonwebtoken::decode::<HashMap<String, Value>>(&token_response.id_token, &key, &validation)
+            .map_err(|e| invalid_id_token(&e.to_string()))?
+            .claims;
+
+    if claims.get("nonce").and_then(Value::as_str) != Some(pending.nonce.as_str()) {
+        return Err(invalid_id_token("nonce mismatch"));
+    }
+    let subject = claims
+        .get("sub")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_id_token("missing subject"))?
+        .to_string();
+    let username = claims
+        .get(&settings.username_claim)
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let groups = match claims.get(&settings.groups_claim) {
+        Some(Value::Array(groups)) => groups
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        Some(Value::String(group)) => HashSet::from([group.clone()]),
+        _ => HashSet::new(),
+    };
+    Ok(OidcIdentity {
+        issuer: metadata.issuer,
+        subject,
+        username,
+        groups,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{SocketAddr, TcpListener},
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{extract::State, routing, Form, Json, Router};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const CLIENT_ID: &str = "lodestone";
+    const CLIENT_SECRET: &str = "mock-secret";
+
+    /// The code challenge and nonce of each code the mock IdP handed out
+    type Codes = Arc<Mutex<HashMap<String, (String, String)>>>;
+
+    #[derive(Clone)]
+    struct MockIdp {
+        issuer: String,
+        codes: Codes,
+        discovery_fetches: Arc<Mutex<usize>>,
+    }
+
+    async fn discovery(State(idp): State<MockIdp>) -> Json<Value> {
+        *idp.discovery_fetches.lock().unwrap() += 1;
+        Json(json!({
+            "issuer": idp.issuer,
+            "authorization_endpoint": format!("{}/authorize", idp.issuer),
+            "token_endpoint": format!("{}/token", idp.issuer),
+        }))
+    }
+
+    async fn token(
+        State(idp): State<MockIdp>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, axum::http::StatusCode> {
+        let (challenge, nonce) = idp
+            .codes
+            .lock()
+            .unwrap()
+            .remove(&form["code"])
+            .ok_or(axum::http::StatusCode::BAD_REQUEST)?;
+        if pkce_challenge(&form["code_verifier"]) != challenge
+            || form["client_secret"] != CLIENT_SECRET
+        {
+            return Err(axum::http::StatusCode::BAD_REQUEST);
+        }
+        let claims = json!({
+            "iss": idp.issuer,
+            "aud": CLIENT_ID,
+            "sub": "user-1234",
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "nonce": nonce,
+            "preferred_username": "steve",
+            "groups": ["lodestone-admins", "everyone"],
+        });
+        let id_token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::HS256),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+        )
+        .unwrap();
+        Ok(Json(
+            json!({ "id_token": id_token, "token_type": "Bearer" }),
+        ))
+    }
+
+    fn spawn_mock_idp() -> MockIdp {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let idp = MockIdp {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            codes: Arc::new(Mutex::new(HashMap::new())),
+            discovery_fetches: Arc::new(Mutex::new(0)),
+        };
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", routing::get(discovery))
+            .route("/token", routing::post(token))
+            .with_state(idp.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        idp
+    }
+
+    #[tokio::test]
+    async fn test_oidc_login_with_mock_idp() {
+        let idp = spawn_mock_idp();
+        let settings = OidcSettings {
+            enabled: true,
+            issuer_url: idp.issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(CLIENT_SECRET.to_string()),
+            redirect_uri: "http://localhost:3000/login/oidc".to_string(),
+            scopes: default_scopes(),
+            username_claim: default_username_claim(),
+            groups_claim: default_groups_claim(),
+            admin_groups: HashSet::from(["lodestone-admins".to_string()]),
+            group_roles: HashMap::new(),
+            allow_password_login: false,
+        };
+
+        let (state, authorization_url, pending) = begin_login(&settings, 0).await.unwrap();
+        let query: HashMap<String, String> = url::Url::parse(&authorization_url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        assert!(authorization_url.starts_with(&format!("{}/authorize?", idp.issuer)));
+        assert_eq!(query["state"], state);
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        // the user approves the login at the IdP, which redirects back with a code
+        idp.codes.lock().unwrap().insert(
+            "code-1".to_string(),
+            (query["code_challenge"].clone(), query["nonce"].clone()),
+        );
+        let identity = complete_login(&settings, pending.clone(), "code-1")
+            .await
+            .unwrap();
+        assert_eq!(identity.issuer, idp.issuer);
+        assert_eq!(identity.subject, "user-1234");
+        assert_eq!(identity.username.as_deref(), Some("steve"));
+        assert!(settings.is_admin(&identity));
+
+        // the ID token must carry the nonce of the login it completes
+        idp.codes.lock().unwrap().insert(
+            "code-2".to_string(),
+            (query["code_challenge"].clone(), "other-nonce".to_string()),
+        );
+        assert!(complete_login(&settings, pending, "code-2").await.is_err());
+
+        // the discovery document is only fetched once
+        assert_eq!(*idp.discovery_fetches.lock().unwrap(), 1);
+    }
+}
@@ -55,6 +55,16 @@ impl UserPermission {
     }
 }
 
+impl UserPermission {
+    pub fn has_unsafe_permission(&self) -> bool {
+        !self.can_write_instance_resource.is_empty()
+            || !self.can_access_instance_macro.is_empty()
+            || self.can_write_global_file
+            || self.can_manage_permission
+            || !self.can_write_instance_file.is_empty()
+    }
+}
+
 impl Default for UserPermission {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::types::InstanceUuid;
+
+use super::user::UserAction;
+
+#[derive(Debug, Clone, Eq, Serialize, Deserialize, TS)]
+#[serde(transparent)]
+#[ts(export)]
+pub struct RoleId(String);
+
+impl Default for RoleId {
+    fn default() -> Self {
+        Self(format!("ROLE_{}", uuid::Uuid::new_v4()))
+    }
+}
+
+impl<T: AsRef<str>> PartialEq<T> for RoleId {
+    fn eq(&self, other: &T) -> bool {
+        self.0 == other.as_ref()
+    }
+}
+
+impl AsRef<str> for RoleId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::hash::Hash for RoleId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl std::fmt::Display for RoleId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The permissions bundled in a role.
+///
+/// Instance permissions apply to every instance the role's scope covers.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, TS, Debug, Default)]
+#[ts(export)]
+pub struct RolePermission {
+    pub can_view_instance: bool,
+    pub can_start_instance: bool,
+    pub can_stop_instance: bool,
+    pub can_access_instance_console: bool,
+    pub can_access_instance_setting: bool,
+    pub can_read_instance_resource: bool,
+    // unsafe permission, owner exclusive unless explicitly granted
+    pub can_write_instance_resource: bool,
+    // unsafe permission, owner exclusive unless explicitly granted
+    pub can_access_instance_macro: bool,
+    pub can_read_instance_file: bool,
+    // unsafe permission, owner exclusive unless explicitly granted
+    pub can_write_instance_file: bool,
+
+    pub can_create_instance: bool,
+    pub can_delete_instance: bool,
+    pub can_read_global_file: bool,
+    // unsafe permission, owner exclusive unless explicitly granted
+    pub can_write_global_file: bool,
+    // owner exclusive unless explicitly granted
+    pub can_manage_permission: bool,
+    pub can_install_extension: bool,
+}
+
+impl RolePermission {
+    pub fn has_unsafe_permission(&self) -> bool {
+        self.can_write_instance_resource
+            || self.can_access_instance_macro
+            || self.can_write_global_file
+            || self.can_manage_permission
+            || self.can_write_instance_file
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, TS, Debug)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum RoleScope {
+    Instances { instances: HashSet<InstanceUuid> },
+    AllInstances,
+    Tag { tag: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, TS, Debug)]
+#[ts(export)]
+pub struct Role {
+    pub id: RoleId,
+    pub name: String,
+    pub permissions: RolePermission,
+    pub scope: RoleScope,
+}
+
+impl Role {
+    /// Resolves the scope of the role against the current instance tags
+    pub fn resolve(&self, instance_tags: &HashMap<InstanceUuid, HashSet<String>>) -> RoleGrant {
+        let instances = match &self.scope {
+            RoleScope::Instances { instances } => Some(instances.clone()),
+            RoleScope::AllInstances => None,
+            RoleScope::Tag { tag } => Some(
+                instance_tags
+                    .iter()
+                    .filter(|(_, tags)| tags.contains(tag))
+                    .map(|(uuid, _)| uuid.clone())
+                    .collect(),
+            ),
+        };
+        RoleGrant {
+            permissions: self.permissions.clone(),
+            instances,
+        }
+    }
+}
+
+/// A role resolved to the concrete set of instances it applies to
+#[derive(Clone, Debug)]
+pub struct RoleGrant {
+    permissions: RolePermission,
+    /// `None` if the role applies to all instances
+    instances: Option<HashSet<InstanceUuid>>,
+}
+
+impl RoleGrant {
+    fn covers(&self, instance_uuid: &InstanceUuid) -> bool {
+        self.instances
+            .as_ref()
+            .map_or(true, |instances| instances.contains(instance_uuid))
+    }
+
+    pub fn grants(&self, action: &UserAction) -> bool {
+        let p = &self.permissions;
+        match action {
+            UserAction::ViewInstance(uuid) => p.can_view_instance && self.covers(uuid),
+            UserAction::StartInstance(uuid) => p.can_start_instance && self.covers(uuid),
+            UserAction::StopInstance(uuid) => p.can_stop_instance && self.covers(uuid),
+            UserAction::AccessConsole(uuid) => p.can_access_instance_console && self.covers(uuid),
+            UserAction::AccessSetting(uuid) => p.can_access_instance_setting && self.covers(uuid),
+            UserAction::ReadResource(uuid) => p.can_read_instance_resource && self.covers(uuid),
+            UserAction::WriteResource(uuid) => p.can_write_instance_resource && self.covers(uuid),
+            UserAction::AccessMacro(Some(uuid)) => p.can_access_instance_macro && self.covers(uuid),
+            UserAction::AccessMacro(None) => false,
+            UserAction::ReadInstanceFile(uuid) => {
+                p.can_read_global_file || (p.can_read_instance_file && self.covers(uuid))
+            }
+            UserAction::WriteInstanceFile(uuid) => {
+                p.can_write_global_file || (p.can_write_instance_file && self.covers(uuid))
+            }
+            UserAction::CreateInstance => p.can_create_instance,
+            UserAction::DeleteInstance => p.can_delete_instance,
+            UserAction::ReadGlobalFile => p.can_read_global_file,
+            UserAction::WriteGlobalFile => p.can_write_global_file,
+            UserAction::ManageUser => false,
+            UserAction::ManagePermission => p.can_manage_permission,
+            UserAction::InstallExtension => p.can_install_extension,
+        }
+    }
+}
+
+#[test]
+fn test_role_grant() {
+    let tagged = InstanceUuid::default();
+    let untagged = InstanceUuid::default();
+    let instance_tags = HashMap::from([(tagged.clone(), HashSet::from(["survival".to_string()]))]);
+    let role = Role {
+        id: RoleId::default(),
+        name: "Moderator".to_string(),
+        permissions: RolePermission {
+            can_view_instance: true,
+            can_access_instance_console: true,
+            ..Default::default()
+        },
+        scope: RoleScope::Tag {
+            tag: "survival".to_string(),
+        },
+    };
+    let grant = role.resolve(&instance_tags);
+    assert!(grant.grants(&UserAction::ViewInstance(tagged.clone())));
+    assert!(grant.grants(&UserAction::AccessConsole(tagged.clone())));
+    assert!(!grant.grants(&UserAction::StopInstance(tagged)));
+    assert!(!grant.grants(&UserAction::ViewInstance(untagged.clone())));
+
+    let all = Role {
+        scope: RoleScope::AllInstances,
+        ..role
+    }
+    .resolve(&instance_tags);
+    assert!(all.grants(&UserAction::ViewInstance(untagged)));
+    assert!(!all.grants(&UserAction::CreateInstance));
+}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::util::rand_alphanumeric;
+
+#[derive(Debug, Clone, Eq, Serialize, Deserialize, TS)]
+#[serde(transparent)]
+#[ts(export)]
+pub struct SessionId(String);
+
+impl Default for SessionId {
+    fn default() -> Self {
+        Self(rand_alphanumeric(16))
+    }
+}
+
+impl<T: AsRef<str>> PartialEq<T> for SessionId {
+    fn eq(&self, other: &T) -> bool {
+        self.0 == other.as_ref()
+    }
+}
+
+impl AsRef<str> for SessionId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::hash::Hash for SessionId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Where a login came from, as reported by the client
+#[derive(Debug, Clone, Default)]
+pub struct LoginClient {
+    pub ip: Option<String>,
+    pub device: Option<String>,
+}
+
+/// A token issued by a login, revoking it only logs out that device
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Session {
+    pub id: SessionId,
+    /// The user agent of the client that logged in
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub creation_time: i64,
+    pub last_used: i64,
+}
+
+impl Session {
+    pub fn new(client: LoginClient, now: i64) -> Self {
+        Self {
+            id: SessionId::default(),
+            device: client.device,
+            ip: client.ip,
+            creation_time: now,
+            last_used: now,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, TS)]
+#[ts(export)]
+pub struct PublicSession {
+    pub id: SessionId,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub creation_time: i64,
+    pub last_used: i64,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
@@ -0,0 +1,165 @@
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use ts_rs::TS;
+
+use crate::util::rand_alphanumeric;
+
+use super::hashed_password::{hash_password, HashedPassword};
+
+const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Number of steps before and after the current one a code is still accepted for
+const TOTP_SKEW: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Base32 encoded TOTP shared secret
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(transparent)]
+#[ts(export)]
+pub struct TotpSecret(String);
+
+impl TotpSecret {
+    pub fn generate() -> Self {
+        let mut key = [0u8; 20];
+        OsRng.fill_bytes(&mut key);
+        Self(data_encoding::BASE32_NOPAD.encode(&key))
+    }
+
+    fn key(&self) -> Vec<u8> {
+        data_encoding::BASE32_NOPAD
+            .decode(self.0.as_bytes())
+            .expect("TOTP secret is always valid base32")
+    }
+
+    pub fn code_at(&self, unix_time: u64) -> String {
+        format_code(hotp(&self.key(), unix_time / TOTP_STEP))
+    }
+
+    /// The `otpauth://` URI understood by authenticator apps, also used as the QR code payload
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> String {
+        let issuer = url::form_urlencoded::byte_serialize(issuer.as_bytes()).collect::<String>();
+        let account = url::form_urlencoded::byte_serialize(account.as_bytes()).collect::<String>();
+        format!(
+            "otpauth://totp/{issuer}:{account}?secret={}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP}",
+            self.0
+        )
+    }
+}
+
+impl AsRef<str> for TotpSecret {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// RFC 4226 HOTP value for `counter`
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10_u32.pow(TOTP_DIGITS)
+}
+
+fn format_code(code: u32) -> String {
+    format!("{:0width$}", code, width = TOTP_DIGITS as usize)
+}
+
+/// A user's TOTP enrollment, stored in the users store next to the password hash
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpConfig {
+    pub secret: TotpSecret,
+    /// Set once the user proved they can generate codes, logins only require a code after that
+    pub confirmed: bool,
+    recovery_codes: Vec<HashedPassword>,
+    /// The last time step a code was accepted for, so a code can't be replayed
+    last_used_step: Option<u64>,
+}
+
+impl TotpConfig {
+    /// Returns the new config along with the plaintext recovery codes
+    pub fn new() -> (Self, Vec<String>) {
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| rand_alphanumeric(10))
+            .collect();
+        (
+            Self {
+                secret: TotpSecret::generate(),
+                confirmed: false,
+                recovery_codes: recovery_codes.iter().map(hash_password).collect(),
+                last_used_step: None,
+            },
+            recovery_codes,
+        )
+    }
+
+    /// Checks a code against the steps around `unix_time`, a code is only accepted once
+    pub fn verify_code(&mut self, code: &str, unix_time: u64) -> bool {
+        let code = code.trim();
+        let key = self.secret.key();
+        let current_step = unix_time / TOTP_STEP;
+        for step in current_step.saturating_sub(TOTP_SKEW)..=current_step + TOTP_SKEW {
+            if self.last_used_step.map_or(false, |last| step <= last) {
+                continue;
+            }
+            if format_code(hotp(&key, step)) == code {
+                self.last_used_step = Some(step);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Consumes the recovery code if it is valid
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let code = code.trim();
+        match self.recovery_codes.iter().position(|hashed| hashed == code) {
+            Some(index) => {
+                self.recovery_codes.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_codes.len()
+    }
+}
+
+#[test]
+fn test_rfc6238_vectors() {
+    // the RFC 6238 SHA1 test secret "12345678901234567890"
+    let secret = TotpSecret(data_encoding::BASE32_NOPAD.encode(b"12345678901234567890"));
+    assert_eq!(secret.code_at(59), "287082");
+    assert_eq!(secret.code_at(1111111109), "081804");
+    assert_eq!(secret.code_at(1234567890), "005924");
+    assert_eq!(secret.code_at(2000000000), "279037");
+}
+
+#[test]
+fn test_totp_config() {
+    let (mut config, recovery_codes) = TotpConfig::new();
+    let now = 1_700_000_000;
+    let code = config.secret.code_at(now);
+    assert!(!config.verify_code("000000x", now));
+    assert!(config.verify_code(&code, now + TOTP_STEP));
+    // replaying the same code fails
+    assert!(!config.verify_code(&code, now));
+    assert!(config.verify_code(&config.secret.code_at(now + TOTP_STEP), now + TOTP_STEP));
+    // codes outside the skew window are rejected
+    let old_code = config.secret.code_at(now - 10 * TOTP_STEP);
+    assert!(!config.verify_code(&old_code, now + 2 * TOTP_STEP));
+
+    assert!(config.use_recovery_code(&recovery_codes[0]));
+    assert!(!config.use_recovery_code(&recovery_codes[0]));
+    assert_eq!(config.recovery_codes_left(), RECOVERY_CODE_COUNT - 1);
+}
@@ -1,7 +1,12 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use argon2::{Argon2, PasswordVerifier};
 use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
 use jsonwebtoken::{Algorithm, Validation};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
@@ -13,12 +18,19 @@ use crate::{
     event_broadcaster::EventBroadcaster,
     events::{CausedBy, Event, EventInner, UserEvent, UserEventInner},
     types::{InstanceUuid, Snowflake},
+    util::rand_alphanumeric,
 };
 
 use super::{
+    api_token::{parse_api_token, ApiToken, ApiTokenId, PublicApiToken},
     hashed_password::{hash_password, HashedPassword},
     jwt_token::JwtToken,
+    login_throttle::LoginThrottle,
+    oidc::{OidcIdentity, OidcSettings, OidcSubject},
     permission::UserPermission,
+    role::{Role, RoleGrant, RoleId},
+    session::{LoginClient, Session, SessionId},
+    totp::{TotpConfig, TotpSecret},
     user_id::UserId,
     user_secrets::UserSecret,
 };
@@ -27,16 +39,44 @@ use super::{
 pub struct Claim {
     pub uid: UserId,
     pub exp: usize,
+    /// Tokens issued by a login belong to a session, others are only revoked by a full logout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<SessionId>,
 }
+
+/// Sessions are dropped once their token would have expired
+const SESSION_LIFETIME_DAYS: i64 = 60;
+/// The least recently used sessions are dropped past this
+const MAX_SESSIONS_PER_USER: usize = 32;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct User {
     pub uid: UserId,
     pub username: String,
     pub hashed_psw: HashedPassword,
+    #[serde(default)]
+    pub totp: Option<TotpConfig>,
+    /// Set for users provisioned by single sign-on
+    #[serde(default)]
+    pub oidc_subject: Option<OidcSubject>,
     pub is_owner: bool,
     pub is_admin: bool,
     pub permissions: UserPermission,
     pub secret: UserSecret,
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+    /// Set if this user was authenticated with an API token
+    #[serde(skip)]
+    pub api_token_id: Option<ApiTokenId>,
+    #[serde(default)]
+    pub roles: HashSet<RoleId>,
+    /// The user's roles resolved by `UsersManager`, empty until then
+    #[serde(skip)]
+    pub role_grants: Vec<RoleGrant>,
+    #[serde(default)]
+    pub sessions: Vec<Session>,
+    /// Set if this user was authenticated with a session token
+    #[serde(skip)]
+    pub session_id: Option<SessionId>,
 }
 
 impl User {
@@ -51,12 +91,24 @@ impl User {
             uid: UserId::default(),
             username,
             hashed_psw: hash_password(password),
+            totp: None,
+            oidc_subject: None,
             is_owner,
             is_admin,
             permissions,
             secret: UserSecret::default(),
+            api_tokens: Vec::new(),
+            api_token_id: None,
+            roles: HashSet::new(),
+            role_grants: Vec::new(),
+            sessions: Vec::new(),
+            session_id: None,
         }
     }
+    pub fn has_totp_enabled(&self) -> bool {
+        self.totp.as_ref().map_or(false, |totp| totp.confirmed)
+    }
+
     fn get_permission_level(&self) -> u8 {
         if self.is_owner {
             u8::MAX
@@ -66,11 +118,8 @@ impl User {
             1
         }
     }
-    pub fn update_permission(
-        &self,
-        other: &mut User,
-        permissions: UserPermission,
-    ) -> Result<(), Error> {
+    /// Checks that this user may change the permissions or roles of `other`
+    fn check_can_grant(&self, other: &User, grants_unsafe: bool) -> Result<(), Error> {
         if self.get_permission_level() <= other.get_permission_level() {
             return Err(Error {
                 kind: ErrorKind::PermissionDenied,
@@ -78,38 +127,64 @@ impl User {
             });
         }
         if self.is_owner {
-            other.permissions = permissions;
             Ok(())
-        } else {
+        } else if grants_unsafe {
             // reject granting any unsafe permission
-            if !permissions.can_write_instance_resource.is_empty()
-                || !permissions.can_access_instance_macro.is_empty()
-                || permissions.can_write_global_file
-                || permissions.can_manage_permission
-                || !permissions.can_write_instance_file.is_empty()
-            {
-                Err(Error {
-                    kind: ErrorKind::PermissionDenied,
-                    source: eyre!(
-                        "Unsafe and owner exclusive permissions can only be granted by the owner"
-                    ),
-                })
-            } else if self.is_admin || self.permissions.can_manage_permission {
-                other.permissions = permissions;
-                Ok(())
-            } else {
-                Err(Error {
-                    kind: ErrorKind::PermissionDenied,
-                    source: eyre!("You don't have permission to manage other users' permission"),
-                })
+            Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!(
+                    "Unsafe and owner exclusive permissions can only be granted by the owner"
+                ),
+            })
+        } else if self.is_admin || self.can_perform_action(&UserAction::ManagePermission) {
+            Ok(())
+        } else {
+            Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("You don't have permission to manage other users' permission"),
+            })
+        }
+    }
+
+    pub fn update_permission(
+        &self,
+        other: &mut User,
+        permissions: UserPermission,
+    ) -> Result<(), Error> {
+        self.check_can_grant(other, permissions.has_unsafe_permission())?;
+        other.permissions = permissions;
+        Ok(())
+    }
+
+    pub fn update_roles(
+        &self,
+        other: &mut User,
+        roles: HashSet<RoleId>,
+        available_roles: &HashMap<RoleId, Role>,
+    ) -> Result<(), Error> {
+        let mut grants_unsafe = false;
+        for role_id in &roles {
+            let role = available_roles.get(role_id).ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Role {} not found", role_id),
+            })?;
+            // only newly assigned roles count, so non-owners can still remove roles
+            if !other.roles.contains(role_id) {
+                grants_unsafe |= role.permissions.has_unsafe_permission();
             }
         }
+        self.check_can_grant(other, grants_unsafe)?;
+        other.roles = roles;
+        Ok(())
     }
 
     pub fn can_perform_action(&self, action: &UserAction) -> bool {
         if self.is_owner {
             return true;
         }
+        if self.role_grants.iter().any(|grant| grant.grants(action)) {
+            return true;
+        }
         match action {
             UserAction::ViewInstance(instance_id) => {
                 self.is_admin || self.permissions.can_view_instance.contains(instance_id)
@@ -255,14 +330,87 @@ impl User {
         }
     }
 
-    pub fn create_jwt(&self) -> Result<JwtToken, Error> {
+    fn scoped_instances(
+        &self,
+        scope: &HashSet<InstanceUuid>,
+        action: fn(InstanceUuid) -> UserAction,
+    ) -> HashSet<InstanceUuid> {
+        scope
+            .iter()
+            .filter(|uuid| self.can_perform_action(&action((*uuid).clone())))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns a copy of this user that can only perform the actions in `scopes` that
+    /// the user itself is allowed to perform.
+    pub fn with_scopes(&self, scopes: &UserPermission, api_token_id: ApiTokenId) -> User {
+        let permissions = UserPermission {
+            can_view_instance: self
+                .scoped_instances(&scopes.can_view_instance, UserAction::ViewInstance),
+            can_start_instance: self
+                .scoped_instances(&scopes.can_start_instance, UserAction::StartInstance),
+            can_stop_instance: self
+                .scoped_instances(&scopes.can_stop_instance, UserAction::StopInstance),
+            can_access_instance_console: self.scoped_instances(
+                &scopes.can_access_instance_console,
+                UserAction::AccessConsole,
+            ),
+            can_access_instance_setting: self.scoped_instances(
+                &scopes.can_access_instance_setting,
+                UserAction::AccessSetting,
+            ),
+            can_read_instance_resource: self
+                .scoped_instances(&scopes.can_read_instance_resource, UserAction::ReadResource),
+            can_write_instance_resource: self.scoped_instances(
+                &scopes.can_write_instance_resource,
+                UserAction::WriteResource,
+            ),
+            can_access_instance_macro: self
+                .scoped_instances(&scopes.can_access_instance_macro, |uuid| {
+                    UserAction::AccessMacro(Some(uuid))
+                }),
+            can_read_instance_file: self
+                .scoped_instances(&scopes.can_read_instance_file, UserAction::ReadInstanceFile),
+            can_write_instance_file: self.scoped_instances(
+                &scopes.can_write_instance_file,
+                UserAction::WriteInstanceFile,
+            ),
+            can_create_instance: scopes.can_create_instance
+                && self.can_perform_action(&UserAction::CreateInstance),
+            can_delete_instance: scopes.can_delete_instance
+                && self.can_perform_action(&UserAction::DeleteInstance),
+            can_read_global_file: scopes.can_read_global_file
+                && self.can_perform_action(&UserAction::ReadGlobalFile),
+            can_write_global_file: scopes.can_write_global_file
+                && self.can_perform_action(&UserAction::WriteGlobalFile),
+            can_manage_permission: scopes.can_manage_permission
+                && self.can_perform_action(&UserAction::ManagePermission),
+            can_install_extension: scopes.can_install_extension
+                && self.can_perform_action(&UserAction::InstallExtension),
+        };
+        User {
+            is_owner: false,
+            is_admin: false,
+            permissions,
+            api_tokens: Vec::new(),
+            api_token_id: Some(api_token_id),
+            role_grants: Vec::new(),
+            sessions: Vec::new(),
+            session_id: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn create_session_jwt(&self, session_id: Option<SessionId>) -> Result<JwtToken, Error> {
         let exp = chrono::Utc::now()
-            .checked_add_signed(chrono::Duration::days(60))
+            .checked_add_signed(chrono::Duration::days(SESSION_LIFETIME_DAYS))
             .ok_or_else(|| eyre!("Failed to create JWT token"))?
             .timestamp();
         let claim = Claim {
             uid: self.uid.clone(),
             exp: exp as usize,
+            sid: session_id,
         };
 
         JwtToken::new(claim, self.secret.clone())
@@ -324,6 +472,8 @@ pub struct PublicUser {
     pub is_owner: bool,
     pub is_admin: bool,
     pub permissions: UserPermission,
+    pub roles: HashSet<RoleId>,
+    pub totp_enabled: bool,
 }
 
 impl From<&User> for PublicUser {
@@ -334,27 +484,47 @@ impl From<&User> for PublicUser {
             is_owner: user.is_owner,
             is_admin: user.is_admin,
             permissions: user.permissions.clone(),
+            roles: user.roles.clone(),
+            totp_enabled: user.has_totp_enabled(),
         }
     }
 }
 
 impl From<User> for PublicUser {
     fn from(user: User) -> Self {
+        let totp_enabled = user.has_totp_enabled();
         PublicUser {
             uid: user.uid,
             username: user.username,
             is_owner: user.is_owner,
             is_admin: user.is_admin,
             permissions: user.permissions,
+            roles: user.roles,
+            totp_enabled,
         }
     }
 }
 
+/// Roles and instance tags, stored next to the users file
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct RolesStore {
+    roles: HashMap<RoleId, Role>,
+    instance_tags: HashMap<InstanceUuid, HashSet<String>>,
+}
+
 #[derive(Clone)]
 pub struct UsersManager {
     event_broadcaster: EventBroadcaster,
     users: HashMap<UserId, User>,
     path_to_users: PathBuf,
+    roles_store: RolesStore,
+    /// Set by the owner, admins without 2FA lose their admin privileges until they enroll
+    require_admin_totp: bool,
+    login_throttle: LoginThrottle,
+    /// Set while single sign-on is enabled, other users have to log in through the identity provider
+    owner_only_password_login: bool,
+    /// Updated on every authenticated request, folded into the users on the next write
+    session_last_used: Arc<DashMap<SessionId, i64>>,
 }
 
 impl UsersManager {
@@ -367,6 +537,11 @@ impl UsersManager {
             event_broadcaster,
             users,
             path_to_users,
+            roles_store: RolesStore::default(),
+            require_admin_totp: false,
+            login_throttle: LoginThrottle::default(),
+            owner_only_password_login: false,
+            session_last_used: Arc::new(DashMap::new()),
         }
     }
     pub async fn load_users(&mut self) -> Result<(), Error> {
@@ -405,9 +580,166 @@ impl UsersManager {
             .context("Failed to deserialize user json")?;
             self.users = users;
         }
+        match tokio::fs::read(self.path_to_roles()).await {
+            Ok(data) => {
+                self.roles_store =
+                    serde_json::from_slice(&data).context("Failed to deserialize roles json")?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.roles_store = RolesStore::default();
+            }
+            Err(e) => Err(e).context(format!(
+                "Failed to read roles file : {}",
+                self.path_to_roles().display()
+            ))?,
+        }
+        Ok(())
+    }
+
+    fn path_to_roles(&self) -> PathBuf {
+        self.path_to_users.with_file_name("roles.json")
+    }
+
+    async fn write_roles_to_file(&self) -> Result<(), Error> {
+        tokio::fs::write(
+            self.path_to_roles(),
+            serde_json::to_string(&self.roles_store).context("Failed to serialize roles json")?,
+        )
+        .await
+        .context(format!(
+            "Failed to write roles json {}",
+            self.path_to_roles().display()
+        ))?;
+        Ok(())
+    }
+
+    /// Attaches the user's resolved roles, which `User::can_perform_action` takes into account.
+    ///
+    /// Also drops the admin privileges of admins without 2FA if the owner requires it.
+    fn with_role_grants(&self, user: &User) -> User {
+        let mut user = user.clone();
+        if self.needs_totp_enrollment(&user) {
+            user.is_admin = false;
+        }
+        user.role_grants = user
+            .roles
+            .iter()
+            .filter_map(|role_id| self.roles_store.roles.get(role_id))
+            .map(|role| role.resolve(&self.roles_store.instance_tags))
+            .collect();
+        user
+    }
+
+    pub fn list_roles(&self) -> Vec<Role> {
+        self.roles_store.roles.values().cloned().collect()
+    }
+
+    pub fn get_role(&self, role_id: &RoleId) -> Option<Role> {
+        self.roles_store.roles.get(role_id).cloned()
+    }
+
+    /// Creates the role, or replaces the existing role with the same id
+    pub async fn upsert_role(&mut self, role: Role) -> Result<(), Error> {
+        let old_role = self.roles_store.roles.insert(role.id.clone(), role.clone());
+        if let Err(e) = self.write_roles_to_file().await {
+            match old_role {
+                Some(old_role) => self.roles_store.roles.insert(role.id, old_role),
+                None => self.roles_store.roles.remove(&role.id),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn delete_role(&mut self, role_id: &RoleId) -> Result<(), Error> {
+        let old_role = self
+            .roles_store
+            .roles
+            .remove(role_id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Role not found"),
+            })?;
+        if let Err(e) = self.write_roles_to_file().await {
+            self.roles_store.roles.insert(role_id.clone(), old_role);
+            return Err(e);
+        }
+        // dangling role ids are ignored when resolving, so failing to clean them up is not fatal
+        for user in self.users.values_mut() {
+            user.roles.remove(role_id);
+        }
+        self.write_to_file().await
+    }
+
+    pub fn get_instance_tags(&self, instance_uuid: &InstanceUuid) -> HashSet<String> {
+        self.roles_store
+            .instance_tags
+            .get(instance_uuid)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn set_instance_tags(
+        &mut self,
+        instance_uuid: InstanceUuid,
+        tags: HashSet<String>,
+    ) -> Result<(), Error> {
+        let old_tags = if tags.is_empty() {
+            self.roles_store.instance_tags.remove(&instance_uuid)
+        } else {
+            self.roles_store
+                .instance_tags
+                .insert(instance_uuid.clone(), tags)
+        };
+        if let Err(e) = self.write_roles_to_file().await {
+            match old_tags {
+                Some(old_tags) => self
+                    .roles_store
+                    .instance_tags
+                    .insert(instance_uuid, old_tags),
+                None => self.roles_store.instance_tags.remove(&instance_uuid),
+            };
+            return Err(e);
+        }
         Ok(())
     }
 
+    /// Replaces the roles of the user `uid`, after checking `requester` is allowed to do so
+    pub async fn update_roles(
+        &mut self,
+        requester: &User,
+        uid: impl AsRef<UserId>,
+        roles: HashSet<RoleId>,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let old_roles = user.roles.clone();
+        requester.update_roles(user, roles.clone(), &self.roles_store.roles)?;
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.as_ref().to_owned(),
+                        user_event_inner: UserEventInner::RolesChanged { new_roles: roles },
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                    user.roles = old_roles;
+                }
+                Err(e)
+            }
+        }
+    }
+
     async fn write_to_file(&self) -> Result<(), Error> {
         let mut file = tokio::fs::File::create(&self.path_to_users)
             .await
@@ -426,7 +758,9 @@ impl UsersManager {
         Ok(())
     }
     pub fn get_user(&self, uid: impl AsRef<UserId>) -> Option<User> {
-        self.users.get(uid.as_ref()).cloned()
+        self.users
+            .get(uid.as_ref())
+            .map(|user| self.with_role_grants(user))
     }
     pub async fn add_user(&mut self, user: User, caused_by: CausedBy) -> Result<(), Error> {
         if self.get_user_by_username(&user.username).is_some() {
@@ -464,7 +798,8 @@ impl UsersManager {
         let user = self.users.remove(uid.as_ref());
         match self.write_to_file().await {
             Ok(()) => {
-                if let Some(_user) = user.as_ref() {
+                if let Some(user) = user.as_ref() {
+                    self.forget_sessions(&user.sessions);
                     self.event_broadcaster.send(Event {
                         event_inner: EventInner::UserEvent(UserEvent {
                             user_id: uid.as_ref().to_owned(),
@@ -500,12 +835,18 @@ impl UsersManager {
             })?
             .secret
             .clone();
-        if let Some(user) = self.users.get_mut(uid.as_ref()) {
-            user.secret = UserSecret::default();
-        }
+        let old_sessions = self
+            .users
+            .get_mut(uid.as_ref())
+            .map(|user| {
+                user.secret = UserSecret::default();
+                std::mem::take(&mut user.sessions)
+            })
+            .unwrap_or_default();
 
         match self.write_to_file().await {
             Ok(_) => {
+                self.forget_sessions(&old_sessions);
                 self.event_broadcaster.send(Event {
                     event_inner: EventInner::UserEvent(UserEvent {
                         user_id: uid.as_ref().to_owned(),
@@ -519,7 +860,8 @@ impl UsersManager {
             }
             Err(e) => {
                 if let Some(user) = self.users.get_mut(uid.as_ref()) {
-                    user.secret = old_secret
+                    user.secret = old_secret;
+                    user.sessions = old_sessions;
                 }
                 Err(e)
             }
@@ -629,7 +971,7 @@ impl UsersManager {
         self.users
             .values()
             .find(|user| user.username == username.as_ref())
-            .cloned()
+            .map(|user| self.with_role_grants(user))
     }
 
     pub async fn update_permissions(
@@ -674,14 +1016,158 @@ impl UsersManager {
         }
     }
 
+    /// Creates an API token for the user, returns the token along with its plaintext form.
+    ///
+    /// The plaintext is not stored and cannot be retrieved later.
+    pub async fn create_api_token(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        name: String,
+        scopes: UserPermission,
+        expiry: Option<i64>,
+        caused_by: CausedBy,
+    ) -> Result<(PublicApiToken, String), Error> {
+        let user = self.get_user(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        if let Some(expiry) = expiry {
+            if expiry <= chrono::Utc::now().timestamp() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Token expiry must be in the future"),
+                });
+            }
+        }
+        let (token, plaintext) = ApiToken::new(name.clone(), scopes, expiry);
+        if user
+            .with_scopes(&token.scopes, token.id.clone())
+            .permissions
+            != token.scopes
+        {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Token scopes exceed the permissions of the user"),
+            });
+        }
+        let public_token = PublicApiToken::from(&token);
+        if let Some(user) = self.users.get_mut(uid.as_ref()) {
+            user.api_tokens.push(token);
+        }
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.as_ref().to_owned(),
+                        user_event_inner: UserEventInner::ApiTokenCreated {
+                            token_id: public_token.id.clone(),
+                            name,
+                        },
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok((public_token, plaintext))
+            }
+            Err(e) => {
+                if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                    user.api_tokens.retain(|t| t.id != public_token.id);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn list_api_tokens(&self, uid: impl AsRef<UserId>) -> Result<Vec<PublicApiToken>, Error> {
+        Ok(self
+            .users
+            .get(uid.as_ref())
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("User id not found"),
+            })?
+            .api_tokens
+            .iter()
+            .map(PublicApiToken::from)
+            .collect())
+    }
+
+    pub async fn revoke_api_token(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        token_id: &ApiTokenId,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let index = user
+            .api_tokens
+            .iter()
+            .position(|t| t.id == token_id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Token not found"),
+            })?;
+        let token = user.api_tokens.remove(index);
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.as_ref().to_owned(),
+                        user_event_inner: UserEventInner::ApiTokenRevoked {
+                            token_id: token_id.clone(),
+                        },
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                    user.api_tokens.insert(index, token);
+                }
+                Err(e)
+            }
+        }
+    }
+
     pub fn try_auth(&self, token: &str) -> Option<User> {
+        if let Some((token_id, secret)) = parse_api_token(token) {
+            let now = chrono::Utc::now().timestamp();
+            return self.users.values().find_map(|user| {
+                let api_token = user.api_tokens.iter().find(|t| t.id == token_id)?;
+                if api_token.verify(secret) && !api_token.is_expired(now) {
+                    Some(
+                        self.with_role_grants(user)
+                            .with_scopes(&api_token.scopes, api_token.id.clone()),
+                    )
+                } else {
+                    None
+                }
+            });
+        }
         let claimed_uid = decode_no_verify(token)?;
         let claimed_requester = self.users.get(&claimed_uid)?;
-        let requester_uid = decode_token(token, &claimed_requester.secret)?;
-        if claimed_uid != requester_uid {
+        let claim = decode_token(token, &claimed_requester.secret)?;
+        if claimed_uid != claim.uid {
             return None;
         }
-        Some(claimed_requester.to_owned())
+        let mut requester = self.with_role_grants(claimed_requester);
+        if let Some(session_id) = claim.sid {
+            // the session was revoked
+            if !requester.sessions.iter().any(|s| s.id == session_id) {
+                return None;
+            }
+            self.session_last_used
+                .insert(session_id.clone(), chrono::Utc::now().timestamp());
+            requester.session_id = Some(session_id);
+        }
+        Some(requester)
     }
 
     pub fn try_auth_or_err(&self, token: &str) -> Result<User, Error> {
@@ -691,82 +1177,720 @@ impl UsersManager {
         })
     }
 
-    pub fn login(
-        &self,
-        username: impl AsRef<str>,
-        password: impl AsRef<str>,
-    ) -> Result<JwtToken, Error> {
-        let user = self.get_user_by_username(username).ok_or_else(|| Error {
-            kind: ErrorKind::Unauthorized,
-            source: eyre!("Credential mismatch"),
-        })?;
-        Argon2::default()
-            .verify_password(
-                password.as_ref().as_bytes(),
-                &argon2::PasswordHash::new(user.hashed_psw.as_ref()).unwrap(),
-            )
-            .map_err(|_| Error {
-                kind: ErrorKind::Unauthorized,
-                source: eyre!("Credential mismatch"),
-            })?;
-        user.create_jwt()
+    pub fn set_require_admin_totp(&mut self, require_admin_totp: bool) {
+        self.require_admin_totp = require_admin_totp;
     }
-}
 
-fn decode_token(token: &str, jwt_secret: &UserSecret) -> Option<UserId> {
-    match jsonwebtoken::decode::<Claim>(
-        token,
-        &jsonwebtoken::DecodingKey::from_secret(jwt_secret.as_ref().as_bytes()),
-        &Validation::new(Algorithm::HS512),
-    ) {
-        Ok(t) => Some(t.claims.uid),
-        Err(_) => None,
+    /// Single sign-on users are exempt, their second factor is up to the identity provider
+    fn needs_totp_enrollment(&self, user: &User) -> bool {
+        self.require_admin_totp
+            && user.is_admin
+            && !user.is_owner
+            && user.oidc_subject.is_none()
+            && !user.has_totp_enabled()
     }
-}
 
-fn decode_no_verify(token: &str) -> Option<UserId> {
-    let mut no_verify = Validation::new(Algorithm::HS512);
-    no_verify.insecure_disable_signature_validation();
-    match jsonwebtoken::decode::<Claim>(
-        token,
-        &jsonwebtoken::DecodingKey::from_secret("noverify".as_bytes()),
-        &no_verify,
-    ) {
-        Ok(t) => Some(t.claims.uid),
-        Err(_) => None,
+    /// Whether the user is an admin that has to enroll in 2FA to keep their privileges
+    pub fn totp_enrollment_required(&self, uid: impl AsRef<UserId>) -> bool {
+        self.users
+            .get(uid.as_ref())
+            .map_or(false, |user| self.needs_totp_enrollment(user))
     }
-}
 
-impl AsRef<HashMap<UserId, User>> for UsersManager {
-    fn as_ref(&self) -> &HashMap<UserId, User> {
-        &self.users
+    pub fn set_owner_only_password_login(&mut self, owner_only_password_login: bool) {
+        self.owner_only_password_login = owner_only_password_login;
     }
-}
-
-mod tests {
-
-    #[tokio::test]
-    async fn test_login() {
-        use super::*;
-        // create a temporary folder
-        let temp_dir = tempdir::TempDir::new("test_login").unwrap().into_path();
-        let (tx, _rx) = EventBroadcaster::new(10);
-        let mut users_manager =
-            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
-        let test_user1 = User::new(
-            "test_user1".to_string(),
-            "12345",
-            true,
-            false,
-            UserPermission::default(),
-        );
+
+    /// Checks a TOTP or recovery code of a user with 2FA enabled, persisting the used code.
+    ///
+    /// Users without 2FA always pass.
+    async fn check_second_factor(
+        &mut self,
+        uid: &UserId,
+        code: Option<&str>,
+        unix_time: u64,
+    ) -> Result<(), Error> {
+        let user = self.users.get_mut(uid).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let totp = match user.totp.as_mut() {
+            Some(totp) if totp.confirmed => totp,
+            _ => return Ok(()),
+        };
+        let code = code.ok_or_else(|| Error {
+            kind: ErrorKind::Unauthorized,
+            source: eyre!("Two-factor authentication code required"),
+        })?;
+        let old_totp = totp.clone();
+        let used_recovery_code = if totp.verify_code(code, unix_time) {
+            false
+        } else if totp.use_recovery_code(code) {
+            true
+        } else {
+            return Err(Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("Invalid two-factor authentication code"),
+            });
+        };
+        let caused_by = CausedBy::User {
+            user_id: user.uid.clone(),
+            user_name: user.username.clone(),
+        };
+        // the used code must not be accepted again, so fail if it can't be persisted
+        if let Err(e) = self.write_to_file().await {
+            if let Some(user) = self.users.get_mut(uid) {
+                user.totp = Some(old_totp);
+            }
+            return Err(e);
+        }
+        if used_recovery_code {
+            self.event_broadcaster.send(Event {
+                event_inner: EventInner::UserEvent(UserEvent {
+                    user_id: uid.clone(),
+                    user_event_inner: UserEventInner::RecoveryCodeUsed,
+                }),
+                details: "".to_string(),
+                snowflake: Snowflake::default(),
+                caused_by,
+            });
+        }
+        Ok(())
+    }
+
+    /// Generates a new TOTP secret and recovery codes for the user.
+    ///
+    /// 2FA is only enforced once the enrollment is confirmed with `confirm_totp`.
+    pub async fn begin_totp_enrollment(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        issuer: &str,
+    ) -> Result<TotpEnrollment, Error> {
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        if user.has_totp_enabled() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Two-factor authentication is already enabled"),
+            });
+        }
+        let (totp, recovery_codes) = TotpConfig::new();
+        let provisioning_uri = totp.secret.provisioning_uri(issuer, &user.username);
+        let enrollment = TotpEnrollment {
+            secret: totp.secret.clone(),
+            qr_payload: provisioning_uri.clone(),
+            provisioning_uri,
+            recovery_codes,
+        };
+        let old_totp = user.totp.replace(totp);
+        if let Err(e) = self.write_to_file().await {
+            if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                user.totp = old_totp;
+            }
+            return Err(e);
+        }
+        Ok(enrollment)
+    }
+
+    pub async fn confirm_totp(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        code: &str,
+        unix_time: u64,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let totp = match user.totp.as_mut() {
+            Some(totp) if !totp.confirmed => totp,
+            Some(_) => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Two-factor authentication is already enabled"),
+                })
+            }
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("No two-factor enrollment in progress"),
+                })
+            }
+        };
+        let old_totp = totp.clone();
+        if !totp.verify_code(code, unix_time) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid two-factor authentication code"),
+            });
+        }
+        totp.confirmed = true;
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.as_ref().to_owned(),
+                        user_event_inner: UserEventInner::TotpEnabled,
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                    user.totp = Some(old_totp);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes 2FA from the user.
+    ///
+    /// If `code` is `None` the caller is responsible for authorizing the reset.
+    pub async fn disable_totp(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        code: Option<&str>,
+        unix_time: u64,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        if code.is_some() {
+            self.check_second_factor(uid.as_ref(), code, unix_time)
+                .await?;
+        }
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        if user.totp.is_none() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Two-factor authentication is not enabled"),
+            });
+        }
+        let old_totp = user.totp.take();
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.as_ref().to_owned(),
+                        user_event_inner: UserEventInner::TotpDisabled,
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                    user.totp = old_totp;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Logs in with a password, and the TOTP or recovery code if the user has 2FA enabled.
+    ///
+    /// Every successful login starts a new session that can be revoked on its own.
+    pub async fn login(
+        &mut self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        second_factor: Option<&str>,
+        client: LoginClient,
+    ) -> Result<JwtToken, Error> {
+        self.login_at(
+            username,
+            password,
+            second_factor,
+            client,
+            chrono::Utc::now().timestamp() as u64,
+        )
+        .await
+    }
+
+    async fn login_at(
+        &mut self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        second_factor: Option<&str>,
+        client: LoginClient,
+        unix_time: u64,
+    ) -> Result<JwtToken, Error> {
+        let user = self
+            .verify_credentials_at(username, password, second_factor, &client, unix_time)
+            .await?;
+        let session_id = self
+            .start_session(&user.uid, client, unix_time as i64)
+            .await?;
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::UserEvent(UserEvent {
+                user_id: user.uid.clone(),
+                user_event_inner: UserEventInner::UserLoggedIn,
+            }),
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            caused_by: CausedBy::User {
+                user_id: user.uid.clone(),
+                user_name: user.username.clone(),
+            },
+        });
+        user.create_session_jwt(Some(session_id))
+    }
+
+    /// Checks a password the same way [`Self::login`] does, without starting a session.
+    ///
+    /// Used by the SFTP server, which keeps the user for the lifetime of the connection instead.
+    pub async fn verify_password(
+        &mut self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        client: &LoginClient,
+    ) -> Result<User, Error> {
+        self.verify_credentials_at(
+            username,
+            password,
+            None,
+            client,
+            chrono::Utc::now().timestamp() as u64,
+        )
+        .await
+    }
+
+    async fn verify_credentials_at(
+        &mut self,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        second_factor: Option<&str>,
+        client: &LoginClient,
+        unix_time: u64,
+    ) -> Result<User, Error> {
+        let username = username.as_ref();
+        let now = unix_time as i64;
+        if let Some(locked_until) =
+            self.login_throttle
+                .locked_until(username, client.ip.as_deref(), now)
+        {
+            return Err(Error {
+                kind: ErrorKind::TooManyRequests,
+                source: eyre!(
+                    "Too many failed login attempts, try again in {} seconds",
+                    locked_until - now
+                ),
+            });
+        }
+        let user = match self.get_user_by_username(username) {
+            Some(user) => user,
+            None => {
+                self.record_failed_login(username, None, client, "Unknown username", now);
+                return Err(Error {
+                    kind: ErrorKind::Unauthorized,
+                    source: eyre!("Credential mismatch"),
+                });
+            }
+        };
+        if Argon2::default()
+            .verify_password(
+                password.as_ref().as_bytes(),
+                &argon2::PasswordHash::new(user.hashed_psw.as_ref()).unwrap(),
+            )
+            .is_err()
+        {
+            self.record_failed_login(username, Some(&user.uid), client, "Wrong password", now);
+            return Err(Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("Credential mismatch"),
+            });
+        }
+        if self.owner_only_password_login && !user.is_owner {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Password login is disabled, log in with single sign-on instead"),
+            });
+        }
+        if let Err(e) = self
+            .check_second_factor(&user.uid, second_factor, unix_time)
+            .await
+        {
+            // asking for the code is part of the normal flow, only wrong codes count as failures
+            if second_factor.is_some() {
+                self.record_failed_login(
+                    username,
+                    Some(&user.uid),
+                    client,
+                    "Invalid two-factor authentication code",
+                    now,
+                );
+            }
+            return Err(e);
+        }
+        self.login_throttle
+            .record_success(username, client.ip.as_deref());
+        Ok(user)
+    }
+
+    /// Refuses to start a single sign-on login for an address that started too many
+    pub fn throttle_oidc_login(&mut self, client: &LoginClient) -> Result<(), Error> {
+        let ip = match &client.ip {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        let now = chrono::Utc::now().timestamp();
+        match self.login_throttle.record_oidc_login(ip, now) {
+            Some(locked_until) => Err(Error {
+                kind: ErrorKind::TooManyRequests,
+                source: eyre!(
+                    "Too many single sign-on logins started, try again in {} seconds",
+                    locked_until - now
+                ),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs in a user authenticated by the identity provider, creating the user on first login.
+    ///
+    /// Admin status and roles are synced from the identity's groups on every login.
+    pub async fn oidc_login(
+        &mut self,
+        identity: OidcIdentity,
+        settings: &OidcSettings,
+        client: LoginClient,
+    ) -> Result<(JwtToken, User), Error> {
+        let subject = OidcSubject {
+            issuer: identity.issuer.clone(),
+            subject: identity.subject.clone(),
+        };
+        let existing_uid = self
+            .users
+            .values()
+            .find(|user| user.oidc_subject.as_ref() == Some(&subject))
+            .map(|user| user.uid.clone());
+        let is_admin = settings.is_admin(&identity);
+        // roles that were deleted since the settings were written are skipped
+        let roles: HashSet<RoleId> = settings
+            .roles(&identity)
+            .into_iter()
+            .filter(|role_id| self.roles_store.roles.contains_key(role_id))
+            .collect();
+        let caused_by = CausedBy::System;
+        let uid = match existing_uid {
+            Some(uid) => {
+                let user = self.users.get_mut(&uid).ok_or_else(|| Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("User id not found"),
+                })?;
+                if user.is_admin != is_admin || user.roles != roles {
+                    let (old_is_admin, old_roles) = (user.is_admin, user.roles.clone());
+                    user.is_admin = is_admin;
+                    user.roles = roles.clone();
+                    if let Err(e) = self.write_to_file().await {
+                        if let Some(user) = self.users.get_mut(&uid) {
+                            user.is_admin = old_is_admin;
+                            user.roles = old_roles;
+                        }
+                        return Err(e);
+                    }
+                    self.event_broadcaster.send(Event {
+                        event_inner: EventInner::UserEvent(UserEvent {
+                            user_id: uid.clone(),
+                            user_event_inner: UserEventInner::RolesChanged { new_roles: roles },
+                        }),
+                        details: "".to_string(),
+                        snowflake: Snowflake::default(),
+                        caused_by,
+                    });
+                }
+                uid
+            }
+            None => {
+                let base_username = identity
+                    .username
+                    .clone()
+                    .unwrap_or_else(|| identity.subject.clone());
+                let mut username = base_username.clone();
+                let mut suffix = 2;
+                while self.get_user_by_username(&username).is_some() {
+                    username = format!("{base_username}_{suffix}");
+                    suffix += 1;
+                }
+                // nobody knows the password, the user can only log in through the identity provider
+                let mut user = User::new(
+                    username,
+                    rand_alphanumeric(32),
+                    false,
+                    is_admin,
+                    UserPermission::default(),
+                );
+                user.roles = roles;
+                user.oidc_subject = Some(subject);
+                let uid = user.uid.clone();
+                self.add_user(user, caused_by).await?;
+                uid
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        let session_id = self.start_session(&uid, client, now).await?;
+        let user = self.get_user(&uid).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::UserEvent(UserEvent {
+                user_id: uid,
+                user_event_inner: UserEventInner::UserLoggedIn,
+            }),
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            caused_by: CausedBy::User {
+                user_id: user.uid.clone(),
+                user_name: user.username.clone(),
+            },
+        });
+        Ok((user.create_session_jwt(Some(session_id))?, user))
+    }
+
+    fn record_failed_login(
+        &mut self,
+        username: &str,
+        uid: Option<&UserId>,
+        client: &LoginClient,
+        reason: &str,
+        now: i64,
+    ) {
+        self.login_throttle
+            .record_failure(username, client.ip.as_deref(), now);
+        match uid {
+            Some(uid) => self.event_broadcaster.send(Event {
+                event_inner: EventInner::UserEvent(UserEvent {
+                    user_id: uid.clone(),
+                    user_event_inner: UserEventInner::LoginFailed {
+                        ip: client.ip.clone(),
+                        reason: reason.to_string(),
+                    },
+                }),
+                details: "".to_string(),
+                snowflake: Snowflake::default(),
+                caused_by: CausedBy::Unknown,
+            }),
+            // there is no user to attach an event to
+            None => warn!(
+                "Failed login for unknown user {} from {}",
+                username,
+                client.ip.as_deref().unwrap_or("unknown address")
+            ),
+        }
+    }
+
+    /// Folds the in-memory last used times into the users so they get persisted
+    fn flush_session_last_used(&mut self) {
+        for user in self.users.values_mut() {
+            for session in user.sessions.iter_mut() {
+                if let Some((_, last_used)) = self.session_last_used.remove(&session.id) {
+                    session.last_used = session.last_used.max(last_used);
+                }
+            }
+        }
+    }
+
+    /// Drops the in-memory last used times of sessions that no longer exist
+    fn forget_sessions(&self, sessions: &[Session]) {
+        for session in sessions {
+            self.session_last_used.remove(&session.id);
+        }
+    }
+
+    /// Starts a session for the user without checking any credential, for a user who was just
+    /// created. The token can be listed and revoked like the ones from [`Self::login`]
+    pub async fn create_session_token(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        client: LoginClient,
+    ) -> Result<JwtToken, Error> {
+        let user = self.get_user(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let session_id = self
+            .start_session(&user.uid, client, chrono::Utc::now().timestamp())
+            .await?;
+        user.create_session_jwt(Some(session_id))
+    }
+
+    async fn start_session(
+        &mut self,
+        uid: &UserId,
+        client: LoginClient,
+        now: i64,
+    ) -> Result<SessionId, Error> {
+        self.flush_session_last_used();
+        let user = self.users.get_mut(uid).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let old_sessions = user.sessions.clone();
+        let session = Session::new(client, now);
+        let session_id = session.id.clone();
+        user.sessions
+            .retain(|s| now - s.creation_time < SESSION_LIFETIME_DAYS * 24 * 60 * 60);
+        user.sessions.push(session);
+        if user.sessions.len() > MAX_SESSIONS_PER_USER {
+            user.sessions
+                .sort_by_key(|s| std::cmp::Reverse(s.last_used));
+            user.sessions.truncate(MAX_SESSIONS_PER_USER);
+        }
+        if let Err(e) = self.write_to_file().await {
+            if let Some(user) = self.users.get_mut(uid) {
+                user.sessions = old_sessions;
+            }
+            return Err(e);
+        }
+        Ok(session_id)
+    }
+
+    pub fn list_sessions(&self, uid: impl AsRef<UserId>) -> Result<Vec<Session>, Error> {
+        Ok(self
+            .users
+            .get(uid.as_ref())
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("User id not found"),
+            })?
+            .sessions
+            .iter()
+            .map(|session| {
+                let mut session = session.clone();
+                if let Some(last_used) = self.session_last_used.get(&session.id) {
+                    session.last_used = session.last_used.max(*last_used);
+                }
+                session
+            })
+            .collect())
+    }
+
+    /// Revokes a single session, other devices stay logged in
+    pub async fn revoke_session(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        session_id: &SessionId,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        self.flush_session_last_used();
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let index = user
+            .sessions
+            .iter()
+            .position(|s| s.id == session_id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Session not found"),
+            })?;
+        let session = user.sessions.remove(index);
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.forget_sessions(std::slice::from_ref(&session));
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.as_ref().to_owned(),
+                        user_event_inner: UserEventInner::SessionRevoked {
+                            session_id: session_id.clone(),
+                        },
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                    user.sessions.insert(index, session);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Handed out once when enrolling in 2FA
+#[derive(Serialize, Clone, TS)]
+#[ts(export)]
+pub struct TotpEnrollment {
+    pub secret: TotpSecret,
+    pub provisioning_uri: String,
+    /// The payload to encode in the QR code scanned by authenticator apps
+    pub qr_payload: String,
+    pub recovery_codes: Vec<String>,
+}
+
+fn decode_token(token: &str, jwt_secret: &UserSecret) -> Option<Claim> {
+    match jsonwebtoken::decode::<Claim>(
+        token,
+        &jsonwebtoken::DecodingKey::from_secret(jwt_secret.as_ref().as_bytes()),
+        &Validation::new(Algorithm::HS512),
+    ) {
+        Ok(t) => Some(t.claims),
+        Err(_) => None,
+    }
+}
+
+fn decode_no_verify(token: &str) -> Option<UserId> {
+    let mut no_verify = Validation::new(Algorithm::HS512);
+    no_verify.insecure_disable_signature_validation();
+    match jsonwebtoken::decode::<Claim>(
+        token,
+        &jsonwebtoken::DecodingKey::from_secret("noverify".as_bytes()),
+        &no_verify,
+    ) {
+        Ok(t) => Some(t.claims.uid),
+        Err(_) => None,
+    }
+}
+
+impl AsRef<HashMap<UserId, User>> for UsersManager {
+    fn as_ref(&self) -> &HashMap<UserId, User> {
+        &self.users
+    }
+}
+
+mod tests {
+
+    #[tokio::test]
+    async fn test_login() {
+        use super::*;
+        // create a temporary folder
+        let temp_dir = tempdir::TempDir::new("test_login").unwrap().into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let test_user1 = User::new(
+            "test_user1".to_string(),
+            "12345",
+            true,
+            false,
+            UserPermission::default(),
+        );
 
         users_manager
             .add_user(test_user1.clone(), CausedBy::System)
             .await
             .unwrap();
 
-        users_manager.login("test_user1", "12345").unwrap();
+        users_manager
+            .login("test_user1", "12345", None, LoginClient::default())
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
@@ -790,7 +1914,10 @@ mod tests {
             .await
             .unwrap();
 
-        users_manager.login("test_user1", "12345").unwrap();
+        users_manager
+            .login("test_user1", "12345", None, LoginClient::default())
+            .await
+            .unwrap();
 
         users_manager
             .change_password(
@@ -802,7 +1929,10 @@ mod tests {
             .await
             .unwrap();
 
-        users_manager.login("test_user1", "54321").unwrap();
+        users_manager
+            .login("test_user1", "54321", None, LoginClient::default())
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
@@ -861,4 +1991,508 @@ mod tests {
 
         assert!(users_manager.get_user_by_username("test_user1").is_some());
     }
+
+    #[tokio::test]
+    async fn test_api_token_auth() {
+        use super::*;
+        let temp_dir = tempdir::TempDir::new("test_api_token").unwrap().into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let instance_a = InstanceUuid::default();
+        let instance_b = InstanceUuid::default();
+        let mut permissions = UserPermission::default();
+        permissions.can_view_instance.insert(instance_a.clone());
+        permissions.can_view_instance.insert(instance_b.clone());
+        permissions.can_start_instance.insert(instance_a.clone());
+        let test_user1 = User::new("test_user1".to_string(), "12345", false, false, permissions);
+        users_manager
+            .add_user(test_user1.clone(), CausedBy::System)
+            .await
+            .unwrap();
+
+        // scopes beyond the user's own permissions are rejected
+        let mut too_broad = UserPermission::default();
+        too_broad.can_stop_instance.insert(instance_a.clone());
+        assert!(users_manager
+            .create_api_token(
+                &test_user1.uid,
+                "ci".to_string(),
+                too_broad,
+                None,
+                CausedBy::System
+            )
+            .await
+            .is_err());
+
+        let mut scopes = UserPermission::default();
+        scopes.can_view_instance.insert(instance_a.clone());
+        let (info, token) = users_manager
+            .create_api_token(
+                &test_user1.uid,
+                "ci".to_string(),
+                scopes,
+                None,
+                CausedBy::System,
+            )
+            .await
+            .unwrap();
+
+        let requester = users_manager.try_auth(&token).unwrap();
+        assert_eq!(requester.uid, test_user1.uid);
+        assert!(requester.can_perform_action(&UserAction::ViewInstance(instance_a.clone())));
+        assert!(!requester.can_perform_action(&UserAction::ViewInstance(instance_b)));
+        assert!(!requester.can_perform_action(&UserAction::StartInstance(instance_a)));
+
+        // tampered tokens are rejected
+        assert!(users_manager.try_auth(&format!("{token}x")).is_none());
+
+        users_manager
+            .revoke_api_token(&test_user1.uid, &info.id, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(&token).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_roles() {
+        use super::*;
+        use crate::auth::role::{RolePermission, RoleScope};
+        let temp_dir = tempdir::TempDir::new("test_roles").unwrap().into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let owner = User::new(
+            "owner".to_string(),
+            "12345",
+            true,
+            false,
+            UserPermission::default(),
+        );
+        let admin = User::new(
+            "admin".to_string(),
+            "12345",
+            false,
+            true,
+            UserPermission::default(),
+        );
+        let moderator = User::new(
+            "moderator".to_string(),
+            "12345",
+            false,
+            false,
+            UserPermission::default(),
+        );
+        for user in [&owner, &admin, &moderator] {
+            users_manager
+                .add_user(user.clone(), CausedBy::System)
+                .await
+                .unwrap();
+        }
+        let instance = InstanceUuid::default();
+        let console_role = Role {
+            id: RoleId::default(),
+            name: "Console".to_string(),
+            permissions: RolePermission {
+                can_access_instance_console: true,
+                ..Default::default()
+            },
+            scope: RoleScope::Tag {
+                tag: "survival".to_string(),
+            },
+        };
+        let file_role = Role {
+            id: RoleId::default(),
+            name: "Files".to_string(),
+            permissions: RolePermission {
+                can_write_instance_file: true,
+                ..Default::default()
+            },
+            scope: RoleScope::AllInstances,
+        };
+        users_manager
+            .upsert_role(console_role.clone())
+            .await
+            .unwrap();
+        users_manager.upsert_role(file_role.clone()).await.unwrap();
+        users_manager
+            .set_instance_tags(instance.clone(), HashSet::from(["survival".to_string()]))
+            .await
+            .unwrap();
+
+        // only the owner can hand out roles with unsafe permissions
+        assert!(users_manager
+            .update_roles(
+                &admin,
+                &moderator.uid,
+                HashSet::from([file_role.id.clone()]),
+                CausedBy::System
+            )
+            .await
+            .is_err());
+        users_manager
+            .update_roles(
+                &admin,
+                &moderator.uid,
+                HashSet::from([console_role.id.clone()]),
+                CausedBy::System,
+            )
+            .await
+            .unwrap();
+
+        let moderator = users_manager.get_user(&moderator.uid).unwrap();
+        assert!(moderator.can_perform_action(&UserAction::AccessConsole(instance.clone())));
+        assert!(!moderator.can_perform_action(&UserAction::AccessConsole(InstanceUuid::default())));
+        assert!(!moderator.can_perform_action(&UserAction::WriteInstanceFile(instance.clone())));
+
+        users_manager
+            .update_roles(
+                &owner,
+                &moderator.uid,
+                HashSet::from([console_role.id.clone(), file_role.id.clone()]),
+                CausedBy::System,
+            )
+            .await
+            .unwrap();
+        let moderator = users_manager.get_user(&moderator.uid).unwrap();
+        assert!(moderator.can_perform_action(&UserAction::WriteInstanceFile(instance.clone())));
+
+        // deleting a role removes it from every user
+        users_manager.delete_role(&file_role.id).await.unwrap();
+        let moderator = users_manager.get_user(&moderator.uid).unwrap();
+        assert!(!moderator.roles.contains(&file_role.id));
+        assert!(!moderator.can_perform_action(&UserAction::WriteInstanceFile(instance)));
+    }
+
+    #[tokio::test]
+    async fn test_totp_login() {
+        use super::*;
+        let temp_dir = tempdir::TempDir::new("test_totp").unwrap().into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let admin = User::new(
+            "admin".to_string(),
+            "12345",
+            false,
+            true,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(admin.clone(), CausedBy::System)
+            .await
+            .unwrap();
+
+        users_manager.set_require_admin_totp(true);
+        assert!(users_manager.totp_enrollment_required(&admin.uid));
+        assert!(!users_manager.get_user(&admin.uid).unwrap().is_admin);
+
+        let now = 1_700_000_000;
+        let enrollment = users_manager
+            .begin_totp_enrollment(&admin.uid, "Lodestone")
+            .await
+            .unwrap();
+        assert!(enrollment
+            .provisioning_uri
+            .starts_with("otpauth://totp/Lodestone:admin?"));
+        // an unconfirmed enrollment does not change how the user logs in
+        users_manager
+            .login_at("admin", "12345", None, LoginClient::default(), now)
+            .await
+            .unwrap();
+        users_manager
+            .confirm_totp(
+                &admin.uid,
+                &enrollment.secret.code_at(now),
+                now,
+                CausedBy::System,
+            )
+            .await
+            .unwrap();
+        assert!(users_manager.get_user(&admin.uid).unwrap().is_admin);
+
+        let later = now + 300;
+        assert!(users_manager
+            .login_at("admin", "12345", None, LoginClient::default(), later)
+            .await
+            .is_err());
+        assert!(users_manager
+            .login_at(
+                "admin",
+                "12345",
+                Some("000000"),
+                LoginClient::default(),
+                later
+            )
+            .await
+            .is_err());
+        users_manager
+            .login_at(
+                "admin",
+                "12345",
+                Some(&enrollment.secret.code_at(later)),
+                LoginClient::default(),
+                later,
+            )
+            .await
+            .unwrap();
+        // recovery codes work once
+        let recovery_code = enrollment.recovery_codes[0].as_str();
+        users_manager
+            .login_at(
+                "admin",
+                "12345",
+                Some(recovery_code),
+                LoginClient::default(),
+                later,
+            )
+            .await
+            .unwrap();
+        assert!(users_manager
+            .login_at(
+                "admin",
+                "12345",
+                Some(recovery_code),
+                LoginClient::default(),
+                later
+            )
+            .await
+            .is_err());
+
+        // the enrollment survives a restart
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager = UsersManager::new(tx, HashMap::new(), temp_dir.join("users.json"));
+        users_manager.load_users().await.unwrap();
+        assert!(users_manager
+            .get_user(&admin.uid)
+            .unwrap()
+            .has_totp_enabled());
+
+        users_manager
+            .disable_totp(&admin.uid, None, later, CausedBy::System)
+            .await
+            .unwrap();
+        users_manager
+            .login_at("admin", "12345", None, LoginClient::default(), later)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sessions_and_lockout() {
+        use super::*;
+        let temp_dir = tempdir::TempDir::new("test_sessions").unwrap().into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let test_user1 = User::new(
+            "test_user1".to_string(),
+            "12345",
+            false,
+            false,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(test_user1.clone(), CausedBy::System)
+            .await
+            .unwrap();
+
+        let now = chrono::Utc::now().timestamp() as u64;
+        let laptop = LoginClient {
+            ip: Some("10.0.0.1".to_string()),
+            device: Some("laptop".to_string()),
+        };
+        let phone = LoginClient {
+            ip: Some("10.0.0.2".to_string()),
+            device: Some("phone".to_string()),
+        };
+        let laptop_token = users_manager
+            .login_at("test_user1", "12345", None, laptop.clone(), now)
+            .await
+            .unwrap();
+        let phone_token = users_manager
+            .login_at("test_user1", "12345", None, phone.clone(), now)
+            .await
+            .unwrap();
+        assert_eq!(
+            users_manager.list_sessions(&test_user1.uid).unwrap().len(),
+            2
+        );
+
+        // revoking one session keeps the other device logged in
+        let laptop_session = users_manager
+            .try_auth(laptop_token.as_ref())
+            .unwrap()
+            .session_id
+            .unwrap();
+        users_manager
+            .revoke_session(&test_user1.uid, &laptop_session, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(laptop_token.as_ref()).is_none());
+        assert!(users_manager.try_auth(phone_token.as_ref()).is_some());
+
+        // repeated failures lock the account, even for the right password
+        for _ in 0..5 {
+            assert!(users_manager
+                .login_at("test_user1", "wrong", None, laptop.clone(), now)
+                .await
+                .is_err());
+        }
+        let err = users_manager
+            .login_at("test_user1", "12345", None, laptop.clone(), now)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::TooManyRequests));
+        // only for the address the failures came from
+        users_manager
+            .login_at("test_user1", "12345", None, phone, now)
+            .await
+            .unwrap();
+        users_manager
+            .login_at("test_user1", "12345", None, laptop, now + 15 * 60)
+            .await
+            .unwrap();
+
+        // a full logout ends every session
+        assert!(users_manager.try_auth(phone_token.as_ref()).is_some());
+        users_manager
+            .logout_user(&test_user1.uid, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(phone_token.as_ref()).is_none());
+        assert!(users_manager
+            .list_sessions(&test_user1.uid)
+            .unwrap()
+            .is_empty());
+        assert!(users_manager.session_last_used.is_empty());
+
+        // tokens handed out without a login can be revoked too
+        let token = users_manager
+            .create_session_token(&test_user1.uid, LoginClient::default())
+            .await
+            .unwrap();
+        let session_id = users_manager
+            .try_auth(token.as_ref())
+            .unwrap()
+            .session_id
+            .unwrap();
+        assert_eq!(
+            users_manager.list_sessions(&test_user1.uid).unwrap().len(),
+            1
+        );
+        users_manager
+            .revoke_session(&test_user1.uid, &session_id, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(token.as_ref()).is_none());
+        assert!(users_manager.session_last_used.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_oidc_provisioning() {
+        use super::*;
+        use crate::auth::role::{RolePermission, RoleScope};
+        let temp_dir = tempdir::TempDir::new("test_oidc").unwrap().into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let owner = User::new(
+            "steve".to_string(),
+            "12345",
+            true,
+            false,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(owner.clone(), CausedBy::System)
+            .await
+            .unwrap();
+        let viewer_role = Role {
+            id: RoleId::default(),
+            name: "Viewer".to_string(),
+            permissions: RolePermission {
+                can_view_instance: true,
+                ..Default::default()
+            },
+            scope: RoleScope::AllInstances,
+        };
+        users_manager
+            .upsert_role(viewer_role.clone())
+            .await
+            .unwrap();
+        let settings = OidcSettings {
+            enabled: true,
+            issuer_url: "https://idp.example.com".to_string(),
+            client_id: "lodestone".to_string(),
+            client_secret: None,
+            redirect_uri: "http://localhost:3000/login/oidc".to_string(),
+            scopes: vec!["openid".to_string()],
+            username_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+            admin_groups: HashSet::from(["admins".to_string()]),
+            group_roles: HashMap::from([(
+                "players".to_string(),
+                HashSet::from([viewer_role.id.clone()]),
+            )]),
+            allow_password_login: false,
+        };
+        let identity = OidcIdentity {
+            issuer: "https://idp.example.com".to_string(),
+            subject: "1234".to_string(),
+            username: Some("steve".to_string()),
+            groups: HashSet::from(["players".to_string()]),
+        };
+
+        // the first login creates the user, without taking over the owner's username
+        let (_, user) = users_manager
+            .oidc_login(identity.clone(), &settings, LoginClient::default())
+            .await
+            .unwrap();
+        assert_eq!(user.username, "steve_2");
+        assert!(!user.is_admin);
+        assert!(user.can_perform_action(&UserAction::ViewInstance(InstanceUuid::default())));
+
+        // later logins link to the same user and sync the groups
+        let (token, promoted) = users_manager
+            .oidc_login(
+                OidcIdentity {
+                    groups: HashSet::from(["admins".to_string()]),
+                    ..identity
+                },
+                &settings,
+                LoginClient::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(promoted.uid, user.uid);
+        assert!(promoted.is_admin);
+        assert!(promoted.roles.is_empty());
+        assert_eq!(
+            users_manager.try_auth(token.as_ref()).unwrap().uid,
+            user.uid
+        );
+
+        // while SSO is on only the owner can use a password
+        users_manager.set_owner_only_password_login(true);
+        users_manager
+            .login("steve", "12345", None, LoginClient::default())
+            .await
+            .unwrap();
+        let member = User::new(
+            "alex".to_string(),
+            "12345",
+            false,
+            false,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(member, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager
+            .login("alex", "12345", None, LoginClient::default())
+            .await
+            .is_err());
+    }
 }
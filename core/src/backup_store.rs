@@ -0,0 +1,1175 @@
+//! A deduplicating backup repository shared by all instances.
+//!
+//! Files are split into content-defined chunks, so a world where only a few region files
+//! changed only stores those chunks again. Chunks are named by their SHA-256 and zstd compressed,
+//! each snapshot is a JSON manifest listing the chunks of every file.
+//!
+//! ```text
+//! backups/
+//!   chunks/ab/abcdef...
+//!   snapshots/<instance uuid>/<snapshot id>.json
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use ts_rs::TS;
+use walkdir::WalkDir;
+
+use crate::{
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    fs_history::HISTORY_DIR_NAME,
+    handlers::instance_fs::is_path_protected,
+    prelude::path_to_tmp,
+    types::{InstanceUuid, Snowflake},
+    util::{archive_files, format_byte, scoped_join_win_safe, ArchiveFormat},
+};
+
+const MIN_CHUNK_SIZE: usize = 512 * 1024;
+const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// Cuts a chunk on average every 1 MiB past the minimum size
+const CHUNK_MASK: u64 = (1 << 20) - 1;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Random values for the rolling hash, generated with splitmix64 so they never change
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut seed: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+/// Where the chunk starting at the beginning of `data` ends
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if hash & CHUNK_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Splits a stream into content-defined chunks
+struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        if !self.eof && self.buf.len() < MAX_CHUNK_SIZE {
+            let wanted = (MAX_CHUNK_SIZE - self.buf.len()) as u64;
+            let read = self
+                .reader
+                .by_ref()
+                .take(wanted)
+                .read_to_end(&mut self.buf)?;
+            if (read as u64) < wanted {
+                self.eof = true;
+            }
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(cut_point(&self.buf));
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum SnapshotEntryKind {
+    Directory,
+    File {
+        size: u64,
+        /// Last modification in unix milliseconds, unchanged files reuse the previous chunks
+        modified: Option<i64>,
+        chunks: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct SnapshotEntry {
+    /// Relative to the instance root, separated by `/`
+    pub path: String,
+    pub kind: SnapshotEntryKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct SnapshotSummary {
+    pub id: Snowflake,
+    pub instance_uuid: InstanceUuid,
+    pub creation_time: i64,
+    pub description: Option<String>,
+    pub caused_by: CausedBy,
+    pub file_count: usize,
+    /// Size of the files in the snapshot
+    pub total_size: u64,
+    /// Compressed size of the chunks this snapshot added to the repository
+    pub added_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct Snapshot {
+    #[serde(flatten)]
+    pub summary: SnapshotSummary,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// How many snapshots to keep, snapshots matching any rule are kept.
+///
+/// Each `keep_<period>` keeps the newest snapshot of that many most recent periods.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, TS)]
+#[ts(export)]
+pub struct RetentionPolicy {
+    pub keep_last: Option<usize>,
+    pub keep_hourly: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+}
+
+impl RetentionPolicy {
+    fn is_empty(&self) -> bool {
+        self.keep_last.is_none()
+            && self.keep_hourly.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+    }
+
+    /// The snapshots to keep, `snapshots` must be sorted newest first
+    fn snapshots_to_keep(&self, snapshots: &[SnapshotSummary]) -> HashSet<Snowflake> {
+        let mut keep: HashSet<Snowflake> = snapshots
+            .iter()
+            .take(self.keep_last.unwrap_or(0))
+            .map(|snapshot| snapshot.id)
+            .collect();
+        let periods = [
+            (self.keep_hourly, "%Y-%m-%d %H"),
+            (self.keep_daily, "%Y-%m-%d"),
+            (self.keep_weekly, "%G-%V"),
+            (self.keep_monthly, "%Y-%m"),
+        ];
+        for (count, format) in periods {
+            let count = match count {
+                Some(count) => count,
+                None => continue,
+            };
+            let mut last_period = None;
+            let mut kept = 0;
+            for snapshot in snapshots {
+                if kept >= count {
+                    break;
+                }
+                let period = chrono::NaiveDateTime::from_timestamp_opt(snapshot.creation_time, 0)
+                    .map(|time| time.format(format).to_string());
+                if period != last_period {
+                    keep.insert(snapshot.id);
+                    kept += 1;
+                    last_period = period;
+                }
+            }
+        }
+        keep
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default, TS)]
+#[ts(export)]
+pub struct PruneReport {
+    pub removed_snapshots: Vec<Snowflake>,
+    pub removed_chunks: usize,
+    pub freed_size: u64,
+}
+
+#[derive(Serialize, Clone, Debug, Default, TS)]
+#[ts(export)]
+pub struct VerifyReport {
+    pub checked_snapshots: usize,
+    pub checked_chunks: usize,
+    pub missing_chunks: Vec<String>,
+    /// Chunks whose content no longer matches their hash, only checked when reading data
+    pub corrupt_chunks: Vec<String>,
+    /// Snapshots that can't be fully restored
+    pub damaged_snapshots: Vec<Snowflake>,
+}
+
+fn relative_key(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn modified_millis(metadata: &std::fs::Metadata) -> Option<i64> {
+    metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_millis() as i64)
+}
+
+fn not_found(what: &str) -> Error {
+    Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("{what} not found"),
+    }
+}
+
+/// The repository on disk, everything here is blocking
+struct Repository {
+    path: PathBuf,
+}
+
+impl Repository {
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.path.join("chunks").join(&hash[..2]).join(hash)
+    }
+
+    fn snapshot_dir(&self, instance_uuid: &InstanceUuid) -> PathBuf {
+        self.path.join("snapshots").join(instance_uuid.to_string())
+    }
+
+    /// Writes atomically, so a crash never leaves a half written chunk or manifest behind
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let parent = path.parent().context("Path has no parent")?;
+        std::fs::create_dir_all(parent).context("Failed to create directory")?;
+        let mut tmp =
+            tempfile::NamedTempFile::new_in(parent).context("Failed to create temporary file")?;
+        tmp.write_all(data).context("Failed to write file")?;
+        tmp.persist(path).context("Failed to persist file")?;
+        Ok(())
+    }
+
+    /// Stores the chunk if it's new, returning its hash and the bytes added
+    fn store_chunk(&self, data: &[u8]) -> Result<(String, u64), Error> {
+        let hash = hex::encode(Sha256::digest(data));
+        let path = self.chunk_path(&hash);
+        if path.exists() {
+            return Ok((hash, 0));
+        }
+        let compressed = zstd::encode_all(data, ZSTD_LEVEL).context("Failed to compress chunk")?;
+        self.write_atomic(&path, &compressed)?;
+        Ok((hash, compressed.len() as u64))
+    }
+
+    fn read_chunk(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        let file =
+            std::fs::File::open(self.chunk_path(hash)).context(format!("Missing chunk {hash}"))?;
+        Ok(zstd::decode_all(file).context(format!("Failed to decompress chunk {hash}"))?)
+    }
+
+    fn list(&self, instance_uuid: &InstanceUuid) -> Result<Vec<Snapshot>, Error> {
+        let dir = self.snapshot_dir(instance_uuid);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(&dir).context("Failed to read snapshots")? {
+            let path = entry.context("Failed to read snapshots")?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            snapshots.push(self.read_manifest(&path)?);
+        }
+        snapshots.sort_by(|a, b| b.summary.id.cmp(&a.summary.id));
+        Ok(snapshots)
+    }
+
+    fn read_manifest(&self, path: &Path) -> Result<Snapshot, Error> {
+        let data = std::fs::read(path).context(format!("Failed to read {}", path.display()))?;
+        Ok(serde_json::from_slice(&data).context(format!("Failed to parse {}", path.display()))?)
+    }
+
+    fn get(&self, instance_uuid: &InstanceUuid, id: Snowflake) -> Result<Snapshot, Error> {
+        let path = self.snapshot_dir(instance_uuid).join(format!("{id}.json"));
+        if !path.exists() {
+            return Err(not_found("Snapshot"));
+        }
+        self.read_manifest(&path)
+    }
+
+    fn create(
+        &self,
+        instance_uuid: &InstanceUuid,
+        root: &Path,
+        description: Option<String>,
+        caused_by: CausedBy,
+        mut on_progress: impl FnMut(u64, u64),
+    ) -> Result<SnapshotSummary, Error> {
+        // unchanged files are looked up in the latest snapshot instead of being read again
+        let previous: HashMap<String, SnapshotEntryKind> = self
+            .list(instance_uuid)?
+            .into_iter()
+            .next()
+            .map(|snapshot| {
+                snapshot
+                    .entries
+                    .into_iter()
+                    .map(|entry| (entry.path, entry.kind))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let history_dir = root.join(HISTORY_DIR_NAME);
+        let walk: Vec<walkdir::DirEntry> = WalkDir::new(root)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| entry.path() != history_dir)
+            .collect::<Result<_, _>>()
+            .context("Failed to walk instance directory")?;
+        let total_size: u64 = walk
+            .iter()
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+
+        let mut entries = Vec::with_capacity(walk.len());
+        let mut done = 0;
+        let mut added_size = 0;
+        let mut file_count = 0;
+        for entry in walk {
+            let path = relative_key(
+                entry
+                    .path()
+                    .strip_prefix(root)
+                    .context("Failed to strip prefix")?,
+            );
+            if entry.file_type().is_dir() {
+                entries.push(SnapshotEntry {
+                    path,
+                    kind: SnapshotEntryKind::Directory,
+                });
+                continue;
+            }
+            // links could point outside of the instance
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let metadata = entry.metadata().context("Failed to read metadata")?;
+            let size = metadata.len();
+            let modified = modified_millis(&metadata);
+            file_count += 1;
+            let unchanged = match previous.get(&path) {
+                Some(SnapshotEntryKind::File {
+                    size: previous_size,
+                    modified: Some(previous_modified),
+                    chunks,
+                }) if *previous_size == size && Some(*previous_modified) == modified => {
+                    Some(chunks.clone())
+                }
+                _ => None,
+            };
+            let chunks = match unchanged {
+                Some(chunks) => chunks,
+                None => {
+                    let file = std::fs::File::open(entry.path())
+                        .context(format!("Failed to open {}", entry.path().display()))?;
+                    let mut chunker = Chunker::new(std::io::BufReader::new(file));
+                    let mut chunks = Vec::new();
+                    while let Some(chunk) = chunker
+                        .next_chunk()
+                        .context(format!("Failed to read {}", entry.path().display()))?
+                    {
+                        let (hash, added) = self.store_chunk(&chunk)?;
+                        added_size += added;
+                        chunks.push(hash);
+                    }
+                    chunks
+                }
+            };
+            entries.push(SnapshotEntry {
+                path,
+                kind: SnapshotEntryKind::File {
+                    size,
+                    modified,
+                    chunks,
+                },
+            });
+            done += size;
+            on_progress(done, total_size);
+        }
+
+        let summary = SnapshotSummary {
+            id: Snowflake::default(),
+            instance_uuid: instance_uuid.clone(),
+            creation_time: chrono::Utc::now().timestamp(),
+            description,
+            caused_by,
+            file_count,
+            total_size,
+            added_size,
+        };
+        let snapshot = Snapshot {
+            summary: summary.clone(),
+            entries,
+        };
+        self.write_atomic(
+            &self
+                .snapshot_dir(instance_uuid)
+                .join(format!("{}.json", summary.id)),
+            &serde_json::to_vec(&snapshot).context("Failed to serialize snapshot")?,
+        )?;
+        Ok(summary)
+    }
+
+    fn restore_file(&self, chunks: &[String], dest: &Path) -> Result<(), Error> {
+        let parent = dest.parent().context("Path has no parent")?;
+        std::fs::create_dir_all(parent).context("Failed to create directory")?;
+        let mut tmp =
+            tempfile::NamedTempFile::new_in(parent).context("Failed to create temporary file")?;
+        for hash in chunks {
+            tmp.write_all(&self.read_chunk(hash)?)
+                .context("Failed to write file")?;
+        }
+        tmp.persist(dest)
+            .context(format!("Failed to restore {}", dest.display()))?;
+        Ok(())
+    }
+
+    /// Restores the entries at or under `relative_path`, or the whole snapshot.
+    ///
+    /// With `clean`, files in `dest_root` that aren't in the snapshot are removed too.
+    ///
+    /// Without `can_write_protected`, nothing is restored if a protected file would be
+    /// written or removed. Nothing is restored either if it would grow `dest_root` by more than `max_growth` bytes.
+    fn restore(
+        &self,
+        snapshot: &Snapshot,
+        dest_root: &Path,
+        relative_path: Option<&Path>,
+        clean: bool,
+        can_write_protected: bool,
+        max_growth: Option<u64>,
+    ) -> Result<(), Error> {
+        let prefix = relative_path.map(relative_key).filter(|p| !p.is_empty());
+        let selected: Vec<&SnapshotEntry> = snapshot
+            .entries
+            .iter()
+            .filter(|entry| match &prefix {
+                Some(prefix) => {
+                    entry.path == *prefix || entry.path.starts_with(&format!("{prefix}/"))
+                }
+                None => true,
+            })
+            .collect();
+        if selected.is_empty() {
+            return Err(not_found("File in snapshot"));
+        }
+        let restored: Vec<(PathBuf, &SnapshotEntry)> = selected
+            .into_iter()
+            .map(|entry| Ok((scoped_join_win_safe(dest_root, &entry.path)?, entry)))
+            .collect::<Result<_, Error>>()?;
+        let mut removed = Vec::new();
+        if clean {
+            let keep: HashSet<&PathBuf> = restored.iter().map(|(dest, _)| dest).collect();
+            let clean_root = match &prefix {
+                Some(prefix) => scoped_join_win_safe(dest_root, prefix)?,
+                None => dest_root.to_owned(),
+            };
+            let history_dir = dest_root.join(HISTORY_DIR_NAME);
+            for entry in WalkDir::new(&clean_root)
+                .min_depth(1)
+                .contents_first(true)
+                .into_iter()
+                .filter_entry(|entry| entry.path() != history_dir)
+            {
+                let entry = entry.context("Failed to walk directory")?;
+                if !keep.contains(&entry.path().to_path_buf()) {
+                    removed.push(entry);
+                }
+            }
+        }
+        if !can_write_protected {
+            let written = restored
+                .iter()
+                .filter(|(_, entry)| matches!(entry.kind, SnapshotEntryKind::File { .. }))
+                .map(|(dest, _)| dest.as_path());
+            let removed_files = removed
+                .iter()
+                .filter(|entry| !entry.file_type().is_dir())
+                .map(|entry| entry.path());
+            if let Some(path) = written
+                .chain(removed_files)
+                .find(|path| is_path_protected(path))
+            {
+                return Err(Error {
+                    kind: ErrorKind::PermissionDenied,
+                    source: eyre!(
+                        "Restoring would change protected file {}",
+                        path.strip_prefix(dest_root).unwrap_or(path).display()
+                    ),
+                });
+            }
+        }
+        if let Some(max_growth) = max_growth {
+            let file_size =
+                |path: &Path| std::fs::symlink_metadata(path).map_or(0, |metadata| metadata.len());
+            let written: u64 = restored
+                .iter()
+                .filter_map(|(_, entry)| match entry.kind {
+                    SnapshotEntryKind::File { size, .. } => Some(size),
+                    SnapshotEntryKind::Directory => None,
+                })
+                .sum();
+            // files that are overwritten or removed free their space
+            let replaced: u64 = restored
+                .iter()
+                .filter(|(dest, _)| dest.is_file())
+                .map(|(dest, _)| file_size(dest))
+                .sum::<u64>()
+                + removed
+                    .iter()
+                    .filter(|entry| entry.file_type().is_file())
+                    .map(|entry| file_size(entry.path()))
+                    .sum::<u64>();
+            let growth = written.saturating_sub(replaced);
+            if growth > max_growth {
+                return Err(Error {
+                    kind: ErrorKind::InsufficientStorage,
+                    source: eyre!(
+                        "Restoring needs {} more, only {} is left in the disk quota",
+                        format_byte(growth),
+                        format_byte(max_growth)
+                    ),
+                });
+            }
+        }
+        for (dest, entry) in &restored {
+            match &entry.kind {
+                SnapshotEntryKind::Directory => {
+                    std::fs::create_dir_all(dest).context("Failed to create directory")?
+                }
+                SnapshotEntryKind::File { chunks, .. } => self.restore_file(chunks, dest)?,
+            }
+        }
+        for entry in removed {
+            if entry.file_type().is_dir() {
+                std::fs::remove_dir(entry.path())
+            } else {
+                std::fs::remove_file(entry.path())
+            }
+            .context(format!("Failed to remove {}", entry.path().display()))?;
+        }
+        Ok(())
+    }
+
+    /// Removes chunks no snapshot of any instance refers to
+    fn collect_garbage(&self, report: &mut PruneReport) -> Result<(), Error> {
+        let mut referenced = HashSet::new();
+        let snapshots_dir = self.path.join("snapshots");
+        if snapshots_dir.exists() {
+            for entry in WalkDir::new(&snapshots_dir).min_depth(2).max_depth(2) {
+                let entry = entry.context("Failed to walk snapshots")?;
+                if entry.path().extension().map_or(true, |ext| ext != "json") {
+                    continue;
+                }
+                // a manifest that can't be read might still need its chunks, don't guess
+                let snapshot = self.read_manifest(entry.path())?;
+                for entry in snapshot.entries {
+                    if let SnapshotEntryKind::File { chunks, .. } = entry.kind {
+                        referenced.extend(chunks);
+                    }
+                }
+            }
+        }
+        let chunks_dir = self.path.join("chunks");
+        if !chunks_dir.exists() {
+            return Ok(());
+        }
+        for entry in WalkDir::new(&chunks_dir).min_depth(2).max_depth(2) {
+            let entry = entry.context("Failed to walk chunks")?;
+            let name = entry.file_name().to_string_lossy();
+            if referenced.contains(name.as_ref()) {
+                continue;
+            }
+            let size = entry.metadata().map_or(0, |metadata| metadata.len());
+            std::fs::remove_file(entry.path())
+                .context(format!("Failed to remove {}", entry.path().display()))?;
+            // leftovers of interrupted writes are cleaned up too, but aren't chunks
+            if name.len() == 64 {
+                report.removed_chunks += 1;
+            }
+            report.freed_size += size;
+        }
+        Ok(())
+    }
+
+    fn forget(&self, instance_uuid: &InstanceUuid, ids: &[Snowflake]) -> Result<(), Error> {
+        for id in ids {
+            std::fs::remove_file(self.snapshot_dir(instance_uuid).join(format!("{id}.json")))
+                .context(format!("Failed to remove snapshot {id}"))?;
+        }
+        Ok(())
+    }
+
+    fn verify(&self, instance_uuid: &InstanceUuid, read_data: bool) -> Result<VerifyReport, Error> {
+        let mut report = VerifyReport::default();
+        let mut checked: HashMap<String, bool> = HashMap::new();
+        for snapshot in self.list(instance_uuid)? {
+            report.checked_snapshots += 1;
+            let mut damaged = false;
+            for entry in &snapshot.entries {
+                let chunks = match &entry.kind {
+                    SnapshotEntryKind::File { chunks, .. } => chunks,
+                    SnapshotEntryKind::Directory => continue,
+                };
+                for hash in chunks {
+                    if let Some(ok) = checked.get(hash) {
+                        damaged |= !ok;
+                        continue;
+                    }
+                    report.checked_chunks += 1;
+                    let ok = if !self.chunk_path(hash).exists() {
+                        report.missing_chunks.push(hash.clone());
+                        false
+                    } else if read_data {
+                        let intact = self
+                            .read_chunk(hash)
+                            .map_or(false, |data| hex::encode(Sha256::digest(data)) == *hash);
+                        if !intact {
+                            report.corrupt_chunks.push(hash.clone());
+                        }
+                        intact
+                    } else {
+                        true
+                    };
+                    checked.insert(hash.clone(), ok);
+                    damaged |= !ok;
+                }
+            }
+            if damaged {
+                report.damaged_snapshots.push(snapshot.summary.id);
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Async access to the repository.
+///
+/// Creating snapshots holds a shared lock and pruning an exclusive one, so a chunk a new snapshot
+/// deduplicated against is never collected from under it.
+#[derive(Clone)]
+pub struct BackupStore {
+    repository: Arc<Repository>,
+    lock: Arc<RwLock<()>>,
+}
+
+impl BackupStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            repository: Arc::new(Repository { path }),
+            lock: Arc::new(RwLock::new(())),
+        }
+    }
+
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Repository) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let repository = self.repository.clone();
+        tokio::task::spawn_blocking(move || f(&repository))
+            .await
+            .context("Backup task panicked")?
+    }
+
+    /// Snapshots of the instance, newest first
+    pub async fn list(&self, instance_uuid: &InstanceUuid) -> Result<Vec<SnapshotSummary>, Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.read().await;
+        self.blocking(move |repository| {
+            Ok(repository
+                .list(&instance_uuid)?
+                .into_iter()
+                .map(|snapshot| snapshot.summary)
+                .collect())
+        })
+        .await
+    }
+
+    pub async fn get(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: Snowflake,
+    ) -> Result<Snapshot, Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.read().await;
+        self.blocking(move |repository| repository.get(&instance_uuid, id))
+            .await
+    }
+
+    pub async fn create(
+        &self,
+        instance_uuid: &InstanceUuid,
+        root: PathBuf,
+        description: Option<String>,
+        caused_by: CausedBy,
+        on_progress: impl FnMut(u64, u64) + Send + 'static,
+    ) -> Result<SnapshotSummary, Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.read().await;
+        self.blocking(move |repository| {
+            repository.create(&instance_uuid, &root, description, caused_by, on_progress)
+        })
+        .await
+    }
+
+    /// Restores a whole snapshot, or only the file or directory at `relative_path`
+    pub async fn restore(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: Snowflake,
+        dest_root: PathBuf,
+        relative_path: Option<PathBuf>,
+        clean: bool,
+        can_write_protected: bool,
+        max_growth: Option<u64>,
+    ) -> Result<(), Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.read().await;
+        self.blocking(move |repository| {
+            let snapshot = repository.get(&instance_uuid, id)?;
+            repository.restore(
+                &snapshot,
+                &dest_root,
+                relative_path.as_deref(),
+                clean,
+                can_write_protected,
+                max_growth,
+            )
+        })
+        .await
+    }
+
+    /// Writes the snapshot out as an archive at `dest`
+    pub async fn export(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: Snowflake,
+        dest: PathBuf,
+        format: ArchiveFormat,
+    ) -> Result<PathBuf, Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.read().await;
+        self.blocking(move |repository| {
+            let snapshot = repository.get(&instance_uuid, id)?;
+            std::fs::create_dir_all(path_to_tmp()).context("Failed to create temporary dir")?;
+            let tmp_dir =
+                tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary dir")?;
+            repository.restore(&snapshot, tmp_dir.path(), None, false, true, None)?;
+            let files = std::fs::read_dir(tmp_dir.path())
+                .context("Failed to read temporary dir")?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .context("Failed to read temporary dir")?;
+            archive_files(&files, &dest, false, format, |_, _| {})
+        })
+        .await
+    }
+
+    /// Removes a snapshot and the chunks only it used
+    pub async fn forget(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: Snowflake,
+    ) -> Result<PruneReport, Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.write().await;
+        self.blocking(move |repository| {
+            repository.get(&instance_uuid, id)?;
+            repository.forget(&instance_uuid, &[id])?;
+            let mut report = PruneReport {
+                removed_snapshots: vec![id],
+                ..Default::default()
+            };
+            repository.collect_garbage(&mut report)?;
+            Ok(report)
+        })
+        .await
+    }
+
+    /// Removes every snapshot of a deleted instance, and the chunks only they used
+    pub async fn remove_instance(
+        &self,
+        instance_uuid: &InstanceUuid,
+    ) -> Result<PruneReport, Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.write().await;
+        self.blocking(move |repository| {
+            let removed: Vec<Snowflake> = repository
+                .list(&instance_uuid)?
+                .into_iter()
+                .map(|snapshot| snapshot.summary.id)
+                .collect();
+            repository.forget(&instance_uuid, &removed)?;
+            let dir = repository.snapshot_dir(&instance_uuid);
+            if dir.exists() {
+                std::fs::remove_dir(&dir).context("Failed to remove snapshots directory")?;
+            }
+            let mut report = PruneReport {
+                removed_snapshots: removed,
+                ..Default::default()
+            };
+            repository.collect_garbage(&mut report)?;
+            Ok(report)
+        })
+        .await
+    }
+
+    /// Removes the snapshots the policy doesn't keep, and the chunks only they used
+    pub async fn prune(
+        &self,
+        instance_uuid: &InstanceUuid,
+        policy: RetentionPolicy,
+    ) -> Result<PruneReport, Error> {
+        if policy.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The retention policy would remove every snapshot"),
+            });
+        }
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.write().await;
+        self.blocking(move |repository| {
+            let snapshots: Vec<SnapshotSummary> = repository
+                .list(&instance_uuid)?
+                .into_iter()
+                .map(|snapshot| snapshot.summary)
+                .collect();
+            let keep = policy.snapshots_to_keep(&snapshots);
+            let removed: Vec<Snowflake> = snapshots
+                .iter()
+                .map(|snapshot| snapshot.id)
+                .filter(|id| !keep.contains(id))
+                .collect();
+            repository.forget(&instance_uuid, &removed)?;
+            let mut report = PruneReport {
+                removed_snapshots: removed,
+                ..Default::default()
+            };
+            repository.collect_garbage(&mut report)?;
+            Ok(report)
+        })
+        .await
+    }
+
+    /// Checks every chunk of the instance's snapshots exists, and with `read_data` that it's intact
+    pub async fn verify(
+        &self,
+        instance_uuid: &InstanceUuid,
+        read_data: bool,
+    ) -> Result<VerifyReport, Error> {
+        let instance_uuid = instance_uuid.clone();
+        let _guard = self.lock.read().await;
+        self.blocking(move |repository| repository.verify(&instance_uuid, read_data))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::init_paths;
+
+    fn summary(creation_time: i64) -> SnapshotSummary {
+        SnapshotSummary {
+            id: Snowflake::default(),
+            instance_uuid: InstanceUuid::default(),
+            creation_time,
+            description: None,
+            caused_by: CausedBy::System,
+            file_count: 0,
+            total_size: 0,
+            added_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_chunker() {
+        // pseudo random data, so there are cut points
+        let mut state: u64 = 1;
+        let data: Vec<u8> = (0..20 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let chunk = |data: &[u8]| {
+            let mut chunker = Chunker::new(data);
+            let mut chunks = Vec::new();
+            while let Some(chunk) = chunker.next_chunk().unwrap() {
+                assert!(chunk.len() <= MAX_CHUNK_SIZE);
+                chunks.push(chunk);
+            }
+            chunks
+        };
+        let chunks = chunk(&data);
+        assert!(chunks.len() > 2);
+        assert_eq!(chunks.concat(), data);
+
+        // an insertion only changes the chunks around it
+        let mut shifted = data.clone();
+        shifted.splice(100..100, [1, 2, 3]);
+        let shifted_chunks = chunk(&shifted);
+        assert_eq!(shifted_chunks.concat(), shifted);
+        let shared = shifted_chunks
+            .iter()
+            .filter(|chunk| chunks.contains(chunk))
+            .count();
+        assert!(shared >= chunks.len() - 2);
+
+        assert!(chunk(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let hour = 60 * 60;
+        // one snapshot every 6 hours over 10 days, newest first
+        let mut snapshots: Vec<SnapshotSummary> = (0..40).map(|i| summary(i * 6 * hour)).collect();
+        snapshots.reverse();
+
+        let keep = RetentionPolicy {
+            keep_last: Some(3),
+            ..Default::default()
+        }
+        .snapshots_to_keep(&snapshots);
+        assert_eq!(keep.len(), 3);
+        assert!(keep.contains(&snapshots[0].id));
+
+        let keep = RetentionPolicy {
+            keep_daily: Some(5),
+            ..Default::default()
+        }
+        .snapshots_to_keep(&snapshots);
+        assert_eq!(keep.len(), 5);
+        // the newest snapshot of each day
+        assert!(keep.contains(&snapshots[0].id));
+        assert!(keep.contains(&snapshots[4].id));
+        assert!(!keep.contains(&snapshots[1].id));
+
+        let keep = RetentionPolicy {
+            keep_last: Some(2),
+            keep_daily: Some(2),
+            ..Default::default()
+        }
+        .snapshots_to_keep(&snapshots);
+        assert_eq!(keep.len(), 3);
+        assert!(RetentionPolicy::default().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backup_store() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        init_paths(temp_dir.path().join("lodestone"));
+        let root = temp_dir.path().join("instance");
+        std::fs::create_dir_all(root.join("world/region")).unwrap();
+        std::fs::create_dir_all(root.join(HISTORY_DIR_NAME)).unwrap();
+        std::fs::write(
+            root.join("world/region/r.0.0.mca"),
+            vec![7; 3 * MIN_CHUNK_SIZE],
+        )
+        .unwrap();
+        std::fs::write(root.join("server.properties"), "motd=hello").unwrap();
+        std::fs::write(root.join(HISTORY_DIR_NAME).join("index.json"), "{}").unwrap();
+
+        let store = BackupStore::new(temp_dir.path().join("backups"));
+        let uuid = InstanceUuid::default();
+        let first = store
+            .create(&uuid, root.clone(), None, CausedBy::System, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(first.file_count, 2);
+        assert!(first.added_size > 0);
+
+        // nothing changed, nothing new is stored
+        let second = store
+            .create(&uuid, root.clone(), None, CausedBy::System, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(second.added_size, 0);
+
+        std::fs::write(root.join("server.properties"), "motd=changed").unwrap();
+        std::fs::remove_dir_all(root.join("world")).unwrap();
+        std::fs::write(root.join("new.txt"), "new").unwrap();
+
+        // a single file
+        store
+            .restore(
+                &uuid,
+                first.id,
+                root.clone(),
+                Some("server.properties".into()),
+                false,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("server.properties")).unwrap(),
+            "motd=hello"
+        );
+        assert!(!root.join("world").exists());
+
+        // removing a protected file needs permission, and nothing is restored without it
+        std::fs::write(root.join("start.sh"), "java -jar server.jar").unwrap();
+        assert!(store
+            .restore(&uuid, first.id, root.clone(), None, true, false, None)
+            .await
+            .is_err());
+        assert!(!root.join("world").exists());
+
+        // nor if it doesn't fit in what's left of the quota
+        assert!(matches!(
+            store
+                .restore(&uuid, first.id, root.clone(), None, true, true, Some(1))
+                .await,
+            Err(Error {
+                kind: ErrorKind::InsufficientStorage,
+                ..
+            })
+        ));
+        assert!(!root.join("world").exists());
+
+        // the whole snapshot, removing what it didn't have
+        store
+            .restore(&uuid, first.id, root.clone(), None, true, true, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(root.join("world/region/r.0.0.mca")).unwrap(),
+            vec![7; 3 * MIN_CHUNK_SIZE]
+        );
+        assert!(!root.join("new.txt").exists());
+        assert!(root.join(HISTORY_DIR_NAME).join("index.json").exists());
+
+        assert!(store
+            .restore(
+                &uuid,
+                first.id,
+                root.clone(),
+                Some("missing".into()),
+                false,
+                false,
+                None,
+            )
+            .await
+            .is_err());
+
+        let report = store.verify(&uuid, true).await.unwrap();
+        assert_eq!(report.checked_snapshots, 2);
+        assert!(report.damaged_snapshots.is_empty());
+
+        let export = store
+            .export(
+                &uuid,
+                first.id,
+                temp_dir.path().join("export.tar.gz"),
+                ArchiveFormat::TarGz,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            ArchiveFormat::from_file_name(&export).unwrap(),
+            ArchiveFormat::TarGz
+        );
+
+        let report = store
+            .prune(
+                &uuid,
+                RetentionPolicy {
+                    keep_last: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.removed_snapshots, vec![first.id]);
+        // the remaining snapshot has the same content
+        assert_eq!(report.removed_chunks, 0);
+
+        let report = store.forget(&uuid, second.id).await.unwrap();
+        assert!(report.removed_chunks > 0);
+        assert!(store.list(&uuid).await.unwrap().is_empty());
+
+        // a lost chunk is reported
+        let third = store
+            .create(&uuid, root.clone(), None, CausedBy::System, |_, _| {})
+            .await
+            .unwrap();
+        let snapshot = store.get(&uuid, third.id).await.unwrap();
+        let chunk = snapshot
+            .entries
+            .iter()
+            .find_map(|entry| match &entry.kind {
+                SnapshotEntryKind::File { chunks, .. } => chunks.first().cloned(),
+                _ => None,
+            })
+            .unwrap();
+        std::fs::remove_file(
+            temp_dir
+                .path()
+                .join("backups/chunks")
+                .join(&chunk[..2])
+                .join(&chunk),
+        )
+        .unwrap();
+        let report = store.verify(&uuid, false).await.unwrap();
+        assert_eq!(report.missing_chunks, vec![chunk]);
+        assert_eq!(report.damaged_snapshots, vec![third.id]);
+
+        let report = store.remove_instance(&uuid).await.unwrap();
+        assert_eq!(report.removed_snapshots, vec![third.id]);
+        assert!(!temp_dir
+            .path()
+            .join("backups/snapshots")
+            .join(uuid.to_string())
+            .exists());
+    }
+}
@@ -0,0 +1,601 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ts_rs::TS;
+use walkdir::WalkDir;
+
+use crate::{
+    disk_usage::DiskUsageTracker,
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget, ProgressionEndValue},
+    fs_history::scoped_join_instance_file,
+    handlers::instance_fs::is_path_protected,
+    types::{InstanceUuid, Snowflake},
+    util::{files_size, resolve_path_conflict},
+};
+
+/// Finished jobs are kept around this long for their report to be fetched
+const FINISHED_JOB_RETENTION: i64 = 60 * 60;
+
+#[derive(Deserialize, Clone, Debug, TS)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum BulkFileOperation {
+    Delete {
+        relative_paths: Vec<PathBuf>,
+    },
+    /// Moves each path into the destination directory
+    Move {
+        relative_paths: Vec<PathBuf>,
+        relative_path_dest: PathBuf,
+    },
+    /// Copies each path into the destination directory
+    Copy {
+        relative_paths: Vec<PathBuf>,
+        relative_path_dest: PathBuf,
+    },
+    /// Sets the unix permission bits, e.g. 0o644. Not supported on Windows.
+    /// Setting the setuid, setgid or sticky bit needs permission to write global files
+    Chmod {
+        relative_paths: Vec<PathBuf>,
+        mode: u32,
+    },
+}
+
+impl BulkFileOperation {
+    fn relative_paths(&self) -> &[PathBuf] {
+        match self {
+            BulkFileOperation::Delete { relative_paths }
+            | BulkFileOperation::Move { relative_paths, .. }
+            | BulkFileOperation::Copy { relative_paths, .. }
+            | BulkFileOperation::Chmod { relative_paths, .. } => relative_paths,
+        }
+    }
+
+    fn relative_path_dest(&self) -> Option<&PathBuf> {
+        match self {
+            BulkFileOperation::Move {
+                relative_path_dest, ..
+            }
+            | BulkFileOperation::Copy {
+                relative_path_dest, ..
+            } => Some(relative_path_dest),
+            _ => None,
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        match self {
+            BulkFileOperation::Delete { .. } => "Deleting",
+            BulkFileOperation::Move { .. } => "Moving",
+            BulkFileOperation::Copy { .. } => "Copying",
+            BulkFileOperation::Chmod { .. } => "Changing permissions of",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct BulkItemResult {
+    pub relative_path: PathBuf,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+pub enum BulkJobState {
+    Running,
+    Done,
+    Cancelled,
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct BulkJobReport {
+    /// Also the id of the job's progression event
+    pub job_id: Snowflake,
+    pub instance_uuid: InstanceUuid,
+    pub state: BulkJobState,
+    pub total: usize,
+    /// One result per processed path, in the order they were given
+    pub items: Vec<BulkItemResult>,
+    pub creation_time: i64,
+    pub finish_time: Option<i64>,
+}
+
+struct BulkJob {
+    report: BulkJobReport,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// What a job needs to know about the instance and the user who started it
+pub struct BulkJobContext {
+    pub instance_uuid: InstanceUuid,
+    pub instance_name: String,
+    pub root: PathBuf,
+    /// Whether the user can touch protected files
+    pub can_write_protected: bool,
+    pub caused_by: CausedBy,
+}
+
+#[derive(Clone)]
+pub struct BulkFileJobs {
+    jobs: Arc<Mutex<HashMap<Snowflake, BulkJob>>>,
+    event_broadcaster: EventBroadcaster,
+    disk_usage: DiskUsageTracker,
+}
+
+impl BulkFileJobs {
+    pub fn new(event_broadcaster: EventBroadcaster, disk_usage: DiskUsageTracker) -> Self {
+        Self {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            event_broadcaster,
+            disk_usage,
+        }
+    }
+
+    /// Validates the request and runs it in the background, returning the job id
+    pub async fn start(
+        &self,
+        context: BulkJobContext,
+        operation: BulkFileOperation,
+    ) -> Result<Snowflake, Error> {
+        let sources = operation.relative_paths().to_vec();
+        if sources.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("No paths given"),
+            });
+        }
+        if let BulkFileOperation::Chmod { mode, .. } = operation {
+            if mode > 0o7777 {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid mode {mode:o}"),
+                });
+            }
+            // setuid, setgid and sticky
+            if mode & !0o777 != 0 && !context.can_write_protected {
+                return Err(Error {
+                    kind: ErrorKind::PermissionDenied,
+                    source: eyre!("Only users who can write global files can set mode {mode:o}"),
+                });
+            }
+        }
+        let dest = match operation.relative_path_dest() {
+            Some(relative_path_dest) => {
+                let dest = scoped_join_instance_file(&context.root, relative_path_dest)?;
+                if !dest.is_dir() {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Destination is not a directory"),
+                    });
+                }
+                Some(dest)
+            }
+            None => None,
+        };
+
+        let (start_event, event_id) = Event::new_progression_event_start(
+            format!("{} {} item(s)", operation.verb(), sources.len()),
+            Some(sources.len() as f64),
+            None,
+            context.caused_by.clone(),
+        );
+        let job_id = event_id.inner();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let now = chrono::Utc::now().timestamp();
+        {
+            let mut jobs = self.jobs.lock().await;
+            jobs.retain(|_, job| {
+                job.report.finish_time.map_or(true, |finish_time| {
+                    now - finish_time < FINISHED_JOB_RETENTION
+                })
+            });
+            jobs.insert(
+                job_id,
+                BulkJob {
+                    report: BulkJobReport {
+                        job_id,
+                        instance_uuid: context.instance_uuid.clone(),
+                        state: BulkJobState::Running,
+                        total: sources.len(),
+                        items: Vec::new(),
+                        creation_time: now,
+                        finish_time: None,
+                    },
+                    cancelled: cancelled.clone(),
+                },
+            );
+        }
+        self.event_broadcaster.send(start_event);
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut succeeded = 0;
+            for relative_path in &sources {
+                if cancelled.load(Ordering::SeqCst) {
+                    break;
+                }
+                let result = this
+                    .run_item(&context, &operation, relative_path, dest.as_deref())
+                    .await;
+                let message = match &result {
+                    Ok(_) => format!("{} {}", operation.verb(), relative_path.display()),
+                    Err(e) => format!("Failed on {}: {e}", relative_path.display()),
+                };
+                if result.is_ok() {
+                    succeeded += 1;
+                }
+                if let Some(job) = this.jobs.lock().await.get_mut(&job_id) {
+                    job.report.items.push(BulkItemResult {
+                        relative_path: relative_path.clone(),
+                        success: result.is_ok(),
+                        error: result.err().map(|e| e.to_string()),
+                    });
+                }
+                this.event_broadcaster
+                    .send(Event::new_progression_event_update(&event_id, message, 1.0));
+            }
+
+            let was_cancelled = cancelled.load(Ordering::SeqCst);
+            let success = !was_cancelled && succeeded == sources.len();
+            let message = if was_cancelled {
+                format!("Cancelled, {succeeded} of {} item(s) done", sources.len())
+            } else {
+                format!("{succeeded} of {} item(s) done", sources.len())
+            };
+            if let Some(job) = this.jobs.lock().await.get_mut(&job_id) {
+                job.report.state = if was_cancelled {
+                    BulkJobState::Cancelled
+                } else {
+                    BulkJobState::Done
+                };
+                job.report.finish_time = Some(chrono::Utc::now().timestamp());
+            }
+            this.event_broadcaster
+                .send(Event::new_progression_event_end(
+                    event_id,
+                    success,
+                    Some(&message),
+                    Some(ProgressionEndValue::FSOperationCompleted {
+                        instance_uuid: context.instance_uuid,
+                        success,
+                        message,
+                    }),
+                ));
+        });
+        Ok(job_id)
+    }
+
+    pub async fn report(
+        &self,
+        instance_uuid: &InstanceUuid,
+        job_id: &Snowflake,
+    ) -> Result<BulkJobReport, Error> {
+        self.jobs
+            .lock()
+            .await
+            .get(job_id)
+            .filter(|job| &job.report.instance_uuid == instance_uuid)
+            .map(|job| job.report.clone())
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Job not found"),
+            })
+    }
+
+    /// Stops the job before its next item, items already processed stay done
+    pub async fn cancel(
+        &self,
+        instance_uuid: &InstanceUuid,
+        job_id: &Snowflake,
+    ) -> Result<(), Error> {
+        let jobs = self.jobs.lock().await;
+        let job = jobs
+            .get(job_id)
+            .filter(|job| &job.report.instance_uuid == instance_uuid)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Job not found"),
+            })?;
+        if job.report.state != BulkJobState::Running {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Job already finished"),
+            });
+        }
+        job.cancelled.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn run_item(
+        &self,
+        context: &BulkJobContext,
+        operation: &BulkFileOperation,
+        relative_path: &Path,
+        dest: Option<&Path>,
+    ) -> Result<(), Error> {
+        let source = scoped_join_instance_file(&context.root, relative_path)?;
+        if source == context.root {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Cannot operate on the instance root"),
+            });
+        }
+        if !source.exists() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("File not found"),
+            });
+        }
+        if !context.can_write_protected && is_path_protected(&source) {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("File extension is protected"),
+            });
+        }
+        let target = |path: PathBuf| {
+            if path.is_dir() {
+                FSTarget::Directory(path)
+            } else {
+                FSTarget::File(path)
+            }
+        };
+        match operation {
+            BulkFileOperation::Delete { .. } => {
+                let fs_target = target(source.clone());
+                let can_write_protected = context.can_write_protected;
+                tokio::task::spawn_blocking(move || -> Result<(), Error> {
+                    if source.is_dir() {
+                        if !can_write_protected {
+                            for entry in WalkDir::new(&source) {
+                                let entry = entry.context(
+                                    "Failed to walk directory while scanning for protected files",
+                                )?;
+                                if entry.file_type().is_file() && is_path_protected(entry.path()) {
+                                    return Err(Error {
+                                        kind: ErrorKind::PermissionDenied,
+                                        source: eyre!("Directory contains protected files"),
+                                    });
+                                }
+                            }
+                        }
+                        std::fs::remove_dir_all(&source).context("Failed to remove directory")?;
+                    } else {
+                        std::fs::remove_file(&source).context("Failed to remove file")?;
+                    }
+                    Ok(())
+                })
+                .await
+                .context("Failed to delete")??;
+                self.event_broadcaster.send(new_fs_event(
+                    FSOperation::Delete,
+                    fs_target,
+                    context.caused_by.clone(),
+                ));
+            }
+            BulkFileOperation::Move { .. } | BulkFileOperation::Copy { .. } => {
+                let dest = dest.ok_or_else(|| eyre!("Missing destination"))?;
+                if dest.starts_with(&source) {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Destination is inside the source"),
+                    });
+                }
+                if !context.can_write_protected && is_path_protected(dest) {
+                    return Err(Error {
+                        kind: ErrorKind::PermissionDenied,
+                        source: eyre!("You don't have permission to write to this directory"),
+                    });
+                }
+                let file_name = source
+                    .file_name()
+                    .ok_or_else(|| eyre!("Path has no file name"))?;
+                let dest = resolve_path_conflict(dest.join(file_name), None);
+                if let BulkFileOperation::Move { .. } = operation {
+                    tokio::fs::rename(&source, &dest)
+                        .await
+                        .context("Failed to move file")?;
+                    self.event_broadcaster.send(new_fs_event(
+                        FSOperation::Move { source },
+                        target(dest),
+                        context.caused_by.clone(),
+                    ));
+                } else {
+                    let size = {
+                        let source = source.clone();
+                        tokio::task::spawn_blocking(move || files_size(&[source]))
+                            .await
+                            .context("Failed to compute size")?
+                    };
+                    self.disk_usage
+                        .check_write(
+                            &context.instance_uuid,
+                            &context.root,
+                            &context.instance_name,
+                            size,
+                        )
+                        .await?;
+                    let dest = {
+                        let dest = dest.clone();
+                        tokio::task::spawn_blocking(move || -> Result<PathBuf, Error> {
+                            if source.is_dir() {
+                                std::fs::create_dir(&dest).context("Failed to create directory")?;
+                                let mut options = fs_extra::dir::CopyOptions::new();
+                                options.content_only = true;
+                                fs_extra::dir::copy(&source, &dest, &options)
+                                    .context("Failed to copy directory")?;
+                            } else {
+                                std::fs::copy(&source, &dest).context("Failed to copy file")?;
+                            }
+                            Ok(dest)
+                        })
+                        .await
+                        .context("Failed to copy")??
+                    };
+                    self.event_broadcaster.send(new_fs_event(
+                        FSOperation::Create,
+                        target(dest),
+                        context.caused_by.clone(),
+                    ));
+                }
+            }
+            BulkFileOperation::Chmod { mode, .. } => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    tokio::fs::set_permissions(&source, std::fs::Permissions::from_mode(*mode))
+                        .await
+                        .context("Failed to set permissions")?;
+                    self.event_broadcaster.send(new_fs_event(
+                        FSOperation::Write,
+                        target(source),
+                        context.caused_by.clone(),
+                    ));
+                }
+                #[cfg(not(unix))]
+                {
+                    let _ = mode;
+                    return Err(Error {
+                        kind: ErrorKind::UnsupportedOperation,
+                        source: eyre!("Changing permissions is only supported on unix"),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bulk_file_jobs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("instance");
+        std::fs::create_dir_all(root.join("world")).unwrap();
+        std::fs::create_dir_all(root.join("backup")).unwrap();
+        std::fs::write(root.join("a.txt"), "a").unwrap();
+        std::fs::write(root.join("b.txt"), "b").unwrap();
+        std::fs::write(root.join("world/level.dat"), "level").unwrap();
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let disk_usage = DiskUsageTracker::new(
+            temp_dir.path().join("disk_quotas.json"),
+            event_broadcaster.clone(),
+        );
+        let jobs = BulkFileJobs::new(event_broadcaster, disk_usage);
+        let uuid = InstanceUuid::default();
+        let context = || BulkJobContext {
+            instance_uuid: uuid.clone(),
+            instance_name: "test".to_string(),
+            root: root.clone(),
+            can_write_protected: false,
+            caused_by: CausedBy::System,
+        };
+        let wait = |job_id: Snowflake| {
+            let jobs = jobs.clone();
+            let uuid = uuid.clone();
+            async move {
+                loop {
+                    let report = jobs.report(&uuid, &job_id).await.unwrap();
+                    if report.state != BulkJobState::Running {
+                        return report;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            }
+        };
+
+        let job_id = jobs
+            .start(
+                context(),
+                BulkFileOperation::Copy {
+                    relative_paths: vec!["a.txt".into(), "world".into(), "missing.txt".into()],
+                    relative_path_dest: "backup".into(),
+                },
+            )
+            .await
+            .unwrap();
+        let report = wait(job_id).await;
+        assert_eq!(report.state, BulkJobState::Done);
+        assert_eq!(report.items.len(), 3);
+        assert!(report.items[0].success && report.items[1].success);
+        assert!(!report.items[2].success);
+        assert!(root.join("backup/a.txt").exists());
+        assert!(root.join("backup/world/level.dat").exists());
+        assert!(root.join("a.txt").exists());
+
+        // moving a directory into itself is refused per item
+        let job_id = jobs
+            .start(
+                context(),
+                BulkFileOperation::Move {
+                    relative_paths: vec!["b.txt".into(), "backup".into()],
+                    relative_path_dest: "backup/world".into(),
+                },
+            )
+            .await
+            .unwrap();
+        let report = wait(job_id).await;
+        assert!(report.items[0].success);
+        assert!(!report.items[1].success);
+        assert!(!root.join("b.txt").exists());
+        assert!(root.join("backup/world/b.txt").exists());
+
+        let job_id = jobs
+            .start(
+                context(),
+                BulkFileOperation::Delete {
+                    relative_paths: vec!["a.txt".into(), "backup".into(), "".into()],
+                },
+            )
+            .await
+            .unwrap();
+        let report = wait(job_id).await;
+        assert!(report.items[0].success && report.items[1].success);
+        // the instance root can't be deleted
+        assert!(!report.items[2].success);
+        assert!(!root.join("a.txt").exists());
+        assert!(!root.join("backup").exists());
+        assert!(root.exists());
+
+        assert!(jobs
+            .start(
+                context(),
+                BulkFileOperation::Delete {
+                    relative_paths: vec![]
+                }
+            )
+            .await
+            .is_err());
+        assert!(jobs
+            .start(
+                context(),
+                BulkFileOperation::Chmod {
+                    relative_paths: vec!["world".into()],
+                    mode: 0o4755,
+                }
+            )
+            .await
+            .is_err());
+        assert!(jobs
+            .report(&InstanceUuid::default(), &job_id)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,587 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use axum::body::Bytes;
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tokio_stream::{Stream, StreamExt};
+use ts_rs::TS;
+
+use crate::{
+    auth::{user::UserAction, user_id::UserId},
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    events::{
+        new_fs_event, CausedBy, Event, FSOperation, FSTarget, ProgressionEndValue,
+        ProgressionEventID,
+    },
+    types::InstanceUuid,
+    util::{format_byte_download, rand_alphanumeric, resolve_path_conflict},
+};
+
+/// Uploads with no activity for this long are removed along with their partial file
+pub const STALE_UPLOAD_TIMEOUT: i64 = 24 * 60 * 60;
+const MAX_UPLOADS_PER_USER: usize = 16;
+
+/// Where a finished upload is moved to
+#[derive(Clone, Debug)]
+pub enum UploadTarget {
+    Instance {
+        uuid: InstanceUuid,
+        /// Already scoped to the instance
+        dir: PathBuf,
+    },
+    Global {
+        dir: PathBuf,
+    },
+}
+
+impl UploadTarget {
+    /// The action the uploader must still be allowed to perform for every chunk
+    pub fn action(&self) -> UserAction {
+        match self {
+            UploadTarget::Instance { uuid, .. } => UserAction::WriteInstanceFile(uuid.clone()),
+            UploadTarget::Global { .. } => UserAction::WriteGlobalFile,
+        }
+    }
+
+    fn dir(&self) -> &PathBuf {
+        match self {
+            UploadTarget::Instance { dir, .. } | UploadTarget::Global { dir } => dir,
+        }
+    }
+
+    fn end_value(&self, success: bool, message: String) -> Option<ProgressionEndValue> {
+        match self {
+            UploadTarget::Instance { uuid, .. } => {
+                Some(ProgressionEndValue::FSOperationCompleted {
+                    instance_uuid: uuid.clone(),
+                    success,
+                    message,
+                })
+            }
+            UploadTarget::Global { .. } => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct NewUpload {
+    pub file_name: String,
+    /// Total size of the file in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 of the whole file, checked when the upload is finalized
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct UploadStatus {
+    pub upload_id: String,
+    pub file_name: String,
+    pub size: u64,
+    /// Bytes received so far, the next chunk must start here
+    pub offset: u64,
+}
+
+pub struct ChunkedUpload {
+    id: String,
+    pub target: UploadTarget,
+    /// Sanitized
+    pub file_name: String,
+    size: u64,
+    offset: u64,
+    sha256: Option<String>,
+    /// Hash of the bytes received so far
+    hasher: Sha256,
+    partial_path: PathBuf,
+    event_id: ProgressionEventID,
+    /// Bytes received when progress was last reported
+    reported: u64,
+    last_activity: i64,
+    caused_by: CausedBy,
+    /// Set once the upload is finalized or discarded
+    finished: bool,
+}
+
+impl ChunkedUpload {
+    pub fn status(&self) -> UploadStatus {
+        UploadStatus {
+            upload_id: self.id.clone(),
+            file_name: self.file_name.clone(),
+            size: self.size,
+            offset: self.offset,
+        }
+    }
+
+    /// Appends a chunk starting at `offset`, returning the new offset.
+    ///
+    /// Bytes received before the stream fails are kept, so the client can resume from the returned offset.
+    pub async fn append<S, E>(
+        &mut self,
+        offset: u64,
+        mut chunk: S,
+        event_broadcaster: &EventBroadcaster,
+    ) -> Result<u64, Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        if self.finished {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Upload not found"),
+            });
+        }
+        if offset != self.offset {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Expected offset {}, got {offset}", self.offset),
+            });
+        }
+        self.last_activity = chrono::Utc::now().timestamp();
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.partial_path)
+            .await
+            .context("Failed to open partial upload")?;
+        let threshold = (self.size / 100).max(1);
+        let result = async {
+            while let Some(bytes) = chunk.next().await {
+                let bytes = bytes.map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Failed to read chunk: {e}"),
+                })?;
+                if self.offset + bytes.len() as u64 > self.size {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Chunk goes past the declared size of {}", self.size),
+                    });
+                }
+                file.write_all(&bytes)
+                    .await
+                    .context("Failed to write chunk")?;
+                self.hasher.update(&bytes);
+                self.offset += bytes.len() as u64;
+                if self.offset - self.reported >= threshold {
+                    event_broadcaster.send(Event::new_progression_event_update(
+                        &self.event_id,
+                        format!(
+                            "Uploading {}, {}",
+                            self.file_name,
+                            format_byte_download(self.offset, self.size)
+                        ),
+                        (self.offset - self.reported) as f64,
+                    ));
+                    self.reported = self.offset;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        file.flush().await.context("Failed to flush chunk")?;
+        self.last_activity = chrono::Utc::now().timestamp();
+        result.map(|_| self.offset)
+    }
+}
+
+/// The owner is kept outside the upload's lock, so it can be checked while a chunk is being written
+struct UploadEntry {
+    owner: UserId,
+    upload: Arc<Mutex<ChunkedUpload>>,
+}
+
+/// Uploads in progress, kept in memory with their partial files under the tmp directory
+#[derive(Clone)]
+pub struct ChunkedUploads {
+    partial_dir: PathBuf,
+    uploads: Arc<Mutex<HashMap<String, UploadEntry>>>,
+}
+
+impl ChunkedUploads {
+    pub fn new(partial_dir: PathBuf) -> Self {
+        Self {
+            partial_dir,
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn create(
+        &self,
+        owner: UserId,
+        target: UploadTarget,
+        new_upload: NewUpload,
+        caused_by: CausedBy,
+        event_broadcaster: &EventBroadcaster,
+    ) -> Result<UploadStatus, Error> {
+        let file_name = sanitize_filename::sanitize(&new_upload.file_name);
+        if file_name.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Missing file name"),
+            });
+        }
+        let sha256 = new_upload
+            .sha256
+            .map(|sha256| {
+                let sha256 = sha256.to_lowercase();
+                if sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    Ok(sha256)
+                } else {
+                    Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Invalid SHA-256 checksum"),
+                    })
+                }
+            })
+            .transpose()?;
+        let mut uploads = self.uploads.lock().await;
+        if uploads
+            .values()
+            .filter(|entry| entry.owner == owner)
+            .count()
+            >= MAX_UPLOADS_PER_USER
+        {
+            return Err(Error {
+                kind: ErrorKind::TooManyRequests,
+                source: eyre!("Too many uploads in progress, finish or cancel some first"),
+            });
+        }
+        crate::util::fs::create_dir_all(&self.partial_dir).await?;
+        let id = rand_alphanumeric(32);
+        let partial_path = self.partial_dir.join(format!("{id}.part"));
+        tokio::fs::File::create(&partial_path)
+            .await
+            .context("Failed to create partial upload")?;
+        let (progression_start_event, event_id) = Event::new_progression_event_start(
+            format!("Uploading {file_name}"),
+            Some(new_upload.size as f64),
+            None,
+            caused_by.clone(),
+        );
+        event_broadcaster.send(progression_start_event);
+        let upload = ChunkedUpload {
+            id: id.clone(),
+            target,
+            file_name,
+            size: new_upload.size,
+            offset: 0,
+            sha256,
+            hasher: Sha256::new(),
+            partial_path,
+            event_id,
+            reported: 0,
+            last_activity: chrono::Utc::now().timestamp(),
+            caused_by,
+            finished: false,
+        };
+        let status = upload.status();
+        uploads.insert(
+            id,
+            UploadEntry {
+                owner,
+                upload: Arc::new(Mutex::new(upload)),
+            },
+        );
+        Ok(status)
+    }
+
+    /// Only the user who created an upload can see it
+    pub async fn get(
+        &self,
+        id: &str,
+        requester: &UserId,
+    ) -> Result<Arc<Mutex<ChunkedUpload>>, Error> {
+        match self.uploads.lock().await.get(id) {
+            Some(entry) if entry.owner == *requester => Ok(entry.upload.clone()),
+            _ => Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Upload not found"),
+            }),
+        }
+    }
+
+    /// Stops tracking an upload, waiting for any chunk still being written
+    async fn take(&self, id: &str, requester: &UserId) -> Result<Arc<Mutex<ChunkedUpload>>, Error> {
+        let upload = self.get(id, requester).await?;
+        self.uploads.lock().await.remove(id);
+        upload.lock().await.finished = true;
+        Ok(upload)
+    }
+
+    /// Verifies the upload and moves it to its target, returning the final path.
+    ///
+    /// An incomplete upload is kept so the rest can still be sent, a checksum mismatch discards it
+    pub async fn finalize(
+        &self,
+        id: &str,
+        requester: &UserId,
+        event_broadcaster: &EventBroadcaster,
+    ) -> Result<PathBuf, Error> {
+        let upload = self.get(id, requester).await?;
+        let mut upload = upload.lock().await;
+        // finalized or cancelled while a chunk was being written
+        if upload.finished {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Upload not found"),
+            });
+        }
+        if upload.offset != upload.size {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Upload is incomplete, {} of {} bytes received",
+                    upload.offset,
+                    upload.size
+                ),
+            });
+        }
+        if let Some(expected) = &upload.sha256 {
+            let actual = hex::encode(upload.hasher.clone().finalize());
+            if *expected != actual {
+                let reason = format!("Checksum mismatch, expected {expected}, got {actual}");
+                self.uploads.lock().await.remove(id);
+                upload.finished = true;
+                Self::discard(&upload, &reason, event_broadcaster).await;
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(reason),
+                });
+            }
+        }
+        crate::util::fs::create_dir_all(upload.target.dir()).await?;
+        let path = resolve_path_conflict(upload.target.dir().join(&upload.file_name), None);
+        // the tmp directory may be on another filesystem than the target
+        if tokio::fs::rename(&upload.partial_path, &path)
+            .await
+            .is_err()
+        {
+            tokio::fs::copy(&upload.partial_path, &path)
+                .await
+                .context("Failed to move upload to its destination")?;
+            crate::util::fs::remove_file(&upload.partial_path).await?;
+        }
+        self.uploads.lock().await.remove(id);
+        upload.finished = true;
+        event_broadcaster.send(new_fs_event(
+            FSOperation::Upload,
+            FSTarget::File(path.clone()),
+            upload.caused_by.clone(),
+        ));
+        event_broadcaster.send(Event::new_progression_event_end(
+            upload.event_id.clone(),
+            true,
+            Some("Upload complete"),
+            upload
+                .target
+                .end_value(true, format!("Uploaded {}", upload.file_name)),
+        ));
+        Ok(path)
+    }
+
+    pub async fn cancel(
+        &self,
+        id: &str,
+        requester: &UserId,
+        event_broadcaster: &EventBroadcaster,
+    ) -> Result<(), Error> {
+        let upload = self.take(id, requester).await?;
+        Self::discard(&*upload.lock().await, "Upload cancelled", event_broadcaster).await;
+        Ok(())
+    }
+
+    async fn discard(upload: &ChunkedUpload, reason: &str, event_broadcaster: &EventBroadcaster) {
+        let _ = crate::util::fs::remove_file(&upload.partial_path).await;
+        event_broadcaster.send(Event::new_progression_event_end(
+            upload.event_id.clone(),
+            false,
+            Some(reason),
+            upload.target.end_value(
+                false,
+                format!("Failed to upload file {}, {reason}", upload.file_name),
+            ),
+        ));
+    }
+
+    /// Removes uploads idle for longer than `STALE_UPLOAD_TIMEOUT`, and partial files no upload owns
+    pub async fn remove_stale(&self, now: i64, event_broadcaster: &EventBroadcaster) {
+        let mut stale = Vec::new();
+        {
+            let mut uploads = self.uploads.lock().await;
+            let mut stale_ids = Vec::new();
+            for (id, entry) in uploads.iter() {
+                // uploads busy receiving a chunk aren't stale
+                if let Ok(upload) = entry.upload.try_lock() {
+                    if now - upload.last_activity >= STALE_UPLOAD_TIMEOUT {
+                        stale_ids.push(id.clone());
+                    }
+                }
+            }
+            for id in stale_ids {
+                stale.extend(uploads.remove(&id).map(|entry| entry.upload));
+            }
+        }
+        for upload in stale {
+            let mut upload = upload.lock().await;
+            upload.finished = true;
+            Self::discard(&upload, "Upload expired", event_broadcaster).await;
+        }
+
+        let uploads = self.uploads.lock().await;
+        if let Ok(mut entries) = tokio::fs::read_dir(&self.partial_dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                let owned = path
+                    .file_stem()
+                    .and_then(|id| id.to_str())
+                    .map_or(false, |id| uploads.contains_key(id));
+                if !owned {
+                    let _ = tokio::fs::remove_file(path).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(data: &'static [u8]) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+        tokio_stream::iter(vec![Ok(Bytes::from_static(data))])
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let uploads = ChunkedUploads::new(temp_dir.path().join("uploads"));
+        let owner = UserId::default();
+        let target = UploadTarget::Global {
+            dir: temp_dir.path().join("target"),
+        };
+        let data = b"hello world";
+        let status = uploads
+            .create(
+                owner.clone(),
+                target.clone(),
+                NewUpload {
+                    file_name: "hello.txt".to_string(),
+                    size: data.len() as u64,
+                    sha256: Some(hex::encode(Sha256::digest(data))),
+                },
+                CausedBy::Unknown,
+                &event_broadcaster,
+            )
+            .await
+            .unwrap();
+
+        // other users can't see the upload
+        assert!(uploads
+            .get(&status.upload_id, &UserId::default())
+            .await
+            .is_err());
+        // finalizing too early keeps the upload
+        assert!(uploads
+            .finalize(&status.upload_id, &owner, &event_broadcaster)
+            .await
+            .is_err());
+
+        let upload = uploads.get(&status.upload_id, &owner).await.unwrap();
+        let mut upload = upload.lock().await;
+        assert_eq!(
+            upload
+                .append(0, chunk(b"hello "), &event_broadcaster)
+                .await
+                .unwrap(),
+            6
+        );
+        // resuming at the wrong offset is rejected
+        assert!(upload
+            .append(0, chunk(b"world"), &event_broadcaster)
+            .await
+            .is_err());
+        assert!(upload
+            .append(6, chunk(b"world and more"), &event_broadcaster)
+            .await
+            .is_err());
+        upload
+            .append(6, chunk(b"world"), &event_broadcaster)
+            .await
+            .unwrap();
+        drop(upload);
+
+        let path = uploads
+            .finalize(&status.upload_id, &owner, &event_broadcaster)
+            .await
+            .unwrap();
+        assert_eq!(path, temp_dir.path().join("target/hello.txt"));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(uploads.get(&status.upload_id, &owner).await.is_err());
+
+        // a bad checksum fails the upload
+        let status = uploads
+            .create(
+                owner.clone(),
+                target,
+                NewUpload {
+                    file_name: "bad.txt".to_string(),
+                    size: 3,
+                    sha256: Some(hex::encode(Sha256::digest(b"abc"))),
+                },
+                CausedBy::Unknown,
+                &event_broadcaster,
+            )
+            .await
+            .unwrap();
+        uploads
+            .get(&status.upload_id, &owner)
+            .await
+            .unwrap()
+            .lock()
+            .await
+            .append(0, chunk(b"xyz"), &event_broadcaster)
+            .await
+            .unwrap();
+        assert!(uploads
+            .finalize(&status.upload_id, &owner, &event_broadcaster)
+            .await
+            .is_err());
+        assert!(!temp_dir.path().join("target/bad.txt").exists());
+        assert!(uploads.get(&status.upload_id, &owner).await.is_err());
+
+        // stale uploads are removed with their partial file
+        let status = uploads
+            .create(
+                owner.clone(),
+                UploadTarget::Global {
+                    dir: temp_dir.path().join("target"),
+                },
+                NewUpload {
+                    file_name: "stale.txt".to_string(),
+                    size: 3,
+                    sha256: None,
+                },
+                CausedBy::Unknown,
+                &event_broadcaster,
+            )
+            .await
+            .unwrap();
+        uploads
+            .remove_stale(
+                chrono::Utc::now().timestamp() + STALE_UPLOAD_TIMEOUT,
+                &event_broadcaster,
+            )
+            .await;
+        assert!(uploads.get(&status.upload_id, &owner).await.is_err());
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path().join("uploads"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+}
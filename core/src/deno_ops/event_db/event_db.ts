@@ -0,0 +1,13 @@
+import { ClientEvent } from "../../../deno_bindings/ClientEvent.ts";
+import { EventQuery } from "../../../deno_bindings/EventQuery.ts";
+
+export type { ClientEvent, EventQuery };
+
+// deno-lint-ignore no-explicit-any
+declare const Deno: any;
+const core = Deno[Deno.internal].core;
+
+/** Past events from the event database, only the ones the user who started the macro may see */
+export function queryEvents(query: EventQuery): Promise<ClientEvent[]> {
+    return core.opAsync("query_events", query);
+}
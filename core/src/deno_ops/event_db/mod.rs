@@ -0,0 +1,34 @@
+use std::{cell::RefCell, rc::Rc};
+
+use deno_core::{anyhow, op, OpState};
+
+use crate::{
+    db::read::search_events,
+    events::{Event, EventQuery},
+    macro_caller::MacroCaller,
+    output_types::ClientEvent,
+    prelude::app_state,
+};
+
+/// Only the events the caller may see are returned
+#[op]
+async fn query_events(
+    state: Rc<RefCell<OpState>>,
+    query: EventQuery,
+) -> Result<Vec<ClientEvent>, anyhow::Error> {
+    let caller = state.borrow().borrow::<MacroCaller>().clone();
+    let events = search_events(&app_state().sqlite_pool, query).await?;
+    let users_manager = app_state().users_manager.read().await;
+    Ok(events
+        .into_iter()
+        .filter(|event| caller.can_view_event(&Event::from(event), &users_manager))
+        .collect())
+}
+
+pub fn register_event_db_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
+    worker_options.extensions.push(
+        deno_core::Extension::builder("event_db_ops")
+            .ops(vec![query_events::decl()])
+            .build(),
+    );
+}
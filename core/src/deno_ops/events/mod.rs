@@ -12,6 +12,7 @@ use crate::{
         ProgressionStartValue,
     },
     macro_executor::MacroPID,
+    macro_history::MacroRunLogger,
     traits::t_server::State,
     types::InstanceUuid,
 };
@@ -86,8 +87,10 @@ async fn next_instance_player_change(
         .await
 }
 
+/// Always detaches the calling macro, the pid passed from JS is ignored
 #[op]
-fn emit_detach(state: Rc<RefCell<OpState>>, macro_pid: MacroPID) {
+fn emit_detach(state: Rc<RefCell<OpState>>, _macro_pid: MacroPID) {
+    let macro_pid = *state.borrow().borrow::<MacroPID>();
     let tx = state.borrow().borrow::<EventBroadcaster>().clone();
     tx.send(Event::new_macro_detach_event(macro_pid));
 }
@@ -99,6 +102,10 @@ fn emit_console_out(
     instance_name: String,
     line: String,
 ) {
+    // kept in the run's log as well, so it outlives the console buffer
+    if let Some(logger) = state.borrow().try_borrow::<MacroRunLogger>() {
+        logger.log(line.clone());
+    }
     let tx = state.borrow().borrow::<EventBroadcaster>().clone();
     tx.send(Event::new_instance_output(
         instance_uuid,
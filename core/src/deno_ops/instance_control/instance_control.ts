@@ -21,19 +21,19 @@ export function allInstanceUuids(): string[] {
 }
 
 export function startInstance(block: boolean, instanceUuid: string): Promise<void> {
-    return core.opAsync("start_instance", instanceUuid, getCurrentTaskPid(), block);
+    return core.opAsync("start_instance", instanceUuid, block);
 }
 
 export function stopInstance(block: boolean, instanceUuid: string): Promise<void> {
-    return core.opAsync("stop_instance", instanceUuid, getCurrentTaskPid(), block);
+    return core.opAsync("stop_instance", instanceUuid, block);
 }
 
 export function restartInstance(block: boolean, instanceUuid: string): Promise<void> {
-    return core.opAsync("restart_instance", instanceUuid, getCurrentTaskPid(), block);
+    return core.opAsync("restart_instance", instanceUuid, block);
 }
 
 export function killInstance(instanceUuid: string): Promise<void> {
-    return core.opAsync("kill_instance", instanceUuid, getCurrentTaskPid());
+    return core.opAsync("kill_instance", instanceUuid);
 }
 
 export function getInstanceState(instanceUuid: string): Promise<InstanceState> {
@@ -1,14 +1,13 @@
-use std::collections::HashSet;
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
 
 use deno_core::{
     anyhow::{self, bail, Context},
-    op,
+    op, OpState,
 };
 
 use crate::{
-    events::CausedBy,
-    macro_executor::MacroPID,
-    prelude::app_state,
+    auth::user::UserAction,
+    prelude::{app_state, GameInstance},
     traits::{
         t_configurable::{Game, TConfigurable},
         t_player::{Player, TPlayerManagement},
@@ -17,6 +16,23 @@ use crate::{
     types::InstanceUuid,
 };
 
+use super::prelude::{caller_cause, try_caller_action};
+
+/// The instance, once the caller may perform `action` on it
+async fn get_instance(
+    state: &Rc<RefCell<OpState>>,
+    action: UserAction,
+    instance_uuid: &InstanceUuid,
+) -> Result<GameInstance, anyhow::Error> {
+    try_caller_action(state, &action).await?;
+    Ok(app_state()
+        .instances
+        .get(instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .value()
+        .clone())
+}
+
 #[op]
 fn instance_exists(instance_uuid: InstanceUuid) -> bool {
     app_state().instances.contains_key(&instance_uuid)
@@ -33,213 +49,261 @@ fn all_instances() -> Vec<InstanceUuid> {
 
 #[op]
 async fn start_instance(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
-    task_pid: MacroPID,
     block: bool,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    let instance = get_instance(
+        &state,
+        UserAction::StartInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     instance
-        .start(
-            CausedBy::Macro {
-                macro_pid: task_pid,
-            },
-            block,
-        )
+        .start(caller_cause(&state), block)
         .await
         .context("Failed to start instance")
 }
 
 #[op]
 async fn stop_instance(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
-    task_pid: MacroPID,
     block: bool,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    let instance = get_instance(
+        &state,
+        UserAction::StopInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     instance
-        .stop(
-            CausedBy::Macro {
-                macro_pid: task_pid,
-            },
-            block,
-        )
+        .stop(caller_cause(&state), block)
         .await
         .context("Failed to start instance")
 }
 
 #[op]
 async fn restart_instance(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
-    task_pid: MacroPID,
     block: bool,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    try_caller_action(&state, &UserAction::StartInstance(instance_uuid.clone())).await?;
+    let instance = get_instance(
+        &state,
+        UserAction::StopInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     instance
-        .restart(
-            CausedBy::Macro {
-                macro_pid: task_pid,
-            },
-            block,
-        )
+        .restart(caller_cause(&state), block)
         .await
         .context("Failed to start instance")
 }
 
 #[op]
 async fn kill_instance(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
-    task_pid: MacroPID,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    let instance = get_instance(
+        &state,
+        UserAction::StopInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     instance
-        .kill(CausedBy::Macro {
-            macro_pid: task_pid,
-        })
+        .kill(caller_cause(&state))
         .await
         .context("Failed to start instance")
 }
 
 #[op]
-async fn get_instance_state(instance_uuid: InstanceUuid) -> Result<State, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_state(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<State, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
 
     Ok(instance.state().await)
 }
 
 #[op]
 async fn send_command(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
     command: String,
-    task_pid: MacroPID,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    let instance = get_instance(
+        &state,
+        UserAction::AccessConsole(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     instance
-        .send_command(
-            &command,
-            CausedBy::Macro {
-                macro_pid: task_pid,
-            },
-        )
+        .send_command(&command, caller_cause(&state))
         .await
         .context("Failed to start instance")
 }
 
 #[op]
-async fn monitor_instance(instance_uuid: InstanceUuid) -> Result<MonitorReport, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn monitor_instance(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<MonitorReport, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.monitor().await)
 }
 
 #[op]
-async fn get_instance_player_count(instance_uuid: InstanceUuid) -> Result<u32, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_player_count(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<u32, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.get_player_count().await?)
 }
 
 #[op]
-async fn get_instance_max_players(instance_uuid: InstanceUuid) -> Result<u32, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_max_players(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<u32, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.get_max_player_count().await?)
 }
 
 #[op]
 async fn get_instance_player_list(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
 ) -> Result<HashSet<Player>, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.get_player_list().await?)
 }
 
 #[op]
-async fn get_instance_name(instance_uuid: InstanceUuid) -> Result<String, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_name(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<String, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.name().await)
 }
 
 #[op]
-async fn get_instance_game(instance_uuid: InstanceUuid) -> Result<Game, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_game(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<Game, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.game_type().await)
 }
 
 #[op]
-async fn get_instance_game_version(instance_uuid: InstanceUuid) -> Result<String, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_game_version(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<String, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.version().await)
 }
 
 #[op]
-async fn get_instance_description(instance_uuid: InstanceUuid) -> Result<String, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_description(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<String, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.description().await)
 }
 
 #[op]
-async fn get_instance_port(instance_uuid: InstanceUuid) -> Result<u32, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_port(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<u32, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.port().await)
 }
 
 #[op]
-async fn get_instance_path(instance_uuid: InstanceUuid) -> Result<String, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn get_instance_path(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<String, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
     Ok(instance.path().await.to_string_lossy().to_string())
 }
 
 #[op]
-async fn set_instance_name(instance_uuid: InstanceUuid, name: String) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn set_instance_name(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    name: String,
+) -> Result<(), anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::AccessSetting(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
 
     instance
         .set_name(name)
@@ -249,13 +313,16 @@ async fn set_instance_name(instance_uuid: InstanceUuid, name: String) -> Result<
 
 #[op]
 async fn set_instance_description(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
     description: String,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    let instance = get_instance(
+        &state,
+        UserAction::AccessSetting(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
 
     instance
         .set_description(description)
@@ -264,11 +331,17 @@ async fn set_instance_description(
 }
 
 #[op]
-async fn set_instance_port(instance_uuid: InstanceUuid, port: u32) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+async fn set_instance_port(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    port: u32,
+) -> Result<(), anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::AccessSetting(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
 
     instance
         .set_port(port)
@@ -278,13 +351,16 @@ async fn set_instance_port(instance_uuid: InstanceUuid, port: u32) -> Result<(),
 
 #[op]
 async fn set_instance_auto_start(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
     auto_start: bool,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+    let instance = get_instance(
+        &state,
+        UserAction::AccessSetting(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
 
     instance
         .set_auto_start(auto_start)
@@ -293,16 +369,19 @@ async fn set_instance_auto_start(
 }
 
 #[op]
-async fn is_rcon_available(instance_uuid: InstanceUuid) -> Result<bool, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
-    match instance.value() {
-        crate::prelude::GameInstance::MinecraftInstance(v) => {
-            Ok(v.get_rcon().lock().await.is_some())
-        }
-        crate::prelude::GameInstance::GenericInstance(_) => {
+async fn is_rcon_available(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<bool, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    match &instance {
+        GameInstance::MinecraftInstance(v) => Ok(v.get_rcon().lock().await.is_some()),
+        GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
     }
@@ -310,16 +389,19 @@ async fn is_rcon_available(instance_uuid: InstanceUuid) -> Result<bool, anyhow::
 
 #[op]
 async fn try_send_rcon_command(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
     command: String,
 ) -> Result<Option<String>, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
-    match instance.value() {
-        crate::prelude::GameInstance::MinecraftInstance(v) => Ok(v.send_rcon(&command).await.ok()),
-        crate::prelude::GameInstance::GenericInstance(_) => {
+    let instance = get_instance(
+        &state,
+        UserAction::AccessConsole(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    match &instance {
+        GameInstance::MinecraftInstance(v) => Ok(v.send_rcon(&command).await.ok()),
+        GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
     }
@@ -327,15 +409,18 @@ async fn try_send_rcon_command(
 
 #[op]
 async fn send_rcon_command(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
     command: String,
 ) -> Result<String, anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
-    match instance.value() {
-        crate::prelude::GameInstance::MinecraftInstance(v) => {
+    let instance = get_instance(
+        &state,
+        UserAction::AccessConsole(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    match &instance {
+        GameInstance::MinecraftInstance(v) => {
             let rcon = v.get_rcon();
             loop {
                 if let Some(rcon) = rcon.lock().await.as_mut() {
@@ -344,20 +429,25 @@ async fn send_rcon_command(
                 tokio::time::sleep(std::time::Duration::from_millis(100)).await;
             }
         }
-        crate::prelude::GameInstance::GenericInstance(_) => {
+        GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
     }
 }
 
 #[op]
-async fn wait_till_rcon_available(instance_uuid: InstanceUuid) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
-    match instance.value() {
-        crate::prelude::GameInstance::MinecraftInstance(v) => {
+async fn wait_till_rcon_available(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<(), anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    match &instance {
+        GameInstance::MinecraftInstance(v) => {
             let rcon = v.get_rcon();
             loop {
                 if rcon.lock().await.is_some() {
@@ -366,7 +456,7 @@ async fn wait_till_rcon_available(instance_uuid: InstanceUuid) -> Result<(), any
                 tokio::time::sleep(std::time::Duration::from_millis(100)).await;
             }
         }
-        crate::prelude::GameInstance::GenericInstance(_) => {
+        GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
     }
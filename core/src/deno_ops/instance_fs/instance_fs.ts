@@ -0,0 +1,29 @@
+import { ClientFile } from "../../../deno_bindings/ClientFile.ts";
+
+export type { ClientFile };
+
+// deno-lint-ignore no-explicit-any
+declare const Deno: any;
+const core = Deno[Deno.internal].core;
+
+/**
+ * Files of an instance, checked against the permissions of the user who started the macro.
+ *
+ * Paths are relative to the root of the instance.
+ */
+
+export function listInstanceFiles(instanceUuid: string, relativePath: string): Promise<ClientFile[]> {
+    return core.opAsync("list_instance_files", instanceUuid, relativePath);
+}
+
+export function readInstanceFile(instanceUuid: string, relativePath: string): Promise<string> {
+    return core.opAsync("read_instance_file", instanceUuid, relativePath);
+}
+
+export function writeInstanceFile(instanceUuid: string, relativePath: string, content: string): Promise<void> {
+    return core.opAsync("write_instance_file", instanceUuid, relativePath, content);
+}
+
+export function removeInstanceFile(instanceUuid: string, relativePath: string): Promise<void> {
+    return core.opAsync("remove_instance_file", instanceUuid, relativePath);
+}
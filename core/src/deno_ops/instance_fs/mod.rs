@@ -0,0 +1,194 @@
+use std::{cell::RefCell, path::PathBuf, rc::Rc};
+
+use deno_core::{
+    anyhow::{self, bail, Context},
+    op, OpState,
+};
+
+use crate::{
+    auth::user::UserAction,
+    events::{new_fs_event, FSOperation, FSTarget},
+    fs_history::{scoped_join_instance_file, FileHistory, HISTORY_DIR_NAME},
+    handlers::{global_fs::FileEntry, instance_fs::is_path_protected},
+    macro_caller::MacroCaller,
+    prelude::app_state,
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::list_dir,
+};
+
+use super::prelude::{caller_cause, try_caller_action};
+
+/// The root of the instance and the path within it, once the caller may perform `action`
+async fn instance_path(
+    state: &Rc<RefCell<OpState>>,
+    instance_uuid: &InstanceUuid,
+    relative_path: &str,
+    action: UserAction,
+) -> Result<(PathBuf, PathBuf), anyhow::Error> {
+    try_caller_action(state, &action).await?;
+    let root = app_state()
+        .instances
+        .get(instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .path()
+        .await;
+    let path = scoped_join_instance_file(&root, relative_path)?;
+    Ok((root, path))
+}
+
+/// Files with a protected extension can only be changed by callers who may write global files
+async fn check_not_protected(
+    state: &Rc<RefCell<OpState>>,
+    path: &std::path::Path,
+) -> Result<(), anyhow::Error> {
+    let caller = state.borrow().borrow::<MacroCaller>().clone();
+    if is_path_protected(path)
+        && !caller.can_perform_action(
+            &UserAction::WriteGlobalFile,
+            &*app_state().users_manager.read().await,
+        )
+    {
+        bail!("File extension is protected");
+    }
+    Ok(())
+}
+
+#[op]
+async fn list_instance_files(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    relative_path: String,
+) -> Result<Vec<FileEntry>, anyhow::Error> {
+    let (root, path) = instance_path(
+        &state,
+        &instance_uuid,
+        &relative_path,
+        UserAction::ReadInstanceFile(instance_uuid.clone()),
+    )
+    .await?;
+    let history_dir = root.join(HISTORY_DIR_NAME);
+    let ret = list_dir(&path, None)
+        .await?
+        .iter()
+        .filter(|p| **p != history_dir)
+        .filter_map(|p| {
+            let mut entry: FileEntry = p.as_path().into();
+            entry.path = p.strip_prefix(&root).ok()?.to_str()?.to_owned();
+            Some(entry)
+        })
+        .collect();
+    app_state().event_broadcaster.send(new_fs_event(
+        FSOperation::Read,
+        FSTarget::Directory(path),
+        caller_cause(&state),
+    ));
+    Ok(ret)
+}
+
+#[op]
+async fn read_instance_file(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    relative_path: String,
+) -> Result<String, anyhow::Error> {
+    let (_, path) = instance_path(
+        &state,
+        &instance_uuid,
+        &relative_path,
+        UserAction::ReadInstanceFile(instance_uuid.clone()),
+    )
+    .await?;
+    let ret = tokio::fs::read_to_string(&path)
+        .await
+        .context("Failed to read file")?;
+    app_state().event_broadcaster.send(new_fs_event(
+        FSOperation::Read,
+        FSTarget::File(path),
+        caller_cause(&state),
+    ));
+    Ok(ret)
+}
+
+#[op]
+async fn write_instance_file(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    relative_path: String,
+    content: String,
+) -> Result<(), anyhow::Error> {
+    let (root, path) = instance_path(
+        &state,
+        &instance_uuid,
+        &relative_path,
+        UserAction::WriteInstanceFile(instance_uuid.clone()),
+    )
+    .await?;
+    check_not_protected(&state, &path).await?;
+    let name = app_state()
+        .instances
+        .get(&instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .name()
+        .await;
+    let existing_size = tokio::fs::metadata(&path).await.map_or(0, |m| m.len());
+    app_state()
+        .disk_usage
+        .check_write(
+            &instance_uuid,
+            &root,
+            &name,
+            (content.len() as u64).saturating_sub(existing_size),
+        )
+        .await?;
+    let caused_by = caller_cause(&state);
+    // the old content can't be restored if its revision wasn't kept
+    FileHistory::new(&root)
+        .record(&path, caused_by.clone())
+        .await?;
+    tokio::fs::write(&path, content)
+        .await
+        .context("Failed to write to file")?;
+    app_state().event_broadcaster.send(new_fs_event(
+        FSOperation::Write,
+        FSTarget::File(path),
+        caused_by,
+    ));
+    Ok(())
+}
+
+#[op]
+async fn remove_instance_file(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    relative_path: String,
+) -> Result<(), anyhow::Error> {
+    let (_, path) = instance_path(
+        &state,
+        &instance_uuid,
+        &relative_path,
+        UserAction::WriteInstanceFile(instance_uuid.clone()),
+    )
+    .await?;
+    check_not_protected(&state, &path).await?;
+    crate::util::fs::remove_file(&path).await?;
+    app_state().event_broadcaster.send(new_fs_event(
+        FSOperation::Delete,
+        FSTarget::File(path),
+        caller_cause(&state),
+    ));
+    Ok(())
+}
+
+pub fn register_instance_fs_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
+    worker_options.extensions.push(
+        deno_core::Extension::builder("instance_fs_ops")
+            .ops(vec![
+                list_instance_files::decl(),
+                read_instance_file::decl(),
+                write_instance_file::decl(),
+                remove_instance_file::decl(),
+            ])
+            .build(),
+    );
+}
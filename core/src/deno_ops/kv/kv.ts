@@ -0,0 +1,46 @@
+import { KvEntry } from "../../../deno_bindings/KvEntry.ts";
+
+export type { KvEntry };
+
+// deno-lint-ignore no-explicit-any
+declare const Deno: any;
+const core = Deno[Deno.internal].core;
+
+/**
+ * Persistent storage of the running macro, kept between runs.
+ *
+ * Keys are private to the macro and the instance it runs for.
+ */
+
+export function get(key: string): Promise<KvEntry | null> {
+    return core.opAsync("kv_get", key);
+}
+
+/** Returns the new version of the key */
+export function set(key: string, value: unknown): Promise<number> {
+    return core.opAsync("kv_set", key, value);
+}
+
+/** Returns whether the key existed */
+export function remove(key: string): Promise<boolean> {
+    return core.opAsync("kv_delete", key);
+}
+
+/** Keys starting with `prefix`, sorted by key */
+export function list(prefix = "", limit?: number): Promise<KvEntry[]> {
+    return core.opAsync("kv_list", prefix, limit ?? null);
+}
+
+/**
+ * Sets the key to `value`, or deletes it if `value` is null, only if it is still at `expectedVersion`.
+ * An `expectedVersion` of null expects the key to not exist.
+ *
+ * Returns whether the swap happened
+ */
+export function compareAndSwap(
+    key: string,
+    expectedVersion: number | null,
+    value: unknown | null,
+): Promise<boolean> {
+    return core.opAsync("kv_compare_and_swap", key, expectedVersion, value);
+}
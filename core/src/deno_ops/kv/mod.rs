@@ -0,0 +1,80 @@
+use std::{cell::RefCell, rc::Rc};
+
+use deno_core::{anyhow, op, OpState};
+use serde_json::Value;
+
+use crate::{
+    macro_kv::{KvEntry, KvNamespace},
+    prelude::app_state,
+};
+
+fn namespace(state: &Rc<RefCell<OpState>>) -> KvNamespace {
+    state.borrow().borrow::<KvNamespace>().clone()
+}
+
+#[op]
+async fn kv_get(
+    state: Rc<RefCell<OpState>>,
+    key: String,
+) -> Result<Option<KvEntry>, anyhow::Error> {
+    Ok(app_state().macro_kv.get(&namespace(&state), &key).await?)
+}
+
+#[op]
+async fn kv_set(
+    state: Rc<RefCell<OpState>>,
+    key: String,
+    value: Value,
+) -> Result<i64, anyhow::Error> {
+    Ok(app_state()
+        .macro_kv
+        .set(&namespace(&state), &key, &value)
+        .await?)
+}
+
+#[op]
+async fn kv_delete(state: Rc<RefCell<OpState>>, key: String) -> Result<bool, anyhow::Error> {
+    Ok(app_state()
+        .macro_kv
+        .delete(&namespace(&state), &key)
+        .await?)
+}
+
+#[op]
+async fn kv_list(
+    state: Rc<RefCell<OpState>>,
+    prefix: String,
+    limit: Option<u32>,
+) -> Result<Vec<KvEntry>, anyhow::Error> {
+    Ok(app_state()
+        .macro_kv
+        .list(&namespace(&state), &prefix, limit)
+        .await?)
+}
+
+#[op]
+async fn kv_compare_and_swap(
+    state: Rc<RefCell<OpState>>,
+    key: String,
+    expected_version: Option<i64>,
+    value: Option<Value>,
+) -> Result<bool, anyhow::Error> {
+    Ok(app_state()
+        .macro_kv
+        .compare_and_swap(&namespace(&state), &key, expected_version, value.as_ref())
+        .await?)
+}
+
+pub fn register_kv_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
+    worker_options.extensions.push(
+        deno_core::Extension::builder("kv_ops")
+            .ops(vec![
+                kv_get::decl(),
+                kv_set::decl(),
+                kv_delete::decl(),
+                kv_list::decl(),
+                kv_compare_and_swap::decl(),
+            ])
+            .build(),
+    );
+}
@@ -1,3 +1,8 @@
+pub mod event_db;
 pub mod events;
 pub mod instance_control;
+pub mod instance_fs;
+pub mod kv;
+pub mod player_management;
 pub mod prelude;
+pub mod testing;
@@ -0,0 +1,108 @@
+use std::{cell::RefCell, rc::Rc};
+
+use deno_core::{anyhow, op, OpState};
+
+use crate::{
+    auth::user::UserAction,
+    prelude::{app_state, GameInstance},
+    traits::t_player::{Player, TPlayer, TPlayerManagement},
+    types::InstanceUuid,
+};
+
+use super::prelude::{caller_cause, try_caller_action};
+
+async fn get_instance(
+    state: &Rc<RefCell<OpState>>,
+    action: UserAction,
+    instance_uuid: &InstanceUuid,
+) -> Result<GameInstance, anyhow::Error> {
+    try_caller_action(state, &action).await?;
+    Ok(app_state()
+        .instances
+        .get(instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .value()
+        .clone())
+}
+
+#[op]
+async fn get_players(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<Vec<Player>, anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::ViewInstance(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    let mut players: Vec<Player> = instance.get_player_list().await?.into_iter().collect();
+    players.sort_by_key(|player| player.get_name());
+    Ok(players)
+}
+
+#[op]
+async fn kick_player(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    player_name: String,
+    reason: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::AccessConsole(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    Ok(instance
+        .kick_player(&player_name, reason.as_deref(), caller_cause(&state))
+        .await?)
+}
+
+#[op]
+async fn ban_player(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    player_name: String,
+    reason: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::AccessConsole(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    Ok(instance
+        .ban_player(&player_name, reason.as_deref(), caller_cause(&state))
+        .await?)
+}
+
+#[op]
+async fn pardon_player(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    player_name: String,
+) -> Result<(), anyhow::Error> {
+    let instance = get_instance(
+        &state,
+        UserAction::AccessConsole(instance_uuid.clone()),
+        &instance_uuid,
+    )
+    .await?;
+    Ok(instance
+        .pardon_player(&player_name, caller_cause(&state))
+        .await?)
+}
+
+pub fn register_player_management_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
+    worker_options.extensions.push(
+        deno_core::Extension::builder("player_management_ops")
+            .ops(vec![
+                get_players::decl(),
+                kick_player::decl(),
+                ban_player::decl(),
+                pardon_player::decl(),
+            ])
+            .build(),
+    );
+}
@@ -0,0 +1,24 @@
+import { Player } from "../../../deno_bindings/Player.ts";
+
+export type { Player };
+
+// deno-lint-ignore no-explicit-any
+declare const Deno: any;
+const core = Deno[Deno.internal].core;
+
+/** Players online, sorted by name */
+export function getPlayers(instanceUuid: string): Promise<Player[]> {
+    return core.opAsync("get_players", instanceUuid);
+}
+
+export function kickPlayer(instanceUuid: string, playerName: string, reason?: string): Promise<void> {
+    return core.opAsync("kick_player", instanceUuid, playerName, reason ?? null);
+}
+
+export function banPlayer(instanceUuid: string, playerName: string, reason?: string): Promise<void> {
+    return core.opAsync("ban_player", instanceUuid, playerName, reason ?? null);
+}
+
+export function pardonPlayer(instanceUuid: string, playerName: string): Promise<void> {
+    return core.opAsync("pardon_player", instanceUuid, playerName);
+}
@@ -1,12 +1,38 @@
-use deno_core::op;
+use std::{cell::RefCell, rc::Rc};
 
-use crate::prelude::VERSION;
+use deno_core::{anyhow, op, OpState};
+
+use crate::{
+    auth::user::UserAction,
+    events::CausedBy,
+    macro_caller::MacroCaller,
+    macro_executor::MacroPID,
+    prelude::{app_state, VERSION},
+};
 
 #[op]
 fn get_lodestone_version() -> String {
     VERSION.with(|v| v.to_string())
 }
 
+/// Checks the action against the permissions of whoever the macro runs for
+pub async fn try_caller_action(
+    state: &Rc<RefCell<OpState>>,
+    action: &UserAction,
+) -> Result<(), anyhow::Error> {
+    let caller = state.borrow().borrow::<MacroCaller>().clone();
+    let safe_mode = app_state().global_settings.lock().await.safe_mode();
+    caller.try_action(action, &*app_state().users_manager.read().await, safe_mode)?;
+    Ok(())
+}
+
+/// The macro making the call, as the cause of what the op does
+pub fn caller_cause(state: &Rc<RefCell<OpState>>) -> CausedBy {
+    CausedBy::Macro {
+        macro_pid: *state.borrow().borrow::<MacroPID>(),
+    }
+}
+
 pub fn register_prelude_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
     worker_options.extensions.push(
         deno_core::Extension::builder("prelude_ops")
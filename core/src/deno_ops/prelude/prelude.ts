@@ -1,4 +1,7 @@
 import { TaskPID } from "../../../deno_bindings/TaskPID.ts";
+import { MacroTriggerFired } from "../../../deno_bindings/MacroTriggerFired.ts";
+
+export type { MacroTriggerFired };
 
 
 declare const __macro_pid: TaskPID;
@@ -19,4 +22,17 @@ export function getCurrentInstanceUUID(): string | null {
 
 export function lodestoneVersion(): string {
     return ops.get_lodestone_version();
-}
\ No newline at end of file
+}
+
+/** The trigger that ran this macro, null if it wasn't run by a trigger */
+export function getTrigger(): MacroTriggerFired | null {
+    if (Deno.args.length !== 1) {
+        return null;
+    }
+    try {
+        const arg = JSON.parse(Deno.args[0]);
+        return arg && typeof arg === "object" && "trigger" in arg ? arg : null;
+    } catch {
+        return null;
+    }
+}
@@ -0,0 +1,314 @@
+use std::{cell::RefCell, rc::Rc};
+
+use deno_core::{
+    anyhow::{self, bail},
+    op, OpState,
+};
+
+use crate::{
+    error::Error,
+    macro_test::{FakeInstance, MacroTestCase, MacroTestHarness},
+    minecraft::player::player_command,
+    traits::{
+        t_player::{Player, TPlayer},
+        t_server::State,
+    },
+    types::InstanceUuid,
+};
+
+// Stand-ins for the instance control and player management ops, with the same names and
+// arguments so macros run unchanged.
+
+fn fake_instance<T>(state: &Rc<RefCell<OpState>>, f: impl FnOnce(&mut FakeInstance) -> T) -> T {
+    f(&mut state.borrow_mut().borrow_mut::<MacroTestHarness>().instance)
+}
+
+fn with_instance<T>(
+    state: &Rc<RefCell<OpState>>,
+    instance_uuid: &InstanceUuid,
+    f: impl FnOnce(&mut FakeInstance) -> Result<T, Error>,
+) -> Result<T, anyhow::Error> {
+    fake_instance(state, |instance| {
+        if &instance.uuid != instance_uuid {
+            bail!("Instance not found");
+        }
+        Ok(f(instance)?)
+    })
+}
+
+#[op]
+fn instance_exists(state: Rc<RefCell<OpState>>, instance_uuid: InstanceUuid) -> bool {
+    fake_instance(&state, |instance| instance.uuid == instance_uuid)
+}
+
+#[op]
+fn all_instances(state: Rc<RefCell<OpState>>) -> Vec<InstanceUuid> {
+    fake_instance(&state, |instance| vec![instance.uuid.clone()])
+}
+
+#[op]
+async fn get_instance_state(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<State, anyhow::Error> {
+    with_instance(&state, &instance_uuid, |instance| Ok(instance.state()))
+}
+
+#[op]
+async fn get_instance_name(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<String, anyhow::Error> {
+    with_instance(&state, &instance_uuid, |instance| Ok(instance.name.clone()))
+}
+
+#[op]
+async fn get_instance_player_count(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<u32, anyhow::Error> {
+    with_instance(&state, &instance_uuid, |instance| {
+        Ok(instance.players().len() as u32)
+    })
+}
+
+#[op]
+async fn get_instance_max_players(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<u32, anyhow::Error> {
+    with_instance(
+        &state,
+        &instance_uuid,
+        |instance| Ok(instance.max_players()),
+    )
+}
+
+#[op]
+async fn get_instance_player_list(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<Vec<Player>, anyhow::Error> {
+    with_instance(&state, &instance_uuid, |instance| {
+        Ok(instance.players().into_iter().collect())
+    })
+}
+
+#[op]
+async fn start_instance(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<(), anyhow::Error> {
+    with_instance(&state, &instance_uuid, FakeInstance::start)
+}
+
+#[op]
+async fn stop_instance(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<(), anyhow::Error> {
+    with_instance(&state, &instance_uuid, FakeInstance::stop)
+}
+
+#[op]
+async fn restart_instance(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<(), anyhow::Error> {
+    with_instance(&state, &instance_uuid, FakeInstance::restart)
+}
+
+#[op]
+async fn kill_instance(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<(), anyhow::Error> {
+    with_instance(&state, &instance_uuid, FakeInstance::kill)
+}
+
+#[op]
+async fn send_command(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    command: String,
+) -> Result<(), anyhow::Error> {
+    with_instance(&state, &instance_uuid, |instance| {
+        instance.send_command(&command)
+    })
+}
+
+#[op]
+async fn get_players(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<Vec<Player>, anyhow::Error> {
+    with_instance(&state, &instance_uuid, |instance| {
+        let mut players: Vec<Player> = instance.players().into_iter().collect();
+        players.sort_by_key(|player| player.get_name());
+        Ok(players)
+    })
+}
+
+#[op]
+async fn kick_player(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    player_name: String,
+    reason: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let command = player_command("kick", &player_name, reason.as_deref())?;
+    with_instance(&state, &instance_uuid, |instance| {
+        instance.send_command(&command)?;
+        instance.leave(&player_name);
+        Ok(())
+    })
+}
+
+#[op]
+async fn ban_player(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    player_name: String,
+    reason: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let command = player_command("ban", &player_name, reason.as_deref())?;
+    with_instance(&state, &instance_uuid, |instance| {
+        instance.send_command(&command)?;
+        instance.leave(&player_name);
+        Ok(())
+    })
+}
+
+#[op]
+async fn pardon_player(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    player_name: String,
+) -> Result<(), anyhow::Error> {
+    let command = player_command("pardon", &player_name, None)?;
+    with_instance(&state, &instance_uuid, |instance| {
+        instance.send_command(&command)
+    })
+}
+
+// Used by tests to drive the fake instance.
+// Events are sent on the next turn of the event loop, so ops the macro already
+// started waiting on see them.
+
+#[op]
+fn fake_reset(state: Rc<RefCell<OpState>>) {
+    fake_instance(&state, FakeInstance::reset)
+}
+
+#[op]
+async fn fake_set_state(state: Rc<RefCell<OpState>>, to: State) {
+    tokio::task::yield_now().await;
+    fake_instance(&state, |instance| instance.set_state(to))
+}
+
+#[op]
+async fn fake_emit_output(state: Rc<RefCell<OpState>>, line: String) {
+    tokio::task::yield_now().await;
+    fake_instance(&state, |instance| instance.emit_output(line))
+}
+
+#[op]
+async fn fake_emit_player_message(state: Rc<RefCell<OpState>>, player: String, message: String) {
+    tokio::task::yield_now().await;
+    fake_instance(&state, |instance| {
+        instance.emit_player_message(player, message)
+    })
+}
+
+#[op]
+async fn fake_emit_system_message(state: Rc<RefCell<OpState>>, message: String) {
+    tokio::task::yield_now().await;
+    fake_instance(&state, |instance| instance.emit_system_message(message))
+}
+
+#[op]
+async fn fake_player_join(state: Rc<RefCell<OpState>>, player_name: String) {
+    tokio::task::yield_now().await;
+    fake_instance(&state, |instance| instance.join(player_name))
+}
+
+#[op]
+async fn fake_player_leave(state: Rc<RefCell<OpState>>, player_name: String) {
+    tokio::task::yield_now().await;
+    fake_instance(&state, |instance| instance.leave(&player_name))
+}
+
+#[op]
+fn fake_respond_to(state: Rc<RefCell<OpState>>, command: String, lines: Vec<String>) {
+    fake_instance(&state, |instance| instance.respond_to(command, lines))
+}
+
+#[op]
+fn fake_commands(state: Rc<RefCell<OpState>>) -> Vec<String> {
+    fake_instance(&state, |instance| instance.commands().to_vec())
+}
+
+#[op]
+fn fake_clear_commands(state: Rc<RefCell<OpState>>) {
+    fake_instance(&state, FakeInstance::clear_commands)
+}
+
+#[op]
+fn report_test_case(
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    error: Option<String>,
+    duration_ms: u32,
+) {
+    state
+        .borrow()
+        .borrow::<MacroTestHarness>()
+        .recorder
+        .record(MacroTestCase {
+            name,
+            error,
+            duration_ms,
+        });
+}
+
+pub fn register_testing_ops(
+    worker_options: &mut deno_runtime::worker::WorkerOptions,
+    test_harness: MacroTestHarness,
+) {
+    worker_options.extensions.push(
+        deno_core::Extension::builder("testing_ops")
+            .ops(vec![
+                instance_exists::decl(),
+                all_instances::decl(),
+                get_instance_state::decl(),
+                get_instance_name::decl(),
+                get_instance_player_count::decl(),
+                get_instance_max_players::decl(),
+                get_instance_player_list::decl(),
+                start_instance::decl(),
+                stop_instance::decl(),
+                restart_instance::decl(),
+                kill_instance::decl(),
+                send_command::decl(),
+                get_players::decl(),
+                kick_player::decl(),
+                ban_player::decl(),
+                pardon_player::decl(),
+                fake_reset::decl(),
+                fake_set_state::decl(),
+                fake_emit_output::decl(),
+                fake_emit_player_message::decl(),
+                fake_emit_system_message::decl(),
+                fake_player_join::decl(),
+                fake_player_leave::decl(),
+                fake_respond_to::decl(),
+                fake_commands::decl(),
+                fake_clear_commands::decl(),
+                report_test_case::decl(),
+            ])
+            .state(|state| {
+                state.put(test_harness);
+            })
+            .build(),
+    );
+}
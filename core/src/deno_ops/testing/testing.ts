@@ -0,0 +1,106 @@
+import { InstanceState } from "../../../deno_bindings/InstanceState.ts";
+import { getCurrentInstanceUUID } from "../prelude/prelude.ts";
+
+export type { InstanceState };
+
+// deno-lint-ignore no-explicit-any
+declare const Deno: any;
+const core = Deno[Deno.internal].core;
+const { ops } = core;
+
+/**
+ * Helpers for `*.test.ts` files, which run against a fake instance instead of a real server.
+ *
+ * The instance control and player management functions work on the fake instance,
+ * whose uuid is `getCurrentInstanceUUID()`. Other instance ops are not available in tests.
+ */
+
+let queue: Promise<void> = Promise.resolve();
+
+/** Registers a test case, cases run one at a time in order, each with a reset fake instance */
+export function test(name: string, fn: () => void | Promise<void>): void {
+    queue = queue.then(async () => {
+        ops.fake_reset();
+        const start = performance.now();
+        let error: string | null = null;
+        try {
+            await fn();
+        } catch (e) {
+            error = e instanceof Error ? e.stack ?? e.message : String(e);
+        }
+        ops.report_test_case(name, error, Math.round(performance.now() - start));
+    });
+}
+
+export class AssertionError extends Error {
+    constructor(message: string) {
+        super(message);
+        this.name = "AssertionError";
+    }
+}
+
+export function assert(condition: unknown, message = "Assertion failed"): asserts condition {
+    if (!condition) {
+        throw new AssertionError(message);
+    }
+}
+
+/** Compares the JSON of both values */
+export function assertEquals(actual: unknown, expected: unknown, message?: string): void {
+    const actualJson = JSON.stringify(actual);
+    const expectedJson = JSON.stringify(expected);
+    if (actualJson !== expectedJson) {
+        throw new AssertionError(message ?? `Expected ${expectedJson}, got ${actualJson}`);
+    }
+}
+
+/**
+ * Drives the fake instance.
+ *
+ * Events are delivered once the returned promise resolves,
+ * after anything the code under test is already waiting on.
+ */
+export const fakeInstance = {
+    uuid(): string {
+        return getCurrentInstanceUUID()!;
+    },
+
+    /** Moves to the state without going through the state machine */
+    setState(state: InstanceState): Promise<void> {
+        return core.opAsync("fake_set_state", state);
+    },
+
+    emitOutput(line: string): Promise<void> {
+        return core.opAsync("fake_emit_output", line);
+    },
+
+    emitPlayerMessage(player: string, message: string): Promise<void> {
+        return core.opAsync("fake_emit_player_message", player, message);
+    },
+
+    emitSystemMessage(message: string): Promise<void> {
+        return core.opAsync("fake_emit_system_message", message);
+    },
+
+    join(playerName: string): Promise<void> {
+        return core.opAsync("fake_player_join", playerName);
+    },
+
+    leave(playerName: string): Promise<void> {
+        return core.opAsync("fake_player_leave", playerName);
+    },
+
+    /** Prints `lines` to the console whenever `command` is sent */
+    respondTo(command: string, lines: string[]): void {
+        ops.fake_respond_to(command, lines);
+    },
+
+    /** Commands sent to the console since the case started, in order */
+    commands(): string[] {
+        return ops.fake_commands();
+    },
+
+    clearCommands(): void {
+        ops.fake_clear_commands();
+    },
+};
@@ -0,0 +1,491 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, broadcast::Receiver, Mutex};
+use tracing::{error, warn};
+use ts_rs::TS;
+use walkdir::WalkDir;
+
+use crate::{
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    events::{
+        CausedBy, Event, EventInner, FSEvent, FSOperation, FSTarget, InstanceEvent,
+        InstanceEventInner,
+    },
+    prelude::GameInstance,
+    traits::t_configurable::TConfigurable,
+    types::{InstanceUuid, Snowflake},
+    util::format_byte,
+};
+
+/// Cached usage older than this is recomputed, catching writes made by the instance itself
+const USAGE_MAX_AGE: i64 = 10 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct DiskQuota {
+    /// A warning is sent when usage goes past this many bytes
+    pub soft_limit: Option<u64>,
+    /// Writes through the API that would go past this many bytes are refused
+    pub hard_limit: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct DiskUsageEntry {
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct InstanceDiskUsage {
+    pub total: u64,
+    pub quota: DiskQuota,
+    /// Top level files and directories of the instance, largest first
+    pub entries: Vec<DiskUsageEntry>,
+    pub computed_at: i64,
+}
+
+struct CachedUsage {
+    root: PathBuf,
+    entries: HashMap<String, DiskUsageEntry>,
+    computed_at: i64,
+    /// Set once a warning was sent for the soft limit, until usage goes back under it
+    soft_limit_warned: bool,
+}
+
+impl CachedUsage {
+    fn total(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+}
+
+/// Size of a file or a whole directory, symlinks are not followed
+fn entry_usage(path: &Path) -> Option<DiskUsageEntry> {
+    let metadata = std::fs::symlink_metadata(path).ok()?;
+    let size = if metadata.is_dir() {
+        WalkDir::new(path)
+            .follow_links(false)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
+    } else {
+        metadata.len()
+    };
+    Some(DiskUsageEntry {
+        name: path.file_name()?.to_string_lossy().to_string(),
+        size,
+        is_dir: metadata.is_dir(),
+    })
+}
+
+fn compute_usage(root: &Path) -> Result<HashMap<String, DiskUsageEntry>, Error> {
+    Ok(std::fs::read_dir(root)
+        .context(format!("Failed to read directory {}", root.display()))?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry_usage(&entry.path()))
+        .map(|entry| (entry.name.clone(), entry))
+        .collect())
+}
+
+/// Tracks how much disk each instance uses and the quotas set on them
+#[derive(Clone)]
+pub struct DiskUsageTracker {
+    path_to_quotas: PathBuf,
+    quotas: Arc<Mutex<HashMap<InstanceUuid, DiskQuota>>>,
+    usage: Arc<Mutex<HashMap<InstanceUuid, CachedUsage>>>,
+    event_broadcaster: EventBroadcaster,
+}
+
+impl DiskUsageTracker {
+    pub fn new(path_to_quotas: PathBuf, event_broadcaster: EventBroadcaster) -> Self {
+        Self {
+            path_to_quotas,
+            quotas: Arc::new(Mutex::new(HashMap::new())),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            event_broadcaster,
+        }
+    }
+
+    pub async fn load_from_file(&self) -> Result<(), Error> {
+        let quotas = match tokio::fs::read(&self.path_to_quotas).await {
+            Ok(data) if !data.is_empty() => serde_json::from_slice(&data).context(format!(
+                "Failed to parse disk quotas at {}",
+                self.path_to_quotas.display()
+            ))?,
+            Ok(_) => HashMap::new(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(color_eyre::Report::new(e)
+                    .wrap_err(format!(
+                        "Failed to read disk quotas at {}",
+                        self.path_to_quotas.display()
+                    ))
+                    .into())
+            }
+        };
+        *self.quotas.lock().await = quotas;
+        Ok(())
+    }
+
+    async fn write_to_file(&self, quotas: &HashMap<InstanceUuid, DiskQuota>) -> Result<(), Error> {
+        tokio::fs::write(
+            &self.path_to_quotas,
+            serde_json::to_string_pretty(quotas).context("Failed to serialize disk quotas")?,
+        )
+        .await
+        .context(format!(
+            "Failed to write disk quotas at {}",
+            self.path_to_quotas.display()
+        ))?;
+        Ok(())
+    }
+
+    pub async fn quota(&self, uuid: &InstanceUuid) -> DiskQuota {
+        self.quotas
+            .lock()
+            .await
+            .get(uuid)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn set_quota(&self, uuid: InstanceUuid, quota: DiskQuota) -> Result<(), Error> {
+        if let (Some(soft_limit), Some(hard_limit)) = (quota.soft_limit, quota.hard_limit) {
+            if soft_limit > hard_limit {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("The soft limit can't be above the hard limit"),
+                });
+            }
+        }
+        let mut quotas = self.quotas.lock().await;
+        let old_quota = if quota == DiskQuota::default() {
+            quotas.remove(&uuid)
+        } else {
+            quotas.insert(uuid.clone(), quota)
+        };
+        if let Err(e) = self.write_to_file(&quotas).await {
+            match old_quota {
+                Some(old_quota) => quotas.insert(uuid, old_quota),
+                None => quotas.remove(&uuid),
+            };
+            return Err(e);
+        }
+        drop(quotas);
+        if let Some(usage) = self.usage.lock().await.get_mut(&uuid) {
+            usage.soft_limit_warned = false;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_instance(&self, uuid: &InstanceUuid) {
+        self.usage.lock().await.remove(uuid);
+        let mut quotas = self.quotas.lock().await;
+        if quotas.remove(uuid).is_some() {
+            if let Err(e) = self.write_to_file(&quotas).await {
+                error!("Failed to remove disk quota of {uuid}: {e}");
+            }
+        }
+    }
+
+    /// Returns the cached usage, recomputing it if it's too old
+    pub async fn usage(
+        &self,
+        uuid: &InstanceUuid,
+        root: &Path,
+        instance_name: &str,
+    ) -> Result<InstanceDiskUsage, Error> {
+        let now = chrono::Utc::now().timestamp();
+        let is_fresh = self.usage.lock().await.get(uuid).map_or(false, |usage| {
+            usage.root == root && now - usage.computed_at < USAGE_MAX_AGE
+        });
+        if !is_fresh {
+            let entries = {
+                let root = root.to_owned();
+                tokio::task::spawn_blocking(move || compute_usage(&root))
+                    .await
+                    .context("Failed to compute disk usage")??
+            };
+            let mut usage = self.usage.lock().await;
+            let soft_limit_warned = usage.get(uuid).map_or(false, |u| u.soft_limit_warned);
+            usage.insert(
+                uuid.clone(),
+                CachedUsage {
+                    root: root.to_owned(),
+                    entries,
+                    computed_at: now,
+                    soft_limit_warned,
+                },
+            );
+        }
+        let quota = self.quota(uuid).await;
+        let mut usage = self.usage.lock().await;
+        let usage = usage.get_mut(uuid).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+        self.check_soft_limit(uuid, instance_name, usage, &quota);
+        let mut entries: Vec<DiskUsageEntry> = usage.entries.values().cloned().collect();
+        entries.sort_by(|a, b| b.size.cmp(&a.size));
+        Ok(InstanceDiskUsage {
+            total: usage.total(),
+            quota,
+            entries,
+            computed_at: usage.computed_at,
+        })
+    }
+
+    fn check_soft_limit(
+        &self,
+        uuid: &InstanceUuid,
+        instance_name: &str,
+        usage: &mut CachedUsage,
+        quota: &DiskQuota,
+    ) {
+        let soft_limit = match quota.soft_limit {
+            Some(soft_limit) => soft_limit,
+            None => return,
+        };
+        let total = usage.total();
+        if total <= soft_limit {
+            usage.soft_limit_warned = false;
+        } else if !usage.soft_limit_warned {
+            usage.soft_limit_warned = true;
+            self.event_broadcaster.send(Event {
+                event_inner: EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid: uuid.clone(),
+                    instance_name: instance_name.to_string(),
+                    instance_event_inner: InstanceEventInner::InstanceWarning {
+                        message: format!(
+                            "Instance is using {} of disk, over its soft limit of {}",
+                            format_byte(total),
+                            format_byte(soft_limit)
+                        ),
+                    },
+                }),
+                details: "".to_string(),
+                snowflake: Snowflake::default(),
+                caused_by: CausedBy::System,
+            });
+        }
+    }
+
+    /// Bytes that can still be written before the instance hits its hard limit, none without one
+    pub async fn remaining(
+        &self,
+        uuid: &InstanceUuid,
+        root: &Path,
+        instance_name: &str,
+    ) -> Result<Option<u64>, Error> {
+        let hard_limit = match self.quota(uuid).await.hard_limit {
+            Some(hard_limit) => hard_limit,
+            None => return Ok(None),
+        };
+        let total = self.usage(uuid, root, instance_name).await?.total;
+        Ok(Some(hard_limit.saturating_sub(total)))
+    }
+
+    /// Refuses writes that would take the instance over its hard limit
+    pub async fn check_write(
+        &self,
+        uuid: &InstanceUuid,
+        root: &Path,
+        instance_name: &str,
+        additional_bytes: u64,
+    ) -> Result<(), Error> {
+        let hard_limit = match self.quota(uuid).await.hard_limit {
+            Some(hard_limit) => hard_limit,
+            None => return Ok(()),
+        };
+        let total = self.usage(uuid, root, instance_name).await?.total;
+        if total.saturating_add(additional_bytes) > hard_limit {
+            return Err(Error {
+                kind: ErrorKind::InsufficientStorage,
+                source: eyre!(
+                    "Instance is using {} of its {} disk quota, not enough for {} more",
+                    format_byte(total),
+                    format_byte(hard_limit),
+                    format_byte(additional_bytes)
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Recomputes the top level entry containing `path`, if the usage of the instance is cached
+    async fn refresh_path(&self, uuid: &InstanceUuid, instance_name: &str, path: &Path) {
+        let (root, name) = {
+            let usage = self.usage.lock().await;
+            let usage = match usage.get(uuid) {
+                Some(usage) => usage,
+                None => return,
+            };
+            let name = match path
+                .strip_prefix(&usage.root)
+                .ok()
+                .and_then(|relative| relative.components().next())
+            {
+                Some(name) => name.as_os_str().to_string_lossy().to_string(),
+                None => return,
+            };
+            (usage.root.clone(), name)
+        };
+        let entry = {
+            let path = root.join(&name);
+            tokio::task::spawn_blocking(move || entry_usage(&path))
+                .await
+                .ok()
+                .flatten()
+        };
+        let quota = self.quota(uuid).await;
+        let mut usage = self.usage.lock().await;
+        if let Some(usage) = usage.get_mut(uuid) {
+            match entry {
+                Some(entry) => usage.entries.insert(name, entry),
+                None => usage.entries.remove(&name),
+            };
+            self.check_soft_limit(uuid, instance_name, usage, &quota);
+        }
+    }
+
+    /// Forgets the cached usage, so it is recomputed in full next time
+    pub async fn invalidate(&self, uuid: &InstanceUuid) {
+        if let Some(usage) = self.usage.lock().await.get_mut(uuid) {
+            usage.computed_at = 0;
+        }
+    }
+
+    /// Keeps cached usage up to date with file system events
+    pub async fn track_fs_events(
+        self,
+        instances: Arc<dashmap::DashMap<InstanceUuid, GameInstance>>,
+        mut event_receiver: Receiver<Event>,
+    ) {
+        loop {
+            let event = match event_receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => {
+                    warn!("Disk usage tracker lagged, recomputing usage");
+                    for usage in self.usage.lock().await.values_mut() {
+                        usage.computed_at = 0;
+                    }
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let FSEvent { operation, target } = match event.event_inner {
+                EventInner::FSEvent(fs_event) => fs_event,
+                _ => continue,
+            };
+            let mut paths = match target {
+                FSTarget::File(path) | FSTarget::Directory(path) => vec![path],
+            };
+            match operation {
+                FSOperation::Read | FSOperation::Download => continue,
+                FSOperation::Move { source } => paths.push(source),
+                _ => {}
+            }
+            let instances: Vec<(InstanceUuid, GameInstance)> = instances
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect();
+            for (uuid, instance) in instances {
+                let root = instance.path().await;
+                for path in paths.iter().filter(|path| path.starts_with(&root)) {
+                    self.refresh_path(&uuid, &instance.name().await, path).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_disk_quota() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("instance");
+        std::fs::create_dir_all(root.join("world/region")).unwrap();
+        std::fs::write(root.join("world/region/r.0.0.mca"), vec![0; 3000]).unwrap();
+        std::fs::write(root.join("server.properties"), vec![0; 100]).unwrap();
+
+        let (event_broadcaster, mut rx) = EventBroadcaster::new(16);
+        let tracker =
+            DiskUsageTracker::new(temp_dir.path().join("disk_quotas.json"), event_broadcaster);
+        let uuid = InstanceUuid::default();
+
+        let usage = tracker.usage(&uuid, &root, "test").await.unwrap();
+        assert_eq!(usage.total, 3100);
+        assert_eq!(usage.entries[0].name, "world");
+        assert!(usage.entries[0].is_dir);
+
+        assert!(tracker
+            .set_quota(
+                uuid.clone(),
+                DiskQuota {
+                    soft_limit: Some(5000),
+                    hard_limit: Some(4000),
+                },
+            )
+            .await
+            .is_err());
+        tracker
+            .set_quota(
+                uuid.clone(),
+                DiskQuota {
+                    soft_limit: Some(3500),
+                    hard_limit: Some(4000),
+                },
+            )
+            .await
+            .unwrap();
+        assert!(tracker.check_write(&uuid, &root, "test", 900).await.is_ok());
+        assert!(matches!(
+            tracker
+                .check_write(&uuid, &root, "test", 901)
+                .await
+                .unwrap_err()
+                .kind,
+            ErrorKind::InsufficientStorage
+        ));
+
+        // going over the soft limit warns once
+        std::fs::write(root.join("world/level.dat"), vec![0; 500]).unwrap();
+        tracker
+            .refresh_path(&uuid, "test", &root.join("world/level.dat"))
+            .await;
+        tracker
+            .refresh_path(&uuid, "test", &root.join("world/level.dat"))
+            .await;
+        assert!(matches!(
+            rx.try_recv().unwrap().event_inner,
+            EventInner::InstanceEvent(InstanceEvent {
+                instance_event_inner: InstanceEventInner::InstanceWarning { .. },
+                ..
+            })
+        ));
+        assert!(rx.try_recv().is_err());
+
+        // quotas persist
+        let reloaded = DiskUsageTracker::new(
+            temp_dir.path().join("disk_quotas.json"),
+            EventBroadcaster::new(1).0,
+        );
+        reloaded.load_from_file().await.unwrap();
+        assert_eq!(reloaded.quota(&uuid).await.hard_limit, Some(4000));
+    }
+}
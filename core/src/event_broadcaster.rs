@@ -1,6 +1,6 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, time::Duration};
 
-use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
 use tracing::error;
 
 use crate::{
@@ -133,6 +133,47 @@ impl EventBroadcaster {
     }
 }
 
+impl EventBroadcaster {
+    /// Collects the output lines of the given instance from `rx`.
+    ///
+    /// Stops once `window` has elapsed, or when no new line arrived for `idle` after the first one.
+    /// `rx` should be subscribed before the command is sent so that no output is missed.
+    pub async fn collect_instance_output(
+        mut rx: Receiver<Event>,
+        instance_uuid: &InstanceUuid,
+        window: Duration,
+        idle: Duration,
+    ) -> Vec<String> {
+        let deadline = tokio::time::Instant::now() + window;
+        let mut last_line_at = None;
+        let mut lines = Vec::new();
+        loop {
+            let wait_until = match last_line_at {
+                Some(last_line_at) => std::cmp::min(deadline, last_line_at + idle),
+                None => deadline,
+            };
+            match tokio::time::timeout_at(wait_until, rx.recv()).await {
+                Ok(Ok(event)) => {
+                    if let EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid: event_instance_uuid,
+                        instance_event_inner: InstanceEventInner::InstanceOutput { message },
+                        ..
+                    }) = event.event_inner
+                    {
+                        if event_instance_uuid == instance_uuid {
+                            lines.push(message.trim_end().to_string());
+                            last_line_at = Some(tokio::time::Instant::now());
+                        }
+                    }
+                }
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) | Err(_) => break,
+            }
+        }
+        lines
+    }
+}
+
 impl From<EventBroadcaster> for Sender<Event> {
     fn from(event_broadcaster: EventBroadcaster) -> Self {
         event_broadcaster.event_tx
@@ -144,3 +185,63 @@ impl AsRef<Sender<Event>> for EventBroadcaster {
         &self.event_tx
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::EventBroadcaster;
+    use crate::events::Event;
+    use crate::types::InstanceUuid;
+
+    #[tokio::test]
+    async fn test_collect_instance_output() {
+        let (tx, _rx) = EventBroadcaster::new(16);
+        let uuid = InstanceUuid::default();
+        let other_uuid = InstanceUuid::default();
+        let rx = tx.subscribe();
+        tx.send(Event::new_instance_output(
+            uuid.clone(),
+            "test".to_string(),
+            "There are 0 of a max of 20 players online\n".to_string(),
+        ));
+        tx.send(Event::new_instance_output(
+            other_uuid,
+            "other".to_string(),
+            "unrelated\n".to_string(),
+        ));
+        tx.send(Event::new_instance_output(
+            uuid.clone(),
+            "test".to_string(),
+            "second line\n".to_string(),
+        ));
+        let lines = EventBroadcaster::collect_instance_output(
+            rx,
+            &uuid,
+            Duration::from_secs(5),
+            Duration::from_millis(50),
+        )
+        .await;
+        assert_eq!(
+            lines,
+            vec![
+                "There are 0 of a max of 20 players online".to_string(),
+                "second line".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collect_instance_output_no_output() {
+        let (tx, _rx) = EventBroadcaster::new(16);
+        let rx = tx.subscribe();
+        let lines = EventBroadcaster::collect_instance_output(
+            rx,
+            &InstanceUuid::default(),
+            Duration::from_millis(50),
+            Duration::from_millis(10),
+        )
+        .await;
+        assert!(lines.is_empty());
+    }
+}
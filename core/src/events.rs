@@ -6,7 +6,10 @@ use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use crate::{
-    auth::{permission::UserPermission, user_id::UserId},
+    auth::{
+        api_token::ApiTokenId, permission::UserPermission, role::RoleId, session::SessionId,
+        user_id::UserId,
+    },
     macro_executor::MacroPID,
     output_types::ClientEvent,
     traits::{t_macro::ExitStatus, t_player::Player, t_server::State, InstanceInfo},
@@ -151,6 +154,26 @@ pub enum UserEventInner {
     PermissionChanged {
         new_permissions: Box<UserPermission>,
     },
+    ApiTokenCreated {
+        token_id: ApiTokenId,
+        name: String,
+    },
+    ApiTokenRevoked {
+        token_id: ApiTokenId,
+    },
+    RolesChanged {
+        new_roles: HashSet<RoleId>,
+    },
+    TotpEnabled,
+    TotpDisabled,
+    RecoveryCodeUsed,
+    LoginFailed {
+        ip: Option<String>,
+        reason: String,
+    },
+    SessionRevoked {
+        session_id: SessionId,
+    },
 }
 
 impl AsRef<UserEventInner> for UserEventInner {
@@ -261,6 +284,7 @@ pub enum FSOperation {
     Delete,
     Upload,
     Download,
+    Restore { revision: Snowflake },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
@@ -286,7 +310,7 @@ pub fn new_fs_event(operation: FSOperation, target: FSTarget, caused_by: CausedB
     }
 }
 
-#[derive(Serialize, Deserialize, TS)]
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
 #[serde(transparent)]
 #[ts(export)]
 pub struct ProgressionEventID(Snowflake);
@@ -14,14 +14,33 @@ impl GitClient {
         self.cwd.clone()
     }
 
+    /// A repository that was already cloned to `path`
+    pub fn open(path: impl AsRef<Path>) -> Self {
+        Self {
+            cwd: path.as_ref().to_owned(),
+        }
+    }
+
+    /// Clones `url` into `path/name`, repositories on this machine are only cloned if `allow_local` is set
     pub async fn clone(
         url: impl AsRef<str>,
         path: impl AsRef<Path>,
         name: impl AsRef<str>,
+        allow_local: bool,
     ) -> Result<Self, Error> {
+        if url.as_ref().starts_with('-') {
+            return Err(eyre!("Invalid repository url {}", url.as_ref()));
+        }
         // get output from stderr
         let output = tokio::process::Command::new("git")
+            .arg("-c")
+            .arg(if allow_local {
+                "protocol.file.allow=always"
+            } else {
+                "protocol.file.allow=never"
+            })
             .arg("clone")
+            .arg("--")
             .arg(url.as_ref())
             .arg(name.as_ref())
             .current_dir(path.as_ref())
@@ -66,6 +85,8 @@ impl GitClient {
     pub async fn fetch(&self) -> Result<(), Error> {
         let status = tokio::process::Command::new("git")
             .arg("fetch")
+            .arg("--tags")
+            .arg("--quiet")
             .current_dir(&self.cwd)
             .status()
             .await
@@ -76,6 +97,23 @@ impl GitClient {
         Ok(())
     }
 
+    pub async fn pull(&self) -> Result<(), Error> {
+        let output = tokio::process::Command::new("git")
+            .arg("pull")
+            .arg("--ff-only")
+            .current_dir(&self.cwd)
+            .output()
+            .await
+            .map_err(|e| eyre!("Failed to get output {}", e))?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "git pull failed : {}",
+                String::from_utf8(output.stderr)?
+            ));
+        }
+        Ok(())
+    }
+
     pub async fn get_latest_commit(&self) -> Result<String, Error> {
         let output = tokio::process::Command::new("git")
             .arg("rev-parse")
@@ -90,4 +128,60 @@ impl GitClient {
         let output = String::from_utf8(output.stdout)?;
         Ok(output.trim().to_string())
     }
+
+    /// The commit `rev` points to, a branch, tag or commit
+    pub async fn rev_parse(&self, rev: &str) -> Result<String, Error> {
+        let output = tokio::process::Command::new("git")
+            .arg("rev-parse")
+            .arg("--verify")
+            .arg("--quiet")
+            .arg(format!("{rev}^{{commit}}"))
+            .current_dir(&self.cwd)
+            .output()
+            .await
+            .map_err(|e| eyre!("Failed to get output {}", e))?;
+        if !output.status.success() {
+            return Err(eyre!("{rev} is not a branch, tag or commit"));
+        }
+        let output = String::from_utf8(output.stdout)?;
+        Ok(output.trim().to_string())
+    }
+
+    /// Checks out `rev` without a branch, so later fetches don't move it
+    pub async fn checkout(&self, rev: &str) -> Result<(), Error> {
+        let output = tokio::process::Command::new("git")
+            .arg("checkout")
+            .arg("--quiet")
+            .arg("--detach")
+            .arg(rev)
+            .current_dir(&self.cwd)
+            .output()
+            .await
+            .map_err(|e| eyre!("Failed to get output {}", e))?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "git checkout failed : {}",
+                String::from_utf8(output.stderr)?
+            ));
+        }
+        Ok(())
+    }
+
+    /// The contents of `path` at `rev`, without checking it out
+    pub async fn show_file(&self, rev: &str, path: &str) -> Result<Vec<u8>, Error> {
+        let output = tokio::process::Command::new("git")
+            .arg("show")
+            .arg(format!("{rev}:{path}"))
+            .current_dir(&self.cwd)
+            .output()
+            .await
+            .map_err(|e| eyre!("Failed to get output {}", e))?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "git show failed : {}",
+                String::from_utf8(output.stderr)?
+            ));
+        }
+        Ok(output.stdout)
+    }
 }
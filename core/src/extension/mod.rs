@@ -1,19 +1,23 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use axum::Json;
 use color_eyre::eyre::{self, Context};
+use deno_runtime::permissions::PermissionsOptions;
+use indexmap::IndexMap;
 use serde_json::Value;
 use tracing::error;
 use ts_rs::TS;
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+use crate::util::rand_alphanumeric;
 
 pub mod atom;
 pub mod git;
 pub mod r#macro;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize, TS)]
 #[ts(export)]
+#[serde(default)]
 /// https://docs.deno.com/runtime/manual/basics/permissions
 pub struct Permission {
     // override permissions
@@ -39,6 +43,82 @@ pub struct Permission {
     pub allow_run: Option<Vec<String>>,
 }
 
+/// Whether every item in `requested` is in `granted`
+fn list_covers<T: PartialEq>(granted: &Option<Vec<T>>, requested: &Option<Vec<T>>) -> bool {
+    match requested {
+        Some(requested) => requested
+            .iter()
+            .all(|item| granted.as_ref().map_or(false, |granted| granted.contains(item))),
+        None => true,
+    }
+}
+
+/// A grant for everything if `all`, otherwise only the listed items
+fn grant<T>(all: bool, items: Option<Vec<T>>) -> Option<Vec<T>> {
+    if all {
+        // an empty list is how deno spells "everything"
+        Some(Vec::new())
+    } else {
+        items.filter(|items| !items.is_empty())
+    }
+}
+
+impl Permission {
+    /// Whether granting `self` also grants everything `requested` asks for
+    pub fn covers(&self, requested: &Permission) -> bool {
+        let read_all = self.full_disk_access || self.disk_read;
+        let write_all = self.full_disk_access || self.disk_write;
+        (self.full_disk_access || !requested.full_disk_access)
+            && (self.full_network_access || !requested.full_network_access)
+            && (self.full_env_access || !requested.full_env_access)
+            && (read_all || !requested.disk_read)
+            && (write_all || !requested.disk_write)
+            && (self.sys_info || !requested.sys_info)
+            && (self.subprocess || !requested.subprocess)
+            && (self.full_env_access || list_covers(&self.allow_env, &requested.allow_env))
+            && (read_all || list_covers(&self.allow_read, &requested.allow_read))
+            && (write_all || list_covers(&self.allow_write, &requested.allow_write))
+            && (self.full_network_access || list_covers(&self.allow_net, &requested.allow_net))
+            && (self.subprocess || list_covers(&self.allow_run, &requested.allow_run))
+    }
+
+    /// The deno permissions of code running from `extension_root`.
+    ///
+    /// Relative paths are relative to `extension_root`, which is always readable.
+    pub fn to_permissions_options(&self, extension_root: &Path) -> PermissionsOptions {
+        let resolve = |paths: &Option<Vec<PathBuf>>| {
+            paths.as_ref().map(|paths| {
+                paths
+                    .iter()
+                    .map(|path| extension_root.join(path))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let mut allow_read = grant(
+            self.full_disk_access || self.disk_read,
+            resolve(&self.allow_read),
+        );
+        if allow_read.as_ref().map_or(true, |paths| !paths.is_empty()) {
+            allow_read
+                .get_or_insert_with(Vec::new)
+                .push(extension_root.to_owned());
+        }
+        PermissionsOptions {
+            allow_env: grant(self.full_env_access, self.allow_env.clone()),
+            allow_net: grant(self.full_network_access, self.allow_net.clone()),
+            allow_read,
+            allow_write: grant(
+                self.full_disk_access || self.disk_write,
+                resolve(&self.allow_write),
+            ),
+            allow_run: grant(self.subprocess, self.allow_run.clone()),
+            allow_sys: grant(self.sys_info, None),
+            prompt: false,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
 #[ts(export)]
 pub enum ExtensionType {
@@ -194,6 +274,141 @@ pub async fn get_manifest(url: impl AsRef<str>) -> Result<ManifestWithMetadata,
     }
 }
 
+/// An installed extension and the permissions the owner approved for it
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
+#[ts(export)]
+pub struct InstalledExtension {
+    pub manifest: Manifest,
+    /// Where it was installed from, a git URL or a local directory
+    pub url: String,
+    pub path: PathBuf,
+    /// Used in place from a local directory instead of cloned
+    #[serde(default)]
+    pub local: bool,
+    /// The commit checked out, none for local directories
+    #[serde(default)]
+    pub revision: Option<String>,
+    /// The branch, tag or commit updates follow, the default branch if none
+    #[serde(default)]
+    pub pinned: Option<String>,
+    /// The commit before the last update or rollback
+    #[serde(default)]
+    pub previous_revision: Option<String>,
+    /// What the extension runs with, never more than the owner approved
+    pub approved_permission: Permission,
+    pub install_time: i64,
+    pub update_time: i64,
+}
+
+/// A newer version of an installed extension
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
+#[ts(export)]
+pub struct ExtensionUpdate {
+    pub revision: String,
+    pub manifest: Manifest,
+}
+
+/// `<name>.<owner>`, the owner being the first path segment of a hosted git URL
+fn extension_id(manifest: &Manifest, source: &str) -> String {
+    let owner = match url::Url::parse(source) {
+        Ok(url) if url.host_str().is_some() => url
+            .path_segments()
+            .and_then(|mut segments| segments.next())
+            .filter(|owner| !owner.is_empty())
+            .map(str::to_string),
+        // scp-like, git@host:owner/repo.git
+        Err(_) => source
+            .split_once(':')
+            .and_then(|(_, path)| path.split('/').next())
+            .filter(|owner| !owner.is_empty())
+            .map(str::to_string),
+        Ok(_) => None,
+    };
+    format!(
+        "{}.{}",
+        manifest.name,
+        owner.unwrap_or_else(|| "local".to_string())
+    )
+}
+
+fn parse_manifest(data: &[u8]) -> Result<Manifest, Error> {
+    let manifest: Manifest = serde_json::from_slice(data).map_err(|e| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre::eyre!("Invalid extension manifest: {e}"),
+    })?;
+    // the name ends up in the directory the extension is installed to
+    if manifest.name.is_empty()
+        || !manifest
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre::eyre!(
+                "Invalid extension name {:?}, only letters, digits, '_' and '-' are allowed",
+                manifest.name
+            ),
+        });
+    }
+    Ok(manifest)
+}
+
+/// Whether git would fetch `source` over the network, as opposed to from this machine
+fn is_remote_source(source: &str) -> bool {
+    match url::Url::parse(source) {
+        Ok(url) => matches!(url.scheme(), "https" | "ssh" | "git") && url.host_str().is_some(),
+        // scp-like, git@host:owner/repo.git
+        Err(_) => source
+            .split_once(':')
+            .map_or(false, |(host, _)| !host.is_empty() && !host.contains(['/', '\\'])),
+    }
+}
+
+async fn read_manifest(path: &Path) -> Result<Manifest, Error> {
+    let data = tokio::fs::read(path.join("lodestone.json"))
+        .await
+        .context("Failed to read lodestone.json of the extension")?;
+    parse_manifest(&data)
+}
+
+/// What the extension gets to run with, if `approved` covers what it requests
+fn approve(manifest: &Manifest, approved: Option<&Permission>) -> Result<Permission, Error> {
+    let requested = manifest.permission.clone().unwrap_or_default();
+    if approved.map_or(false, |approved| approved.covers(&requested)) {
+        Ok(requested)
+    } else {
+        Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre::eyre!(
+                "{} requests permissions that have not been approved: {}",
+                manifest.name,
+                serde_json::to_string(&requested).context("Failed to serialize permissions")?
+            ),
+        })
+    }
+}
+
+/// The commit `pinned` points to after a fetch, the default branch if none
+async fn resolve_revision(client: &git::GitClient, pinned: Option<&str>) -> Result<String, Error> {
+    let revision = match pinned {
+        // a branch has to be followed on the remote, the local one never moves
+        Some(pinned) => match client.rev_parse(&format!("origin/{pinned}")).await {
+            Ok(revision) => revision,
+            Err(_) => client.rev_parse(pinned).await?,
+        },
+        None => client.rev_parse("origin/HEAD").await?,
+    };
+    Ok(revision)
+}
+
+fn not_installed(id: &str) -> Error {
+    Error {
+        kind: ErrorKind::NotFound,
+        source: eyre::eyre!("Extension {id} is not installed"),
+    }
+}
+
 pub struct ExtensionManager {
     extension_path: PathBuf,
     atom_path: PathBuf,
@@ -211,31 +426,513 @@ impl ExtensionManager {
         }
     }
 
-    pub async fn install_extension(&self, url: impl AsRef<str>) -> Result<PathBuf, Error> {
+    fn path_to_installed(&self) -> PathBuf {
+        self.extension_path.join("installed.json")
+    }
+
+    /// Where clones are checked before they are installed
+    fn path_to_staging(&self) -> PathBuf {
+        self.extension_path.join(".staging")
+    }
+
+    /// Installed extensions keyed by `<name>.<owner>`
+    pub async fn installed(&self) -> Result<IndexMap<String, InstalledExtension>, Error> {
+        let path = self.path_to_installed();
+        if !path.exists() {
+            return Ok(IndexMap::new());
+        }
+        let data = tokio::fs::read(&path)
+            .await
+            .context("Failed to read installed extensions")?;
+        Ok(serde_json::from_slice(&data).context("Failed to parse installed extensions")?)
+    }
+
+    async fn write_installed(
+        &self,
+        installed: &IndexMap<String, InstalledExtension>,
+    ) -> Result<(), Error> {
+        tokio::fs::write(
+            self.path_to_installed(),
+            serde_json::to_vec_pretty(installed)
+                .context("Failed to serialize installed extensions")?,
+        )
+        .await
+        .context("Failed to write installed extensions")?;
+        Ok(())
+    }
+
+    /// Installs the extension from a git repository on any host, at `pinned` if set.
+    ///
+    /// A local directory is used in place instead of cloned, so extensions can be developed
+    /// and tested offline.
+    ///
+    /// `approved_permission` is what the owner agreed to, it must cover what the manifest asks for.
+    pub async fn install_extension(
+        &self,
+        source: impl AsRef<str>,
+        pinned: Option<String>,
+        approved_permission: Option<Permission>,
+        allow_local: bool,
+    ) -> Result<InstalledExtension, Error> {
+        let source = source.as_ref();
+        if source.starts_with('-') {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre::eyre!("Invalid extension source {source}"),
+            });
+        }
+        // a directory or file url could be anywhere on this machine
+        if !allow_local && (Path::new(source).is_dir() || !is_remote_source(source)) {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre::eyre!(
+                    "Only the owner can install an extension from this machine, use an https, ssh or git url"
+                ),
+            });
+        }
+        if Path::new(source).is_dir() {
+            return self
+                .install_local(source, approved_permission.as_ref())
+                .await;
+        }
+        tokio::fs::create_dir_all(self.path_to_staging())
+            .await
+            .context("Failed to create extension staging directory")?;
+        let staging_name = rand_alphanumeric(16);
+        git::GitClient::clone(source, self.path_to_staging(), &staging_name, allow_local).await?;
+        let staging = self.path_to_staging().join(staging_name);
+        let result = self
+            .install_staged(source, &staging, pinned, approved_permission.as_ref())
+            .await;
+        if staging.exists() {
+            if let Err(e) = crate::util::fs::remove_dir_all(&staging).await {
+                error!(
+                    "Failed to remove staged extension {}: {e}",
+                    staging.display()
+                );
+            }
+        }
+        result
+    }
+
+    async fn install_staged(
+        &self,
+        source: &str,
+        staging: &Path,
+        pinned: Option<String>,
+        approved_permission: Option<&Permission>,
+    ) -> Result<InstalledExtension, Error> {
+        let client = git::GitClient::open(staging);
+        if pinned.is_some() {
+            client
+                .checkout(&resolve_revision(&client, pinned.as_deref()).await?)
+                .await?;
+        }
+        let manifest = read_manifest(staging).await?;
+        let id = extension_id(&manifest, source);
         // a possible race condition, but it's fine
-        let manifest = get_manifest(url).await?;
-        let extension_path = match manifest.manifest.r#type {
-            ExtensionType::Atom => self.install_atom(&manifest.username, &manifest.url, &manifest.manifest.name).await?,
-            ExtensionType::Macro => todo!(),
+        let mut installed = self.installed().await?;
+        if installed.contains_key(&id) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre::eyre!("Extension {id} is already installed, update it instead"),
+            });
+        }
+        let approved_permission = approve(&manifest, approved_permission)?;
+        let parent = match manifest.r#type {
+            ExtensionType::Atom => &self.atom_path,
+            ExtensionType::Macro => &self.macro_path,
         };
-        Ok(extension_path)
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("Failed to create extension directory")?;
+        let path = parent.join(&id);
+        // left over from an install that wasn't recorded
+        if path.exists() {
+            crate::util::fs::remove_dir_all(&path).await?;
+        }
+        tokio::fs::rename(staging, &path)
+            .await
+            .context("Failed to move extension into place")?;
+        let revision = git::GitClient::open(&path).get_current_commit().await?;
+        let now = chrono::Utc::now().timestamp();
+        let extension = InstalledExtension {
+            manifest,
+            url: source.to_string(),
+            path,
+            local: false,
+            revision: Some(revision),
+            pinned,
+            previous_revision: None,
+            approved_permission,
+            install_time: now,
+            update_time: now,
+        };
+        installed.insert(id, extension.clone());
+        self.write_installed(&installed).await?;
+        Ok(extension)
     }
 
-    async fn install_atom(
+    async fn install_local(
         &self,
-        username: &str,
-        url: &str,
-        atom_name: &str,
-    ) -> Result<PathBuf, Error> {
-        tokio::fs::create_dir_all(&self.atom_path)
+        source: &str,
+        approved_permission: Option<&Permission>,
+    ) -> Result<InstalledExtension, Error> {
+        let path = tokio::fs::canonicalize(source)
             .await
-            .context("Failed to create atom directory")?;
-        // check if the extension already exists
-        let path = self.atom_path.join(atom_name);
-        if path.exists() {
-            return Ok(path);
+            .context("Failed to resolve extension directory")?;
+        let manifest = read_manifest(&path).await?;
+        let id = extension_id(&manifest, &path.to_string_lossy());
+        let mut installed = self.installed().await?;
+        if installed.contains_key(&id) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre::eyre!("Extension {id} is already installed, update it instead"),
+            });
+        }
+        let approved_permission = approve(&manifest, approved_permission)?;
+        let now = chrono::Utc::now().timestamp();
+        let extension = InstalledExtension {
+            manifest,
+            url: path.to_string_lossy().to_string(),
+            path,
+            local: true,
+            revision: None,
+            pinned: None,
+            previous_revision: None,
+            approved_permission,
+            install_time: now,
+            update_time: now,
+        };
+        installed.insert(id, extension.clone());
+        self.write_installed(&installed).await?;
+        Ok(extension)
+    }
+
+    /// The version an update would move to, none if it's up to date or installed from a local directory
+    pub async fn check_update(&self, id: &str) -> Result<Option<ExtensionUpdate>, Error> {
+        let extension = self
+            .installed()
+            .await?
+            .swap_remove(id)
+            .ok_or_else(|| not_installed(id))?;
+        if extension.local {
+            return Ok(None);
+        }
+        let client = git::GitClient::open(&extension.path);
+        client.fetch().await?;
+        let revision = resolve_revision(&client, extension.pinned.as_deref()).await?;
+        if extension.revision.as_ref() == Some(&revision) {
+            return Ok(None);
+        }
+        let manifest = parse_manifest(&client.show_file(&revision, "lodestone.json").await?)?;
+        Ok(Some(ExtensionUpdate { revision, manifest }))
+    }
+
+    /// Moves the extension to the latest commit of `pinned`, or of what it was pinned to before.
+    /// A local directory only has its manifest read again.
+    ///
+    /// Updates within the permissions approved before don't need approving again.
+    pub async fn update_extension(
+        &self,
+        id: &str,
+        pinned: Option<String>,
+        approved_permission: Option<Permission>,
+    ) -> Result<InstalledExtension, Error> {
+        let mut installed = self.installed().await?;
+        let extension = installed.get_mut(id).ok_or_else(|| not_installed(id))?;
+        let approved = approved_permission
+            .as_ref()
+            .unwrap_or(&extension.approved_permission);
+        if extension.local {
+            let manifest = read_manifest(&extension.path).await?;
+            extension.approved_permission = approve(&manifest, Some(approved))?;
+            extension.manifest = manifest;
+        } else {
+            let client = git::GitClient::open(&extension.path);
+            client.fetch().await?;
+            let pinned = pinned.or_else(|| extension.pinned.clone());
+            let revision = resolve_revision(&client, pinned.as_deref()).await?;
+            let manifest = parse_manifest(&client.show_file(&revision, "lodestone.json").await?)?;
+            let approved_permission = approve(&manifest, Some(approved))?;
+            let current = match &extension.revision {
+                Some(current) => current.clone(),
+                None => client.get_current_commit().await?,
+            };
+            if current != revision {
+                client.checkout(&revision).await?;
+                extension.previous_revision = Some(current);
+            }
+            extension.revision = Some(revision);
+            extension.pinned = pinned;
+            extension.manifest = manifest;
+            extension.approved_permission = approved_permission;
         }
-        let git = git::GitClient::clone(url, &self.atom_path, format!("{atom_name}.{username}")).await?;
-        Ok(git.cwd())
+        extension.update_time = chrono::Utc::now().timestamp();
+        let extension = extension.clone();
+        self.write_installed(&installed).await?;
+        Ok(extension)
+    }
+
+    /// Goes back to the commit before the last update, and stays there until updated to another version
+    pub async fn rollback_extension(
+        &self,
+        id: &str,
+        approved_permission: Option<Permission>,
+    ) -> Result<InstalledExtension, Error> {
+        let mut installed = self.installed().await?;
+        let extension = installed.get_mut(id).ok_or_else(|| not_installed(id))?;
+        let previous = extension.previous_revision.clone().ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre::eyre!("Extension {id} has no previous version to roll back to"),
+        })?;
+        let client = git::GitClient::open(&extension.path);
+        let manifest = parse_manifest(&client.show_file(&previous, "lodestone.json").await?)?;
+        let approved_permission = approve(
+            &manifest,
+            Some(
+                approved_permission
+                    .as_ref()
+                    .unwrap_or(&extension.approved_permission),
+            ),
+        )?;
+        client.checkout(&previous).await?;
+        extension.previous_revision = extension.revision.replace(previous.clone());
+        extension.pinned = Some(previous);
+        extension.manifest = manifest;
+        extension.approved_permission = approved_permission;
+        extension.update_time = chrono::Utc::now().timestamp();
+        let extension = extension.clone();
+        self.write_installed(&installed).await?;
+        Ok(extension)
+    }
+
+    /// Removes the extension from the registry, and its clone.
+    /// A local directory is left alone.
+    ///
+    /// `instance_sources` is where each generic instance loads its atom from, by instance name.
+    /// Refused while any of them is inside the extension.
+    pub async fn uninstall_extension(
+        &self,
+        id: &str,
+        instance_sources: &[(String, PathBuf)],
+    ) -> Result<InstalledExtension, Error> {
+        let mut installed = self.installed().await?;
+        let extension = installed
+            .shift_remove(id)
+            .ok_or_else(|| not_installed(id))?;
+        let extension_path =
+            std::fs::canonicalize(&extension.path).unwrap_or_else(|_| extension.path.clone());
+        let users: Vec<&str> = instance_sources
+            .iter()
+            .filter(|(_, source)| {
+                std::fs::canonicalize(source)
+                    .unwrap_or_else(|_| source.clone())
+                    .starts_with(&extension_path)
+            })
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !users.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre::eyre!(
+                    "Extension {id} is used by {}, delete those instances first",
+                    users.join(", ")
+                ),
+            });
+        }
+        self.write_installed(&installed).await?;
+        if !extension.local && extension.path.exists() {
+            crate::util::fs::remove_dir_all(&extension.path).await?;
+        }
+        Ok(extension)
+    }
+
+    /// The sandbox of code loaded from `source`, `None` if it isn't from an installed extension
+    pub async fn sandbox_permissions(
+        &self,
+        source: &Path,
+    ) -> Result<Option<PermissionsOptions>, Error> {
+        // so `..` can't climb out of an extension
+        let source = match tokio::fs::canonicalize(source).await {
+            Ok(source) => source,
+            Err(_) => return Ok(None),
+        };
+        Ok(self
+            .installed()
+            .await?
+            .into_values()
+            .find(|extension| {
+                std::fs::canonicalize(&extension.path)
+                    .map_or(false, |path| source.starts_with(path))
+            })
+            .map(|extension| {
+                extension
+                    .approved_permission
+                    .to_permissions_options(&extension.path)
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permission_covers() {
+        let approved = Permission {
+            disk_read: true,
+            allow_net: Some(vec!["api.mojang.com".to_string()]),
+            ..Default::default()
+        };
+        assert!(approved.covers(&Permission::default()));
+        assert!(approved.covers(&Permission {
+            allow_read: Some(vec!["config".into()]),
+            allow_net: Some(vec!["api.mojang.com".to_string()]),
+            ..Default::default()
+        }));
+        assert!(!approved.covers(&Permission {
+            allow_net: Some(vec!["example.com".to_string()]),
+            ..Default::default()
+        }));
+        assert!(!approved.covers(&Permission {
+            subprocess: true,
+            ..Default::default()
+        }));
+        assert!(!approved.covers(&Permission {
+            allow_write: Some(vec!["config".into()]),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_permissions_options() {
+        let root = Path::new("/extensions/atom/minecraft.lodestone");
+        let options = Permission {
+            allow_write: Some(vec!["cache".into()]),
+            allow_net: Some(vec!["api.mojang.com".to_string()]),
+            subprocess: true,
+            ..Default::default()
+        }
+        .to_permissions_options(root);
+        assert_eq!(options.allow_read, Some(vec![root.to_owned()]));
+        assert_eq!(options.allow_write, Some(vec![root.join("cache")]));
+        assert_eq!(options.allow_net, Some(vec!["api.mojang.com".to_string()]));
+        assert_eq!(options.allow_run, Some(vec![]));
+        assert_eq!(options.allow_env, None);
+
+        let options = Permission {
+            full_disk_access: true,
+            ..Default::default()
+        }
+        .to_permissions_options(root);
+        assert_eq!(options.allow_read, Some(vec![]));
+        assert_eq!(options.allow_write, Some(vec![]));
+    }
+
+    fn git(repo: &Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(repo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn commit_manifest(repo: &Path, version: &str, permission: Permission) {
+        let manifest = Manifest {
+            r#type: ExtensionType::Macro,
+            author: "steve".to_string(),
+            name: "greeter".to_string(),
+            version: version.to_string(),
+            description: "Greets players".to_string(),
+            release_notes: None,
+            permission: Some(permission),
+        };
+        std::fs::write(
+            repo.join("lodestone.json"),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        git(repo, &["add", "-A"]);
+        git(repo, &["commit", "-q", "-m", version]);
+    }
+
+    #[tokio::test]
+    async fn test_extension_registry() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let repo = temp_dir.path().join("repo");
+        std::fs::create_dir_all(&repo).unwrap();
+        git(&repo, &["init", "-q"]);
+        commit_manifest(&repo, "1.0.0", Permission::default());
+        let manager = ExtensionManager::new(temp_dir.path().join("extensions"));
+        let source = url::Url::from_directory_path(&repo).unwrap().to_string();
+        let id = "greeter.local";
+
+        assert!(manager
+            .install_extension(&source, None, None, true)
+            .await
+            .is_err());
+        // only the owner can clone from this machine
+        assert!(manager
+            .install_extension(&source, None, Some(Permission::default()), false)
+            .await
+            .is_err());
+        assert!(manager
+            .install_extension("--upload-pack=touch pwned", None, None, true)
+            .await
+            .is_err());
+        let extension = manager
+            .install_extension(&source, None, Some(Permission::default()), true)
+            .await
+            .unwrap();
+        assert_eq!(extension.manifest.version, "1.0.0");
+        assert!(extension.path.join("lodestone.json").exists());
+        assert!(manager.check_update(id).await.unwrap().is_none());
+
+        commit_manifest(&repo, "1.1.0", Permission::default());
+        let update = manager.check_update(id).await.unwrap().unwrap();
+        assert_eq!(update.manifest.version, "1.1.0");
+        let extension = manager.update_extension(id, None, None).await.unwrap();
+        assert_eq!(extension.manifest.version, "1.1.0");
+        assert_eq!(extension.revision, Some(update.revision));
+
+        // asks for more than was approved
+        let subprocess = Permission {
+            subprocess: true,
+            ..Default::default()
+        };
+        commit_manifest(&repo, "2.0.0", subprocess.clone());
+        assert!(manager.update_extension(id, None, None).await.is_err());
+
+        let extension = manager.rollback_extension(id, None).await.unwrap();
+        assert_eq!(extension.manifest.version, "1.0.0");
+        // stays on the version rolled back to
+        assert!(manager.check_update(id).await.unwrap().is_none());
+
+        // instances created from the atom keep it installed
+        let installed_path = manager.installed().await.unwrap()[id].path.clone();
+        let instance_sources = vec![("survival".to_string(), installed_path.join("src"))];
+        assert!(manager
+            .uninstall_extension(id, &instance_sources)
+            .await
+            .is_err());
+        assert!(installed_path.exists());
+        assert!(manager.installed().await.unwrap().contains_key(id));
+
+        let extension = manager.uninstall_extension(id, &[]).await.unwrap();
+        assert!(!extension.path.exists());
+        assert!(manager.installed().await.unwrap().is_empty());
+
+        // a local directory is used in place and left alone
+        let extension = manager
+            .install_extension(repo.to_string_lossy(), None, Some(subprocess), true)
+            .await
+            .unwrap();
+        assert!(extension.local);
+        assert_eq!(extension.manifest.version, "2.0.0");
+        manager.uninstall_extension(id, &[]).await.unwrap();
+        assert!(repo.join("lodestone.json").exists());
     }
 }
@@ -0,0 +1,411 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    types::Snowflake,
+    util::scoped_join_win_safe,
+};
+
+/// Hidden directory in the instance root holding the revisions of every file written through the API
+pub const HISTORY_DIR_NAME: &str = ".lodestone_history";
+/// Older revisions are dropped past this many
+const MAX_REVISIONS_PER_FILE: usize = 20;
+/// Larger files are written without keeping a revision
+const MAX_REVISION_SIZE: u64 = 4 * 1024 * 1024;
+/// Diffs fall back to replacing the whole changed region past this many compared line pairs
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Whether `path` is in the revisions of the instance at `root`.
+///
+/// File systems on Windows and macOS usually ignore case, so there the comparison does too.
+fn is_in_history(root: &Path, path: &Path) -> bool {
+    let history_dir = root.join(HISTORY_DIR_NAME);
+    if cfg!(any(windows, target_os = "macos")) {
+        let lowercase = |path: &Path| PathBuf::from(path.to_string_lossy().to_lowercase());
+        lowercase(path).starts_with(lowercase(&history_dir))
+    } else {
+        path.starts_with(history_dir)
+    }
+}
+
+/// Refuses `path` if it is in the revisions of the instance at `root`, which can only be
+/// read and restored through [`FileHistory`]
+pub fn check_not_history(root: &Path, path: &Path) -> Result<(), Error> {
+    if is_in_history(root, path) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Revisions can only be accessed through the file history"),
+        });
+    }
+    Ok(())
+}
+
+/// [`scoped_join_win_safe`] for the files of an instance, see [`check_not_history`]
+pub fn scoped_join_instance_file(
+    root: impl AsRef<Path>,
+    unsafe_path: impl AsRef<Path>,
+) -> Result<PathBuf, Error> {
+    let path = scoped_join_win_safe(root.as_ref(), unsafe_path)?;
+    check_not_history(root.as_ref(), &path)?;
+    Ok(path)
+}
+
+/// A previous version of a file, as it was before being overwritten
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct FileRevision {
+    pub id: Snowflake,
+    pub creation_time: i64,
+    pub size: u64,
+    /// Who overwrote this version
+    pub caused_by: CausedBy,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RevisionIndex {
+    path: String,
+    /// Oldest first
+    revisions: Vec<FileRevision>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(tag = "type", content = "line")]
+pub enum DiffLine {
+    Unchanged(String),
+    Added(String),
+    Removed(String),
+}
+
+/// The revision history of the files in one instance
+pub struct FileHistory {
+    root: PathBuf,
+}
+
+impl FileHistory {
+    pub fn new(instance_root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: instance_root.into(),
+        }
+    }
+
+    /// `path` must already be scoped to the instance
+    fn relative_key(&self, path: &Path) -> Result<String, Error> {
+        let relative = path.strip_prefix(&self.root).map_err(|_| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Path is not in the instance"),
+        })?;
+        if is_in_history(&self.root, path) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Revisions have no history of their own"),
+            });
+        }
+        Ok(relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"))
+    }
+
+    fn file_dir(&self, key: &str) -> PathBuf {
+        self.root
+            .join(HISTORY_DIR_NAME)
+            .join(hex::encode(Sha256::digest(key.as_bytes())))
+    }
+
+    async fn read_index(&self, key: &str) -> Result<RevisionIndex, Error> {
+        let index_path = self.file_dir(key).join("index.json");
+        match tokio::fs::read(&index_path).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .context("Failed to parse revision index")
+                .map_err(Into::into),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RevisionIndex {
+                path: key.to_string(),
+                revisions: Vec::new(),
+            }),
+            Err(e) => Err(color_eyre::eyre::Report::new(e)
+                .wrap_err("Failed to read revision index")
+                .into()),
+        }
+    }
+
+    async fn write_index(&self, key: &str, index: &RevisionIndex) -> Result<(), Error> {
+        tokio::fs::write(
+            self.file_dir(key).join("index.json"),
+            serde_json::to_vec_pretty(index).context("Failed to serialize revision index")?,
+        )
+        .await
+        .context("Failed to write revision index")?;
+        Ok(())
+    }
+
+    /// Keeps the current content of `path` as a revision, call before overwriting it.
+    ///
+    /// Does nothing if the file doesn't exist, is too large, or is unchanged since the last revision.
+    pub async fn record(
+        &self,
+        path: &Path,
+        caused_by: CausedBy,
+    ) -> Result<Option<FileRevision>, Error> {
+        let key = self.relative_key(path)?;
+        let metadata = match tokio::fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Ok(None),
+        };
+        if metadata.len() > MAX_REVISION_SIZE {
+            return Ok(None);
+        }
+        let content = tokio::fs::read(path).await.context("Failed to read file")?;
+        let mut index = self.read_index(&key).await?;
+        let dir = self.file_dir(&key);
+        if let Some(latest) = index.revisions.last() {
+            if tokio::fs::read(dir.join(latest.id.to_string()))
+                .await
+                .map_or(false, |latest| latest == content)
+            {
+                return Ok(None);
+            }
+        }
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("Failed to create revision directory")?;
+        let revision = FileRevision {
+            id: Snowflake::default(),
+            creation_time: chrono::Utc::now().timestamp(),
+            size: content.len() as u64,
+            caused_by,
+        };
+        tokio::fs::write(dir.join(revision.id.to_string()), &content)
+            .await
+            .context("Failed to write revision")?;
+        index.revisions.push(revision.clone());
+        let excess = index.revisions.len().saturating_sub(MAX_REVISIONS_PER_FILE);
+        for old in index.revisions.drain(..excess) {
+            let _ = tokio::fs::remove_file(dir.join(old.id.to_string())).await;
+        }
+        self.write_index(&key, &index).await?;
+        Ok(Some(revision))
+    }
+
+    /// Newest first
+    pub async fn list(&self, path: &Path) -> Result<Vec<FileRevision>, Error> {
+        let key = self.relative_key(path)?;
+        let mut revisions = self.read_index(&key).await?.revisions;
+        revisions.reverse();
+        Ok(revisions)
+    }
+
+    pub async fn read(&self, path: &Path, revision: Snowflake) -> Result<Vec<u8>, Error> {
+        let key = self.relative_key(path)?;
+        if !self
+            .read_index(&key)
+            .await?
+            .revisions
+            .iter()
+            .any(|r| r.id == revision)
+        {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Revision not found"),
+            });
+        }
+        Ok(
+            tokio::fs::read(self.file_dir(&key).join(revision.to_string()))
+                .await
+                .context("Failed to read revision")?,
+        )
+    }
+
+    /// Overwrites `path` with a revision, keeping the current content as a new revision first
+    pub async fn restore(
+        &self,
+        path: &Path,
+        revision: Snowflake,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        let content = self.read(path, revision).await?;
+        self.record(path, caused_by).await?;
+        tokio::fs::write(path, content)
+            .await
+            .context("Failed to write file")?;
+        Ok(())
+    }
+}
+
+/// A line based diff from `old` to `new`
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut diff: Vec<DiffLine> = old[..prefix]
+        .iter()
+        .map(|line| DiffLine::Unchanged(line.to_string()))
+        .collect();
+    if old_mid.len().saturating_mul(new_mid.len()) > MAX_DIFF_CELLS {
+        diff.extend(old_mid.iter().map(|l| DiffLine::Removed(l.to_string())));
+        diff.extend(new_mid.iter().map(|l| DiffLine::Added(l.to_string())));
+    } else {
+        // longest common subsequence, lcs[i][j] is the length for old_mid[i..] and new_mid[j..]
+        let mut lcs = vec![vec![0u32; new_mid.len() + 1]; old_mid.len() + 1];
+        for i in (0..old_mid.len()).rev() {
+            for j in (0..new_mid.len()).rev() {
+                lcs[i][j] = if old_mid[i] == new_mid[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_mid.len() && j < new_mid.len() {
+            if old_mid[i] == new_mid[j] {
+                diff.push(DiffLine::Unchanged(old_mid[i].to_string()));
+                i += 1;
+                j += 1;
+            } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+                diff.push(DiffLine::Removed(old_mid[i].to_string()));
+                i += 1;
+            } else {
+                diff.push(DiffLine::Added(new_mid[j].to_string()));
+                j += 1;
+            }
+        }
+        diff.extend(
+            old_mid[i..]
+                .iter()
+                .map(|l| DiffLine::Removed(l.to_string())),
+        );
+        diff.extend(new_mid[j..].iter().map(|l| DiffLine::Added(l.to_string())));
+    }
+    diff.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| DiffLine::Unchanged(line.to_string())),
+    );
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scoped_join_instance_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join(HISTORY_DIR_NAME)).unwrap();
+        std::fs::create_dir_all(root.join("world")).unwrap();
+
+        assert_eq!(
+            scoped_join_instance_file(root, "world/level.dat").unwrap(),
+            root.join("world").join("level.dat")
+        );
+        for path in [
+            HISTORY_DIR_NAME,
+            ".lodestone_history/0123/index.json",
+            "world/../.lodestone_history",
+            "/.lodestone_history",
+        ] {
+            assert!(
+                matches!(
+                    scoped_join_instance_file(root, path),
+                    Err(Error {
+                        kind: ErrorKind::PermissionDenied,
+                        ..
+                    })
+                ),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            is_in_history(root, &root.join(".LODESTONE_HISTORY").join("0123")),
+            cfg!(any(windows, target_os = "macos"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let history = FileHistory::new(temp_dir.path());
+        let path = temp_dir.path().join("server.properties");
+
+        // nothing to keep before the first write
+        assert!(history
+            .record(&path, CausedBy::Unknown)
+            .await
+            .unwrap()
+            .is_none());
+        std::fs::write(&path, "motd=first\n").unwrap();
+        let first = history
+            .record(&path, CausedBy::Unknown)
+            .await
+            .unwrap()
+            .unwrap();
+        // unchanged content isn't kept twice
+        assert!(history
+            .record(&path, CausedBy::Unknown)
+            .await
+            .unwrap()
+            .is_none());
+        std::fs::write(&path, "motd=second\n").unwrap();
+
+        history
+            .restore(&path, first.id, CausedBy::Unknown)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "motd=first\n");
+        let revisions = history.list(&path).await.unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(
+            history.read(&path, revisions[0].id).await.unwrap(),
+            b"motd=second\n"
+        );
+
+        for i in 0..MAX_REVISIONS_PER_FILE + 5 {
+            std::fs::write(&path, format!("motd={i}\n")).unwrap();
+            history.record(&path, CausedBy::Unknown).await.unwrap();
+        }
+        assert_eq!(
+            history.list(&path).await.unwrap().len(),
+            MAX_REVISIONS_PER_FILE
+        );
+        assert!(matches!(
+            history.read(&path, first.id).await.unwrap_err().kind,
+            ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let diff = diff_lines("a\nb\nc\nd\n", "a\nc\nx\nd\n");
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Unchanged("a".to_string()),
+                DiffLine::Removed("b".to_string()),
+                DiffLine::Unchanged("c".to_string()),
+                DiffLine::Added("x".to_string()),
+                DiffLine::Unchanged("d".to_string()),
+            ]
+        );
+        assert!(diff_lines("same\n", "same\n")
+            .iter()
+            .all(|line| matches!(line, DiffLine::Unchanged(_))));
+    }
+}
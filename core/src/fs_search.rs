@@ -0,0 +1,329 @@
+use std::{
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Context};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use ts_rs::TS;
+use walkdir::WalkDir;
+
+use crate::{
+    error::{Error, ErrorKind},
+    fs_history::HISTORY_DIR_NAME,
+};
+
+const DEFAULT_MAX_RESULTS: usize = 500;
+const MAX_RESULTS_LIMIT: usize = 10_000;
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+const MAX_FILE_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+/// Files with a NUL byte in this many leading bytes are treated as binary
+const BINARY_SNIFF_LEN: usize = 8 * 1024;
+/// Matched lines are cut off past this many characters
+const MAX_LINE_LEN: usize = 512;
+
+#[derive(Deserialize, Clone, Debug, Default, TS)]
+#[ts(export)]
+pub struct FileSearchQuery {
+    /// Directory to search in, relative to the instance root
+    #[serde(default)]
+    pub path: String,
+    /// Glob the path relative to `path` must match, e.g. `plugins/**/*.yml`
+    pub glob: Option<String>,
+    /// Regex to search file contents for, only file names are matched if not set
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    pub max_results: Option<usize>,
+    /// Larger files are skipped when searching contents
+    pub max_file_size: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum FileSearchResult {
+    FileMatch {
+        path: String,
+    },
+    LineMatch {
+        path: String,
+        line_number: u64,
+        line: String,
+    },
+    /// Always the last result
+    Done {
+        files_scanned: u64,
+        matches: u64,
+        /// Set if the search stopped at `max_results`
+        truncated: bool,
+    },
+}
+
+/// A validated search, ready to run
+pub struct FileSearch {
+    root: PathBuf,
+    glob: Option<GlobMatcher>,
+    pattern: Option<Regex>,
+    max_results: usize,
+    max_file_size: u64,
+}
+
+impl FileSearch {
+    /// `base` must already be scoped to the instance
+    pub fn new(base: PathBuf, query: &FileSearchQuery) -> Result<Self, Error> {
+        let glob = query
+            .glob
+            .as_ref()
+            .map(|glob| {
+                GlobBuilder::new(glob)
+                    .case_insensitive(query.case_insensitive)
+                    .build()
+                    .map(|glob| glob.compile_matcher())
+                    .map_err(|e| Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Invalid glob: {e}"),
+                    })
+            })
+            .transpose()?;
+        let pattern = query
+            .pattern
+            .as_ref()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(query.case_insensitive)
+                    .size_limit(1024 * 1024)
+                    .build()
+                    .map_err(|e| Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Invalid pattern: {e}"),
+                    })
+            })
+            .transpose()?;
+        if glob.is_none() && pattern.is_none() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("A glob or a pattern is required"),
+            });
+        }
+        if !base.is_dir() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Search path is not a directory"),
+            });
+        }
+        Ok(Self {
+            root: base,
+            glob,
+            pattern,
+            max_results: query
+                .max_results
+                .unwrap_or(DEFAULT_MAX_RESULTS)
+                .min(MAX_RESULTS_LIMIT),
+            max_file_size: query
+                .max_file_size
+                .unwrap_or(DEFAULT_MAX_FILE_SIZE)
+                .min(MAX_FILE_SIZE_LIMIT),
+        })
+    }
+
+    /// Walks the tree, sending results as they are found.
+    ///
+    /// Blocking, stops early if the receiver is dropped.
+    /// Paths in results are relative to `display_root`.
+    pub fn run(self, display_root: &Path, tx: mpsc::Sender<FileSearchResult>) {
+        let mut files_scanned = 0;
+        let mut matches = 0;
+        let mut truncated = false;
+        // symlinks are not followed so the search can't leave the instance,
+        // and file revisions are not searched
+        'walk: for entry in WalkDir::new(&self.root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != HISTORY_DIR_NAME)
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+        {
+            let relative = match entry.path().strip_prefix(&self.root) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            if let Some(glob) = &self.glob {
+                if !glob.is_match(relative) {
+                    continue;
+                }
+            }
+            files_scanned += 1;
+            let display_path = entry
+                .path()
+                .strip_prefix(display_root)
+                .unwrap_or(relative)
+                .to_string_lossy()
+                .to_string();
+            let results = match &self.pattern {
+                None => vec![FileSearchResult::FileMatch { path: display_path }],
+                Some(pattern) => {
+                    if entry
+                        .metadata()
+                        .map_or(true, |m| m.len() > self.max_file_size)
+                    {
+                        continue;
+                    }
+                    match grep_file(entry.path(), pattern, self.max_results - matches) {
+                        Ok(lines) => lines
+                            .into_iter()
+                            .map(|(line_number, line)| FileSearchResult::LineMatch {
+                                path: display_path.clone(),
+                                line_number,
+                                line,
+                            })
+                            .collect(),
+                        Err(_) => continue,
+                    }
+                }
+            };
+            for result in results {
+                if matches >= self.max_results {
+                    truncated = true;
+                    break 'walk;
+                }
+                if tx.blocking_send(result).is_err() {
+                    return;
+                }
+                matches += 1;
+            }
+        }
+        let _ = tx.blocking_send(FileSearchResult::Done {
+            files_scanned,
+            matches: matches as u64,
+            truncated,
+        });
+    }
+}
+
+/// Returns up to `limit + 1` matching lines, so the caller can tell the results were truncated.
+///
+/// Binary files have no matches.
+fn grep_file(path: &Path, pattern: &Regex, limit: usize) -> Result<Vec<(u64, String)>, Error> {
+    let mut reader = BufReader::new(
+        std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?,
+    );
+    let head = reader
+        .fill_buf()
+        .context(format!("Failed to read {}", path.display()))?;
+    if head[..head.len().min(BINARY_SNIFF_LEN)].contains(&0) {
+        return Ok(Vec::new());
+    }
+    let mut lines = Vec::new();
+    let mut buf = Vec::new();
+    let mut line_number = 0;
+    loop {
+        buf.clear();
+        if reader
+            .read_until(b'\n', &mut buf)
+            .context(format!("Failed to read {}", path.display()))?
+            == 0
+        {
+            break;
+        }
+        line_number += 1;
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if pattern.is_match(line) {
+            lines.push((line_number, line.chars().take(MAX_LINE_LEN).collect()));
+            if lines.len() > limit {
+                break;
+            }
+        }
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(root: &Path, query: FileSearchQuery) -> Vec<FileSearchResult> {
+        let (tx, mut rx) = mpsc::channel(1024);
+        FileSearch::new(root.to_path_buf(), &query)
+            .unwrap()
+            .run(root, tx);
+        let mut results = Vec::new();
+        while let Ok(result) = rx.try_recv() {
+            results.push(result);
+        }
+        results
+    }
+
+    #[test]
+    fn test_file_search() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("plugins/Essentials")).unwrap();
+        std::fs::write(
+            root.join("plugins/Essentials/config.yml"),
+            "spawn-on-join: true\nmotd: Welcome\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("server.properties"), "motd=A Minecraft Server\n").unwrap();
+        std::fs::write(root.join("world.dat"), b"motd\0\x01\x02").unwrap();
+
+        let results = search(
+            root,
+            FileSearchQuery {
+                glob: Some("**/*.yml".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            results[0],
+            FileSearchResult::FileMatch {
+                path: "plugins/Essentials/config.yml".to_string()
+            }
+        );
+
+        let results = search(
+            root,
+            FileSearchQuery {
+                pattern: Some("^MOTD".to_string()),
+                case_insensitive: true,
+                ..Default::default()
+            },
+        );
+        // the binary file is skipped
+        assert_eq!(results.len(), 3);
+        assert!(results.contains(&FileSearchResult::LineMatch {
+            path: "server.properties".to_string(),
+            line_number: 1,
+            line: "motd=A Minecraft Server".to_string(),
+        }));
+        assert!(matches!(
+            results.last(),
+            Some(FileSearchResult::Done {
+                matches: 2,
+                truncated: false,
+                ..
+            })
+        ));
+
+        let results = search(
+            root,
+            FileSearchQuery {
+                pattern: Some("motd".to_string()),
+                max_results: Some(1),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            results.last(),
+            Some(FileSearchResult::Done {
+                matches: 1,
+                truncated: true,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::eyre::Context;
+use indexmap::IndexMap;
+use notify::{
+    event::{CreateKind, MetadataKind, ModifyKind, RemoveKind, RenameMode},
+    EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{
+    error::Error,
+    events::{FSEvent, FSOperation, FSTarget},
+    fs_history::HISTORY_DIR_NAME,
+};
+
+/// Changes arriving within this long of the first one are sent together
+const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches a directory subtree, using inotify on Linux.
+///
+/// Bursts of changes are coalesced, so a file written many times over is reported once.
+/// Paths in the events are relative to the instance root.
+pub struct DirectoryWatcher {
+    root: PathBuf,
+    path: PathBuf,
+    // dropping the watcher stops it
+    _watcher: RecommendedWatcher,
+    rx: UnboundedReceiver<notify::Result<notify::Event>>,
+}
+
+impl DirectoryWatcher {
+    pub fn new(root: PathBuf, path: PathBuf) -> Result<Self, Error> {
+        let (tx, rx) = unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .context("Failed to create file watcher")?;
+        watcher
+            .watch(&path, RecursiveMode::Recursive)
+            .context("Failed to watch directory")?;
+        Ok(Self {
+            root,
+            path,
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    /// Waits for the next burst of changes, `None` once the watcher stopped
+    pub async fn next_batch(&mut self) -> Option<Result<Vec<FSEvent>, Error>> {
+        let mut changes = Changes::default();
+        let first = self.rx.recv().await?;
+        if let Err(e) = self.push(&mut changes, first) {
+            return Some(Err(e));
+        }
+        let deadline = tokio::time::sleep(DEBOUNCE_INTERVAL);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                event = self.rx.recv() => match event {
+                    Some(event) => {
+                        if let Err(e) = self.push(&mut changes, event) {
+                            return Some(Err(e));
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+        Some(Ok(changes.into_events()))
+    }
+
+    fn relative(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        if relative.starts_with(HISTORY_DIR_NAME) {
+            return None;
+        }
+        Some(relative.to_owned())
+    }
+
+    fn push(
+        &self,
+        changes: &mut Changes,
+        event: notify::Result<notify::Event>,
+    ) -> Result<(), Error> {
+        let event = event.context("File watcher failed")?;
+        // events were dropped, tell the client to list the whole directory again
+        if event.need_rescan() {
+            if let Some(path) = self.relative(&self.path) {
+                changes.push(path, FSOperation::Write, true);
+            }
+            return Ok(());
+        }
+        let mut paths = event.paths.iter().filter_map(|path| self.relative(path));
+        match event.kind {
+            EventKind::Create(kind) => {
+                for path in paths {
+                    changes.push(path, FSOperation::Create, kind == CreateKind::Folder);
+                }
+            }
+            EventKind::Remove(kind) => {
+                for path in paths {
+                    changes.push(path, FSOperation::Delete, kind == RemoveKind::Folder);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let (Some(source), Some(dest)) = (paths.next(), paths.next()) {
+                    let is_dir = self.root.join(&dest).is_dir();
+                    changes.rename(source, dest, is_dir);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                for path in paths {
+                    changes.push(path, FSOperation::Delete, false);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                for path in paths {
+                    let is_dir = self.root.join(&path).is_dir();
+                    changes.push(path, FSOperation::Create, is_dir);
+                }
+            }
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime)) => {}
+            EventKind::Modify(_) => {
+                for path in paths {
+                    let is_dir = self.root.join(&path).is_dir();
+                    changes.push(path, FSOperation::Write, is_dir);
+                }
+            }
+            EventKind::Access(_) | EventKind::Any | EventKind::Other => {}
+        }
+        Ok(())
+    }
+}
+
+/// Pending changes by path, in the order they first happened
+#[derive(Default)]
+struct Changes {
+    changes: IndexMap<PathBuf, (FSOperation, bool)>,
+}
+
+impl Changes {
+    fn push(&mut self, path: PathBuf, operation: FSOperation, is_dir: bool) {
+        let previous = self
+            .changes
+            .get(&path)
+            .map(|(operation, _)| operation.clone());
+        let operation = match (previous, operation) {
+            // a file that came and went within the burst was never seen
+            (Some(FSOperation::Create), FSOperation::Delete) => {
+                self.changes.shift_remove(&path);
+                return;
+            }
+            (Some(FSOperation::Create), FSOperation::Write) => FSOperation::Create,
+            (Some(FSOperation::Move { source }), FSOperation::Write) => {
+                FSOperation::Move { source }
+            }
+            (Some(FSOperation::Delete), FSOperation::Create) => FSOperation::Write,
+            (_, operation) => operation,
+        };
+        self.changes.insert(path, (operation, is_dir));
+    }
+
+    fn rename(&mut self, source: PathBuf, dest: PathBuf, is_dir: bool) {
+        // the halves of the rename may have been reported on their own already
+        if let Some((FSOperation::Delete, _)) = self.changes.get(&source) {
+            self.changes.shift_remove(&source);
+        }
+        if let Some((FSOperation::Create, _)) = self.changes.get(&dest) {
+            self.changes.shift_remove(&dest);
+        }
+        self.changes
+            .insert(dest, (FSOperation::Move { source }, is_dir));
+    }
+
+    fn into_events(self) -> Vec<FSEvent> {
+        self.changes
+            .into_iter()
+            .map(|(path, (operation, is_dir))| FSEvent {
+                operation,
+                target: if is_dir {
+                    FSTarget::Directory(path)
+                } else {
+                    FSTarget::File(path)
+                },
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce_changes() {
+        let mut changes = Changes::default();
+        changes.push("logs/latest.log".into(), FSOperation::Create, false);
+        changes.push("logs/latest.log".into(), FSOperation::Write, false);
+        changes.push("server.properties".into(), FSOperation::Write, false);
+        changes.push("server.properties".into(), FSOperation::Write, false);
+        changes.push("tmp".into(), FSOperation::Create, false);
+        changes.push("tmp".into(), FSOperation::Delete, false);
+        changes.push("world".into(), FSOperation::Delete, true);
+        changes.push("world".into(), FSOperation::Create, true);
+        changes.push("old.txt".into(), FSOperation::Delete, false);
+        changes.push("new.txt".into(), FSOperation::Create, false);
+        changes.rename("old.txt".into(), "new.txt".into(), false);
+
+        assert_eq!(
+            changes.into_events(),
+            vec![
+                FSEvent {
+                    operation: FSOperation::Create,
+                    target: FSTarget::File("logs/latest.log".into()),
+                },
+                FSEvent {
+                    operation: FSOperation::Write,
+                    target: FSTarget::File("server.properties".into()),
+                },
+                FSEvent {
+                    operation: FSOperation::Write,
+                    target: FSTarget::Directory("world".into()),
+                },
+                FSEvent {
+                    operation: FSOperation::Move {
+                        source: "old.txt".into()
+                    },
+                    target: FSTarget::File("new.txt".into()),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_directory_watcher() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("logs")).unwrap();
+        let mut watcher = DirectoryWatcher::new(root.clone(), root.join("logs")).unwrap();
+        std::fs::write(root.join("logs/latest.log"), "started").unwrap();
+        // outside of the watched directory
+        std::fs::write(root.join("server.properties"), "").unwrap();
+
+        let events = tokio::time::timeout(Duration::from_secs(5), watcher.next_batch())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![FSEvent {
+                operation: FSOperation::Create,
+                target: FSTarget::File("logs/latest.log".into()),
+            }]
+        );
+    }
+}
@@ -5,7 +5,10 @@ use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
 use ts_rs::TS;
 
-use crate::{error::Error, event_broadcaster::EventBroadcaster};
+use crate::{
+    auth::oidc::OidcSettings, error::Error, event_broadcaster::EventBroadcaster,
+    macro_history::MacroRunRetention, macro_limits::MacroLimits,
+};
 
 #[derive(Serialize, Deserialize, Clone, TS)]
 #[ts(export)]
@@ -15,6 +18,24 @@ pub struct GlobalSettingsData {
     pub domain: Option<String>,
     #[serde(default)]
     pub playit_enabled: bool,
+    /// Admins without two-factor authentication lose their admin privileges until they enroll
+    #[serde(default)]
+    pub require_admin_totp: bool,
+    /// Single sign-on through an OpenID Connect identity provider
+    #[serde(default)]
+    pub oidc: Option<OidcSettings>,
+    /// Macros only import remote modules that were already cached
+    #[serde(default)]
+    pub macro_offline_mode: bool,
+    /// How long finished macro runs and their logs are kept
+    #[serde(default)]
+    pub macro_run_retention: MacroRunRetention,
+    /// Limits of every macro, a macro can only set lower ones of its own
+    #[serde(default = "MacroLimits::global_default")]
+    pub macro_limits: MacroLimits,
+    /// Instance macros run with full access instead of the sandbox, as they did before
+    #[serde(default)]
+    pub unsandboxed_macros: bool,
 }
 
 impl Default for GlobalSettingsData {
@@ -24,6 +45,12 @@ impl Default for GlobalSettingsData {
             safe_mode: true,
             domain: None,
             playit_enabled: true,
+            require_admin_totp: false,
+            oidc: None,
+            macro_offline_mode: false,
+            macro_run_retention: MacroRunRetention::default(),
+            macro_limits: MacroLimits::global_default(),
+            unsandboxed_macros: false,
         }
     }
 }
@@ -165,6 +192,113 @@ impl GlobalSettings {
     pub fn playit_enabled(&self) -> bool {
         self.global_settings_data.playit_enabled
     }
+
+    pub async fn set_require_admin_totp(&mut self, require_admin_totp: bool) -> Result<(), Error> {
+        let old_require_admin_totp = self.global_settings_data.require_admin_totp;
+        self.global_settings_data.require_admin_totp = require_admin_totp;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.require_admin_totp = old_require_admin_totp;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn require_admin_totp(&self) -> bool {
+        self.global_settings_data.require_admin_totp
+    }
+
+    pub async fn set_oidc(&mut self, oidc: Option<OidcSettings>) -> Result<(), Error> {
+        let old_oidc = self.global_settings_data.oidc.clone();
+        self.global_settings_data.oidc = oidc;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.oidc = old_oidc;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn oidc(&self) -> Option<OidcSettings> {
+        self.global_settings_data.oidc.clone()
+    }
+
+    pub async fn set_macro_offline_mode(&mut self, macro_offline_mode: bool) -> Result<(), Error> {
+        let old_macro_offline_mode = self.global_settings_data.macro_offline_mode;
+        self.global_settings_data.macro_offline_mode = macro_offline_mode;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.macro_offline_mode = old_macro_offline_mode;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn macro_offline_mode(&self) -> bool {
+        self.global_settings_data.macro_offline_mode
+    }
+
+    pub async fn set_macro_run_retention(
+        &mut self,
+        macro_run_retention: MacroRunRetention,
+    ) -> Result<(), Error> {
+        let old_macro_run_retention = self.global_settings_data.macro_run_retention.clone();
+        self.global_settings_data.macro_run_retention = macro_run_retention;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.macro_run_retention = old_macro_run_retention;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn macro_run_retention(&self) -> MacroRunRetention {
+        self.global_settings_data.macro_run_retention.clone()
+    }
+
+    pub async fn set_macro_limits(&mut self, macro_limits: MacroLimits) -> Result<(), Error> {
+        let old_macro_limits = self.global_settings_data.macro_limits;
+        self.global_settings_data.macro_limits = macro_limits;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.macro_limits = old_macro_limits;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn macro_limits(&self) -> MacroLimits {
+        self.global_settings_data.macro_limits
+    }
+
+    pub async fn set_unsandboxed_macros(&mut self, unsandboxed_macros: bool) -> Result<(), Error> {
+        let old_unsandboxed_macros = self.global_settings_data.unsandboxed_macros;
+        self.global_settings_data.unsandboxed_macros = unsandboxed_macros;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.unsandboxed_macros = old_unsandboxed_macros;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn unsandboxed_macros(&self) -> bool {
+        self.global_settings_data.unsandboxed_macros
+    }
+
+    /// Whether password logins are limited to the owner
+    pub fn owner_only_password_login(&self) -> bool {
+        self.global_settings_data
+            .oidc
+            .as_ref()
+            .map_or(false, |oidc| oidc.enabled && !oidc.allow_password_login)
+    }
 }
 
 impl AsRef<GlobalSettingsData> for GlobalSettings {
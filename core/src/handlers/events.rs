@@ -167,7 +167,7 @@ pub async fn event_stream(
     let event_receiver = state.event_broadcaster.subscribe();
 
     Ok(ws.on_upgrade(move |socket| {
-        event_stream_ws(socket, event_receiver, query, user.uid, state.users_manager)
+        event_stream_ws(socket, event_receiver, query, token, state.users_manager)
     }))
 }
 
@@ -175,7 +175,7 @@ async fn event_stream_ws(
     stream: WebSocket,
     mut event_receiver: Receiver<Event>,
     query: EventQuery,
-    uid: UserId,
+    token: String,
     users_manager: Arc<RwLock<UsersManager>>,
 ) {
     let (mut sender, mut receiver) = stream.split();
@@ -185,7 +185,8 @@ async fn event_stream_ws(
                 if event.is_event_console_message() {
                     continue;
                 }
-                let user = match users_manager.read().await.get_user(&uid) {
+                // re-authenticate so revoked sessions and tokens stop receiving events
+                let user = match users_manager.read().await.try_auth(&token) {
                     Some(user) => user,
                     None => {
                         break;
@@ -216,17 +217,23 @@ pub async fn console_stream(
 ) -> Result<Response, Error> {
     let users_manager = state.users_manager.read().await;
 
-    let user = parse_bearer_token(query.token.as_str())
-        .and_then(|token| users_manager.try_auth(&token))
-        .ok_or_else(|| Error {
-            kind: ErrorKind::Unauthorized,
-            source: eyre!("Token error"),
-        })?;
+    let token = parse_bearer_token(query.token.as_str()).ok_or_else(|| Error {
+        kind: ErrorKind::Unauthorized,
+        source: eyre!("Token error"),
+    })?;
+    let user = users_manager.try_auth_or_err(&token)?;
     drop(users_manager);
     let event_receiver = state.event_broadcaster.subscribe();
 
     Ok(ws.on_upgrade(move |socket| {
-        console_stream_ws(socket, event_receiver, user.uid, uuid, state.users_manager)
+        console_stream_ws(
+            socket,
+            event_receiver,
+            user.uid,
+            token,
+            uuid,
+            state.users_manager,
+        )
     }))
 }
 
@@ -234,6 +241,7 @@ async fn console_stream_ws(
     stream: WebSocket,
     mut event_receiver: Receiver<Event>,
     uid: UserId,
+    token: String,
     uuid: InstanceUuid,
     users_manager: Arc<RwLock<UsersManager>>,
 ) {
@@ -243,7 +251,7 @@ async fn console_stream_ws(
             Ok(event) = event_receiver.recv() => {
                 match &event.event_inner {
                     EventInner::InstanceEvent(instance_event) => {
-                        let user = match users_manager.read().await.get_user(&uid) {
+                        let user = match users_manager.read().await.try_auth(&token) {
                             Some(user) => user,
                             None => break,
                         };
@@ -2,12 +2,13 @@ use std::num::NonZeroU16;
 
 use axum::{
     extract::Path,
-    routing::{get, put},
+    routing::{delete, get, put},
     Json, Router,
 };
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::{eyre, Context};
+use indexmap::IndexMap;
 use serde_json::Value;
 use tracing::error;
 
@@ -15,7 +16,9 @@ use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
     extension::{self, FetchExtensionManifestError},
-    prelude::lodestone_path,
+    implementations::generic,
+    prelude::{lodestone_path, GameInstance},
+    traits::t_configurable::TConfigurable,
     AppState,
 };
 
@@ -71,34 +74,155 @@ async fn fetch_extension_manifest(
     }))
 }
 
-async fn install_extension(
-    axum::extract::State(state): axum::extract::State<AppState>,
-    Json(body): Json<ExtensionRequestBody>,
-    // AuthBearer(token): AuthBearer,
-) -> Result<(), Error> {
-    // let requester = state
-    //     .users_manager
-    //     .read()
-    //     .await
-    //     .try_auth(&token)
-    //     .ok_or_else(|| Error {
-    //         kind: ErrorKind::Unauthorized,
-    //         source: eyre!("Token error"),
-    //     })?;
-    // requester.try_action(&UserAction::InstallExtension)?;
+#[derive(serde::Deserialize)]
+struct InstallExtensionBody {
+    /// A git URL on any host, or a directory on this machine to use in place
+    url: String,
+    /// A branch, tag or commit to install and follow on update, the default branch if none
+    #[serde(default)]
+    version: Option<String>,
+    /// The permissions the owner approves, must cover what the manifest requests.
+    /// Only needed again on update if the extension asks for more.
+    #[serde(default)]
+    approved_permission: Option<extension::Permission>,
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateExtensionBody {
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    approved_permission: Option<extension::Permission>,
+}
+
+#[derive(serde::Deserialize)]
+struct RollbackExtensionBody {
+    #[serde(default)]
+    approved_permission: Option<extension::Permission>,
+}
+
+async fn extension_manager(
+    state: &AppState,
+    token: &str,
+    approved_permission: Option<&extension::Permission>,
+) -> Result<extension::ExtensionManager, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(token)?;
+    requester.try_action(
+        &UserAction::InstallExtension,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if approved_permission.is_some() && !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can approve extension permissions"),
+        });
+    }
     let path = lodestone_path().join("extensions");
     tokio::fs::create_dir_all(&path)
         .await
         .context("Failed to create extensions directory")?;
-    let manager = extension::ExtensionManager::new(path);
-    manager.install_extension(&body.url).await?;
-    Ok(())
+    Ok(extension::ExtensionManager::new(path))
+}
+
+async fn list_extensions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<IndexMap<String, extension::InstalledExtension>>, Error> {
+    let manager = extension_manager(&state, &token, None).await?;
+    Ok(Json(manager.installed().await?))
+}
+
+async fn install_extension(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(body): Json<InstallExtensionBody>,
+) -> Result<Json<extension::InstalledExtension>, Error> {
+    let manager = extension_manager(&state, &token, body.approved_permission.as_ref()).await?;
+    let is_owner = state
+        .users_manager
+        .read()
+        .await
+        .try_auth_or_err(&token)?
+        .is_owner;
+    Ok(Json(
+        manager
+            .install_extension(&body.url, body.version, body.approved_permission, is_owner)
+            .await?,
+    ))
+}
+
+async fn check_extension_update(
+    Path(id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<extension::ExtensionUpdate>>, Error> {
+    let manager = extension_manager(&state, &token, None).await?;
+    Ok(Json(manager.check_update(&id).await?))
+}
+
+async fn update_extension(
+    Path(id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(body): Json<UpdateExtensionBody>,
+) -> Result<Json<extension::InstalledExtension>, Error> {
+    let manager = extension_manager(&state, &token, body.approved_permission.as_ref()).await?;
+    Ok(Json(
+        manager
+            .update_extension(&id, body.version, body.approved_permission)
+            .await?,
+    ))
+}
+
+async fn rollback_extension(
+    Path(id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(body): Json<RollbackExtensionBody>,
+) -> Result<Json<extension::InstalledExtension>, Error> {
+    let manager = extension_manager(&state, &token, body.approved_permission.as_ref()).await?;
+    Ok(Json(
+        manager
+            .rollback_extension(&id, body.approved_permission)
+            .await?,
+    ))
+}
+
+async fn uninstall_extension(
+    Path(id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<extension::InstalledExtension>, Error> {
+    let manager = extension_manager(&state, &token, None).await?;
+    let instances: Vec<GameInstance> = state
+        .instances
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+    let mut instance_sources = Vec::new();
+    for instance in instances {
+        if let GameInstance::GenericInstance(_) = instance {
+            if let Some(source) = generic::GenericInstance::source(&instance.path().await).await {
+                instance_sources.push((instance.name().await, source));
+            }
+        }
+    }
+    Ok(Json(
+        manager.uninstall_extension(&id, &instance_sources).await?,
+    ))
 }
 
 pub fn get_extension_routes(state: AppState) -> Router {
     Router::new()
         .route("/extension/gitstatus", get(is_git_installed))
         .route("/extension/fetchmanifest", get(fetch_extension_manifest))
+        .route("/extension/list", get(list_extensions))
         .route("/extension/install", put(install_extension))
+        .route(
+            "/extension/update/:id",
+            get(check_extension_update).put(update_extension),
+        )
+        .route("/extension/rollback/:id", put(rollback_extension))
+        .route("/extension/:id", delete(uninstall_extension))
         .with_state(state)
 }
@@ -5,7 +5,7 @@ use axum::{
     body::{Bytes, StreamBody},
     extract::{Multipart, Path},
     http,
-    routing::{delete, get, put},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use axum_auth::AuthBearer;
@@ -21,6 +21,7 @@ use ts_rs::TS;
 
 use crate::{
     auth::user::UserAction,
+    chunked_upload::{NewUpload, UploadStatus, UploadTarget},
     error::{Error, ErrorKind},
     events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget},
     util::{list_dir, rand_alphanumeric, zip_files},
@@ -581,6 +582,38 @@ async fn upload_file(
     Ok(Json(()))
 }
 
+/// Starts a resumable upload into a directory, chunks are then sent to `/upload/:upload_id`
+async fn create_file_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(base64_absolute_path): Path<String>,
+    AuthBearer(token): AuthBearer,
+    Json(new_upload): Json<NewUpload>,
+) -> Result<Json<UploadStatus>, Error> {
+    let absolute_path = decode_base64(&base64_absolute_path)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteGlobalFile,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let status = state
+        .chunked_uploads
+        .create(
+            requester.uid,
+            UploadTarget::Global {
+                dir: PathBuf::from(absolute_path),
+            },
+            new_upload,
+            caused_by,
+            &state.event_broadcaster,
+        )
+        .await?;
+    Ok(Json(status))
+}
+
 async fn download(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(key): Path<String>,
@@ -651,6 +684,7 @@ pub fn get_global_fs_routes(state: AppState) -> Router {
         .route("/fs/:base64_absolute_path/new", put(new_file))
         .route("/fs/:base64_absolute_path/download", get(download_file))
         .route("/fs/:base64_absolute_path/upload", put(upload_file))
+        .route("/fs/:base64_absolute_path/uploads", post(create_file_upload))
         .route("/file/:key", get(download))
         .with_state(state)
 }
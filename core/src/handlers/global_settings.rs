@@ -5,7 +5,10 @@ use axum::{
 use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 
-use crate::{error::ErrorKind, AppState, Error, GlobalSettingsData};
+use crate::{
+    auth::oidc::OidcSettings, error::ErrorKind, macro_history::MacroRunRetention,
+    macro_limits::MacroLimits, module_cache::set_offline_mode, AppState, Error, GlobalSettingsData,
+};
 
 pub async fn get_core_settings(
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -21,7 +24,11 @@ pub async fn get_core_settings(
             source: eyre!("Token error"),
         })?;
 
-    Ok(Json(state.global_settings.lock().await.as_ref().clone()))
+    let mut global_settings = state.global_settings.lock().await.as_ref().clone();
+    if let Some(oidc) = global_settings.oidc.as_mut() {
+        oidc.client_secret = None;
+    }
+    Ok(Json(global_settings))
 }
 
 pub async fn change_core_name(
@@ -134,6 +141,166 @@ pub async fn change_core_playit_enabled(
     Ok(())
 }
 
+pub async fn change_require_admin_totp(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(require_admin_totp): Json<bool>,
+) -> Result<(), Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change two-factor requirements"),
+        });
+    }
+
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_require_admin_totp(require_admin_totp)
+        .await?;
+    users_manager.set_require_admin_totp(require_admin_totp);
+    Ok(())
+}
+
+pub async fn change_macro_offline_mode(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(macro_offline_mode): Json<bool>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change macro offline mode"),
+        });
+    }
+
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_macro_offline_mode(macro_offline_mode)
+        .await?;
+    set_offline_mode(macro_offline_mode);
+    Ok(())
+}
+
+/// Runs the new policy are not keeping are deleted right away
+pub async fn change_macro_run_retention(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(macro_run_retention): Json<MacroRunRetention>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change macro run retention"),
+        });
+    }
+
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_macro_run_retention(macro_run_retention.clone())
+        .await?;
+    state
+        .macro_executor
+        .history()
+        .prune(&macro_run_retention)
+        .await?;
+    Ok(())
+}
+
+/// Applies to macros started from now on
+pub async fn change_macro_limits(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(macro_limits): Json<MacroLimits>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change macro limits"),
+        });
+    }
+
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_macro_limits(macro_limits)
+        .await?;
+    state.macro_executor.set_default_limits(macro_limits);
+    Ok(())
+}
+
+/// Applies to macros started from now on
+pub async fn change_unsandboxed_macros(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(unsandboxed_macros): Json<bool>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the macro sandbox"),
+        });
+    }
+
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_unsandboxed_macros(unsandboxed_macros)
+        .await?;
+    state
+        .macro_executor
+        .set_unsandboxed_macros(unsandboxed_macros);
+    Ok(())
+}
+
+/// The client secret is never sent to the dashboard, so if it is left out the current one is kept
+pub async fn change_oidc_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(oidc): Json<Option<OidcSettings>>,
+) -> Result<(), Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change single sign-on settings"),
+        });
+    }
+
+    let mut global_settings = state.global_settings.lock().await;
+    let oidc = oidc.map(|mut oidc| {
+        if oidc.client_secret.is_none() {
+            oidc.client_secret = global_settings
+                .oidc()
+                .and_then(|old_oidc| old_oidc.client_secret);
+        }
+        oidc
+    });
+    global_settings.set_oidc(oidc).await?;
+    users_manager.set_owner_only_password_login(global_settings.owner_only_password_login());
+    Ok(())
+}
+
 pub fn get_global_settings_routes(state: AppState) -> Router {
     Router::new()
         .route("/global_settings", get(get_core_settings))
@@ -144,5 +311,23 @@ pub fn get_global_settings_routes(state: AppState) -> Router {
             "/global_settings/playit_enabled",
             put(change_core_playit_enabled),
         )
+        .route(
+            "/global_settings/require_admin_totp",
+            put(change_require_admin_totp),
+        )
+        .route("/global_settings/oidc", put(change_oidc_settings))
+        .route(
+            "/global_settings/macro_offline_mode",
+            put(change_macro_offline_mode),
+        )
+        .route(
+            "/global_settings/macro_run_retention",
+            put(change_macro_run_retention),
+        )
+        .route("/global_settings/macro_limits", put(change_macro_limits))
+        .route(
+            "/global_settings/unsandboxed_macros",
+            put(change_unsandboxed_macros),
+        )
         .with_state(state)
 }
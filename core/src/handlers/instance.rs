@@ -7,17 +7,18 @@ use bollard::container::ListContainersOptions;
 use bollard::Docker;
 use color_eyre::eyre::{eyre, Context};
 use serde::Deserialize;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::auth::user::UserAction;
 use crate::error::{Error, ErrorKind};
 use crate::events::{CausedBy, Event, ProgressionEndValue, ProgressionStartValue};
+use crate::extension::ExtensionManager;
 
 use crate::implementations::generic;
 use crate::traits::t_configurable::GameType;
 
 use crate::implementations::minecraft::MinecraftInstance;
-use crate::prelude::{path_to_instances, GameInstance};
+use crate::prelude::{lodestone_path, path_to_instances, GameInstance};
 use crate::traits::t_configurable::manifest::SetupValue;
 use crate::traits::t_configurable::Game::Generic;
 use crate::traits::{t_configurable::TConfigurable, t_server::TServer, InstanceInfo, TInstance};
@@ -234,6 +235,13 @@ pub async fn create_generic_instance(
         .context("Failed to create instance directory")?;
 
     let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), GameType::Generic);
+    let permissions = ExtensionManager::new(lodestone_path().join("extensions"))
+        .sandbox_permissions(std::path::Path::new(&setup_config.url))
+        .await?
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Atoms must be installed and approved as an extension before use"),
+        })?;
     let event_broadcaster = state.event_broadcaster.clone();
     tokio::task::spawn(async move {
         let (progression_start_event, event_id) = Event::new_progression_event_start(
@@ -256,6 +264,7 @@ pub async fn create_generic_instance(
             &event_id,
             state.event_broadcaster.clone(),
             state.macro_executor.clone(),
+            permissions,
         )
         .await
         {
@@ -356,6 +365,16 @@ pub async fn delete_instance(
                 .lock()
                 .await
                 .deallocate(instance.port().await);
+            state.disk_usage.remove_instance(&uuid).await;
+            if let Err(e) = state.macro_kv.clear_instance(&uuid).await {
+                warn!("Failed to clear macro key-value store of deleted instance: {e}");
+            }
+            if let Err(e) = state.macro_triggers.clear_instance(&uuid).await {
+                warn!("Failed to clear macro triggers of deleted instance: {e}");
+            }
+            if let Err(e) = state.backup_store.remove_instance(&uuid).await {
+                warn!("Failed to remove snapshots of deleted instance: {e}");
+            }
             let instance_path = instance.path().await;
             // if instance is generic
             if let GameInstance::GenericInstance(i) = instance {
@@ -0,0 +1,360 @@
+use std::path::PathBuf;
+
+use axum::{
+    extract::Path,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use ts_rs::TS;
+
+use super::instance_fs::{is_path_protected, progress_reporter};
+use crate::{
+    auth::user::UserAction,
+    backup_store::{PruneReport, RetentionPolicy, Snapshot, SnapshotSummary, VerifyReport},
+    error::{Error, ErrorKind},
+    events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget, ProgressionEndValue},
+    fs_history::scoped_join_instance_file,
+    traits::{t_configurable::TConfigurable, t_server::State, t_server::TServer},
+    types::{InstanceUuid, Snowflake},
+    util::ArchiveFormat,
+    AppState,
+};
+
+/// The instance's root directory and name
+async fn instance_root(state: &AppState, uuid: &InstanceUuid) -> Result<(PathBuf, String), Error> {
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Backups are not supported for docker instances"),
+        });
+    }
+    let instance = state.instances.get(uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    let name = instance.name().await;
+    drop(instance);
+    Ok((root, name))
+}
+
+async fn list_backups(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<SnapshotSummary>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    instance_root(&state, &uuid).await?;
+    Ok(Json(state.backup_store.list(&uuid).await?))
+}
+
+async fn get_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, snapshot_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Snapshot>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    instance_root(&state, &uuid).await?;
+    Ok(Json(state.backup_store.get(&uuid, snapshot_id).await?))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct NewBackup {
+    #[serde(default)]
+    description: Option<String>,
+}
+
+/// Starts backing up the instance in the background, progress is reported with progression events
+async fn create_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(NewBackup { description }): Json<NewBackup>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let (root, name) = instance_root(&state, &uuid).await?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    let event_broadcaster = state.event_broadcaster.clone();
+    let backup_store = state.backup_store.clone();
+    tokio::spawn(async move {
+        let (progression_start_event, event_id) = Event::new_progression_event_start(
+            format!("Backing up {name}"),
+            None,
+            None,
+            caused_by.clone(),
+        );
+        event_broadcaster.send(progression_start_event);
+        let on_progress = progress_reporter(
+            event_broadcaster.clone(),
+            event_id.clone(),
+            format!("Backing up {name}"),
+        );
+        match backup_store
+            .create(&uuid, root, description, caused_by, on_progress)
+            .await
+        {
+            Ok(snapshot) => event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                true,
+                Some("Backup complete"),
+                Some(ProgressionEndValue::FSOperationCompleted {
+                    instance_uuid: uuid,
+                    success: true,
+                    message: format!("Backed up {} files of {name}", snapshot.file_count),
+                }),
+            )),
+            Err(e) => event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(&format!("Backup failed: {e}")),
+                Some(ProgressionEndValue::FSOperationCompleted {
+                    instance_uuid: uuid,
+                    success: false,
+                    message: format!("Backing up {name} failed: {e}"),
+                }),
+            )),
+        }
+    });
+    Ok(Json(()))
+}
+
+async fn delete_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, snapshot_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PruneReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    instance_root(&state, &uuid).await?;
+    Ok(Json(state.backup_store.forget(&uuid, snapshot_id).await?))
+}
+
+async fn prune_backups(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(policy): Json<RetentionPolicy>,
+) -> Result<Json<PruneReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    instance_root(&state, &uuid).await?;
+    Ok(Json(state.backup_store.prune(&uuid, policy).await?))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct VerifyRequest {
+    /// Also decompress every chunk and check its hash, instead of only checking it exists
+    #[serde(default)]
+    read_data: bool,
+}
+
+async fn verify_backups(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(VerifyRequest { read_data }): Json<VerifyRequest>,
+) -> Result<Json<VerifyReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    instance_root(&state, &uuid).await?;
+    Ok(Json(state.backup_store.verify(&uuid, read_data).await?))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct RestoreRequest {
+    /// A file or directory to restore, the whole snapshot if not set
+    #[serde(default)]
+    relative_path: Option<PathBuf>,
+    /// Remove files the snapshot doesn't have, under `relative_path` if set
+    #[serde(default)]
+    clean: bool,
+}
+
+async fn restore_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, snapshot_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+    Json(RestoreRequest {
+        relative_path,
+        clean,
+    }): Json<RestoreRequest>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let (root, name) = instance_root(&state, &uuid).await?;
+    let target = match &relative_path {
+        Some(relative_path) => {
+            let path = scoped_join_instance_file(&root, relative_path)?;
+            if !requester.can_perform_action(&UserAction::WriteGlobalFile)
+                && (path.exists() || path.extension().is_some())
+                && is_path_protected(&path)
+            {
+                return Err(Error {
+                    kind: ErrorKind::PermissionDenied,
+                    source: eyre!("File extension is protected"),
+                });
+            }
+            path
+        }
+        None => {
+            // the server would be writing to the files being replaced
+            let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Instance not found"),
+            })?;
+            if instance.state().await != State::Stopped {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Instance must be stopped to restore a whole snapshot"),
+                });
+            }
+            drop(instance);
+            root.clone()
+        }
+    };
+    // only what the restore adds on top of the files it replaces counts
+    let remaining = state.disk_usage.remaining(&uuid, &root, &name).await?;
+    state
+        .backup_store
+        .restore(
+            &uuid,
+            snapshot_id,
+            root,
+            relative_path,
+            clean,
+            requester.can_perform_action(&UserAction::WriteGlobalFile),
+            remaining,
+        )
+        .await?;
+    state.disk_usage.invalidate(&uuid).await;
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Write,
+        if target.is_dir() {
+            FSTarget::Directory(target)
+        } else {
+            FSTarget::File(target)
+        },
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(Json(()))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct ExportRequest {
+    destination_relative_path: PathBuf,
+    /// Guessed from the destination's extension if not set, defaulting to zip
+    #[serde(default)]
+    format: Option<ArchiveFormat>,
+}
+
+/// Writes a snapshot out as an archive inside the instance, returning where it was written
+async fn export_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, snapshot_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+    Json(ExportRequest {
+        destination_relative_path,
+        format,
+    }): Json<ExportRequest>,
+) -> Result<Json<PathBuf>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let (root, name) = instance_root(&state, &uuid).await?;
+    let format = format
+        .or_else(|| ArchiveFormat::from_file_name(&destination_relative_path))
+        .unwrap_or_default();
+    let destination = scoped_join_instance_file(&root, &destination_relative_path)?;
+    if !requester.can_perform_action(&UserAction::WriteGlobalFile)
+        && is_path_protected(&destination)
+    {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Destination is protected"),
+        });
+    }
+    let snapshot = state.backup_store.get(&uuid, snapshot_id).await?;
+    state
+        .disk_usage
+        .check_write(&uuid, &root, &name, snapshot.summary.total_size)
+        .await?;
+    let destination = state
+        .backup_store
+        .export(&uuid, snapshot_id, destination, format)
+        .await?;
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Create,
+        FSTarget::File(destination.clone()),
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(Json(
+        destination
+            .strip_prefix(&root)
+            .map(|path| path.to_owned())
+            .unwrap_or_default(),
+    ))
+}
+
+pub fn get_instance_backup_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/backups", get(list_backups))
+        .route("/instance/:uuid/backups", post(create_backup))
+        .route("/instance/:uuid/backups/prune", post(prune_backups))
+        .route("/instance/:uuid/backups/verify", post(verify_backups))
+        .route("/instance/:uuid/backups/:snapshot_id", get(get_backup))
+        .route(
+            "/instance/:uuid/backups/:snapshot_id",
+            delete(delete_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/:snapshot_id/restore",
+            put(restore_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/:snapshot_id/export",
+            put(export_backup),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,66 @@
+use axum::{
+    extract::Path,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    disk_usage::{DiskQuota, InstanceDiskUsage},
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+pub async fn get_instance_disk_usage(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<InstanceDiskUsage>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    let name = instance.name().await;
+    drop(instance);
+    Ok(Json(state.disk_usage.usage(&uuid, &root, &name).await?))
+}
+
+pub async fn set_instance_disk_quota(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(quota): Json<DiskQuota>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can set disk quotas"),
+        });
+    }
+    if !state.instances.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    state.disk_usage.set_quota(uuid, quota).await?;
+    Ok(Json(()))
+}
+
+pub fn get_instance_disk_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/disk", get(get_instance_disk_usage))
+        .route("/instance/:uuid/disk/quota", put(set_instance_disk_quota))
+        .with_state(state)
+}
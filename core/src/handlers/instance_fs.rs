@@ -1,33 +1,52 @@
+use std::convert::Infallible;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use axum::{
-    body::Bytes,
-    extract::{DefaultBodyLimit, Multipart, Path},
-    routing::{delete, get, put},
+    body::{Bytes, StreamBody},
+    extract::{ws::WebSocket, DefaultBodyLimit, Multipart, Path, Query, WebSocketUpgrade},
+    http,
+    response::Response,
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use axum_auth::AuthBearer;
 use color_eyre::eyre::{eyre, Context};
 use fs_extra::TransitProcess;
+use futures::SinkExt;
 use headers::HeaderMap;
 use reqwest::header::CONTENT_LENGTH;
 use serde::Deserialize;
-use tokio::io::AsyncWriteExt;
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 use tracing::error;
 use ts_rs::TS;
 use walkdir::WalkDir;
 
 use crate::{
-    auth::user::UserAction,
+    auth::user::{UserAction, UsersManager},
+    bulk_fs::{BulkFileOperation, BulkJobContext, BulkJobReport},
+    chunked_upload::{NewUpload, UploadStatus, UploadTarget},
     error::{Error, ErrorKind},
-    events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget, ProgressionEndValue},
+    event_broadcaster::EventBroadcaster,
+    events::{
+        new_fs_event, CausedBy, Event, FSOperation, FSTarget, ProgressionEndValue,
+        ProgressionEventID,
+    },
+    fs_history::{
+        check_not_history, diff_lines, scoped_join_instance_file, DiffLine, FileHistory,
+        FileRevision, HISTORY_DIR_NAME,
+    },
+    fs_search::{FileSearch, FileSearchQuery},
+    fs_watch::DirectoryWatcher,
     prelude::path_to_tmp,
     traits::t_configurable::TConfigurable,
-    types::InstanceUuid,
+    types::{InstanceUuid, Snowflake},
     util::{
-        format_byte, format_byte_download, list_dir, rand_alphanumeric, resolve_path_conflict,
-        scoped_join_win_safe, unzip_file_async, zip_files, zip_files_async, UnzipOption,
+        archive_files_async, files_size, format_byte, format_byte_download, list_dir,
+        rand_alphanumeric, resolve_path_conflict, scoped_join_win_safe,
+        unzip_file_with_progress_async, zip_files, ArchiveFormat, UnzipOption,
     },
     AppState,
 };
@@ -48,7 +67,7 @@ static PROTECTED_EXTENSIONS: [&str; 10] = [
 
 static PROTECTED_DIR_NAME: [&str; 1] = ["mods"];
 
-fn is_path_protected(path: impl AsRef<std::path::Path>) -> bool {
+pub(crate) fn is_path_protected(path: impl AsRef<std::path::Path>) -> bool {
     let path = path.as_ref();
     if path.is_dir() {
         path.file_name()
@@ -65,7 +84,7 @@ fn is_path_protected(path: impl AsRef<std::path::Path>) -> bool {
 
 use super::{
     global_fs::{DownloadableFile, FileEntry},
-    util::decode_base64,
+    util::{decode_base64, parse_bearer_token},
 };
 
 async fn list_instance_files(
@@ -93,11 +112,13 @@ async fn list_instance_files(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path = scoped_join_win_safe(&root, relative_path)?;
+    let path = scoped_join_instance_file(&root, relative_path)?;
 
+    let history_dir = root.join(HISTORY_DIR_NAME);
     let ret: Vec<FileEntry> = list_dir(&path, None)
         .await?
         .iter()
+        .filter(|p| **p != history_dir)
         .filter_map(move |p| -> Option<FileEntry> {
             // remove the root path from the file path
             let mut r: FileEntry = p.as_path().into();
@@ -121,6 +142,63 @@ async fn list_instance_files(
     Ok(Json(ret))
 }
 
+/// Streams results as newline delimited JSON, ending with a `Done` result
+async fn search_instance_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<FileSearchQuery>,
+) -> Result<
+    (
+        [(http::HeaderName, &'static str); 1],
+        StreamBody<impl Stream<Item = Result<String, Infallible>>>,
+    ),
+    Error,
+> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Searching files is not supported for docker instances"),
+        });
+    }
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+    let path = scoped_join_instance_file(&root, &query.path)?;
+    let search = FileSearch::new(path.clone(), &query)?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+    tokio::task::spawn_blocking(move || search.run(&root, tx));
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Read,
+        FSTarget::Directory(path),
+        caused_by,
+    ));
+    let stream = ReceiverStream::new(rx).map(|result| {
+        Ok(format!(
+            "{}\n",
+            serde_json::to_string(&result).unwrap_or_default()
+        ))
+    });
+    Ok((
+        [(http::header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(stream),
+    ))
+}
+
 async fn read_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
@@ -145,7 +223,7 @@ async fn read_instance_file(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
+    let path = scoped_join_instance_file(root, relative_path)?;
 
     let ret = tokio::fs::read_to_string(&path)
         .await
@@ -186,8 +264,9 @@ async fn write_instance_file(
         source: eyre!("Instance not found"),
     })?;
     let root = instance.path().await;
+    let name = instance.name().await;
     drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
+    let path = scoped_join_instance_file(&root, relative_path)?;
     // if target has a protected extension, or no extension, deny
     if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
         return Err(Error {
@@ -195,6 +274,24 @@ async fn write_instance_file(
             source: eyre!("You don't have permission to write to this file"),
         });
     }
+    let existing_size = tokio::fs::metadata(&path).await.map_or(0, |m| m.len());
+    state
+        .disk_usage
+        .check_write(
+            &uuid,
+            &root,
+            &name,
+            (body.len() as u64).saturating_sub(existing_size),
+        )
+        .await?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    // the old content can't be restored if its revision wasn't kept
+    FileHistory::new(&root)
+        .record(&path, caused_by.clone())
+        .await?;
     let mut file = tokio::fs::File::create(&path)
         .await
         .context("Failed to create file")?;
@@ -202,12 +299,127 @@ async fn write_instance_file(
         .await
         .context("Failed to write to file")?;
 
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Write,
+        FSTarget::File(path),
+        caused_by,
+    ));
+    Ok(Json(()))
+}
+
+async fn list_instance_file_revisions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<FileRevision>>, Error> {
+    let relative_path = decode_base64(&base64_relative_path)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("File history is not supported for docker instances"),
+        });
+    }
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+    let path = scoped_join_instance_file(&root, relative_path)?;
+    Ok(Json(FileHistory::new(root).list(&path).await?))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct RevisionDiffQuery {
+    pub from: Snowflake,
+    /// Compares against the current content of the file if not set
+    pub to: Option<Snowflake>,
+}
+
+async fn diff_instance_file_revisions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<RevisionDiffQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<DiffLine>>, Error> {
+    let relative_path = decode_base64(&base64_relative_path)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("File history is not supported for docker instances"),
+        });
+    }
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+    let path = scoped_join_instance_file(&root, relative_path)?;
+    let history = FileHistory::new(root);
+    let old = history.read(&path, query.from).await?;
+    let new = match query.to {
+        Some(to) => history.read(&path, to).await?,
+        None => tokio::fs::read(&path)
+            .await
+            .context("Failed to read file")?,
+    };
+    Ok(Json(diff_lines(
+        &String::from_utf8_lossy(&old),
+        &String::from_utf8_lossy(&new),
+    )))
+}
+
+async fn restore_instance_file_revision(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path, revision)): Path<(InstanceUuid, String, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let relative_path = decode_base64(&base64_relative_path)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("File history is not supported for docker instances"),
+        });
+    }
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+    let path = scoped_join_instance_file(&root, relative_path)?;
+    if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You don't have permission to write to this file"),
+        });
+    }
     let caused_by = CausedBy::User {
         user_id: requester.uid,
         user_name: requester.username,
     };
+    FileHistory::new(root)
+        .restore(&path, revision, caused_by.clone())
+        .await?;
     state.event_broadcaster.send(new_fs_event(
-        FSOperation::Write,
+        FSOperation::Restore { revision },
         FSTarget::File(path),
         caused_by,
     ));
@@ -231,7 +443,7 @@ async fn make_instance_directory(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
+    let path = scoped_join_instance_file(root, relative_path)?;
     // create the file if it doesn't exist
     crate::util::fs::create_dir_all(&path).await?;
 
@@ -277,10 +489,10 @@ async fn copy_instance_files(
     // join each path to the root
     let paths_source = relative_paths_source
         .iter()
-        .map(|p| scoped_join_win_safe(root.clone(), p))
+        .map(|p| scoped_join_instance_file(root.clone(), p))
         .collect::<Result<Vec<_>, _>>()?;
 
-    let path_dest = scoped_join_win_safe(root, &relative_path_dest)?;
+    let path_dest = scoped_join_instance_file(root, &relative_path_dest)?;
 
     if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path_dest)
     {
@@ -420,8 +632,8 @@ async fn move_instance_file(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path_source = scoped_join_win_safe(&root, relative_path_source)?;
-    let path_dest = scoped_join_win_safe(&root, relative_path_dest)?;
+    let path_source = scoped_join_instance_file(&root, relative_path_source)?;
+    let path_dest = scoped_join_instance_file(&root, relative_path_dest)?;
 
     let relative_path_source = path_source
         .strip_prefix(&root)
@@ -490,7 +702,7 @@ async fn remove_instance_file(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
+    let path = scoped_join_instance_file(root, relative_path)?;
     // if target has a protected extension, or no extension, deny
     if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
         return Err(Error {
@@ -530,7 +742,7 @@ async fn remove_instance_dir(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path = scoped_join_win_safe(&root, relative_path)?;
+    let path = scoped_join_instance_file(&root, relative_path)?;
     if path == root {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
@@ -595,7 +807,7 @@ async fn new_instance_file(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
+    let path = scoped_join_instance_file(root, relative_path)?;
     // if target has a protected extension, or no extension, deny
     if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
         return Err(Error {
@@ -635,7 +847,7 @@ async fn get_instance_file_url(
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path = scoped_join_win_safe(&root, &relative_path)?;
+    let path = scoped_join_instance_file(&root, &relative_path)?;
 
     let downloadable_file = if fs::metadata(&path)
         .map_err(|_| Error {
@@ -723,14 +935,22 @@ async fn upload_instance_file(
         source: eyre!("Instance not found"),
     })?;
     let root = instance.path().await;
+    let name = instance.name().await;
     drop(instance);
-    let path_to_dir = scoped_join_win_safe(&root, relative_path)?;
-    crate::util::fs::create_dir_all(&path_to_dir).await?;
+    let path_to_dir = scoped_join_instance_file(&root, relative_path)?;
 
     let total = headers
         .get(CONTENT_LENGTH)
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.parse::<f64>().ok());
+    state
+        .disk_usage
+        .check_write(&uuid, &root, &name, total.unwrap_or(0.0) as u64)
+        .await?;
+    // the header can be left out or lie, so the bytes are counted as they arrive too
+    let remaining = state.disk_usage.remaining(&uuid, &root, &name).await?;
+    let mut uploaded_bytes = 0_u64;
+    crate::util::fs::create_dir_all(&path_to_dir).await?;
     let (progression_start_event, event_id) =
         Event::new_progression_event_start("Uploading files", total, None, caused_by.clone());
     state.event_broadcaster.send(progression_start_event);
@@ -740,7 +960,9 @@ async fn upload_instance_file(
             source: eyre!("Missing file name"),
         })?;
         let name = sanitize_filename::sanitize(name);
-        let path = resolve_path_conflict(scoped_join_win_safe(&path_to_dir, &name)?, None);
+        let path = scoped_join_win_safe(&path_to_dir, &name)?;
+        check_not_history(&root, &path)?;
+        let path = resolve_path_conflict(path, None);
         // if the file has a protected extension, or no extension, deny
         if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
             return Err(Error {
@@ -779,6 +1001,30 @@ async fn upload_instance_file(
             }
         } {
             elapsed_bytes += chunk.len() as u64;
+            uploaded_bytes += chunk.len() as u64;
+            if let Some(remaining) = remaining.filter(|remaining| uploaded_bytes > *remaining) {
+                tokio::fs::remove_file(&path).await.ok();
+                let message = format!(
+                    "Upload is larger than the {} left in the disk quota",
+                    format_byte(remaining)
+                );
+                state
+                    .event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        false,
+                        Some(&message),
+                        Some(ProgressionEndValue::FSOperationCompleted {
+                            instance_uuid: uuid.clone(),
+                            success: false,
+                            message: format!("Failed to upload file {name}, {message}"),
+                        }),
+                    ));
+                return Err(Error {
+                    kind: ErrorKind::InsufficientStorage,
+                    source: eyre!(message),
+                });
+            }
             let progression = (elapsed_bytes as f64 / threshold).floor() as u64;
             if progression > last_progression {
                 last_progression = progression;
@@ -841,6 +1087,67 @@ async fn upload_instance_file(
     Ok(Json(()))
 }
 
+/// Starts a resumable upload into a directory, chunks are then sent to `/upload/:upload_id`
+async fn create_instance_file_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(new_upload): Json<NewUpload>,
+) -> Result<Json<UploadStatus>, Error> {
+    let relative_path = decode_base64(&base64_relative_path)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Resumable uploads are not supported for docker instances"),
+        });
+    }
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    let instance_name = instance.name().await;
+    drop(instance);
+    state
+        .disk_usage
+        .check_write(&uuid, &root, &instance_name, new_upload.size)
+        .await?;
+    let path_to_dir = scoped_join_instance_file(&root, relative_path)?;
+    let name = sanitize_filename::sanitize(&new_upload.file_name);
+    let path = scoped_join_win_safe(&path_to_dir, &name)?;
+    check_not_history(&root, &path)?;
+    // if the file has a protected extension, or no extension, deny
+    if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("File extension is protected"),
+        });
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let status = state
+        .chunked_uploads
+        .create(
+            requester.uid,
+            UploadTarget::Instance {
+                uuid,
+                dir: path_to_dir,
+            },
+            new_upload,
+            caused_by,
+            &state.event_broadcaster,
+        )
+        .await?;
+    Ok(Json(status))
+}
+
 pub async fn unzip_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
@@ -858,9 +1165,15 @@ pub async fn unzip_instance_file(
         source: eyre!("Instance not found"),
     })?;
     let root = instance.path().await;
+    let name = instance.name().await;
     drop(instance);
-    let path_to_zip_file = scoped_join_win_safe(root, &relative_path)?;
+    let path_to_zip_file = scoped_join_instance_file(&root, &relative_path)?;
 
+    // entries are scoped to the destination, so the destination itself must be in the instance
+    let unzip_option = match unzip_option {
+        UnzipOption::ToDir(dir) => UnzipOption::ToDir(scoped_join_instance_file(&root, dir)?),
+        unzip_option => unzip_option,
+    };
     if let UnzipOption::ToDir(ref dir) = unzip_option {
         if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(dir) {
             return Err(Error {
@@ -869,11 +1182,18 @@ pub async fn unzip_instance_file(
             });
         }
     }
+    let total = tokio::fs::metadata(&path_to_zip_file)
+        .await
+        .context("Failed to read archive")?
+        .len();
+    // extraction stops once the archive would take the instance over its quota
+    let remaining = state.disk_usage.remaining(&uuid, &root, &name).await?;
     let event_broadcaster = state.event_broadcaster.clone();
+    let disk_usage = state.disk_usage.clone();
     tokio::spawn(async move {
         let (progression_event_start, event_id) = Event::new_progression_event_start(
             format!("Unzipping {relative_path}"),
-            None,
+            Some(total as f64),
             None,
             CausedBy::User {
                 user_id: requester.uid.clone(),
@@ -883,7 +1203,18 @@ pub async fn unzip_instance_file(
 
         event_broadcaster.send(progression_event_start);
 
-        if let Err(e) = unzip_file_async(path_to_zip_file, unzip_option).await {
+        if let Err(e) = unzip_file_with_progress_async(
+            path_to_zip_file,
+            unzip_option,
+            remaining,
+            progress_reporter(
+                event_broadcaster.clone(),
+                event_id.clone(),
+                format!("Unzipping {relative_path}"),
+            ),
+        )
+        .await
+        {
             event_broadcaster.send(Event::new_progression_event_end(
                 event_id,
                 false,
@@ -895,6 +1226,10 @@ pub async fn unzip_instance_file(
                 }),
             ));
         } else {
+            disk_usage.invalidate(&uuid).await;
+            if let Err(e) = disk_usage.usage(&uuid, &root, &name).await {
+                error!("Failed to update disk usage of {uuid}: {e}");
+            }
             event_broadcaster.send(Event::new_progression_event_end(
                 event_id,
                 true,
@@ -911,11 +1246,35 @@ pub async fn unzip_instance_file(
     Ok(Json(()))
 }
 
+/// Reports progress on a progression event at most once per percent
+pub(crate) fn progress_reporter(
+    event_broadcaster: EventBroadcaster,
+    event_id: ProgressionEventID,
+    message: String,
+) -> impl FnMut(u64, u64) + Send + 'static {
+    let mut reported = 0;
+    move |done, total| {
+        let progress = done.saturating_sub(reported);
+        if progress == 0 || (progress < total / 100 && done < total) {
+            return;
+        }
+        event_broadcaster.send(Event::new_progression_event_update(
+            &event_id,
+            format!("{message}, {}", format_byte_download(done, total)),
+            progress as f64,
+        ));
+        reported = done;
+    }
+}
+
 #[derive(Deserialize, TS)]
 #[ts(export)]
 struct ZipRequest {
     target_relative_paths: Vec<PathBuf>,
     destination_relative_path: PathBuf,
+    /// Guessed from the destination's extension if not set, defaulting to zip
+    #[serde(default)]
+    format: Option<ArchiveFormat>,
 }
 
 async fn zip_instance_files(
@@ -938,13 +1297,17 @@ async fn zip_instance_files(
     let ZipRequest {
         mut target_relative_paths,
         mut destination_relative_path,
+        format,
     } = zip_request;
+    let format = format
+        .or_else(|| ArchiveFormat::from_file_name(&destination_relative_path))
+        .unwrap_or_default();
 
-    // apply scoped_join_win_safe to all paths
+    // scope all paths to the instance
     for path in &mut target_relative_paths {
-        *path = scoped_join_win_safe(&root, &*path)?;
+        *path = scoped_join_instance_file(&root, &*path)?;
     }
-    destination_relative_path = scoped_join_win_safe(&root, &destination_relative_path)?;
+    destination_relative_path = scoped_join_instance_file(&root, &destination_relative_path)?;
 
     if !requester.can_perform_action(&UserAction::ReadGlobalFile)
         && is_path_protected(&destination_relative_path)
@@ -970,9 +1333,15 @@ async fn zip_instance_files(
                 format!("{} files", target_relative_paths.len())
             }
         };
+        let total = {
+            let target_relative_paths = target_relative_paths.clone();
+            tokio::task::spawn_blocking(move || files_size(&target_relative_paths))
+                .await
+                .ok()
+        };
         let (progression_start_event, event_id) = Event::new_progression_event_start(
             format!("Zipping {aggregate_name}"),
-            None,
+            total.map(|total| total as f64),
             None,
             CausedBy::User {
                 user_id: requester.uid.clone(),
@@ -981,8 +1350,18 @@ async fn zip_instance_files(
         );
         event_broadcaster.send(progression_start_event);
 
-        if let Err(e) =
-            zip_files_async(&target_relative_paths, destination_relative_path, false).await
+        if let Err(e) = archive_files_async(
+            &target_relative_paths,
+            destination_relative_path,
+            false,
+            format,
+            progress_reporter(
+                event_broadcaster.clone(),
+                event_id.clone(),
+                format!("Zipping {aggregate_name}"),
+            ),
+        )
+        .await
         {
             event_broadcaster.send(Event::new_progression_event_end(
                 event_id,
@@ -1013,6 +1392,178 @@ async fn zip_instance_files(
     Ok(Json(()))
 }
 
+#[derive(Deserialize)]
+struct FileWatchQuery {
+    token: String,
+    /// The directory to watch, the instance root if not set
+    base64_relative_path: Option<String>,
+}
+
+/// Streams changes under a directory as `FSEvent`s, with paths relative to the instance root
+async fn watch_instance_files(
+    ws: WebSocketUpgrade,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<FileWatchQuery>,
+) -> Result<Response, Error> {
+    let token = parse_bearer_token(query.token.as_str()).ok_or_else(|| Error {
+        kind: ErrorKind::Unauthorized,
+        source: eyre!("Token error"),
+    })?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Watching files is not supported for docker instances"),
+        });
+    }
+    let relative_path = match query.base64_relative_path {
+        Some(base64_relative_path) => decode_base64(&base64_relative_path)?,
+        None => String::new(),
+    };
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+    let path = scoped_join_instance_file(&root, relative_path)?;
+    if !path.is_dir() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Path is not a directory"),
+        });
+    }
+    let watcher = DirectoryWatcher::new(root, path)?;
+
+    Ok(ws.on_upgrade(move |socket| {
+        watch_instance_files_ws(socket, watcher, token, uuid, state.users_manager)
+    }))
+}
+
+async fn watch_instance_files_ws(
+    stream: WebSocket,
+    mut watcher: DirectoryWatcher,
+    token: String,
+    uuid: InstanceUuid,
+    users_manager: Arc<RwLock<UsersManager>>,
+) {
+    let (mut sender, mut receiver) = futures::StreamExt::split(stream);
+    loop {
+        tokio::select! {
+            batch = watcher.next_batch() => {
+                let events = match batch {
+                    Some(Ok(events)) => events,
+                    Some(Err(e)) => {
+                        error!("File watcher for {uuid} stopped: {e}");
+                        break;
+                    }
+                    None => break,
+                };
+                // re-authenticate so revoked sessions and permissions stop receiving changes
+                match users_manager.read().await.try_auth(&token) {
+                    Some(user) if user.can_perform_action(&UserAction::ReadInstanceFile(uuid.clone())) => {}
+                    _ => break,
+                }
+                for event in events {
+                    if sender
+                        .send(axum::extract::ws::Message::Text(
+                            serde_json::to_string(&event).unwrap(),
+                        ))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+            ws_msg = receiver.next() => match ws_msg {
+                Some(Ok(ws_msg)) => {
+                    if sender.send(ws_msg).await.is_err() {
+                        break;
+                    }
+                }
+                _ => break,
+            },
+        }
+    }
+}
+
+/// Runs the operation on every path in the background, returns the job id
+async fn start_bulk_file_job(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(operation): Json<BulkFileOperation>,
+) -> Result<Json<Snowflake>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Bulk file operations are not supported for docker instances"),
+        });
+    }
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    let instance_name = instance.name().await;
+    drop(instance);
+    let job_id = state
+        .bulk_file_jobs
+        .start(
+            BulkJobContext {
+                instance_uuid: uuid,
+                instance_name,
+                root,
+                can_write_protected: requester.can_perform_action(&UserAction::WriteGlobalFile),
+                caused_by: CausedBy::User {
+                    user_id: requester.uid,
+                    user_name: requester.username,
+                },
+            },
+            operation,
+        )
+        .await?;
+    Ok(Json(job_id))
+}
+
+async fn get_bulk_file_job(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, job_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<BulkJobReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(Json(state.bulk_file_jobs.report(&uuid, &job_id).await?))
+}
+
+async fn cancel_bulk_file_job(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, job_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state.bulk_file_jobs.cancel(&uuid, &job_id).await?;
+    Ok(Json(()))
+}
+
 pub fn get_instance_fs_routes(state: AppState) -> Router {
     Router::new()
         .route(
@@ -1027,10 +1578,23 @@ pub fn get_instance_fs_routes(state: AppState) -> Router {
             "/instance/:uuid/fs/:base64_relative_path/write",
             put(write_instance_file),
         )
+        .route(
+            "/instance/:uuid/fs/:base64_relative_path/revisions",
+            get(list_instance_file_revisions),
+        )
+        .route(
+            "/instance/:uuid/fs/:base64_relative_path/revisions/diff",
+            get(diff_instance_file_revisions),
+        )
+        .route(
+            "/instance/:uuid/fs/:base64_relative_path/revisions/:revision/restore",
+            put(restore_instance_file_revision),
+        )
         .route(
             "/instance/:uuid/fs/:base64_relative_path/mkdir",
             put(make_instance_directory),
         )
+        .route("/instance/:uuid/fs/search", get(search_instance_files))
         .route("/instance/:uuid/fs/cpr", put(copy_instance_files))
         .route(
             "/instance/:uuid/fs/:base64_relative_path/move/:base64_relative_path_dest",
@@ -1057,10 +1621,21 @@ pub fn get_instance_fs_routes(state: AppState) -> Router {
             put(upload_instance_file),
         )
         .layer(DefaultBodyLimit::disable())
+        .route(
+            "/instance/:uuid/fs/:base64_relative_path/uploads",
+            post(create_instance_file_upload),
+        )
         .route(
             "/instance/:uuid/fs/:base64_relative_path/unzip",
             put(unzip_instance_file),
         )
         .route("/instance/:uuid/fs/zip", put(zip_instance_files))
+        .route("/instance/:uuid/fs/watch", get(watch_instance_files))
+        .route("/instance/:uuid/fs/jobs", post(start_bulk_file_job))
+        .route("/instance/:uuid/fs/jobs/:job_id", get(get_bulk_file_job))
+        .route(
+            "/instance/:uuid/fs/jobs/:job_id",
+            delete(cancel_bulk_file_job),
+        )
         .with_state(state)
 }
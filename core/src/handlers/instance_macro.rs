@@ -1,6 +1,6 @@
 use axum::{
-    extract::Path,
-    routing::{get, post, put},
+    extract::{Path, Query},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 
@@ -15,9 +15,15 @@ use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
     events::CausedBy,
-    macro_executor::MacroPID,
+    macro_executor::{MacroDebugOptions, MacroPID},
+    macro_history::{MacroRun, MacroRunLogLine, MacroRunQuery},
+    macro_kv::{KvEntry, KvNamespace, KvUsage},
+    macro_limits::MacroLimits,
+    macro_test::MacroTestReport,
+    macro_triggers::MacroTriggers,
+    module_cache::VendorReport,
     traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
-    types::InstanceUuid,
+    types::{InstanceUuid, Snowflake},
     AppState,
 };
 
@@ -83,10 +89,25 @@ pub async fn get_instance_history_list(
     Ok(Json(history))
 }
 
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct RunMacroQuery {
+    /// Start the macro with an inspector on localhost, see `TaskEntry::inspector_url`
+    #[serde(default)]
+    pub debug: bool,
+    /// Pause before the first statement until a debugger attaches, implies `debug`
+    #[serde(default)]
+    pub break_on_start: bool,
+}
+
 pub async fn run_macro(
     Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
+    Query(RunMacroQuery {
+        debug,
+        break_on_start,
+    }): Query<RunMacroQuery>,
     Json(args): Json<Vec<String>>,
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
@@ -115,6 +136,7 @@ pub async fn run_macro(
                     user_id: requester.uid,
                     user_name: requester.username,
                 },
+                (debug || break_on_start).then_some(MacroDebugOptions { break_on_start }),
             )
             .await?;
 
@@ -217,6 +239,270 @@ pub async fn store_config_to_local(
     Ok(())
 }
 
+/// Caches the remote modules the macro imports and locks their hashes
+pub async fn vendor_macro(
+    Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<VendorReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    Ok(Json(instance.vendor_macro(&macro_name).await?))
+}
+
+pub async fn test_macro(
+    Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<MacroTestReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    Ok(Json(instance.test_macro(&macro_name).await?))
+}
+
+pub async fn get_macro_limits(
+    Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<MacroLimits>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    Ok(Json(instance.get_macro_limits(&macro_name).await?))
+}
+
+/// Limits left unset use the global limits, which are a ceiling the macro can only go under
+pub async fn set_macro_limits(
+    Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(limits): Json<MacroLimits>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    instance.set_macro_limits(&macro_name, limits).await
+}
+
+pub async fn get_macro_triggers(
+    Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<MacroTriggers>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    check_macro_exists(&state, &uuid, &macro_name).await?;
+    Ok(Json(state.macro_triggers.get(&uuid, &macro_name).await?))
+}
+
+/// Takes effect within a few seconds, an empty list of triggers turns them off.
+///
+/// The macro runs on behalf of the requester whenever its triggers fire.
+pub async fn set_macro_triggers(
+    Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(triggers): Json<MacroTriggers>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    check_macro_exists(&state, &uuid, &macro_name).await?;
+    state
+        .macro_triggers
+        .set(&uuid, &macro_name, &triggers, &requester.uid)
+        .await
+}
+
+async fn check_macro_exists(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    macro_name: &str,
+) -> Result<(), Error> {
+    let instance = state
+        .instances
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .value()
+        .clone();
+    if instance
+        .get_macro_list()
+        .await?
+        .iter()
+        .any(|entry| entry.name == macro_name)
+    {
+        Ok(())
+    } else {
+        Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Macro {macro_name} not found"),
+        })
+    }
+}
+
+/// Newest first, pass the id of the last run as `before` for the next page
+pub async fn get_macro_runs(
+    Path(uuid): Path<InstanceUuid>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<MacroRunQuery>,
+) -> Result<Json<Vec<MacroRun>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    if !state.instances.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    Ok(Json(
+        state
+            .macro_executor
+            .history()
+            .list(Some(&uuid), query)
+            .await?,
+    ))
+}
+
+async fn get_instance_macro_run(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    run_id: Snowflake,
+) -> Result<MacroRun, Error> {
+    let run = state.macro_executor.history().get(run_id).await?;
+    if run.instance_uuid.as_ref() != Some(uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Macro run not found"),
+        });
+    }
+    Ok(run)
+}
+
+pub async fn get_macro_run(
+    Path((uuid, run_id)): Path<(InstanceUuid, Snowflake)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<MacroRun>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    Ok(Json(get_instance_macro_run(&state, &uuid, run_id).await?))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct MacroRunLogQuery {
+    /// Only lines after the line with this id
+    #[serde(default)]
+    pub after: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+pub async fn get_macro_run_log(
+    Path((uuid, run_id)): Path<(InstanceUuid, Snowflake)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(MacroRunLogQuery { after, limit }): Query<MacroRunLogQuery>,
+) -> Result<Json<Vec<MacroRunLogLine>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    let run = get_instance_macro_run(&state, &uuid, run_id).await?;
+    Ok(Json(
+        state
+            .macro_executor
+            .history()
+            .logs(run.id, after, limit)
+            .await?,
+    ))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct MacroKvQuery {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct MacroKvListing {
+    pub entries: Vec<KvEntry>,
+    pub usage: KvUsage,
+}
+
+pub async fn get_macro_kv(
+    Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(MacroKvQuery { prefix, limit }): Query<MacroKvQuery>,
+) -> Result<Json<MacroKvListing>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    let namespace = KvNamespace::new(Some(&uuid), &macro_name);
+    Ok(Json(MacroKvListing {
+        entries: state.macro_kv.list(&namespace, &prefix, limit).await?,
+        usage: state.macro_kv.usage(&namespace).await?,
+    }))
+}
+
+pub async fn delete_macro_kv_key(
+    Path((uuid, macro_name, key)): Path<(InstanceUuid, String, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
+
+    let namespace = KvNamespace::new(Some(&uuid), &macro_name);
+    if !state.macro_kv.delete(&namespace, &key).await? {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Key not found"),
+        });
+    }
+    Ok(Json(()))
+}
+
 pub fn get_instance_macro_routes(state: AppState) -> Router {
     Router::new()
         .route("/instance/:uuid/macro/run/:macro_name", put(run_macro))
@@ -230,6 +516,30 @@ pub fn get_instance_macro_routes(state: AppState) -> Router {
             "/instance/:uuid/macro/config/store/:macro_name",
             post(store_config_to_local),
         )
+        .route(
+            "/instance/:uuid/macro/vendor/:macro_name",
+            put(vendor_macro),
+        )
+        .route("/instance/:uuid/macro/test/:macro_name", post(test_macro))
+        .route(
+            "/instance/:uuid/macro/limits/:macro_name",
+            get(get_macro_limits).put(set_macro_limits),
+        )
+        .route(
+            "/instance/:uuid/macro/triggers/:macro_name",
+            get(get_macro_triggers).put(set_macro_triggers),
+        )
+        .route("/instance/:uuid/macro/runs", get(get_macro_runs))
+        .route("/instance/:uuid/macro/runs/:run_id", get(get_macro_run))
+        .route(
+            "/instance/:uuid/macro/runs/:run_id/log",
+            get(get_macro_run_log),
+        )
+        .route("/instance/:uuid/macro/kv/:macro_name", get(get_macro_kv))
+        .route(
+            "/instance/:uuid/macro/kv/:macro_name/:key",
+            delete(delete_macro_kv_key),
+        )
         .route("/instance/:uuid/task/list", get(get_instance_task_list))
         .route(
             "/instance/:uuid/history/list",
@@ -4,16 +4,22 @@ use axum::{
     Router,
 };
 
+use std::time::Duration;
+
 use axum::Json;
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use ts_rs::TS;
 
 use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
     events::CausedBy,
+    prelude::GameInstance,
     types::InstanceUuid,
 };
 
@@ -177,6 +183,86 @@ pub async fn send_command(
         .map(|_| Json(()))
 }
 
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct CommandRequest {
+    pub command: String,
+    /// How long to wait for output in milliseconds, capped at `MAX_COMMAND_RESPONSE_TIMEOUT`
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub enum CommandResponseSource {
+    Rcon,
+    Console,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct CommandResponse {
+    pub source: CommandResponseSource,
+    pub output: Vec<String>,
+}
+
+const DEFAULT_COMMAND_RESPONSE_TIMEOUT: Duration = Duration::from_millis(2000);
+const MAX_COMMAND_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+// stop collecting console output once the server has been quiet for this long
+const COMMAND_RESPONSE_IDLE: Duration = Duration::from_millis(250);
+
+pub async fn send_command_with_response(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<CommandRequest>,
+) -> Result<Json<CommandResponse>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessConsole(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .value()
+        .clone();
+
+    // "stop" has to go through stdin so the instance state transitions correctly
+    if let GameInstance::MinecraftInstance(minecraft_instance) = &instance {
+        if request.command != "stop" && minecraft_instance.get_rcon().lock().await.is_some() {
+            if let Ok(output) = minecraft_instance.send_rcon(&request.command).await {
+                return Ok(Json(CommandResponse {
+                    source: CommandResponseSource::Rcon,
+                    output: output.lines().map(|line| line.to_string()).collect(),
+                }));
+            }
+        }
+    }
+
+    let window = request
+        .timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_COMMAND_RESPONSE_TIMEOUT)
+        .min(MAX_COMMAND_RESPONSE_TIMEOUT);
+    // subscribe before sending so the first lines of output are not missed
+    let rx = state.event_broadcaster.subscribe();
+    instance.send_command(&request.command, caused_by).await?;
+    let output =
+        EventBroadcaster::collect_instance_output(rx, &uuid, window, COMMAND_RESPONSE_IDLE).await;
+    Ok(Json(CommandResponse {
+        source: CommandResponseSource::Console,
+        output,
+    }))
+}
+
 pub async fn get_instance_state(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -214,6 +300,10 @@ pub fn get_instance_server_routes(state: AppState) -> Router {
         .route("/instance/:uuid/restart", put(restart_instance))
         .route("/instance/:uuid/kill", put(kill_instance))
         .route("/instance/:uuid/console", post(send_command))
+        .route(
+            "/instance/:uuid/console/response",
+            post(send_command_with_response),
+        )
         .route("/instance/:uuid/state", get(get_instance_state))
         .with_state(state)
 }
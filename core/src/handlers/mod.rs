@@ -1,5 +1,6 @@
 // pub mod jar;
 // pub mod instance;
+pub mod instance_backup;
 // pub mod users;
 pub mod checks;
 pub mod core_info;
@@ -9,15 +10,19 @@ pub mod global_fs;
 pub mod global_settings;
 pub mod instance;
 pub mod instance_config;
+pub mod instance_disk;
 pub mod instance_fs;
 pub mod instance_macro;
 pub mod instance_players;
 pub mod instance_server;
 pub mod instance_setup_configs;
 pub mod monitor;
+pub mod oidc;
 pub mod playitgg;
+pub mod roles;
 pub mod setup;
 pub mod system;
+pub mod uploads;
 pub mod users;
 mod util;
 pub mod extension;
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::ConnectInfo,
+    headers::UserAgent,
+    routing::{get, post},
+    Json, Router, TypedHeader,
+};
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::{
+    auth::{
+        oidc::{self, OidcSettings, MAX_PENDING_LOGINS, PENDING_LOGIN_TIMEOUT},
+        session::LoginClient,
+    },
+    error::{Error, ErrorKind},
+    handlers::users::LoginReply,
+    AppState,
+};
+
+async fn enabled_oidc_settings(state: &AppState) -> Result<OidcSettings, Error> {
+    state
+        .global_settings
+        .lock()
+        .await
+        .oidc()
+        .filter(|oidc| oidc.enabled)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Single sign-on is not enabled"),
+        })
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct OidcAuthorization {
+    /// Where to send the user to log in at the identity provider
+    pub authorization_url: String,
+}
+
+pub async fn begin_oidc_login(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> Result<Json<OidcAuthorization>, Error> {
+    let settings = enabled_oidc_settings(&state).await?;
+    let client = LoginClient {
+        ip: connect_info.map(|ConnectInfo(addr)| addr.ip().to_string()),
+        device: None,
+    };
+    state
+        .users_manager
+        .write()
+        .await
+        .throttle_oidc_login(&client)?;
+    let now = chrono::Utc::now().timestamp();
+    let (login_state, authorization_url, pending) = oidc::begin_login(&settings, now).await?;
+    let mut pending_logins = state.oidc_pending_logins.lock().await;
+    pending_logins.retain(|_, pending| now - pending.creation_time < PENDING_LOGIN_TIMEOUT);
+    // refused rather than evicting, so a flood can't cancel logins that are in progress
+    if pending_logins.len() >= MAX_PENDING_LOGINS {
+        return Err(Error {
+            kind: ErrorKind::TooManyRequests,
+            source: eyre!("Too many single sign-on logins in progress, please try again later"),
+        });
+    }
+    pending_logins.insert(login_state, pending);
+    Ok(Json(OidcAuthorization { authorization_url }))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct OidcCallback {
+    pub code: String,
+    pub state: String,
+}
+
+pub async fn complete_oidc_login(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Json(callback): Json<OidcCallback>,
+) -> Result<Json<LoginReply>, Error> {
+    let settings = enabled_oidc_settings(&state).await?;
+    // a state can only be used once
+    let pending = state
+        .oidc_pending_logins
+        .lock()
+        .await
+        .remove(&callback.state)
+        .filter(|pending| {
+            chrono::Utc::now().timestamp() - pending.creation_time < PENDING_LOGIN_TIMEOUT
+        })
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Unknown or expired login, please try again"),
+        })?;
+    let identity = oidc::complete_login(&settings, pending, &callback.code).await?;
+
+    let client = LoginClient {
+        ip: connect_info.map(|ConnectInfo(addr)| addr.ip().to_string()),
+        device: user_agent.map(|TypedHeader(user_agent)| user_agent.as_str().to_string()),
+    };
+    let mut users_manager = state.users_manager.write().await;
+    let (token, user) = users_manager
+        .oidc_login(identity, &settings, client)
+        .await?;
+    Ok(Json(LoginReply {
+        token,
+        totp_enrollment_required: users_manager.totp_enrollment_required(&user.uid),
+        user: user.into(),
+    }))
+}
+
+pub fn get_oidc_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/user/oidc/authorize", get(begin_oidc_login))
+        .route("/user/oidc/callback", post(complete_oidc_login))
+        .with_state(state)
+}
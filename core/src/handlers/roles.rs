@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::Path,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use ts_rs::TS;
+
+use crate::{
+    auth::{
+        role::{Role, RoleId, RolePermission, RoleScope},
+        user::{User, UserAction},
+        user_id::UserId,
+    },
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    types::InstanceUuid,
+    AppState,
+};
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct RoleConfig {
+    pub name: String,
+    pub permissions: RolePermission,
+    pub scope: RoleScope,
+}
+
+fn check_can_edit_role(requester: &User, permissions: &RolePermission) -> Result<(), Error> {
+    if permissions.has_unsafe_permission() && !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can create roles with unsafe permissions"),
+        });
+    }
+    Ok(())
+}
+
+pub async fn get_all_roles(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Role>>, Error> {
+    let users_manager = state.users_manager.read().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(Json(users_manager.list_roles()))
+}
+
+pub async fn create_role(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<RoleConfig>,
+) -> Result<Json<Role>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    check_can_edit_role(&requester, &config.permissions)?;
+    let role = Role {
+        id: RoleId::default(),
+        name: config.name,
+        permissions: config.permissions,
+        scope: config.scope,
+    };
+    users_manager.upsert_role(role.clone()).await?;
+    Ok(Json(role))
+}
+
+pub async fn update_role(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(role_id): Path<RoleId>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<RoleConfig>,
+) -> Result<Json<Role>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let old_role = users_manager.get_role(&role_id).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Role not found"),
+    })?;
+    // editing a role changes the permissions of everyone assigned to it
+    check_can_edit_role(&requester, &old_role.permissions)?;
+    check_can_edit_role(&requester, &config.permissions)?;
+    let role = Role {
+        id: role_id,
+        name: config.name,
+        permissions: config.permissions,
+        scope: config.scope,
+    };
+    users_manager.upsert_role(role.clone()).await?;
+    Ok(Json(role))
+}
+
+pub async fn delete_role(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(role_id): Path<RoleId>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let role = users_manager.get_role(&role_id).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Role not found"),
+    })?;
+    check_can_edit_role(&requester, &role.permissions)?;
+    users_manager.delete_role(&role_id).await?;
+    Ok(Json(()))
+}
+
+pub async fn update_user_roles(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(roles): Json<HashSet<RoleId>>,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    users_manager
+        .update_roles(&requester, uid, roles, caused_by)
+        .await?;
+    Ok(Json(()))
+}
+
+pub async fn get_instance_tags(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<HashSet<String>>, Error> {
+    let users_manager = state.users_manager.read().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(Json(users_manager.get_instance_tags(&uuid)))
+}
+
+pub async fn set_instance_tags(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(tags): Json<HashSet<String>>,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    // tags decide which instances tag-scoped roles apply to
+    if !requester.is_owner {
+        let old_tags = users_manager.get_instance_tags(&uuid);
+        if users_manager.list_roles().iter().any(|role| {
+            role.permissions.has_unsafe_permission()
+                && matches!(&role.scope, RoleScope::Tag { tag } if old_tags.contains(tag) != tags.contains(tag))
+        }) {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Only the owner can change tags used by roles with unsafe permissions"),
+            });
+        }
+    }
+    if !state.instances.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    users_manager.set_instance_tags(uuid, tags).await?;
+    Ok(Json(()))
+}
+
+pub fn get_role_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/role/list", get(get_all_roles))
+        .route("/role", post(create_role))
+        .route("/role/:role_id", put(update_role))
+        .route("/role/:role_id", delete(delete_role))
+        .route("/user/:uid/roles", put(update_user_roles))
+        .route("/instance/:uuid/tags", get(get_instance_tags))
+        .route("/instance/:uuid/tags", put(set_instance_tags))
+        .with_state(state)
+}
@@ -1,4 +1,10 @@
-use axum::{extract::Path, Json, Router};
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path},
+    headers::UserAgent,
+    Json, Router, TypedHeader,
+};
 use color_eyre::eyre::eyre;
 
 use crate::{
@@ -8,7 +14,7 @@ use crate::{
     AppState,
 };
 
-use super::users::LoginReply;
+use super::users::{login_client, LoginReply};
 
 #[derive(serde::Deserialize)]
 pub struct OwnerSetup {
@@ -19,6 +25,8 @@ pub struct OwnerSetup {
 pub async fn setup_owner(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(key): Path<String>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Json(owner_setup): Json<OwnerSetup>,
 ) -> Result<Json<LoginReply>, Error> {
     let mut setup_key_lock = state.first_time_setup_key.lock().await;
@@ -32,15 +40,17 @@ pub async fn setup_owner(
                 false,
                 UserPermission::default(),
             );
-            state
-                .users_manager
-                .write()
-                .await
+            let mut users_manager = state.users_manager.write().await;
+            users_manager
                 .add_user(owner.clone(), CausedBy::System)
                 .await?;
+            let token = users_manager
+                .create_session_token(&owner.uid, login_client(connect_info, user_agent))
+                .await?;
             Ok(Json(LoginReply {
-                token: owner.create_jwt()?,
+                token,
                 user: owner.into(),
+                totp_enrollment_required: false,
             }))
         }
         None => Err(Error {
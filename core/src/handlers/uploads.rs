@@ -0,0 +1,96 @@
+use axum::{
+    extract::{BodyStream, Path, Query},
+    routing::{delete, get, patch, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use serde::Deserialize;
+use ts_rs::TS;
+
+use crate::{chunked_upload::UploadStatus, error::Error, AppState};
+
+pub async fn get_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(upload_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<UploadStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let upload = state
+        .chunked_uploads
+        .get(&upload_id, &requester.uid)
+        .await?;
+    let status = upload.lock().await.status();
+    Ok(Json(status))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct UploadChunkQuery {
+    /// Where the chunk starts in the file, must match the current offset of the upload
+    pub offset: u64,
+}
+
+/// The request body is the raw chunk
+pub async fn upload_chunk(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(upload_id): Path<String>,
+    Query(query): Query<UploadChunkQuery>,
+    AuthBearer(token): AuthBearer,
+    body: BodyStream,
+) -> Result<Json<UploadStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let upload = state
+        .chunked_uploads
+        .get(&upload_id, &requester.uid)
+        .await?;
+    let mut upload = upload.lock().await;
+    requester.try_action(
+        &upload.target.action(),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    upload
+        .append(query.offset, body, &state.event_broadcaster)
+        .await?;
+    Ok(Json(upload.status()))
+}
+
+pub async fn finalize_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(upload_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let upload = state
+        .chunked_uploads
+        .get(&upload_id, &requester.uid)
+        .await?;
+    let action = upload.lock().await.target.action();
+    requester.try_action(&action, state.global_settings.lock().await.safe_mode())?;
+    state
+        .chunked_uploads
+        .finalize(&upload_id, &requester.uid, &state.event_broadcaster)
+        .await?;
+    Ok(Json(()))
+}
+
+pub async fn cancel_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(upload_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    state
+        .chunked_uploads
+        .cancel(&upload_id, &requester.uid, &state.event_broadcaster)
+        .await?;
+    Ok(Json(()))
+}
+
+pub fn get_upload_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/upload/:upload_id", get(get_upload))
+        .route("/upload/:upload_id", patch(upload_chunk))
+        .route("/upload/:upload_id", delete(cancel_upload))
+        .route("/upload/:upload_id/finalize", post(finalize_upload))
+        .with_state(state)
+}
@@ -1,8 +1,10 @@
 use crate::{
     auth::{
+        api_token::{ApiTokenId, PublicApiToken},
         jwt_token::JwtToken,
         permission::UserPermission,
-        user::{PublicUser, User, UserAction},
+        session::{LoginClient, PublicSession, SessionId},
+        user::{PublicUser, TotpEnrollment, User, UserAction},
         user_id::UserId,
     },
     error::{Error, ErrorKind},
@@ -10,10 +12,13 @@ use crate::{
     AppState,
 };
 
+use std::net::SocketAddr;
+
 use axum::{
-    extract::Path,
+    extract::{ConnectInfo, Path},
+    headers::UserAgent,
     routing::{delete, get, post, put},
-    Json, Router,
+    Json, Router, TypedHeader,
 };
 use axum_auth::{AuthBasic, AuthBearer};
 
@@ -28,9 +33,22 @@ pub struct NewUser {
     pub password: String,
 }
 
+/// Where the request came from, to tell the sessions of a user apart
+pub(super) fn login_client(
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+) -> LoginClient {
+    LoginClient {
+        ip: connect_info.map(|ConnectInfo(addr)| addr.ip().to_string()),
+        device: user_agent.map(|TypedHeader(user_agent)| user_agent.as_str().to_string()),
+    }
+}
+
 pub async fn new_user(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Json(config): Json<NewUser>,
 ) -> Result<Json<LoginReply>, Error> {
     let mut users_manager = state.users_manager.write().await;
@@ -53,12 +71,27 @@ pub async fn new_user(
     users_manager
         .add_user(user.clone(), caused_by.clone())
         .await?;
+    let token = users_manager
+        .create_session_token(&user.uid, login_client(connect_info, user_agent))
+        .await?;
     Ok(Json(LoginReply {
-        token: user.create_jwt()?,
+        token,
         user: user.into(),
+        totp_enrollment_required: false,
     }))
 }
 
+/// API tokens only act within their scopes, none of which cover managing the account itself
+fn check_not_api_token(requester: &User, action: &str) -> Result<(), Error> {
+    if requester.api_token_id.is_some() {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("API tokens cannot be used to {action}"),
+        });
+    }
+    Ok(())
+}
+
 pub async fn delete_user(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uid): Path<UserId>,
@@ -107,9 +140,25 @@ pub async fn logout(
         user_id: requester.uid.clone(),
         user_name: requester.username,
     };
-    users_manager
-        .logout_user(uid.clone(), caused_by.clone())
-        .await?;
+    match (requester.api_token_id, requester.session_id) {
+        // an API token can only log itself out, it can't manage other users
+        (Some(api_token_id), _) => {
+            users_manager
+                .revoke_api_token(&uid, &api_token_id, caused_by)
+                .await?
+        }
+        // logging out yourself only ends the current session
+        (None, Some(session_id)) if requester.uid == uid => {
+            users_manager
+                .revoke_session(&uid, &session_id, caused_by)
+                .await?
+        }
+        _ => {
+            users_manager
+                .logout_user(uid.clone(), caused_by.clone())
+                .await?
+        }
+    }
     Ok(Json(()))
 }
 
@@ -191,6 +240,7 @@ pub async fn rename_user(
             source: eyre!("You are not authorized to rename other users"),
         });
     }
+    check_not_api_token(&requester, "rename users")?;
 
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
@@ -222,6 +272,7 @@ pub async fn change_password(
             source: eyre!("You are not authorized to change other users password"),
         });
     }
+    check_not_api_token(&requester, "change passwords")?;
 
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
@@ -251,24 +302,46 @@ pub async fn change_password(
 pub struct LoginReply {
     pub token: JwtToken,
     pub user: PublicUser,
+    /// The owner requires admins to use 2FA and this user has yet to enroll
+    pub totp_enrollment_required: bool,
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct LoginSecondFactor {
+    /// A TOTP code or one of the recovery codes
+    pub code: String,
 }
 
 pub async fn login(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBasic((username, password)): AuthBasic,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    second_factor: Option<Json<LoginSecondFactor>>,
 ) -> Result<Json<LoginReply>, Error> {
     if let Some(password) = password {
-        let users_manager = state.users_manager.read().await;
-
+        let mut users_manager = state.users_manager.write().await;
+
+        let client = login_client(connect_info, user_agent);
+        let token = users_manager
+            .login(
+                &username,
+                &password,
+                second_factor.as_ref().map(|Json(f)| f.code.as_str()),
+                client,
+            )
+            .await?;
+        let user = users_manager
+            .get_user_by_username(&username)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("User not found"),
+            })?;
         Ok(Json(LoginReply {
-            token: users_manager.login(&username, &password)?,
-            user: users_manager
-                .get_user_by_username(&username)
-                .ok_or_else(|| Error {
-                    kind: ErrorKind::NotFound,
-                    source: eyre!("User not found"),
-                })?
-                .into(),
+            token,
+            totp_enrollment_required: users_manager.totp_enrollment_required(&user.uid),
+            user: user.into(),
         }))
     } else {
         Err(Error {
@@ -300,6 +373,268 @@ pub async fn get_all_users(
     ))
 }
 
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: UserPermission,
+    /// Unix timestamp in seconds, the token never expires if not set
+    pub expiry: Option<i64>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct NewApiTokenReply {
+    /// The plaintext token, only returned once
+    pub token: String,
+    pub info: PublicApiToken,
+}
+
+pub async fn create_api_token(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<NewApiToken>,
+) -> Result<Json<NewApiTokenReply>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    if requester.uid != uid {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You are not authorized to create tokens for other users"),
+        });
+    }
+    check_not_api_token(&requester, "create other API tokens")?;
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username,
+    };
+    let (info, token) = users_manager
+        .create_api_token(&uid, config.name, config.scopes, config.expiry, caused_by)
+        .await?;
+    Ok(Json(NewApiTokenReply { token, info }))
+}
+
+pub async fn list_api_tokens(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<PublicApiToken>>, Error> {
+    let users_manager = state.users_manager.read().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    if requester.uid != uid && !requester.can_perform_action(&UserAction::ManageUser) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You are not authorized to view other users tokens"),
+        });
+    }
+    check_not_api_token(&requester, "list API tokens")?;
+    Ok(Json(users_manager.list_api_tokens(&uid)?))
+}
+
+pub async fn revoke_api_token(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uid, token_id)): Path<(UserId, ApiTokenId)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    if requester.uid != uid && !requester.can_perform_action(&UserAction::ManageUser) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You are not authorized to revoke other users tokens"),
+        });
+    }
+    if let Some(api_token_id) = &requester.api_token_id {
+        if *api_token_id != token_id {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("API tokens can only revoke themselves"),
+            });
+        }
+    }
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username,
+    };
+    users_manager
+        .revoke_api_token(&uid, &token_id, caused_by)
+        .await?;
+    Ok(Json(()))
+}
+
+pub async fn list_sessions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<PublicSession>>, Error> {
+    let users_manager = state.users_manager.read().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    if requester.uid != uid && !requester.can_perform_action(&UserAction::ManageUser) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You are not authorized to view other users sessions"),
+        });
+    }
+    check_not_api_token(&requester, "list sessions")?;
+    Ok(Json(
+        users_manager
+            .list_sessions(&uid)?
+            .into_iter()
+            .map(|session| PublicSession {
+                current: requester.session_id.as_ref() == Some(&session.id),
+                id: session.id,
+                device: session.device,
+                ip: session.ip,
+                creation_time: session.creation_time,
+                last_used: session.last_used,
+            })
+            .collect(),
+    ))
+}
+
+pub async fn revoke_session(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uid, session_id)): Path<(UserId, SessionId)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    if requester.uid != uid && !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You are not authorized to revoke other users sessions"),
+        });
+    }
+    check_not_api_token(&requester, "revoke sessions")?;
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username,
+    };
+    users_manager
+        .revoke_session(&uid, &session_id, caused_by)
+        .await?;
+    Ok(Json(()))
+}
+
+fn check_is_self(requester: &User, uid: &UserId) -> Result<(), Error> {
+    if &requester.uid != uid {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You can only manage your own two-factor authentication"),
+        });
+    }
+    check_not_api_token(requester, "manage two-factor authentication")
+}
+
+pub async fn begin_totp_enrollment(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<TotpEnrollment>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+    check_is_self(&requester, &uid)?;
+
+    let issuer = state.global_settings.lock().await.core_name();
+    Ok(Json(
+        users_manager.begin_totp_enrollment(&uid, &issuer).await?,
+    ))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+pub async fn confirm_totp(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(TotpCode { code }): Json<TotpCode>,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+    check_is_self(&requester, &uid)?;
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username,
+    };
+    users_manager
+        .confirm_totp(
+            &uid,
+            &code,
+            chrono::Utc::now().timestamp() as u64,
+            caused_by,
+        )
+        .await?;
+    Ok(Json(()))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct DisableTotp {
+    /// Required when disabling your own 2FA, the owner can reset other users without it
+    pub code: Option<String>,
+}
+
+pub async fn disable_totp(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(DisableTotp { code }): Json<DisableTotp>,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+
+    let code = if requester.uid == uid {
+        check_is_self(&requester, &uid)?;
+        Some(code.ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("You must provide a two-factor authentication code"),
+        })?)
+    } else if requester.can_perform_action(&UserAction::ManageUser) {
+        None
+    } else {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You are not authorized to reset other users two-factor authentication"),
+        });
+    };
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username,
+    };
+    users_manager
+        .disable_totp(
+            &uid,
+            code.as_deref(),
+            chrono::Utc::now().timestamp() as u64,
+            caused_by,
+        )
+        .await?;
+    Ok(Json(()))
+}
+
 // return the thing created by Router::new() so we can nest it in main
 pub fn get_user_routes(state: AppState) -> Router {
     Router::new()
@@ -313,5 +648,13 @@ pub fn get_user_routes(state: AppState) -> Router {
         .route("/user/:uid/password", put(change_password))
         .route("/user/login", post(login))
         .route("/user/logout/:uid", post(logout))
+        .route("/user/:uid/tokens", get(list_api_tokens))
+        .route("/user/:uid/tokens", post(create_api_token))
+        .route("/user/:uid/tokens/:token_id", delete(revoke_api_token))
+        .route("/user/:uid/sessions", get(list_sessions))
+        .route("/user/:uid/sessions/:session_id", delete(revoke_session))
+        .route("/user/:uid/totp", post(begin_totp_enrollment))
+        .route("/user/:uid/totp/confirm", post(confirm_totp))
+        .route("/user/:uid/totp", delete(disable_totp))
         .with_state(state)
 }
@@ -6,7 +6,7 @@ use indexmap::IndexMap;
 
 use crate::error::{Error, ErrorKind};
 use crate::events::CausedBy;
-use crate::macro_executor::{self, WorkerOptionGenerator};
+use crate::macro_executor::{self, MacroDebugOptions, WorkerOptionGenerator};
 use crate::traits::t_configurable::manifest::SettingLocalCache;
 use crate::traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry};
 
@@ -71,6 +71,7 @@ impl TMacro for GenericInstance {
         _args: Vec<String>,
         _configs: Option<IndexMap<String, SettingLocalCache>>,
         _caused_by: CausedBy,
+        _debug: Option<MacroDebugOptions>,
     ) -> Result<TaskEntry, Error> {
         Err(Error {
             kind: ErrorKind::UnsupportedOperation,
@@ -1,7 +1,12 @@
-use std::{path::PathBuf, rc::Rc, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use color_eyre::eyre::Context;
+use deno_runtime::permissions::PermissionsOptions;
 use tracing::{debug, error};
 
 use self::{
@@ -95,6 +100,7 @@ impl GenericInstance {
         progression_event_id: &ProgressionEventID,
         event_broadcaster: EventBroadcaster,
         core_macro_executor: MacroExecutor,
+        permissions: PermissionsOptions,
     ) -> Result<Self, Error> {
         tokio::fs::create_dir_all(&path).await.context(format!(
             "Failed to create directory for instance at {}",
@@ -136,8 +142,9 @@ impl GenericInstance {
                 CausedBy::System,
                 Box::new(GenericMainWorkerGenerator::new(procedure_bridge.clone())),
                 None,
-                None,
+                MacroExecutor::with_dir(permissions, &path),
                 Some(dot_lodestone_config.uuid().clone()),
+                None,
             )
             .await?;
         detach_future.await;
@@ -168,6 +175,7 @@ impl GenericInstance {
         dot_lodestone_config: DotLodestoneConfig,
         event_broadcaster: EventBroadcaster,
         core_macro_executor: MacroExecutor,
+        permissions: PermissionsOptions,
     ) -> Result<Self, Error> {
         let procedure_bridge = bridge::procedure_call::ProcedureBridge::new();
         let SpawnResult {
@@ -181,8 +189,9 @@ impl GenericInstance {
                 CausedBy::System,
                 Box::new(GenericMainWorkerGenerator::new(procedure_bridge.clone())),
                 None,
-                None,
+                MacroExecutor::with_dir(permissions, &path_to_instance),
                 Some(dot_lodestone_config.uuid().clone()),
+                None,
             )
             .await?;
 
@@ -215,9 +224,22 @@ impl GenericInstance {
         })
     }
 
+    /// Where the atom of an instance was loaded from, read back from its bootstrap
+    pub async fn source(path_to_instance: &Path) -> Option<PathBuf> {
+        let bootstrap = tokio::fs::read_to_string(path_to_instance.join("run.ts"))
+            .await
+            .ok()?;
+        let main = bootstrap
+            .lines()
+            .find_map(|line| line.strip_prefix("import * as a from \""))?
+            .strip_suffix("\";")?;
+        Some(PathBuf::from(main).parent()?.to_owned())
+    }
+
     pub async fn setup_manifest(
         link_to_source: &str,
         macro_executor: MacroExecutor,
+        permissions: PermissionsOptions,
     ) -> Result<SetupManifest, Error> {
         // create a tempfile
         let temp_dir = tempfile::TempDir::new().context("Failed to create temp dir")?;
@@ -241,6 +263,7 @@ impl GenericInstance {
                     bridge: procedure_bridge.clone(),
                 }),
                 None,
+                MacroExecutor::with_dir(permissions, temp_dir.path()),
                 None,
                 None,
             )
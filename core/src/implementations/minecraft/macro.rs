@@ -12,8 +12,14 @@ use crate::traits::t_configurable::manifest::{
 use crate::{
     error::Error,
     events::CausedBy,
-    macro_executor::{DefaultWorkerOptionGenerator, MacroPID, SpawnResult},
+    macro_executor::{DefaultWorkerOptionGenerator, MacroDebugOptions, MacroPID, SpawnResult},
+    macro_history::MacroRunQuery,
+    macro_limits::MacroLimits,
+    macro_test::MacroTestReport,
+    module_cache::{self, ModuleCache, VendorReport},
+    prelude::path_to_module_cache,
     traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
+    util::scoped_join_win_safe,
 };
 
 use super::MinecraftInstance;
@@ -69,26 +75,34 @@ impl TMacro for MinecraftInstance {
     }
 
     async fn get_task_list(&self) -> Result<Vec<TaskEntry>, Error> {
-        let mut ret = Vec::new();
-        for (pid, task_entry) in self.pid_to_task_entry.lock().await.iter() {
-            if self.macro_executor.get_macro_status(*pid).await.is_none() {
-                ret.push(task_entry.clone());
-            }
-        }
+        let mut pid_to_task_entry = self.pid_to_task_entry.lock().await;
+        // finished tasks are in the history
+        pid_to_task_entry.retain(|pid, _| self.macro_executor.is_running(*pid));
+        let mut ret: Vec<TaskEntry> = pid_to_task_entry.values().cloned().collect();
         ret.sort_by(|a, b| a.creation_time.cmp(&b.creation_time));
         Ok(ret)
     }
 
     async fn get_history_list(&self) -> Result<Vec<HistoryEntry>, Error> {
-        let mut ret = Vec::new();
-        for (pid, task_entry) in self.pid_to_task_entry.lock().await.iter() {
-            if let Some(exit_status) = self.macro_executor.get_macro_status(*pid).await {
-                ret.push(HistoryEntry {
-                    task: task_entry.clone(),
-                    exit_status,
-                });
-            }
-        }
+        let runs = self
+            .macro_executor
+            .history()
+            .list(Some(&self.uuid), MacroRunQuery::default())
+            .await?;
+        let mut ret: Vec<HistoryEntry> = runs
+            .into_iter()
+            .filter_map(|run| {
+                Some(HistoryEntry {
+                    exit_status: run.exit_status?,
+                    task: TaskEntry {
+                        name: run.name,
+                        creation_time: run.start_time,
+                        pid: run.pid,
+                        inspector_url: None,
+                    },
+                })
+            })
+            .collect();
         ret.sort_by(|a, b| b.exit_status.time().cmp(&a.exit_status.time()));
         Ok(ret)
     }
@@ -109,6 +123,7 @@ impl TMacro for MinecraftInstance {
         args: Vec<String>,
         configs: Option<IndexMap<String, SettingLocalCache>>,
         caused_by: CausedBy,
+        debug: Option<MacroDebugOptions>,
     ) -> Result<TaskEntry, Error> {
         let path_to_macro = resolve_macro_invocation(&self.path_to_macros, name)
             .ok_or_else(|| eyre!("Failed to resolve macro invocation for {}", name))?;
@@ -152,7 +167,12 @@ impl TMacro for MinecraftInstance {
             None => None,
         };
 
-        let SpawnResult { macro_pid: pid, .. } = self
+        let permissions = self.macro_executor.instance_sandbox(&path_to_macro)?;
+        let SpawnResult {
+            macro_pid: pid,
+            inspector_url,
+            ..
+        } = self
             .macro_executor
             .spawn(
                 path_to_macro,
@@ -160,14 +180,16 @@ impl TMacro for MinecraftInstance {
                 caused_by,
                 Box::new(DefaultWorkerOptionGenerator),
                 config_code,
-                None,
+                permissions,
                 Some(self.uuid.clone()),
+                debug,
             )
             .await?;
         let entry = TaskEntry {
             pid,
             name: name.to_string(),
             creation_time: chrono::Utc::now().timestamp(),
+            inspector_url,
         };
         self.pid_to_task_entry
             .lock()
@@ -280,4 +302,39 @@ impl TMacro for MinecraftInstance {
             }),
         }
     }
+
+    async fn vendor_macro(&self, name: &str) -> Result<VendorReport, Error> {
+        let path_to_macro = resolve_macro_invocation(&self.path_to_macros, name)
+            .ok_or_else(|| eyre!("Failed to resolve macro invocation for {}", name))?;
+        module_cache::vendor(
+            &ModuleCache::new(path_to_module_cache().clone()),
+            &path_to_macro,
+        )
+        .await
+    }
+
+    async fn get_macro_limits(&self, name: &str) -> Result<MacroLimits, Error> {
+        let path_to_macro = resolve_macro_invocation(&self.path_to_macros, name)
+            .ok_or_else(|| eyre!("Failed to resolve macro invocation for {}", name))?;
+        MacroLimits::load(&path_to_macro)
+    }
+
+    async fn set_macro_limits(&self, name: &str, limits: MacroLimits) -> Result<(), Error> {
+        let path_to_macro = resolve_macro_invocation(&self.path_to_macros, name)
+            .ok_or_else(|| eyre!("Failed to resolve macro invocation for {}", name))?;
+        limits.write(&path_to_macro)
+    }
+
+    async fn test_macro(&self, name: &str) -> Result<MacroTestReport, Error> {
+        let path_to_macro = scoped_join_win_safe(&self.path_to_macros, name)?;
+        // tests live in the directory of the macro, so they get the same permissions
+        let permissions = self
+            .macro_executor
+            .instance_sandbox(&path_to_macro.join("index.ts"))?;
+        // test runs don't belong in the history of the instance
+        MacroExecutor::new_for_tests(self.macro_executor.default_limits())
+            .await?
+            .run_tests(&path_to_macro, permissions)
+            .await
+    }
 }
@@ -1,10 +1,14 @@
 use async_trait::async_trait;
 
+use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
+use crate::error::ErrorKind;
+use crate::events::CausedBy;
 use crate::traits::t_player::Player;
 use crate::traits::t_player::{TPlayer, TPlayerManagement};
+use crate::traits::t_server::TServer;
 use crate::Error;
 
 use super::configurable::ServerPropertySetting;
@@ -69,4 +73,69 @@ impl TPlayerManagement for MinecraftInstance {
     async fn get_player_list(&self) -> Result<HashSet<Player>, Error> {
         Ok(self.players_manager.lock().await.clone().into())
     }
+
+    async fn kick_player(
+        &self,
+        player_name: &str,
+        reason: Option<&str>,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        self.send_command(&player_command("kick", player_name, reason)?, caused_by)
+            .await
+    }
+
+    async fn ban_player(
+        &self,
+        player_name: &str,
+        reason: Option<&str>,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        self.send_command(&player_command("ban", player_name, reason)?, caused_by)
+            .await
+    }
+
+    async fn pardon_player(&self, player_name: &str, caused_by: CausedBy) -> Result<(), Error> {
+        self.send_command(&player_command("pardon", player_name, None)?, caused_by)
+            .await
+    }
+}
+
+/// Player names are checked so they can't smuggle in another command
+pub(crate) fn player_command(
+    command: &str,
+    player_name: &str,
+    reason: Option<&str>,
+) -> Result<String, Error> {
+    if player_name.is_empty()
+        || player_name.len() > 16
+        || !player_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid player name {player_name}"),
+        });
+    }
+    let reason = reason.map(|reason| reason.replace(['\r', '\n'], " "));
+    Ok(match reason.as_deref().map(str::trim) {
+        Some(reason) if !reason.is_empty() => format!("{command} {player_name} {reason}"),
+        _ => format!("{command} {player_name}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::player_command;
+
+    #[test]
+    fn test_player_command() {
+        assert_eq!(player_command("kick", "Steve", None).unwrap(), "kick Steve");
+        assert_eq!(
+            player_command("ban", "Steve_2", Some("griefing\nop Steve")).unwrap(),
+            "ban Steve_2 griefing op Steve"
+        );
+        assert!(player_command("kick", "Steve\nop Alex", None).is_err());
+        assert!(player_command("kick", "", None).is_err());
+    }
 }
@@ -56,6 +56,7 @@ impl TServer for MinecraftInstance {
 
         let prelaunch = resolve_macro_invocation(&self.path_to_instance, "prelaunch");
         if let Some(prelaunch) = prelaunch {
+            let permissions = self.macro_executor.instance_sandbox(&prelaunch)?;
             let res: Result<SpawnResult, Error> = self
                 .macro_executor
                 .spawn(
@@ -64,8 +65,9 @@ impl TServer for MinecraftInstance {
                     CausedBy::System,
                     Box::new(DefaultWorkerOptionGenerator),
                     None,
-                    None,
+                    permissions,
                     Some(self.uuid.clone()),
+                    None,
                 )
                 .await;
 
@@ -73,6 +75,7 @@ impl TServer for MinecraftInstance {
                 macro_pid: pid,
                 exit_future,
                 detach_future,
+                ..
             }) = res
             {
                 self.pid_to_task_entry.lock().await.insert(
@@ -81,6 +84,7 @@ impl TServer for MinecraftInstance {
                         pid,
                         name: "prelaunch".to_string(),
                         creation_time: chrono::Utc::now().timestamp(),
+                        inspector_url: None,
                     },
                 );
                 tokio::select! {
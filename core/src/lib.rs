@@ -5,8 +5,8 @@ use crate::event_broadcaster::EventBroadcaster;
 use crate::handlers::extension::get_extension_routes;
 use crate::migration::migrate;
 use crate::prelude::{
-    init_app_state, init_paths, lodestone_path, path_to_global_settings, path_to_stores,
-    path_to_tmp, path_to_users, VERSION,
+    init_app_state, init_paths, lodestone_path, path_to_backups, path_to_global_settings,
+    path_to_stores, path_to_tmp, path_to_users, VERSION,
 };
 use crate::traits::t_configurable::GameType;
 use crate::traits::t_server::State;
@@ -17,18 +17,26 @@ use crate::{
         checks::get_checks_routes, core_info::get_core_info_routes, events::get_events_routes,
         gateway::get_gateway_routes, global_fs::get_global_fs_routes,
         global_settings::get_global_settings_routes, instance::*,
-        instance_config::get_instance_config_routes, instance_fs::get_instance_fs_routes,
+        instance_backup::get_instance_backup_routes, instance_config::get_instance_config_routes,
+        instance_disk::get_instance_disk_routes, instance_fs::get_instance_fs_routes,
         instance_macro::get_instance_macro_routes, instance_players::get_instance_players_routes,
         instance_server::get_instance_server_routes,
         instance_setup_configs::get_instance_setup_config_routes, monitor::get_monitor_routes,
-        playitgg::get_playitgg_routes, setup::get_setup_route, system::get_system_routes,
+        oidc::get_oidc_routes, playitgg::get_playitgg_routes, roles::get_role_routes,
+        setup::get_setup_route, system::get_system_routes, uploads::get_upload_routes,
         users::get_user_routes,
     },
     util::rand_alphanumeric,
 };
 
-use auth::user::UsersManager;
+use auth::{oidc::PendingOidcLogin, user::UsersManager};
 use axum::Router;
+use backup_store::BackupStore;
+use bulk_fs::BulkFileJobs;
+use chunked_upload::ChunkedUploads;
+use disk_usage::DiskUsageTracker;
+use extension::ExtensionManager;
+use sftp::{run_sftp_server, DEFAULT_SFTP_PORT};
 
 use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
@@ -41,6 +49,10 @@ use futures::Future;
 use global_settings::GlobalSettings;
 use implementations::{generic, minecraft};
 use macro_executor::MacroExecutor;
+use macro_history::MacroHistory;
+use macro_kv::MacroKvStore;
+use macro_limits::MacroLimits;
+use macro_triggers::MacroTriggerStore;
 use playitgg::utils::is_valid_secret_key;
 use port_manager::PortManager;
 use prelude::GameInstance;
@@ -76,23 +88,38 @@ use types::{DotLodestoneConfig, InstanceUuid};
 use uuid::Uuid;
 
 pub mod auth;
+mod backup_store;
+mod bulk_fs;
+mod chunked_upload;
 mod command_console;
 pub mod db;
 mod deno_ops;
+mod disk_usage;
 mod docker_bridge;
 pub mod error;
 mod event_broadcaster;
 mod events;
 mod extension;
+mod fs_history;
+mod fs_search;
+mod fs_watch;
 pub mod global_settings;
 mod handlers;
 pub mod implementations;
 pub mod macro_executor;
+mod macro_caller;
+mod macro_history;
+mod macro_kv;
+mod macro_limits;
+mod macro_test;
+mod macro_triggers;
 mod migration;
+mod module_cache;
 mod output_types;
 pub mod playitgg;
 mod port_manager;
 pub mod prelude;
+mod sftp;
 pub mod tauri_export;
 mod traits;
 pub mod types;
@@ -115,7 +142,15 @@ pub struct AppState {
     first_time_setup_key: Arc<Mutex<Option<String>>>,
     playitgg_key: Arc<Mutex<Option<String>>>,
     download_urls: Arc<Mutex<HashMap<String, DownloadableFile>>>,
+    /// Single sign-on logins waiting for the identity provider to redirect back, keyed by state
+    oidc_pending_logins: Arc<Mutex<HashMap<String, PendingOidcLogin>>>,
+    chunked_uploads: ChunkedUploads,
+    disk_usage: DiskUsageTracker,
+    bulk_file_jobs: BulkFileJobs,
+    backup_store: BackupStore,
     macro_executor: MacroExecutor,
+    macro_kv: MacroKvStore,
+    macro_triggers: MacroTriggerStore,
     sqlite_pool: sqlx::SqlitePool,
     docker_bridge: docker_bridge::DockerBridge,
     playit_keep_running: Arc<Mutex<Option<Arc<AtomicBool>>>>,
@@ -141,6 +176,7 @@ async fn restore_instances(
     macro_executor: MacroExecutor,
 ) -> Result<DashMap<InstanceUuid, GameInstance>, Error> {
     let ret: DashMap<InstanceUuid, GameInstance> = DashMap::new();
+    let extension_manager = ExtensionManager::new(lodestone_path().join("extensions"));
 
     for entry in instances_path
         .read_dir()
@@ -194,11 +230,32 @@ async fn restore_instances(
                 (dot_lodestone_config.uuid().to_owned(), instance.into())
             }
             GameType::Generic => {
+                let permissions = match generic::GenericInstance::source(&path).await {
+                    Some(source) => match extension_manager.sandbox_permissions(&source).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            error!(
+                                "Error while restoring atom instance {}, failed to read extension permissions : {e}",
+                                path.display()
+                            );
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let permissions = permissions.unwrap_or_else(|| {
+                    warn!(
+                        "Atom of instance {} is not an installed extension, running it with the default sandbox",
+                        path.display()
+                    );
+                    MacroExecutor::default_sandbox()
+                });
                 let instance = match generic::GenericInstance::restore(
                     path.to_owned(),
                     dot_lodestone_config.clone(),
                     event_broadcaster.clone(),
                     macro_executor.clone(),
+                    permissions,
                 )
                 .await
                 {
@@ -398,23 +455,19 @@ pub struct Args {
     pub is_desktop: bool,
     #[arg(short, long)]
     pub lodestone_path: Option<PathBuf>,
+    /// Port of the embedded SFTP server, 2022 if not set
+    #[arg(long)]
+    pub sftp_port: Option<u16>,
+    /// Start the embedded SFTP server, it listens on all interfaces
+    #[arg(long, default_value = "false")]
+    pub sftp: bool,
+    /// Run the tests of an extension and exit, either a directory or the id of an installed one
+    #[arg(long)]
+    pub test_extension: Option<String>,
 }
 
-pub async fn run(
-    args: Args,
-) -> Result<
-    (
-        impl Future<Output = ()>,
-        AppState,
-        tracing_appender::non_blocking::WorkerGuard,
-        tokio::sync::oneshot::Sender<()>,
-    ),
-    Error,
-> {
-    let _ = color_eyre::install().map_err(|e| {
-        error!("Failed to install color_eyre: {}", e);
-    });
-    let lodestone_path = if let Some(path) = args.lodestone_path {
+fn resolve_lodestone_path(lodestone_path: Option<PathBuf>) -> Result<PathBuf, Error> {
+    Ok(if let Some(path) = lodestone_path {
         path
     } else {
         PathBuf::from(match std::env::var("LODESTONE_PATH") {
@@ -438,7 +491,66 @@ pub async fn run(
                 }
             }
         })
+    })
+}
+
+/// Runs the `*.test.ts` files of an extension without starting the core,
+/// printing the report to stdout as JSON. Returns whether every test passed.
+///
+/// An installed extension runs with the permissions approved for it,
+/// anything else with the default sandbox.
+pub async fn test_extension(
+    lodestone_path: Option<PathBuf>,
+    extension: &str,
+) -> Result<bool, Error> {
+    let lodestone_path = resolve_lodestone_path(lodestone_path)?;
+    init_paths(lodestone_path.clone());
+    let (path, permissions) = match ExtensionManager::new(lodestone_path.join("extensions"))
+        .installed()
+        .await?
+        .swap_remove(extension)
+    {
+        Some(installed) => {
+            let permissions = installed
+                .approved_permission
+                .to_permissions_options(&installed.path);
+            (installed.path, permissions)
+        }
+        None => (
+            std::fs::canonicalize(extension).with_context(|| {
+                format!("{extension} is neither an installed extension nor a directory")
+            })?,
+            MacroExecutor::default_sandbox(),
+        ),
     };
+
+    // runs are only kept for as long as the tests run
+    let report = MacroExecutor::new_for_tests(MacroLimits::global_default())
+        .await?
+        .run_tests(&path, permissions)
+        .await?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).context("Failed to serialize test report")?
+    );
+    Ok(report.is_success())
+}
+
+pub async fn run(
+    args: Args,
+) -> Result<
+    (
+        impl Future<Output = ()>,
+        AppState,
+        tracing_appender::non_blocking::WorkerGuard,
+        tokio::sync::oneshot::Sender<()>,
+    ),
+    Error,
+> {
+    let _ = color_eyre::install().map_err(|e| {
+        error!("Failed to install color_eyre: {}", e);
+    });
+    let lodestone_path = resolve_lodestone_path(args.lodestone_path)?;
     init_paths(lodestone_path.clone());
     info!("Lodestone path: {}", lodestone_path.display());
     std::env::set_current_dir(&lodestone_path).map_err(|_| Error {
@@ -486,6 +598,10 @@ pub async fn run(
 
     global_settings.load_from_file().await?;
 
+    users_manager.set_require_admin_totp(global_settings.require_admin_totp());
+    users_manager.set_owner_only_password_login(global_settings.owner_only_password_login());
+    module_cache::set_offline_mode(global_settings.macro_offline_mode());
+
     let first_time_setup_key = if !users_manager.as_ref().iter().any(|(_, user)| user.is_owner) {
         let key = rand_alphanumeric(16);
         // log the first time setup key in green so it's easy to find
@@ -521,7 +637,29 @@ pub async fn run(
         None
     };
 
-    let macro_executor = MacroExecutor::new(tx.clone(), tokio::runtime::Handle::current());
+    let sqlite_pool = Pool::connect_with(
+        SqliteConnectOptions::from_str(&format!("sqlite://{}/data.db", path_to_stores().display()))
+            .map_err(|_| Error {
+                kind: ErrorKind::Internal,
+                source: Report::msg("Failed to create sqlite connection options"),
+            })?
+            .create_if_missing(true),
+    )
+    .await
+    .map_err(|_| Error {
+        kind: ErrorKind::Internal,
+        source: Report::msg("Failed to create sqlite pool"),
+    })?;
+
+    let macro_executor = MacroExecutor::new(
+        tx.clone(),
+        MacroHistory::new(sqlite_pool.clone()).await?,
+        tokio::runtime::Handle::current(),
+    );
+    macro_executor.set_default_limits(global_settings.macro_limits());
+    macro_executor.set_unsandboxed_macros(global_settings.unsandboxed_macros());
+    let macro_kv = MacroKvStore::new(sqlite_pool.clone()).await?;
+    let macro_triggers = MacroTriggerStore::new(sqlite_pool.clone()).await?;
     let instances = restore_instances(&path_to_instances, tx.clone(), macro_executor.clone())
         .await
         .map_err(|_| Error {
@@ -529,6 +667,9 @@ pub async fn run(
             source: Report::msg("failed to restore instances"),
         })?;
 
+    let disk_usage = DiskUsageTracker::new(path_to_stores().join("disk_quotas.json"), tx.clone());
+    disk_usage.load_from_file().await?;
+
     let mut allocated_ports = HashSet::new();
     for instance_entry in instances.iter() {
         allocated_ports.insert(instance_entry.value().port().await);
@@ -547,25 +688,17 @@ pub async fn run(
         playitgg_key: Arc::new(Mutex::new(playitgg_key)),
         system: Arc::new(Mutex::new(sysinfo::System::new_all())),
         download_urls: Arc::new(Mutex::new(HashMap::new())),
+        oidc_pending_logins: Arc::new(Mutex::new(HashMap::new())),
+        chunked_uploads: ChunkedUploads::new(path_to_tmp().join("uploads")),
+        bulk_file_jobs: BulkFileJobs::new(tx.clone(), disk_usage.clone()),
+        backup_store: BackupStore::new(path_to_backups().clone()),
+        disk_usage,
         playit_keep_running: Arc::new(Mutex::new(None)),
         global_settings: Arc::new(Mutex::new(global_settings)),
         macro_executor,
-        sqlite_pool: Pool::connect_with(
-            SqliteConnectOptions::from_str(&format!(
-                "sqlite://{}/data.db",
-                path_to_stores().display()
-            ))
-            .map_err(|_| Error {
-                kind: ErrorKind::Internal,
-                source: Report::msg("Failed to create sqlite connection options"),
-            })?
-            .create_if_missing(true),
-        )
-        .await
-        .map_err(|_| Error {
-            kind: ErrorKind::Internal,
-            source: Report::msg("Failed to create sqlite pool"),
-        })?,
+        macro_kv,
+        macro_triggers,
+        sqlite_pool,
         docker_bridge: docker_bridge::DockerBridge::new(
             tx.clone(),
             path_to_stores().join("docker_bridge.json"),
@@ -647,6 +780,53 @@ pub async fn run(
         }
     };
 
+    let stale_upload_task = {
+        let chunked_uploads = shared_state.chunked_uploads.clone();
+        let event_broadcaster = tx.clone();
+        async move {
+            // the first tick is immediate, clearing partials left behind by a crash
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                chunked_uploads
+                    .remove_stale(chrono::Utc::now().timestamp(), &event_broadcaster)
+                    .await;
+            }
+        }
+    };
+
+    let macro_run_retention_task = {
+        let macro_executor = shared_state.macro_executor.clone();
+        let global_settings = shared_state.global_settings.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                let retention = global_settings.lock().await.macro_run_retention();
+                if let Err(e) = macro_executor.history().prune(&retention).await {
+                    error!("Failed to prune macro runs: {}", e);
+                }
+            }
+        }
+    };
+
+    let macro_trigger_task = macro_triggers::run_macro_triggers(
+        shared_state.instances.clone(),
+        shared_state.users_manager.clone(),
+        shared_state.macro_triggers.clone(),
+        shared_state.macro_executor.clone(),
+        tx.subscribe(),
+    );
+
+    let disk_usage_task = shared_state
+        .disk_usage
+        .clone()
+        .track_fs_events(shared_state.instances.clone(), tx.subscribe());
+
+    let sftp_port = args
+        .sftp
+        .then(|| args.sftp_port.unwrap_or(DEFAULT_SFTP_PORT));
+
     let tls_config_result = RustlsConfig::from_pem_file(
         lodestone_path.join("tls").join("cert.pem"),
         lodestone_path.join("tls").join("key.pem"),
@@ -682,12 +862,17 @@ pub async fn run(
                     .merge(get_system_routes(shared_state.clone()))
                     .merge(get_checks_routes(shared_state.clone()))
                     .merge(get_user_routes(shared_state.clone()))
+                    .merge(get_role_routes(shared_state.clone()))
+                    .merge(get_oidc_routes(shared_state.clone()))
                     .merge(get_core_info_routes(shared_state.clone()))
                     .merge(get_setup_route(shared_state.clone()))
                     .merge(get_monitor_routes(shared_state.clone()))
                     .merge(get_instance_macro_routes(shared_state.clone()))
                     .merge(get_instance_fs_routes(shared_state.clone()))
+                    .merge(get_instance_disk_routes(shared_state.clone()))
+                    .merge(get_instance_backup_routes(shared_state.clone()))
                     .merge(get_global_fs_routes(shared_state.clone()))
+                    .merge(get_upload_routes(shared_state.clone()))
                     .merge(get_global_settings_routes(shared_state.clone()))
                     .merge(get_gateway_routes(shared_state.clone()))
                     .merge(get_extension_routes(shared_state.clone()))
@@ -719,7 +904,7 @@ pub async fn run(
                                 info!("Note that Lodestone Core does not host the web dashboard itself. Please visit https://www.lodestone.cc for setup instructions.");
                                 axum_server::bind_rustls(addr, config)
                                     .handle(axum_server_handle)
-                                    .serve(app.into_make_service())
+                                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                                     .await
                             }
                             Err(e) => {
@@ -728,19 +913,31 @@ pub async fn run(
                                 info!("Note that Lodestone Core does not host the web dashboard itself. Please visit https://www.lodestone.cc for setup instructions.");
                                 axum_server::bind(addr)
                                     .handle(axum_server_handle)
-                                    .serve(app.into_make_service())
+                                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                                     .await
                             }
                         }
                         .unwrap();
                     }
                 });
+                if let Some(port) = sftp_port {
+                    let shared_state = shared_state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = run_sftp_server(shared_state, port).await {
+                            error!("Failed to run SFTP server : {e}");
+                        }
+                    });
+                }
                 // capture file into the move block
                 let _lock_file = lock_file;
                 select! {
                     _ = write_to_db_task => info!("Write to db task exited"),
                     _ = event_buffer_task => info!("Event buffer task exited"),
                     _ = monitor_report_task => info!("Monitor report task exited"),
+                    _ = stale_upload_task => info!("Stale upload task exited"),
+                    _ = disk_usage_task => info!("Disk usage task exited"),
+                    _ = macro_run_retention_task => info!("Macro run retention task exited"),
+                    _ = macro_trigger_task => info!("Macro trigger task exited"),
                     _ = shutdown_rx => info!("Shutdown signal received"),
                     _ = tokio::signal::ctrl_c() => info!("Ctrl+C received"),
                 }
@@ -0,0 +1,179 @@
+//! Who a macro acts on behalf of.
+//!
+//! Ops that touch files, players or the event database check the permissions of the caller
+//! instead of letting every macro act as the owner.
+
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::{
+        user::{UserAction, UsersManager},
+        user_id::UserId,
+    },
+    error::{Error, ErrorKind},
+    events::{CausedBy, Event, EventInner},
+    types::InstanceUuid,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MacroCaller {
+    /// Started by a user, the macro has their permissions at the time of each call
+    User(UserId),
+    /// Started by the core for an instance, such as the prelaunch script or a trigger,
+    /// the macro can only act on that instance
+    Instance(InstanceUuid),
+    /// Can't do anything that needs a permission
+    Nobody,
+}
+
+impl MacroCaller {
+    pub fn new(caused_by: &CausedBy, instance_uuid: Option<&InstanceUuid>) -> Self {
+        match (caused_by, instance_uuid) {
+            (CausedBy::User { user_id, .. }, _) => MacroCaller::User(user_id.clone()),
+            (CausedBy::System | CausedBy::Instance { .. }, Some(instance_uuid)) => {
+                MacroCaller::Instance(instance_uuid.clone())
+            }
+            _ => MacroCaller::Nobody,
+        }
+    }
+
+    pub fn can_perform_action(&self, action: &UserAction, users_manager: &UsersManager) -> bool {
+        match self {
+            MacroCaller::User(user_id) => users_manager
+                .get_user(user_id)
+                .map_or(false, |user| user.can_perform_action(action)),
+            MacroCaller::Instance(instance_uuid) => {
+                instance_of(action).map_or(false, |target| target == instance_uuid)
+            }
+            MacroCaller::Nobody => false,
+        }
+    }
+
+    pub fn try_action(
+        &self,
+        action: &UserAction,
+        users_manager: &UsersManager,
+        safe_mode: bool,
+    ) -> Result<(), Error> {
+        match self {
+            MacroCaller::User(user_id) => users_manager
+                .get_user(user_id)
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::PermissionDenied,
+                    source: eyre!("The user who started this macro no longer exists"),
+                })?
+                .try_action(action, safe_mode),
+            _ if self.can_perform_action(action, users_manager) => Ok(()),
+            _ => Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Macros not started by a user can only act on their own instance"),
+            }),
+        }
+    }
+
+    pub fn can_view_event(&self, event: &Event, users_manager: &UsersManager) -> bool {
+        match self {
+            MacroCaller::User(user_id) => users_manager
+                .get_user(user_id)
+                .map_or(false, |user| user.can_view_event(event)),
+            MacroCaller::Instance(instance_uuid) => match &event.event_inner {
+                EventInner::InstanceEvent(event) => &event.instance_uuid == instance_uuid,
+                EventInner::MacroEvent(event) => event.instance_uuid.as_ref() == Some(instance_uuid),
+                _ => false,
+            },
+            MacroCaller::Nobody => false,
+        }
+    }
+}
+
+/// The instance an action is on, none for global actions
+fn instance_of(action: &UserAction) -> Option<&InstanceUuid> {
+    match action {
+        UserAction::ViewInstance(instance_uuid)
+        | UserAction::StartInstance(instance_uuid)
+        | UserAction::StopInstance(instance_uuid)
+        | UserAction::AccessConsole(instance_uuid)
+        | UserAction::AccessSetting(instance_uuid)
+        | UserAction::ReadResource(instance_uuid)
+        | UserAction::WriteResource(instance_uuid)
+        | UserAction::ReadInstanceFile(instance_uuid)
+        | UserAction::WriteInstanceFile(instance_uuid)
+        | UserAction::AccessMacro(Some(instance_uuid)) => Some(instance_uuid),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        auth::{permission::UserPermission, user::User},
+        event_broadcaster::EventBroadcaster,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_macro_caller() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx, HashMap::new(), temp_dir.path().join("users.json"));
+        let instance_uuid = InstanceUuid::default();
+        let other_instance_uuid = InstanceUuid::default();
+        let mut permissions = UserPermission::default();
+        permissions
+            .can_read_instance_file
+            .insert(instance_uuid.clone());
+        let user = User::new("steve".to_string(), "12345", false, false, permissions);
+        users_manager
+            .add_user(user.clone(), CausedBy::System)
+            .await
+            .unwrap();
+
+        let caller = MacroCaller::new(
+            &CausedBy::User {
+                user_id: user.uid.clone(),
+                user_name: user.username.clone(),
+            },
+            Some(&other_instance_uuid),
+        );
+        assert_eq!(caller, MacroCaller::User(user.uid.clone()));
+        assert!(caller
+            .try_action(
+                &UserAction::ReadInstanceFile(instance_uuid.clone()),
+                &users_manager,
+                false
+            )
+            .is_ok());
+        assert!(caller
+            .try_action(
+                &UserAction::WriteInstanceFile(instance_uuid.clone()),
+                &users_manager,
+                false
+            )
+            .is_err());
+
+        let caller = MacroCaller::new(&CausedBy::System, Some(&instance_uuid));
+        assert!(caller.can_perform_action(
+            &UserAction::WriteInstanceFile(instance_uuid.clone()),
+            &users_manager
+        ));
+        assert!(!caller.can_perform_action(
+            &UserAction::WriteInstanceFile(other_instance_uuid.clone()),
+            &users_manager
+        ));
+        assert!(!caller.can_perform_action(&UserAction::WriteGlobalFile, &users_manager));
+
+        let caller = MacroCaller::new(&CausedBy::System, None);
+        assert_eq!(caller, MacroCaller::Nobody);
+        assert!(caller
+            .try_action(
+                &UserAction::ViewInstance(instance_uuid),
+                &users_manager,
+                false
+            )
+            .is_err());
+    }
+}
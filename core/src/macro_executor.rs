@@ -1,10 +1,12 @@
 use std::{
+    cell::RefCell,
     fmt::{Debug, Display},
     iter::zip,
-    path::PathBuf,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     rc::Rc,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
     time::Duration,
@@ -12,7 +14,10 @@ use std::{
 
 use color_eyre::eyre::Context;
 use dashmap::DashMap;
-use deno_runtime::permissions::{Permissions, PermissionsOptions};
+use deno_runtime::{
+    inspector_server::InspectorServer,
+    permissions::{Permissions, PermissionsOptions},
+};
 use futures_util::Future;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -22,14 +27,25 @@ use ts_rs::TS;
 
 use crate::{
     deno_ops::{
-        events::register_all_event_ops, instance_control::register_instance_control_ops,
-        prelude::register_prelude_ops,
+        event_db::register_event_db_ops, events::register_all_event_ops,
+        instance_control::register_instance_control_ops, instance_fs::register_instance_fs_ops,
+        kv::register_kv_ops, player_management::register_player_management_ops,
+        prelude::register_prelude_ops, testing::register_testing_ops,
     },
     error::{Error, ErrorKind},
     event_broadcaster::EventBroadcaster,
     events::{CausedBy, EventInner, MacroEvent, MacroEventInner},
+    macro_caller::MacroCaller,
+    macro_history::{MacroHistory, MacroRun},
+    macro_kv::KvNamespace,
+    macro_limits::{
+        current_thread, thread_cpu_time, watchdog, LimitExceeded, MacroLimits, TerminationReason,
+    },
+    macro_test::MacroTestHarness,
+    module_cache::{offline_mode, Lockfile, ModuleCache},
+    prelude::path_to_module_cache,
     traits::t_macro::ExitStatus,
-    types::InstanceUuid,
+    types::{InstanceUuid, Snowflake},
 };
 
 use color_eyre::eyre::eyre;
@@ -40,13 +56,13 @@ use anyhow::bail;
 use deno_ast::MediaType;
 use deno_ast::ParseParams;
 use deno_ast::SourceTextInfo;
+use deno_core::anyhow;
 use deno_core::ModuleLoader;
 use deno_core::ModuleSource;
 use deno_core::ModuleSourceFuture;
 use deno_core::ModuleSpecifier;
 use deno_core::ModuleType;
 use deno_core::ResolutionKind;
-use deno_core::{anyhow, error::generic_error};
 use deno_core::{resolve_import, ModuleCode};
 
 use crate::traits::t_configurable::manifest::{
@@ -58,6 +74,11 @@ use indexmap::IndexMap;
 
 pub trait WorkerOptionGenerator: Send + Sync {
     fn generate(&self) -> deno_runtime::worker::WorkerOptions;
+
+    /// Runs the macro as a test file against a fake instance instead of the instance ops
+    fn test_harness(&self) -> Option<MacroTestHarness> {
+        None
+    }
 }
 
 pub struct DefaultWorkerOptionGenerator;
@@ -72,7 +93,9 @@ impl WorkerOptionGenerator for DefaultWorkerOptionGenerator {
 }
 
 pub struct TypescriptModuleLoader {
-    http: reqwest::Client,
+    cache: ModuleCache,
+    /// The lockfile of the main module, opened once it is loaded
+    lockfile: Rc<RefCell<Option<Lockfile>>>,
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash, TS)]
@@ -107,7 +130,8 @@ impl Display for MacroPID {
 impl Default for TypescriptModuleLoader {
     fn default() -> Self {
         Self {
-            http: reqwest::Client::new(),
+            cache: ModuleCache::new(path_to_module_cache().clone()),
+            lockfile: Rc::new(RefCell::new(None)),
         }
     }
 }
@@ -125,11 +149,13 @@ impl ModuleLoader for TypescriptModuleLoader {
     fn load(
         &self,
         module_specifier: &ModuleSpecifier,
-        _maybe_referrer: Option<&ModuleSpecifier>,
-        _is_dyn_import: bool,
+        maybe_referrer: Option<&ModuleSpecifier>,
+        is_dyn_import: bool,
     ) -> Pin<Box<ModuleSourceFuture>> {
         let module_specifier = module_specifier.clone();
-        let http = self.http.clone();
+        let is_main_module = maybe_referrer.is_none() && !is_dyn_import;
+        let cache = self.cache.clone();
+        let lockfile = self.lockfile.clone();
         async move {
             let (code, module_type, media_type, should_transpile) = match module_specifier
                 .to_file_path()
@@ -151,6 +177,9 @@ impl ModuleLoader for TypescriptModuleLoader {
                         MediaType::Json => (ModuleType::Json, false),
                         _ => bail!("Unknown extension {:?}", path.extension()),
                     };
+                    if is_main_module && lockfile.borrow().is_none() {
+                        *lockfile.borrow_mut() = Some(Lockfile::load(Lockfile::path_for(&path))?);
+                    }
 
                     (
                         tokio::fs::read_to_string(&path).await?,
@@ -161,15 +190,11 @@ impl ModuleLoader for TypescriptModuleLoader {
                 }
                 Err(_) => {
                     if module_specifier.scheme() == "http" || module_specifier.scheme() == "https" {
-                        let http_res = http.get(module_specifier.to_string()).send().await?;
-                        if !http_res.status().is_success() {
-                            bail!("Failed to fetch module: {module_specifier}");
+                        let module = cache.load(&module_specifier, offline_mode()).await?;
+                        if let Some(lockfile) = lockfile.borrow_mut().as_mut() {
+                            lockfile.check(&module_specifier, &module.hash)?;
                         }
-                        let content_type = http_res
-                            .headers()
-                            .get("content-type")
-                            .and_then(|ct| ct.to_str().ok())
-                            .ok_or_else(|| generic_error("No content-type header"))?;
+                        let content_type = module.content_type.as_str();
                         let media_type =
                             MediaType::from_content_type(&module_specifier, content_type);
                         let (module_type, should_transpile) = match media_type {
@@ -187,8 +212,7 @@ impl ModuleLoader for TypescriptModuleLoader {
                             MediaType::Json => (ModuleType::Json, false),
                             _ => bail!("Unknown content-type {:?}", content_type),
                         };
-                        let code = http_res.text().await?;
-                        (code, module_type, media_type, should_transpile)
+                        (module.code, module_type, media_type, should_transpile)
                     } else {
                         bail!("Unsupported module specifier: {}", module_specifier);
                     }
@@ -218,7 +242,13 @@ impl ModuleLoader for TypescriptModuleLoader {
 #[derive(Clone, Debug)]
 pub struct MacroExecutor {
     macro_process_table: Arc<DashMap<MacroPID, deno_core::v8::IsolateHandle>>,
-    exit_status_table: Arc<DashMap<MacroPID, ExitStatus>>,
+    /// The run of every macro still running
+    run_table: Arc<DashMap<MacroPID, Snowflake>>,
+    history: MacroHistory,
+    /// Limits of macros that don't set their own
+    default_limits: Arc<std::sync::Mutex<MacroLimits>>,
+    /// Instance macros run with full access, as they did before they were sandboxed
+    unsandboxed_macros: Arc<AtomicBool>,
     #[allow(dead_code)]
     channel_table:
         Arc<DashMap<MacroPID, (mpsc::UnboundedSender<Value>, mpsc::UnboundedSender<Value>)>>,
@@ -227,68 +257,103 @@ pub struct MacroExecutor {
     rt: tokio::runtime::Handle,
 }
 
+/// Runs the macro with a V8 inspector server on localhost, for Chrome DevTools or VS Code to attach to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct MacroDebugOptions {
+    /// Pause before the first statement until a debugger attaches
+    #[serde(default)]
+    pub break_on_start: bool,
+}
+
 pub struct SpawnResult {
     pub macro_pid: MacroPID,
+    /// Websocket URL for a debugger to attach to, if the macro runs in debug mode
+    pub inspector_url: Option<String>,
     pub detach_future: Pin<Box<dyn Future<Output = ()> + Send>>,
     pub exit_future: Pin<Box<dyn Future<Output = Result<ExitStatus, Error>> + Send>>,
 }
 
 impl MacroExecutor {
-    pub fn new(event_broadcaster: EventBroadcaster, rt: tokio::runtime::Handle) -> MacroExecutor {
-        let process_table = Arc::new(DashMap::new());
-        let process_id = Arc::new(AtomicUsize::new(0));
-        let exit_status_table = Arc::new(DashMap::new());
-
-        // spawn a task to listen for exit events and update the exit status table
-        tokio::task::spawn({
-            let exit_status_table = exit_status_table.clone();
-            let mut rx = event_broadcaster.subscribe();
-            async move {
-                loop {
-                    if let Ok(event) = rx.recv().await {
-                        if let Some(MacroEvent {
-                            macro_pid,
-                            macro_event_inner: MacroEventInner::Stopped { exit_status },
-                            ..
-                        }) = event.try_macro_event()
-                        {
-                            exit_status_table.insert(*macro_pid, exit_status.clone());
-                        }
-                    }
-                }
-            }
-        });
-
+    pub fn new(
+        event_broadcaster: EventBroadcaster,
+        history: MacroHistory,
+        rt: tokio::runtime::Handle,
+    ) -> MacroExecutor {
         MacroExecutor {
-            macro_process_table: process_table,
+            macro_process_table: Arc::new(DashMap::new()),
             event_broadcaster,
             channel_table: Arc::new(DashMap::new()),
-            exit_status_table,
-            next_process_id: process_id,
+            run_table: Arc::new(DashMap::new()),
+            history,
+            default_limits: Arc::new(std::sync::Mutex::new(MacroLimits::global_default())),
+            unsandboxed_macros: Arc::new(AtomicBool::new(false)),
+            next_process_id: Arc::new(AtomicUsize::new(0)),
             rt,
         }
     }
 
-    fn add_default_permissions(
-        perm: Option<PermissionsOptions>,
-        path_to_main: PathBuf,
-    ) -> PermissionsOptions {
-        let parent = path_to_main.parent().unwrap().to_path_buf();
-        if let Some(mut perm) = perm {
-            perm.allow_read
-                .get_or_insert_with(std::vec::Vec::new)
-                .push(parent.clone());
-            perm.allow_write
-                .get_or_insert_with(std::vec::Vec::new)
-                .push(parent);
-            perm
-        } else {
-            PermissionsOptions {
-                allow_read: Some(vec![parent.clone()]),
-                allow_write: Some(vec![parent]),
+    pub fn history(&self) -> &MacroHistory {
+        &self.history
+    }
+
+    pub fn default_limits(&self) -> MacroLimits {
+        *self.default_limits.lock().unwrap()
+    }
+
+    pub fn set_default_limits(&self, limits: MacroLimits) {
+        *self.default_limits.lock().unwrap() = limits;
+    }
+
+    pub fn set_unsandboxed_macros(&self, unsandboxed_macros: bool) {
+        self.unsandboxed_macros
+            .store(unsandboxed_macros, Ordering::Relaxed);
+    }
+
+    /// Denies everything, for code not from an extension
+    pub fn default_sandbox() -> PermissionsOptions {
+        PermissionsOptions {
+            prompt: false,
+            ..Default::default()
+        }
+    }
+
+    /// The permissions of an instance macro: [`MacroExecutor::default_sandbox`] and its data directory,
+    /// or full access if the owner turned the sandbox off
+    pub fn instance_sandbox(&self, path_to_main: &Path) -> Result<PermissionsOptions, Error> {
+        if self.unsandboxed_macros.load(Ordering::Relaxed) {
+            return Ok(PermissionsOptions {
+                allow_env: Some(Vec::new()),
+                allow_hrtime: true,
+                allow_net: Some(Vec::new()),
+                allow_ffi: Some(Vec::new()),
+                allow_read: Some(Vec::new()),
+                allow_run: Some(Vec::new()),
+                allow_sys: Some(Vec::new()),
+                allow_write: Some(Vec::new()),
+                prompt: false,
                 ..Default::default()
+            });
+        }
+        let data_dir = macro_data_dir(path_to_main);
+        std::fs::create_dir_all(&data_dir).context(format!(
+            "Failed to create macro data directory at {}",
+            data_dir.display()
+        ))?;
+        Ok(Self::with_dir(Self::default_sandbox(), &data_dir))
+    }
+
+    /// Lets the code also read and write `dir`
+    pub fn with_dir(mut perm: PermissionsOptions, dir: &Path) -> PermissionsOptions {
+        // an empty list already grants everything
+        for paths in [&mut perm.allow_read, &mut perm.allow_write] {
+            if paths.as_ref().map_or(true, |paths| !paths.is_empty()) {
+                paths
+                    .get_or_insert_with(std::vec::Vec::new)
+                    .push(dir.to_path_buf());
             }
         }
+        perm
     }
 
     /// For timeout:
@@ -300,16 +365,24 @@ impl MacroExecutor {
     /// Note that this does not terminate the process, it just stops the handle from waiting for it.
     ///
     /// It is up to the caller to terminate the process if it is still running.
+    ///
+    /// The macro is sandboxed to `permissions`,
+    /// see [`MacroExecutor::default_sandbox`] and [`MacroExecutor::instance_sandbox`].
+    ///
+    /// The macro is terminated if it goes over its limits, see [`MacroLimits`].
+    ///
+    /// With `debug`, the macro can be debugged through the inspector URL in the result.
     #[allow(clippy::too_many_arguments)]
     pub async fn spawn(
         &self,
         path_to_main_module: PathBuf,
         args: Vec<String>,
-        _caused_by: CausedBy,
+        caused_by: CausedBy,
         worker_options_generator: Box<dyn WorkerOptionGenerator>,
         pre_injection_code: Option<String>,
-        permissions: Option<PermissionsOptions>,
+        permissions: PermissionsOptions,
         instance_uuid: Option<InstanceUuid>,
+        debug: Option<MacroDebugOptions>,
     ) -> Result<SpawnResult, Error> {
         let pid = MacroPID(self.next_process_id.fetch_add(1, Ordering::SeqCst));
         let exit_future = Box::pin({
@@ -327,32 +400,94 @@ impl MacroExecutor {
             &std::env::current_dir().context("Failed to get current directory")?,
         )
         .context("Failed to resolve path")?;
-
-        std::thread::spawn({
+        let permissions = Permissions::from_options(&permissions)
+            .map_err(|e| eyre!("Invalid macro permissions: {e}"))?;
+        let caller = MacroCaller::new(&caused_by, instance_uuid.as_ref());
+        let run = MacroRun {
+            id: Snowflake::new(),
+            pid,
+            instance_uuid: instance_uuid.clone(),
+            name: macro_name_of(&path_to_main_module),
+            args: args.clone(),
+            caused_by,
+            start_time: chrono::Utc::now().timestamp(),
+            end_time: None,
+            exit_status: None,
+            cpu_time_ms: None,
+        };
+        let limits =
+            MacroLimits::load(&path_to_main_module)?.within(*self.default_limits.lock().unwrap());
+        let termination_reason = TerminationReason::default();
+        self.history.start(&run).await?;
+        self.run_table.insert(pid, run.id);
+        let run_id = run.id;
+        let logger = self.history.logger(run.id);
+        let kv_namespace = KvNamespace::new(instance_uuid.as_ref(), &run.name);
+        let inspector_server = debug.map(start_inspector_server).transpose()?;
+
+        let thread = std::thread::spawn({
             let process_table = self.macro_process_table.clone();
+            let run_table = self.run_table.clone();
             let event_broadcaster = self.event_broadcaster.clone();
+            let history = self.history.clone();
+            let termination_reason = termination_reason.clone();
+            let inspector_server = inspector_server.clone();
             let rt = self.rt.clone();
             move || {
                 let _guard = rt.enter();
                 let local = LocalSet::new();
-                local.spawn_local({
+                let worker = local.spawn_local({
                     let event_broadcaster = event_broadcaster.clone();
                     let instance_uuid = instance_uuid.clone();
                     async move {
                         let mut worker_option = worker_options_generator.generate();
+                        let test_harness = worker_options_generator.test_harness();
+                        if let Some(max_heap_bytes) = limits.max_heap_bytes() {
+                            worker_option.create_params = Some(
+                                deno_core::v8::CreateParams::default()
+                                    .heap_limits(0, max_heap_bytes),
+                            );
+                        }
                         worker_option.get_error_class_fn = Some(&deno_errors::get_error_class_name);
+                        if let Some((inspector_server, debug)) = inspector_server {
+                            worker_option.maybe_inspector_server = Some(inspector_server);
+                            worker_option.should_break_on_first_statement = debug.break_on_start;
+                        }
                         register_prelude_ops(&mut worker_option);
-                        register_all_event_ops(&mut worker_option, event_broadcaster.clone());
-                        register_instance_control_ops(&mut worker_option);
+                        match test_harness {
+                            Some(test_harness) => {
+                                register_all_event_ops(
+                                    &mut worker_option,
+                                    test_harness.instance.event_broadcaster(),
+                                );
+                                register_testing_ops(&mut worker_option, test_harness);
+                            }
+                            None => {
+                                register_all_event_ops(
+                                    &mut worker_option,
+                                    event_broadcaster.clone(),
+                                );
+                                register_instance_control_ops(&mut worker_option);
+                                register_kv_ops(&mut worker_option);
+                                register_instance_fs_ops(&mut worker_option);
+                                register_player_management_ops(&mut worker_option);
+                                register_event_db_ops(&mut worker_option);
+                            }
+                        }
 
                         let mut main_worker = deno_runtime::worker::MainWorker::from_options(
                             main_module,
-                            deno_runtime::permissions::PermissionsContainer::new(
-                                // TODO: limit permissions
-                                Permissions::allow_all(),
-                            ),
+                            deno_runtime::permissions::PermissionsContainer::new(permissions),
                             worker_option,
                         );
+                        main_worker.js_runtime.op_state().borrow_mut().put(logger);
+                        main_worker
+                            .js_runtime
+                            .op_state()
+                            .borrow_mut()
+                            .put(kv_namespace);
+                        main_worker.js_runtime.op_state().borrow_mut().put(caller);
+                        main_worker.js_runtime.op_state().borrow_mut().put(pid);
                         main_worker.bootstrap(&deno_runtime::BootstrapOptions {
                             args,
                             ..Default::default()
@@ -383,6 +518,19 @@ impl MacroExecutor {
                         let isolate_handle =
                             main_worker.js_runtime.v8_isolate().thread_safe_handle();
 
+                        if let Some(max_heap_mb) = limits.max_heap_mb {
+                            let isolate_handle = isolate_handle.clone();
+                            let termination_reason = termination_reason.clone();
+                            main_worker.js_runtime.add_near_heap_limit_callback(
+                                move |current_limit, _initial_limit| {
+                                    termination_reason.set(LimitExceeded::Heap(max_heap_mb));
+                                    isolate_handle.terminate_execution();
+                                    // leave the isolate room to unwind
+                                    current_limit * 2
+                                },
+                            );
+                        }
+
                         process_table.insert(pid, isolate_handle);
 
                         let main_module = match deno_core::resolve_path(
@@ -392,7 +540,7 @@ impl MacroExecutor {
                             Ok(v) => v,
                             Err(e) => {
                                 error!("Error resolving main module: {}", e);
-                                return;
+                                return None;
                             }
                         };
 
@@ -405,87 +553,49 @@ impl MacroExecutor {
                             .into(),
                         );
 
-                        if let Err(e) = main_worker.execute_main_module(&main_module).await {
-                            if e.to_string() == "Uncaught Error: execution terminated" {
-                                warn!("User terminated macro execution");
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Killed {
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid,
+                        let run = async {
+                            match main_worker.execute_main_module(&main_module).await {
+                                Ok(()) => match main_worker.run_event_loop(false).await {
+                                    Ok(()) => {
+                                        debug!("Macro event loop exited");
+                                        ExitStatus::Success {
+                                            time: chrono::Utc::now().timestamp(),
+                                        }
                                     }
-                                    .into(),
-                                );
-                            } else {
-                                error!("Error executing main module {main_module}: {}", e);
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Error {
-                                                error_msg: e.to_string(),
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid,
+                                    Err(e) => {
+                                        if e.to_string() != "Uncaught Error: execution terminated" {
+                                            error!("Error running event loops: {}", e);
+                                        }
+                                        exit_status_of(&e, &termination_reason)
                                     }
-                                    .into(),
-                                );
-                            }
-                            return;
-                        }
-
-                        if let Err(e) = main_worker.run_event_loop(false).await {
-                            if e.to_string() == "Uncaught Error: execution terminated" {
-                                warn!("User terminated macro execution");
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Killed {
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid: instance_uuid.clone(),
-                                    }
-                                    .into(),
-                                );
-                            } else {
-                                error!("Error running event loops: {}", e);
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Error {
-                                                error_msg: e.to_string(),
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid: instance_uuid.clone(),
+                                },
+                                Err(e) => {
+                                    if e.to_string() != "Uncaught Error: execution terminated" {
+                                        error!("Error executing main module {main_module}: {}", e);
                                     }
-                                    .into(),
-                                );
+                                    exit_status_of(&e, &termination_reason)
+                                }
                             }
-                        }
-
-                        debug!("Macro event loop exited");
-
+                        };
+                        // a macro waiting on a timer or I/O never sees the isolate being terminated,
+                        // so its event loop is dropped instead
+                        let exit_status = tokio::select! {
+                            exit_status = run => exit_status,
+                            _ = termination_reason.exceeded() => {
+                                terminated_exit_status(&termination_reason)
+                            }
+                        };
                         event_broadcaster.send(
                             MacroEvent {
                                 macro_pid: pid,
                                 macro_event_inner: MacroEventInner::Stopped {
-                                    exit_status: ExitStatus::Success {
-                                        time: chrono::Utc::now().timestamp(),
-                                    },
+                                    exit_status: exit_status.clone(),
                                 },
                                 instance_uuid,
                             }
                             .into(),
                         );
+                        Some(exit_status)
 
                         // If the while loop returns, then all the LocalSpawner
                         // objects have been dropped.
@@ -496,22 +606,48 @@ impl MacroExecutor {
                 // spawned tasks have returned.
                 rt.block_on(local);
                 debug!("MacroExecutor thread exited");
-                event_broadcaster.send(
-                    MacroEvent {
-                        macro_pid: pid,
-                        macro_event_inner: MacroEventInner::Stopped {
-                            exit_status: ExitStatus::Error {
-                                time: chrono::Utc::now().timestamp(),
-                                error_msg: "Macro executor thread unexpectedly panicked"
-                                    .to_string(),
-                            },
-                        },
-                        instance_uuid: instance_uuid.clone(),
+                if let Some(cpu_time) = thread_cpu_time(current_thread()) {
+                    if let Err(e) = rt.block_on(history.record_cpu_time(run_id, cpu_time)) {
+                        error!("Failed to record CPU time of macro {pid}: {e}");
                     }
-                    .into(),
-                );
+                }
+                let exit_status = match rt.block_on(worker) {
+                    Ok(Some(exit_status)) => exit_status,
+                    _ => {
+                        let exit_status = ExitStatus::Error {
+                            time: chrono::Utc::now().timestamp(),
+                            error_msg: "Macro executor thread unexpectedly panicked".to_string(),
+                        };
+                        event_broadcaster.send(
+                            MacroEvent {
+                                macro_pid: pid,
+                                macro_event_inner: MacroEventInner::Stopped {
+                                    exit_status: exit_status.clone(),
+                                },
+                                instance_uuid: instance_uuid.clone(),
+                            }
+                            .into(),
+                        );
+                        exit_status
+                    }
+                };
+                // finished here rather than from the `Stopped` event, which a lagging receiver can miss
+                process_table.remove(&pid);
+                run_table.remove(&pid);
+                if let Err(e) = rt.block_on(history.finish(run_id, &exit_status)) {
+                    error!("Failed to record exit status of macro {pid}: {e}");
+                }
             }
         });
+        if limits.timeout_secs.is_some() || limits.max_cpu_secs.is_some() {
+            tokio::spawn(watchdog(
+                pid,
+                limits,
+                thread,
+                self.macro_process_table.clone(),
+                termination_reason,
+            ));
+        }
 
         // listen to event broadcaster for macro started event
         // and return the pid
@@ -541,8 +677,13 @@ impl MacroExecutor {
         tokio::time::timeout(Duration::from_secs(1), fut)
             .await
             .context("Failed to spawn macro")??;
+        let inspector_url = match inspector_server {
+            Some((inspector_server, _)) => inspector_url(inspector_server.host).await,
+            None => None,
+        };
         Ok(SpawnResult {
             macro_pid: pid,
+            inspector_url,
             detach_future,
             exit_future,
         })
@@ -599,8 +740,8 @@ impl MacroExecutor {
         }
     }
 
-    pub async fn get_macro_status(&self, pid: MacroPID) -> Option<ExitStatus> {
-        self.exit_status_table.get(&pid).map(|v| v.clone())
+    pub fn is_running(&self, pid: MacroPID) -> bool {
+        self.run_table.contains_key(&pid)
     }
 
     pub async fn get_config_manifest(
@@ -622,6 +763,95 @@ impl MacroExecutor {
     }
 }
 
+/// Only reachable from this machine, on a port picked by the OS
+fn start_inspector_server(
+    debug: MacroDebugOptions,
+) -> Result<(Arc<InspectorServer>, MacroDebugOptions), Error> {
+    let host = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .context("Failed to find a free port for the inspector")?;
+    Ok((Arc::new(InspectorServer::new(host, "Lodestone")), debug))
+}
+
+/// The websocket URL of the macro, once its isolate has registered with the inspector server
+async fn inspector_url(host: SocketAddr) -> Option<String> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct InspectorTarget {
+        web_socket_debugger_url: String,
+    }
+    for _ in 0..10 {
+        if let Ok(response) = reqwest::get(format!("http://{host}/json/list")).await {
+            if let Ok(targets) = response.json::<Vec<InspectorTarget>>().await {
+                if let Some(target) = targets.into_iter().next() {
+                    return Some(target.web_socket_debugger_url);
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    warn!("Inspector at {host} has no macro to debug");
+    None
+}
+
+/// Killed, unless it was for going over a limit
+fn terminated_exit_status(termination_reason: &TerminationReason) -> ExitStatus {
+    match termination_reason.get() {
+        Some(reason) => {
+            warn!("Terminated macro: {reason}");
+            ExitStatus::Error {
+                time: chrono::Utc::now().timestamp(),
+                error_msg: reason.to_string(),
+            }
+        }
+        None => {
+            warn!("User terminated macro execution");
+            ExitStatus::Killed {
+                time: chrono::Utc::now().timestamp(),
+            }
+        }
+    }
+}
+
+/// How a macro that failed with `e` exited
+fn exit_status_of(e: &anyhow::Error, termination_reason: &TerminationReason) -> ExitStatus {
+    if e.to_string() == "Uncaught Error: execution terminated" {
+        terminated_exit_status(termination_reason)
+    } else {
+        ExitStatus::Error {
+            error_msg: e.to_string(),
+            time: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+/// `macros/backup/index.ts` keeps its data in its own directory,
+/// `macros/backup.ts` shares its directory with the other macros so it gets `macros/.data/backup`
+fn macro_data_dir(path_to_main_module: &Path) -> PathBuf {
+    let parent = path_to_main_module.parent().unwrap_or(Path::new(""));
+    if path_to_main_module.file_stem().unwrap_or_default() == "index" {
+        parent.to_path_buf()
+    } else {
+        parent
+            .join(".data")
+            .join(macro_name_of(path_to_main_module))
+    }
+}
+
+/// `macros/backup.ts` and `macros/backup/index.ts` are both the `backup` macro
+fn macro_name_of(path_to_main_module: &Path) -> String {
+    let file_stem = path_to_main_module.file_stem().unwrap_or_default();
+    let name = if file_stem == "index" {
+        path_to_main_module
+            .parent()
+            .and_then(|parent| parent.file_name())
+            .unwrap_or(file_stem)
+    } else {
+        file_stem
+    };
+    name.to_string_lossy().to_string()
+}
+
 ///
 /// extract the class definition and the name of the declared config instance
 /// from the typescript code
@@ -987,12 +1217,22 @@ mod tests {
 
     use super::{TypescriptModuleLoader, WorkerOptionGenerator};
 
+    use std::path::Path;
+    use std::str::FromStr;
+
+    use sqlx::sqlite::SqliteConnectOptions;
+
     use crate::event_broadcaster::EventBroadcaster;
     use crate::events::CausedBy;
     use crate::macro_executor::{
-        extract_config_code, get_config_from_code, parse_config_single, SpawnResult,
+        extract_config_code, get_config_from_code, macro_data_dir, macro_name_of,
+        parse_config_single, MacroExecutor, SpawnResult,
     };
+    use crate::macro_history::{MacroHistory, MacroRunQuery};
+    use crate::macro_limits::MacroLimits;
+    use crate::prelude::init_paths;
     use crate::traits::t_configurable::manifest::ConfigurableValue;
+    use crate::traits::t_macro::ExitStatus;
 
     struct BasicMainWorkerGenerator;
 
@@ -1018,17 +1258,32 @@ mod tests {
             }
         }
     }
+    async fn new_history(temp_dir: &Path) -> MacroHistory {
+        let pool = sqlx::Pool::connect_with(
+            SqliteConnectOptions::from_str(&format!("sqlite://{}/data.db", temp_dir.display()))
+                .unwrap()
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        MacroHistory::new(pool).await.unwrap()
+    }
+
     #[tokio::test]
     async fn basic_execution() {
         // init tracing
         let _ = tracing_subscriber::fmt::try_init();
         let (event_broadcaster, _rx) = EventBroadcaster::new(10);
-        // construct a macro executor
-        let executor =
-            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
-
         // create a temp directory
         let temp_dir = tempdir::TempDir::new("macro_test").unwrap().into_path();
+        init_paths(temp_dir.clone());
+
+        // construct a macro executor
+        let executor = super::MacroExecutor::new(
+            event_broadcaster,
+            new_history(&temp_dir).await,
+            tokio::runtime::Handle::current(),
+        );
 
         // create a macro file
 
@@ -1047,19 +1302,41 @@ mod tests {
 
         let basic_worker_generator = BasicMainWorkerGenerator;
 
-        let SpawnResult { exit_future, .. } = executor
+        let SpawnResult {
+            macro_pid,
+            exit_future,
+            ..
+        } = executor
             .spawn(
                 path_to_macro,
                 Vec::new(),
                 CausedBy::Unknown,
                 Box::new(basic_worker_generator),
                 None,
+                MacroExecutor::default_sandbox(),
                 None,
                 None,
             )
             .await
             .unwrap();
         exit_future.await.unwrap();
+
+        // the exit status is recorded in the background
+        let mut runs = Vec::new();
+        for _ in 0..50 {
+            runs = executor
+                .history()
+                .list(None, MacroRunQuery::default())
+                .await
+                .unwrap();
+            if runs[0].exit_status.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(runs[0].name, "test");
+        assert!(runs[0].exit_status.as_ref().unwrap().is_success());
+        assert!(!executor.is_running(macro_pid));
     }
 
     #[tokio::test]
@@ -1067,12 +1344,16 @@ mod tests {
         let _ = tracing_subscriber::fmt::try_init();
 
         let (event_broadcaster, _rx) = EventBroadcaster::new(10);
-        // construct a macro executor
-        let executor =
-            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
-
         // create a temp directory
         let temp_dir = tempdir::TempDir::new("macro_test").unwrap().into_path();
+        init_paths(temp_dir.clone());
+
+        // construct a macro executor
+        let executor = super::MacroExecutor::new(
+            event_broadcaster,
+            new_history(&temp_dir).await,
+            tokio::runtime::Handle::current(),
+        );
 
         // create a macro file
 
@@ -1096,6 +1377,7 @@ mod tests {
                 CausedBy::Unknown,
                 Box::new(basic_worker_generator),
                 None,
+                MacroExecutor::default_sandbox(),
                 None,
                 None,
             )
@@ -1104,6 +1386,74 @@ mod tests {
         exit_future.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_timeout_while_waiting() {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let temp_dir = tempdir::TempDir::new("macro_test").unwrap().into_path();
+        init_paths(temp_dir.clone());
+        let executor = super::MacroExecutor::new(
+            event_broadcaster,
+            new_history(&temp_dir).await,
+            tokio::runtime::Handle::current(),
+        );
+        executor.set_default_limits(MacroLimits {
+            timeout_secs: Some(1),
+            ..MacroLimits::global_default()
+        });
+
+        // parked on a timer, so terminating the isolate alone never stops it
+        let path_to_macro = temp_dir.join("test.ts");
+        std::fs::write(
+            &path_to_macro,
+            "await new Promise((resolve) => setTimeout(resolve, 60_000));",
+        )
+        .unwrap();
+
+        let SpawnResult { exit_future, .. } = executor
+            .spawn(
+                path_to_macro,
+                Vec::new(),
+                CausedBy::Unknown,
+                Box::new(BasicMainWorkerGenerator),
+                None,
+                MacroExecutor::default_sandbox(),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let exit_status = tokio::time::timeout(std::time::Duration::from_secs(10), exit_future)
+            .await
+            .expect("the macro should time out")
+            .unwrap();
+        assert!(matches!(
+            exit_status,
+            ExitStatus::Error { error_msg, .. } if error_msg.contains("timed out")
+        ));
+    }
+
+    #[test]
+    fn test_macro_name() {
+        assert_eq!(macro_name_of(Path::new("/macros/backup.ts")), "backup");
+        assert_eq!(
+            macro_name_of(Path::new("/macros/backup/index.ts")),
+            "backup"
+        );
+    }
+
+    #[test]
+    fn test_macro_data_dir() {
+        assert_eq!(
+            macro_data_dir(Path::new("/macros/backup.ts")),
+            Path::new("/macros/.data/backup")
+        );
+        assert_eq!(
+            macro_data_dir(Path::new("/macros/backup/index.ts")),
+            Path::new("/macros/backup")
+        );
+    }
+
     #[test]
     fn test_macro_config_extraction() {
         // should return None if no there is no config definition
@@ -0,0 +1,645 @@
+//! Every macro run with its console output, kept in sqlite so it survives restarts.
+//!
+//! The schema is in `migrations/2026-10-18.sql`.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use tokio::sync::mpsc;
+use tracing::error;
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    macro_executor::MacroPID,
+    traits::t_macro::ExitStatus,
+    types::{InstanceUuid, Snowflake},
+};
+
+/// Runs and log lines are listed in pages of at most this many
+const MAX_PAGE_SIZE: u32 = 200;
+/// Log lines are written in batches of at most this many
+const LOG_BATCH_SIZE: usize = 256;
+/// Log lines waiting to be written, lines past this are dropped until the writer catches up
+const LOG_CHANNEL_CAPACITY: usize = 4096;
+/// Lines kept of each run, the rest of its output is dropped
+const MAX_LOG_LINES_PER_RUN: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MacroRun {
+    pub id: Snowflake,
+    pub pid: MacroPID,
+    pub instance_uuid: Option<InstanceUuid>,
+    pub name: String,
+    pub args: Vec<String>,
+    pub caused_by: CausedBy,
+    pub start_time: i64,
+    pub end_time: Option<i64>,
+    /// `None` while the macro is running
+    pub exit_status: Option<ExitStatus>,
+    /// CPU time the macro used, only measured on Linux and Windows
+    pub cpu_time_ms: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MacroRunLogLine {
+    /// Pass as `after` to get the lines following this one
+    pub id: i64,
+    pub time: i64,
+    pub line: String,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, TS)]
+#[ts(export)]
+pub struct MacroRunQuery {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Only runs older than this one, for the next page
+    #[serde(default)]
+    pub before: Option<Snowflake>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct MacroRunRetention {
+    /// Runs that ended longer ago than this are deleted
+    pub max_age_days: Option<u32>,
+    /// Only the latest runs of each instance are kept
+    pub max_runs_per_instance: Option<u32>,
+}
+
+impl Default for MacroRunRetention {
+    fn default() -> Self {
+        Self {
+            max_age_days: Some(30),
+            max_runs_per_instance: Some(1000),
+        }
+    }
+}
+
+struct MacroRunRow {
+    id: Snowflake,
+    pid: i64,
+    instance_id: Option<InstanceUuid>,
+    name: String,
+    args: String,
+    caused_by: String,
+    start_time: i64,
+    end_time: Option<i64>,
+    exit_status: Option<String>,
+    cpu_time_ms: Option<i64>,
+}
+
+impl TryFrom<MacroRunRow> for MacroRun {
+    type Error = Error;
+
+    fn try_from(row: MacroRunRow) -> Result<Self, Error> {
+        Ok(MacroRun {
+            id: row.id,
+            pid: MacroPID(row.pid as usize),
+            instance_uuid: row.instance_id,
+            name: row.name,
+            args: serde_json::from_str(&row.args).context("Failed to parse macro run args")?,
+            caused_by: serde_json::from_str(&row.caused_by)
+                .context("Failed to parse macro run caused_by")?,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            exit_status: row
+                .exit_status
+                .map(|exit_status| serde_json::from_str(&exit_status))
+                .transpose()
+                .context("Failed to parse macro run exit status")?,
+            cpu_time_ms: row.cpu_time_ms,
+        })
+    }
+}
+
+struct LogLine {
+    run_id: Snowflake,
+    time: i64,
+    line: String,
+}
+
+/// Console output of one run, kept in the macro's op state
+#[derive(Clone)]
+pub struct MacroRunLogger {
+    run_id: Snowflake,
+    log_tx: mpsc::Sender<LogLine>,
+    /// Lines logged so far, including dropped ones
+    lines: Arc<AtomicUsize>,
+    /// Lines dropped because the writer fell behind, noted in the log once it catches up
+    dropped: Arc<AtomicUsize>,
+}
+
+impl MacroRunLogger {
+    /// Past [`MAX_LOG_LINES_PER_RUN`] lines, the log is marked as truncated and the rest is dropped
+    pub fn log(&self, line: String) {
+        let lines = self.lines.fetch_add(1, Ordering::Relaxed);
+        let line = match lines.cmp(&MAX_LOG_LINES_PER_RUN) {
+            std::cmp::Ordering::Less => line,
+            std::cmp::Ordering::Equal => {
+                format!("[Log truncated, only the first {MAX_LOG_LINES_PER_RUN} lines are kept]")
+            }
+            std::cmp::Ordering::Greater => return,
+        };
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 && !self.send(format!("[Dropped {dropped} lines logged too quickly]")) {
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        }
+        if !self.send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn send(&self, line: String) -> bool {
+        self.log_tx
+            .try_send(LogLine {
+                run_id: self.run_id,
+                time: chrono::Utc::now().timestamp(),
+                line,
+            })
+            .is_ok()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MacroHistory {
+    pool: SqlitePool,
+    log_tx: mpsc::Sender<LogLine>,
+}
+
+impl MacroHistory {
+    /// Runs left unfinished by the last shutdown are marked as failed
+    pub async fn new(pool: SqlitePool) -> Result<Self, Error> {
+        init_macro_run_tables(&pool).await?;
+        let exit_status = serde_json::to_string(&ExitStatus::Error {
+            time: chrono::Utc::now().timestamp(),
+            error_msg: "Lodestone Core stopped while the macro was running".to_string(),
+        })
+        .context("Failed to serialize exit status")?;
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query!(
+            r#"UPDATE MacroRuns SET end_time = ?1, exit_status = ?2 WHERE end_time IS NULL"#,
+            now,
+            exit_status,
+        )
+        .execute(&pool)
+        .await
+        .context("Failed to mark interrupted macro runs")?;
+
+        let (log_tx, log_rx) = mpsc::channel(LOG_CHANNEL_CAPACITY);
+        tokio::spawn(write_logs_task(pool.clone(), log_rx));
+        Ok(Self { pool, log_tx })
+    }
+
+    pub async fn start(&self, run: &MacroRun) -> Result<(), Error> {
+        let pid = run.pid.0 as i64;
+        let args = serde_json::to_string(&run.args).context("Failed to serialize args")?;
+        let caused_by =
+            serde_json::to_string(&run.caused_by).context("Failed to serialize caused_by")?;
+        sqlx::query!(
+            r#"
+INSERT INTO MacroRuns
+(id, pid, instance_id, name, args, caused_by, start_time)
+VALUES
+(?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#,
+            run.id,
+            pid,
+            run.instance_uuid,
+            run.name,
+            args,
+            caused_by,
+            run.start_time,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to record macro run")?;
+        Ok(())
+    }
+
+    pub async fn finish(&self, run_id: Snowflake, exit_status: &ExitStatus) -> Result<(), Error> {
+        let end_time = exit_status.time();
+        let exit_status =
+            serde_json::to_string(exit_status).context("Failed to serialize exit status")?;
+        sqlx::query!(
+            r#"UPDATE MacroRuns SET end_time = ?1, exit_status = ?2 WHERE id = ?3"#,
+            end_time,
+            exit_status,
+            run_id,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to record macro exit status")?;
+        Ok(())
+    }
+
+    pub async fn record_cpu_time(
+        &self,
+        run_id: Snowflake,
+        cpu_time: std::time::Duration,
+    ) -> Result<(), Error> {
+        let cpu_time_ms = cpu_time.as_millis() as i64;
+        sqlx::query!(
+            r#"UPDATE MacroRuns SET cpu_time_ms = ?1 WHERE id = ?2"#,
+            cpu_time_ms,
+            run_id,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to record macro CPU time")?;
+        Ok(())
+    }
+
+    pub fn logger(&self, run_id: Snowflake) -> MacroRunLogger {
+        MacroRunLogger {
+            run_id,
+            log_tx: self.log_tx.clone(),
+            lines: Arc::new(AtomicUsize::new(0)),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Newest first
+    pub async fn list(
+        &self,
+        instance_uuid: Option<&InstanceUuid>,
+        query: MacroRunQuery,
+    ) -> Result<Vec<MacroRun>, Error> {
+        let limit = query.limit.unwrap_or(50).min(MAX_PAGE_SIZE);
+        let rows = sqlx::query_as!(
+            MacroRunRow,
+            r#"
+SELECT
+id as "id!: Snowflake", pid as "pid!", instance_id as "instance_id?: InstanceUuid",
+name as "name!", args as "args!", caused_by as "caused_by!", start_time as "start_time!",
+end_time as "end_time?", exit_status as "exit_status?", cpu_time_ms as "cpu_time_ms?"
+FROM MacroRuns
+WHERE (?1 IS NULL OR instance_id = ?1)
+AND (?2 IS NULL OR name = ?2)
+AND (?3 IS NULL OR id < ?3)
+ORDER BY id DESC
+LIMIT ?4"#,
+            instance_uuid,
+            query.name,
+            query.before,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch macro runs")?;
+        rows.into_iter().map(MacroRun::try_from).collect()
+    }
+
+    pub async fn get(&self, run_id: Snowflake) -> Result<MacroRun, Error> {
+        sqlx::query_as!(
+            MacroRunRow,
+            r#"
+SELECT
+id as "id!: Snowflake", pid as "pid!", instance_id as "instance_id?: InstanceUuid",
+name as "name!", args as "args!", caused_by as "caused_by!", start_time as "start_time!",
+end_time as "end_time?", exit_status as "exit_status?", cpu_time_ms as "cpu_time_ms?"
+FROM MacroRuns
+WHERE id = ?1"#,
+            run_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Failed to fetch macro run")?
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Macro run {} not found", run_id.to_string()),
+        })?
+        .try_into()
+    }
+
+    /// The run's console output in the order it was emitted, starting after the line `after`
+    pub async fn logs(
+        &self,
+        run_id: Snowflake,
+        after: Option<i64>,
+        limit: Option<u32>,
+    ) -> Result<Vec<MacroRunLogLine>, Error> {
+        let after = after.unwrap_or(0);
+        let limit = limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        Ok(sqlx::query_as!(
+            MacroRunLogLine,
+            r#"
+SELECT id as "id!", time as "time!", line as "line!"
+FROM MacroRunLogs
+WHERE run_id = ?1 AND id > ?2
+ORDER BY id
+LIMIT ?3"#,
+            run_id,
+            after,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch macro run logs")?)
+    }
+
+    /// Deletes finished runs the retention policy doesn't keep, with their logs.
+    /// Returns how many runs were deleted
+    pub async fn prune(&self, retention: &MacroRunRetention) -> Result<u64, Error> {
+        let mut deleted = 0;
+        if let Some(max_age_days) = retention.max_age_days {
+            let cutoff = chrono::Utc::now().timestamp() - max_age_days as i64 * 24 * 60 * 60;
+            deleted += sqlx::query!(
+                r#"DELETE FROM MacroRuns WHERE end_time IS NOT NULL AND end_time < ?1"#,
+                cutoff,
+            )
+            .execute(&self.pool)
+            .await
+            .context("Failed to prune macro runs")?
+            .rows_affected();
+        }
+        if let Some(max_runs_per_instance) = retention.max_runs_per_instance {
+            deleted += sqlx::query!(
+                r#"
+DELETE FROM MacroRuns
+WHERE end_time IS NOT NULL AND id IN (
+    SELECT id FROM (
+        SELECT id, ROW_NUMBER() OVER (PARTITION BY instance_id ORDER BY id DESC) AS row_rank
+        FROM MacroRuns
+    )
+    WHERE row_rank > ?1
+)"#,
+                max_runs_per_instance,
+            )
+            .execute(&self.pool)
+            .await
+            .context("Failed to prune macro runs")?
+            .rows_affected();
+        }
+        sqlx::query!(r#"DELETE FROM MacroRunLogs WHERE run_id NOT IN (SELECT id FROM MacroRuns)"#)
+            .execute(&self.pool)
+            .await
+            .context("Failed to prune macro run logs")?;
+        Ok(deleted)
+    }
+}
+
+async fn write_logs_task(pool: SqlitePool, mut log_rx: mpsc::Receiver<LogLine>) {
+    while let Some(line) = log_rx.recv().await {
+        let mut batch = vec![line];
+        while batch.len() < LOG_BATCH_SIZE {
+            match log_rx.try_recv() {
+                Ok(line) => batch.push(line),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = write_logs(&pool, batch).await {
+            error!("Failed to write macro run logs: {}", e);
+        }
+    }
+}
+
+async fn write_logs(pool: &SqlitePool, batch: Vec<LogLine>) -> Result<(), Error> {
+    let mut transaction = pool.begin().await.context("Failed to begin transaction")?;
+    for LogLine { run_id, time, line } in batch {
+        sqlx::query!(
+            r#"INSERT INTO MacroRunLogs (run_id, time, line) VALUES (?1, ?2, ?3)"#,
+            run_id,
+            time,
+            line,
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to write macro run log")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit macro run logs")?;
+    Ok(())
+}
+
+pub async fn init_macro_run_tables(pool: &SqlitePool) -> Result<(), Error> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .context("Failed to aquire db connection")?;
+
+    sqlx::query!(
+        r#"
+        CREATE TABLE IF NOT EXISTS MacroRuns (
+            id                  BIGINT      PRIMARY KEY     NOT NULL,
+            pid                 BIGINT      NOT NULL,
+            instance_id         TEXT,
+            name                TEXT        NOT NULL,
+            args                TEXT        NOT NULL,
+            caused_by           TEXT        NOT NULL,
+            start_time          BIGINT      NOT NULL,
+            end_time            BIGINT,
+            exit_status         TEXT,
+            cpu_time_ms         BIGINT
+        );
+        "#
+    )
+    .execute(&mut connection)
+    .await
+    .context("Failed to create table")?;
+
+    sqlx::query!(
+        r#"
+        CREATE TABLE IF NOT EXISTS MacroRunLogs (
+            id                  INTEGER     PRIMARY KEY     AUTOINCREMENT,
+            run_id              BIGINT      NOT NULL,
+            time                BIGINT      NOT NULL,
+            line                TEXT        NOT NULL
+        );
+        "#
+    )
+    .execute(&mut connection)
+    .await
+    .context("Failed to create table")?;
+
+    sqlx::query!(r#"CREATE INDEX IF NOT EXISTS MacroRunLogsRunId ON MacroRunLogs (run_id);"#)
+        .execute(&mut connection)
+        .await
+        .context("Failed to create index")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use sqlx::{sqlite::SqliteConnectOptions, Pool};
+
+    use super::*;
+
+    fn new_run(instance_uuid: &InstanceUuid, name: &str) -> MacroRun {
+        MacroRun {
+            id: Snowflake::new(),
+            pid: MacroPID(0),
+            instance_uuid: Some(instance_uuid.clone()),
+            name: name.to_string(),
+            args: vec!["--verbose".to_string()],
+            caused_by: CausedBy::System,
+            start_time: chrono::Utc::now().timestamp(),
+            end_time: None,
+            exit_status: None,
+            cpu_time_ms: None,
+        }
+    }
+
+    fn test_logger(capacity: usize) -> (MacroRunLogger, mpsc::Receiver<LogLine>) {
+        let (log_tx, log_rx) = mpsc::channel(capacity);
+        let logger = MacroRunLogger {
+            run_id: Snowflake::new(),
+            log_tx,
+            lines: Arc::new(AtomicUsize::new(0)),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        };
+        (logger, log_rx)
+    }
+
+    fn drain(log_rx: &mut mpsc::Receiver<LogLine>) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(LogLine { line, .. }) = log_rx.try_recv() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn test_logger_limits() {
+        // the log is marked as truncated past the line cap
+        let (logger, mut log_rx) = test_logger(MAX_LOG_LINES_PER_RUN + 10);
+        for i in 0..MAX_LOG_LINES_PER_RUN + 5 {
+            logger.log(format!("line {i}"));
+        }
+        let lines = drain(&mut log_rx);
+        assert_eq!(lines.len(), MAX_LOG_LINES_PER_RUN + 1);
+        assert!(lines.last().unwrap().starts_with("[Log truncated"));
+
+        // lines dropped while the channel is full are noted once there is room
+        let (logger, mut log_rx) = test_logger(2);
+        for line in ["first", "second", "dropped"] {
+            logger.log(line.to_string());
+        }
+        assert_eq!(drain(&mut log_rx), vec!["first", "second"]);
+        logger.log("third".to_string());
+        assert_eq!(
+            drain(&mut log_rx),
+            vec!["[Dropped 1 lines logged too quickly]", "third"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_macro_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let pool = Pool::connect_with(
+            SqliteConnectOptions::from_str(&format!(
+                "sqlite://{}/data.db",
+                temp_dir.path().display()
+            ))
+            .unwrap()
+            .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        let history = MacroHistory::new(pool.clone()).await.unwrap();
+        let instance_uuid = InstanceUuid::default();
+
+        let backup = new_run(&instance_uuid, "backup");
+        history.start(&backup).await.unwrap();
+        let logger = history.logger(backup.id);
+        for i in 0..3 {
+            logger.log(format!("line {i}"));
+        }
+        let exit_status = ExitStatus::Success {
+            time: chrono::Utc::now().timestamp(),
+        };
+        history.finish(backup.id, &exit_status).await.unwrap();
+        let restart = new_run(&instance_uuid, "restart");
+        history.start(&restart).await.unwrap();
+
+        let run = history.get(backup.id).await.unwrap();
+        assert_eq!(run.args, backup.args);
+        assert_eq!(run.exit_status, Some(exit_status));
+
+        // newest first, paged with `before`
+        let page = history
+            .list(
+                Some(&instance_uuid),
+                MacroRunQuery {
+                    limit: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, restart.id);
+        assert_eq!(page[0].exit_status, None);
+        let page = history
+            .list(
+                Some(&instance_uuid),
+                MacroRunQuery {
+                    before: Some(restart.id),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, backup.id);
+
+        // logs are written in the background
+        let mut logs = Vec::new();
+        for _ in 0..50 {
+            logs = history.logs(backup.id, None, None).await.unwrap();
+            if logs.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            logs.iter().map(|l| l.line.as_str()).collect::<Vec<_>>(),
+            vec!["line 0", "line 1", "line 2"]
+        );
+        let rest = history
+            .logs(backup.id, Some(logs[0].id), None)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 2);
+
+        // the running macro is never pruned
+        let deleted = history
+            .prune(&MacroRunRetention {
+                max_age_days: None,
+                max_runs_per_instance: Some(0),
+            })
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+        assert!(history.get(backup.id).await.is_err());
+        assert!(history
+            .logs(backup.id, None, None)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(history.get(restart.id).await.is_ok());
+
+        // a restart marks it as interrupted
+        let history = MacroHistory::new(pool).await.unwrap();
+        assert!(matches!(
+            history.get(restart.id).await.unwrap().exit_status,
+            Some(ExitStatus::Error { .. })
+        ));
+    }
+}
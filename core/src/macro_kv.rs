@@ -0,0 +1,532 @@
+//! Persistent key-value storage for macros, so they can keep state between runs.
+//!
+//! Every macro gets its own namespace per instance, and can't see the keys of other macros.
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::sqlite::SqlitePool;
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    types::InstanceUuid,
+};
+
+pub const MAX_KEY_LENGTH: usize = 512;
+/// Size of a value as JSON
+pub const MAX_VALUE_SIZE: usize = 1024 * 1024;
+pub const MAX_NAMESPACE_KEYS: i64 = 10_000;
+/// Size of all keys and values of a namespace
+pub const MAX_NAMESPACE_SIZE: i64 = 16 * 1024 * 1024;
+const MAX_LIST_LIMIT: u32 = 1000;
+
+/// The keys of one macro of one instance
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KvNamespace {
+    /// Empty for macros not running for an instance
+    instance_id: String,
+    macro_name: String,
+}
+
+impl KvNamespace {
+    pub fn new(instance_uuid: Option<&InstanceUuid>, macro_name: &str) -> Self {
+        Self {
+            instance_id: instance_uuid
+                .map(|uuid| uuid.to_string())
+                .unwrap_or_default(),
+            macro_name: macro_name.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct KvEntry {
+    pub key: String,
+    #[ts(type = "unknown")]
+    pub value: Value,
+    /// Increases on every write, for compare-and-swap.
+    /// Never reused within a namespace, even after the key is deleted
+    #[ts(type = "number")]
+    pub version: i64,
+    #[ts(type = "number")]
+    pub update_time: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct KvUsage {
+    pub keys: i64,
+    pub size: i64,
+    pub max_keys: i64,
+    pub max_size: i64,
+}
+
+struct KvRow {
+    key: String,
+    value: String,
+    version: i64,
+    update_time: i64,
+}
+
+impl TryFrom<KvRow> for KvEntry {
+    type Error = Error;
+
+    fn try_from(row: KvRow) -> Result<Self, Error> {
+        Ok(KvEntry {
+            value: serde_json::from_str(&row.value)
+                .context(format!("Failed to parse value of {}", row.key))?,
+            key: row.key,
+            version: row.version,
+            update_time: row.update_time,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MacroKvStore {
+    pool: SqlitePool,
+}
+
+impl MacroKvStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, Error> {
+        init_macro_kv_table(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    pub async fn get(&self, namespace: &KvNamespace, key: &str) -> Result<Option<KvEntry>, Error> {
+        sqlx::query_as!(
+            KvRow,
+            r#"
+SELECT key as "key!", value as "value!", version as "version!", update_time as "update_time!"
+FROM MacroKv
+WHERE instance_id = ?1 AND macro_name = ?2 AND key = ?3"#,
+            namespace.instance_id,
+            namespace.macro_name,
+            key,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Failed to fetch key")?
+        .map(KvEntry::try_from)
+        .transpose()
+    }
+
+    /// Keys starting with `prefix`, in order
+    pub async fn list(
+        &self,
+        namespace: &KvNamespace,
+        prefix: &str,
+        limit: Option<u32>,
+    ) -> Result<Vec<KvEntry>, Error> {
+        let limit = limit.unwrap_or(MAX_LIST_LIMIT).min(MAX_LIST_LIMIT);
+        sqlx::query_as!(
+            KvRow,
+            r#"
+SELECT key as "key!", value as "value!", version as "version!", update_time as "update_time!"
+FROM MacroKv
+WHERE instance_id = ?1 AND macro_name = ?2 AND substr(key, 1, length(?3)) = ?3
+ORDER BY key
+LIMIT ?4"#,
+            namespace.instance_id,
+            namespace.macro_name,
+            prefix,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to list keys")?
+        .into_iter()
+        .map(KvEntry::try_from)
+        .collect()
+    }
+
+    pub async fn usage(&self, namespace: &KvNamespace) -> Result<KvUsage, Error> {
+        let row = sqlx::query!(
+            r#"
+SELECT COUNT(*) as "keys!: i64", COALESCE(SUM(length(key) + length(value)), 0) as "size!: i64"
+FROM MacroKv
+WHERE instance_id = ?1 AND macro_name = ?2"#,
+            namespace.instance_id,
+            namespace.macro_name,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Failed to get key-value store usage")?;
+        Ok(KvUsage {
+            keys: row.keys,
+            size: row.size,
+            max_keys: MAX_NAMESPACE_KEYS,
+            max_size: MAX_NAMESPACE_SIZE,
+        })
+    }
+
+    /// Returns the new version of the key
+    pub async fn set(
+        &self,
+        namespace: &KvNamespace,
+        key: &str,
+        value: &Value,
+    ) -> Result<i64, Error> {
+        self.write(namespace, key, value, None)
+            .await
+            .map(|version| version.expect("an unconditional write always succeeds"))
+    }
+
+    pub async fn delete(&self, namespace: &KvNamespace, key: &str) -> Result<bool, Error> {
+        Ok(sqlx::query!(
+            r#"DELETE FROM MacroKv WHERE instance_id = ?1 AND macro_name = ?2 AND key = ?3"#,
+            namespace.instance_id,
+            namespace.macro_name,
+            key,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to delete key")?
+        .rows_affected()
+            > 0)
+    }
+
+    /// Writes `value`, or deletes the key if it is `None`, only if the key is still at
+    /// `expected_version`. A `None` version expects the key to not exist.
+    ///
+    /// Returns whether the swap happened
+    pub async fn compare_and_swap(
+        &self,
+        namespace: &KvNamespace,
+        key: &str,
+        expected_version: Option<i64>,
+        value: Option<&Value>,
+    ) -> Result<bool, Error> {
+        match (value, expected_version) {
+            (Some(value), _) => Ok(self
+                .write(namespace, key, value, Some(expected_version))
+                .await?
+                .is_some()),
+            (None, Some(expected_version)) => Ok(sqlx::query!(
+                r#"
+DELETE FROM MacroKv
+WHERE instance_id = ?1 AND macro_name = ?2 AND key = ?3 AND version = ?4"#,
+                namespace.instance_id,
+                namespace.macro_name,
+                key,
+                expected_version,
+            )
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete key")?
+            .rows_affected()
+                > 0),
+            // deleting a key that must not exist
+            (None, None) => Ok(self.get(namespace, key).await?.is_none()),
+        }
+    }
+
+    /// Deletes every key of the macros of an instance
+    pub async fn clear_instance(&self, instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        let instance_id = instance_uuid.to_string();
+        sqlx::query!(r#"DELETE FROM MacroKv WHERE instance_id = ?1"#, instance_id)
+            .execute(&self.pool)
+            .await
+            .context("Failed to clear key-value store")?;
+        // the instance is gone for good, its namespaces won't be written again
+        sqlx::query!(
+            r#"DELETE FROM MacroKvVersions WHERE instance_id = ?1"#,
+            instance_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to clear key-value store versions")?;
+        Ok(())
+    }
+
+    /// With `condition` set, only writes if the key is at that version, see [`Self::compare_and_swap`].
+    /// Returns the new version if it was written
+    async fn write(
+        &self,
+        namespace: &KvNamespace,
+        key: &str,
+        value: &Value,
+        condition: Option<Option<i64>>,
+    ) -> Result<Option<i64>, Error> {
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Keys must be between 1 and {MAX_KEY_LENGTH} bytes long"),
+            });
+        }
+        let value = serde_json::to_string(value).context("Failed to serialize value")?;
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Values can't be larger than {MAX_VALUE_SIZE} bytes"),
+            });
+        }
+
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to begin transaction")?;
+        let current = sqlx::query!(
+            r#"
+SELECT version as "version!", length(key) + length(value) as "size!: i64"
+FROM MacroKv
+WHERE instance_id = ?1 AND macro_name = ?2 AND key = ?3"#,
+            namespace.instance_id,
+            namespace.macro_name,
+            key,
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .context("Failed to fetch key")?;
+        if let Some(expected_version) = condition {
+            if current.as_ref().map(|current| current.version) != expected_version {
+                return Ok(None);
+            }
+        }
+
+        let usage = sqlx::query!(
+            r#"
+SELECT COUNT(*) as "keys!: i64", COALESCE(SUM(length(key) + length(value)), 0) as "size!: i64"
+FROM MacroKv
+WHERE instance_id = ?1 AND macro_name = ?2"#,
+            namespace.instance_id,
+            namespace.macro_name,
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .context("Failed to get key-value store usage")?;
+        let (keys, size) = match &current {
+            Some(current) => (usage.keys, usage.size - current.size),
+            None => (usage.keys + 1, usage.size),
+        };
+        if keys > MAX_NAMESPACE_KEYS {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Macro can't store more than {MAX_NAMESPACE_KEYS} keys"),
+            });
+        }
+        if size + (key.len() + value.len()) as i64 > MAX_NAMESPACE_SIZE {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Macro can't store more than {MAX_NAMESPACE_SIZE} bytes"),
+            });
+        }
+
+        // versions come from a counter of the namespace, so a key deleted and written again
+        // doesn't go back to a version a stale compare-and-swap could still expect
+        sqlx::query!(
+            r#"
+INSERT INTO MacroKvVersions (instance_id, macro_name, last_version)
+VALUES (
+    ?1,
+    ?2,
+    COALESCE((SELECT MAX(version) FROM MacroKv WHERE instance_id = ?1 AND macro_name = ?2), 0) + 1
+)
+ON CONFLICT (instance_id, macro_name) DO UPDATE SET last_version = last_version + 1"#,
+            namespace.instance_id,
+            namespace.macro_name,
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to increment version")?;
+        let version = sqlx::query!(
+            r#"
+SELECT last_version as "last_version!"
+FROM MacroKvVersions
+WHERE instance_id = ?1 AND macro_name = ?2"#,
+            namespace.instance_id,
+            namespace.macro_name,
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .context("Failed to fetch version")?
+        .last_version;
+        let update_time = chrono::Utc::now().timestamp();
+        sqlx::query!(
+            r#"
+INSERT INTO MacroKv (instance_id, macro_name, key, value, version, update_time)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (instance_id, macro_name, key)
+DO UPDATE SET value = excluded.value, version = excluded.version, update_time = excluded.update_time"#,
+            namespace.instance_id,
+            namespace.macro_name,
+            key,
+            value,
+            version,
+            update_time,
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to write key")?;
+        transaction.commit().await.context("Failed to commit key")?;
+        Ok(Some(version))
+    }
+}
+
+pub async fn init_macro_kv_table(pool: &SqlitePool) -> Result<(), Error> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .context("Failed to aquire db connection")?;
+
+    sqlx::query!(
+        r#"
+        CREATE TABLE IF NOT EXISTS MacroKv (
+            instance_id         TEXT        NOT NULL,
+            macro_name          TEXT        NOT NULL,
+            key                 TEXT        NOT NULL,
+            value               TEXT        NOT NULL,
+            version             BIGINT      NOT NULL,
+            update_time         BIGINT      NOT NULL,
+            PRIMARY KEY (instance_id, macro_name, key)
+        );
+        "#
+    )
+    .execute(&mut connection)
+    .await
+    .context("Failed to create table")?;
+
+    sqlx::query!(
+        r#"
+        CREATE TABLE IF NOT EXISTS MacroKvVersions (
+            instance_id         TEXT        NOT NULL,
+            macro_name          TEXT        NOT NULL,
+            last_version        BIGINT      NOT NULL,
+            PRIMARY KEY (instance_id, macro_name)
+        );
+        "#
+    )
+    .execute(&mut connection)
+    .await
+    .context("Failed to create table")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+    use sqlx::{sqlite::SqliteConnectOptions, Pool};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_macro_kv_store() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let pool = Pool::connect_with(
+            SqliteConnectOptions::from_str(&format!(
+                "sqlite://{}/data.db",
+                temp_dir.path().display()
+            ))
+            .unwrap()
+            .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        let store = MacroKvStore::new(pool).await.unwrap();
+        let instance_uuid = InstanceUuid::default();
+        let backup = KvNamespace::new(Some(&instance_uuid), "backup");
+        let warnings = KvNamespace::new(Some(&instance_uuid), "warnings");
+
+        assert_eq!(
+            store.set(&backup, "last_backup", &json!(1)).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            store.set(&backup, "last_backup", &json!(2)).await.unwrap(),
+            2
+        );
+        store
+            .set(&warnings, "player/steve", &json!({"count": 1}))
+            .await
+            .unwrap();
+        store
+            .set(&warnings, "player/alex", &json!({"count": 3}))
+            .await
+            .unwrap();
+        store.set(&warnings, "total", &json!(4)).await.unwrap();
+
+        // namespaces don't see each other
+        assert_eq!(
+            store
+                .get(&backup, "last_backup")
+                .await
+                .unwrap()
+                .unwrap()
+                .value,
+            json!(2)
+        );
+        assert!(store.get(&warnings, "last_backup").await.unwrap().is_none());
+
+        let players = store.list(&warnings, "player/", None).await.unwrap();
+        assert_eq!(
+            players.iter().map(|e| e.key.as_str()).collect::<Vec<_>>(),
+            vec!["player/alex", "player/steve"]
+        );
+        assert_eq!(store.usage(&warnings).await.unwrap().keys, 3);
+
+        // compare-and-swap only goes through at the expected version
+        assert!(!store
+            .compare_and_swap(&backup, "last_backup", Some(1), Some(&json!(3)))
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap(&backup, "last_backup", Some(2), Some(&json!(3)))
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_swap(&backup, "last_backup", None, Some(&json!(4)))
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_swap(&backup, "lock", None, Some(&json!(true)))
+            .await
+            .unwrap());
+        // versions are counted per namespace, not per key
+        let lock_version = store.get(&backup, "lock").await.unwrap().unwrap().version;
+        assert_eq!(lock_version, 4);
+        assert!(store
+            .compare_and_swap(&backup, "lock", Some(lock_version), None)
+            .await
+            .unwrap());
+        assert!(store.get(&backup, "lock").await.unwrap().is_none());
+
+        assert!(store.delete(&backup, "last_backup").await.unwrap());
+        assert!(!store.delete(&backup, "last_backup").await.unwrap());
+
+        // a recreated key doesn't reuse the versions it had before it was deleted
+        assert!(store
+            .compare_and_swap(&backup, "lock", None, Some(&json!(true)))
+            .await
+            .unwrap());
+        let stale_version = store.get(&backup, "lock").await.unwrap().unwrap().version;
+        assert!(store.delete(&backup, "lock").await.unwrap());
+        let version = store.set(&backup, "lock", &json!(false)).await.unwrap();
+        assert!(version > stale_version);
+        assert!(!store
+            .compare_and_swap(&backup, "lock", Some(stale_version), Some(&json!(true)))
+            .await
+            .unwrap());
+        assert_eq!(
+            store.get(&backup, "lock").await.unwrap().unwrap().value,
+            json!(false)
+        );
+        assert!(store
+            .compare_and_swap(&backup, "lock", Some(version), None)
+            .await
+            .unwrap());
+
+        // quotas
+        assert!(store.set(&backup, "", &json!(1)).await.is_err());
+        let too_large = json!("a".repeat(MAX_VALUE_SIZE));
+        assert!(store.set(&backup, "large", &too_large).await.is_err());
+
+        store.clear_instance(&instance_uuid).await.unwrap();
+        assert_eq!(store.usage(&warnings).await.unwrap().keys, 0);
+    }
+}
@@ -0,0 +1,339 @@
+//! Resource limits of macros, so a runaway macro can't take the rest of the core down with it.
+//!
+//! A macro's own limits are in `<main>.limits.json` next to its main module. The global limits
+//! are a ceiling, a macro's own limits can only be lower.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::Context;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::{error::Error, macro_executor::MacroPID};
+
+/// How often the watchdog checks a running macro
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, TS)]
+#[ts(export)]
+pub struct MacroLimits {
+    /// Size of the V8 heap, the macro is terminated when it runs out
+    #[serde(default)]
+    pub max_heap_mb: Option<u32>,
+    /// How long the macro may run for
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// How much CPU time the macro may use, only enforced on Linux and Windows
+    #[serde(default)]
+    pub max_cpu_secs: Option<u64>,
+}
+
+impl MacroLimits {
+    pub fn global_default() -> Self {
+        Self {
+            max_heap_mb: Some(512),
+            timeout_secs: None,
+            max_cpu_secs: None,
+        }
+    }
+
+    /// `macros/backup.ts` is limited by `macros/backup.limits.json`
+    pub fn path_for(main_module: &Path) -> PathBuf {
+        main_module.with_extension("limits.json")
+    }
+
+    /// The macro's own limits, none if it has no limits file
+    pub fn load(main_module: &Path) -> Result<Self, Error> {
+        let path = Self::path_for(main_module);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_slice(
+            &std::fs::read(&path).context(format!("Failed to read {}", path.display()))?,
+        )
+        .context(format!("Failed to parse {}", path.display()))?)
+    }
+
+    pub fn write(&self, main_module: &Path) -> Result<(), Error> {
+        let path = Self::path_for(main_module);
+        std::fs::write(
+            &path,
+            serde_json::to_vec_pretty(self).context("Failed to serialize macro limits")?,
+        )
+        .context(format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The lower of each limit and the one in `ceiling`, a limit of 0 is the same as not set
+    pub fn within(self, ceiling: MacroLimits) -> Self {
+        fn pick<T: Default + Ord>(own: Option<T>, ceiling: Option<T>) -> Option<T> {
+            let set = |limit: Option<T>| limit.filter(|limit| *limit != T::default());
+            match (set(own), set(ceiling)) {
+                (Some(own), Some(ceiling)) => Some(own.min(ceiling)),
+                (own, ceiling) => own.or(ceiling),
+            }
+        }
+        Self {
+            max_heap_mb: pick(self.max_heap_mb, ceiling.max_heap_mb),
+            timeout_secs: pick(self.timeout_secs, ceiling.timeout_secs),
+            max_cpu_secs: pick(self.max_cpu_secs, ceiling.max_cpu_secs),
+        }
+    }
+
+    pub fn max_heap_bytes(&self) -> Option<usize> {
+        self.max_heap_mb.map(|mb| mb as usize * 1024 * 1024)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitExceeded {
+    Heap(u32),
+    Timeout(u64),
+    CpuTime(u64),
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::Heap(mb) => write!(f, "Macro ran out of its {mb} MiB heap"),
+            LimitExceeded::Timeout(secs) => write!(f, "Macro timed out after {secs} seconds"),
+            LimitExceeded::CpuTime(secs) => {
+                write!(f, "Macro used up its {secs} seconds of CPU time")
+            }
+        }
+    }
+}
+
+/// Why the macro was terminated, if it was for going over a limit
+#[derive(Clone, Default)]
+pub struct TerminationReason {
+    reason: Arc<Mutex<Option<LimitExceeded>>>,
+    terminated: Arc<tokio::sync::Notify>,
+}
+
+impl TerminationReason {
+    /// Only the first limit exceeded is kept
+    pub fn set(&self, reason: LimitExceeded) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.terminated.notify_one();
+    }
+
+    pub fn get(&self) -> Option<LimitExceeded> {
+        *self.reason.lock().unwrap()
+    }
+
+    /// Resolves once a limit is exceeded, for stopping a macro that is waiting rather than running
+    /// JavaScript, which terminating the isolate doesn't interrupt
+    pub async fn exceeded(&self) {
+        self.terminated.notified().await
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod thread_time {
+    use std::{thread::JoinHandle, time::Duration};
+
+    pub type RawThread = libc::pthread_t;
+
+    pub fn current_thread() -> RawThread {
+        unsafe { libc::pthread_self() }
+    }
+
+    pub fn raw_thread<T>(thread: &JoinHandle<T>) -> RawThread {
+        std::os::unix::thread::JoinHandleExt::as_pthread_t(thread)
+    }
+
+    pub fn thread_cpu_time(thread: RawThread) -> Option<Duration> {
+        let mut clock_id: libc::clockid_t = 0;
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe {
+            if libc::pthread_getcpuclockid(thread, &mut clock_id) != 0
+                || libc::clock_gettime(clock_id, &mut time) != 0
+            {
+                return None;
+            }
+        }
+        Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+    }
+}
+
+#[cfg(windows)]
+mod thread_time {
+    use std::{os::windows::io::AsRawHandle, thread::JoinHandle, time::Duration};
+
+    use winapi::{
+        shared::minwindef::FILETIME,
+        um::processthreadsapi::{GetCurrentThread, GetThreadTimes},
+    };
+
+    pub type RawThread = std::os::windows::io::RawHandle;
+
+    pub fn current_thread() -> RawThread {
+        unsafe { GetCurrentThread() as RawThread }
+    }
+
+    pub fn raw_thread<T>(thread: &JoinHandle<T>) -> RawThread {
+        thread.as_raw_handle()
+    }
+
+    pub fn thread_cpu_time(thread: RawThread) -> Option<Duration> {
+        let mut times: [FILETIME; 4] = unsafe { std::mem::zeroed() };
+        let [creation_time, exit_time, kernel_time, user_time] = &mut times;
+        if unsafe {
+            GetThreadTimes(
+                thread as _,
+                creation_time,
+                exit_time,
+                kernel_time,
+                user_time,
+            )
+        } == 0
+        {
+            return None;
+        }
+        // in 100 nanosecond intervals
+        let ticks =
+            |time: &FILETIME| ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64;
+        Some(Duration::from_nanos(
+            (ticks(kernel_time) + ticks(user_time)) * 100,
+        ))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod thread_time {
+    use std::{thread::JoinHandle, time::Duration};
+
+    pub type RawThread = ();
+
+    pub fn current_thread() -> RawThread {}
+
+    pub fn raw_thread<T>(_thread: &JoinHandle<T>) -> RawThread {}
+
+    pub fn thread_cpu_time(_thread: RawThread) -> Option<Duration> {
+        None
+    }
+}
+
+// the CPU time of a thread can be read until it is joined or detached
+pub use thread_time::{current_thread, raw_thread, thread_cpu_time};
+
+/// Terminates the macro once it runs past its timeout or CPU time, returns when the macro thread exits.
+///
+/// The macro thread also stops its event loop on [`TerminationReason::exceeded`].
+pub async fn watchdog(
+    pid: MacroPID,
+    limits: MacroLimits,
+    thread: JoinHandle<()>,
+    process_table: Arc<DashMap<MacroPID, deno_core::v8::IsolateHandle>>,
+    termination_reason: TerminationReason,
+) {
+    let start = Instant::now();
+    let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+    let mut exceeded = None;
+    // the thread handle is kept until here so its CPU clock stays valid
+    while !thread.is_finished() {
+        interval.tick().await;
+        if exceeded.is_none() {
+            exceeded = if limits
+                .timeout_secs
+                .map_or(false, |timeout| start.elapsed().as_secs() >= timeout)
+            {
+                limits.timeout_secs.map(LimitExceeded::Timeout)
+            } else if limits.max_cpu_secs.map_or(false, |max_cpu_secs| {
+                thread_cpu_time(raw_thread(&thread))
+                    .map_or(false, |cpu_time| cpu_time.as_secs() >= max_cpu_secs)
+            }) {
+                limits.max_cpu_secs.map(LimitExceeded::CpuTime)
+            } else {
+                None
+            };
+        }
+        // terminated again on every tick until the thread exits, in case it runs more JavaScript
+        if let Some(exceeded) = exceeded {
+            termination_reason.set(exceeded);
+            if let Some(isolate_handle) = process_table.get(&pid) {
+                isolate_handle.terminate_execution();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_macro_limits() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let main_module = temp_dir.path().join("backup.ts");
+        assert_eq!(
+            MacroLimits::load(&main_module).unwrap(),
+            MacroLimits::default()
+        );
+
+        MacroLimits {
+            max_heap_mb: None,
+            timeout_secs: Some(5),
+            max_cpu_secs: Some(0),
+        }
+        .write(&main_module)
+        .unwrap();
+        assert!(temp_dir.path().join("backup.limits.json").exists());
+
+        let limits = MacroLimits::load(&main_module)
+            .unwrap()
+            .within(MacroLimits {
+                max_heap_mb: Some(512),
+                timeout_secs: Some(10),
+                max_cpu_secs: Some(10),
+            });
+        assert_eq!(
+            limits,
+            MacroLimits {
+                max_heap_mb: Some(512),
+                timeout_secs: Some(5),
+                max_cpu_secs: Some(10),
+            }
+        );
+
+        // can't go over the global limits
+        let limits = MacroLimits {
+            max_heap_mb: Some(4096),
+            timeout_secs: Some(60),
+            max_cpu_secs: Some(60),
+        }
+        .within(MacroLimits {
+            max_heap_mb: Some(512),
+            timeout_secs: None,
+            max_cpu_secs: Some(0),
+        });
+        assert_eq!(
+            limits,
+            MacroLimits {
+                max_heap_mb: Some(512),
+                timeout_secs: Some(60),
+                max_cpu_secs: Some(60),
+            }
+        );
+    }
+
+    #[test]
+    fn test_termination_reason() {
+        let reason = TerminationReason::default();
+        assert_eq!(reason.get(), None);
+        reason.set(LimitExceeded::Timeout(10));
+        reason.set(LimitExceeded::Heap(512));
+        assert_eq!(reason.get(), Some(LimitExceeded::Timeout(10)));
+    }
+}
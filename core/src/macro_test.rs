@@ -0,0 +1,527 @@
+//! Runs the `*.test.ts` files of a macro against a fake instance.
+//!
+//! Test files register cases with `test()` from `deno_ops/testing/testing.ts`. The instance
+//! control and player management ops are replaced by [`FakeInstance`], which tests drive through
+//! `fakeInstance`, so macros can be tested without a real server.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Context};
+use deno_runtime::permissions::PermissionsOptions;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Receiver;
+use ts_rs::TS;
+use walkdir::WalkDir;
+
+use crate::{
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner},
+    macro_executor::{
+        DefaultWorkerOptionGenerator, MacroExecutor, SpawnResult, WorkerOptionGenerator,
+    },
+    macro_history::MacroHistory,
+    macro_limits::MacroLimits,
+    minecraft::player::MinecraftPlayer,
+    traits::{
+        t_macro::ExitStatus,
+        t_player::Player,
+        t_server::{State, StateAction},
+    },
+    types::{InstanceUuid, Snowflake},
+};
+
+/// Files that haven't finished by then are killed
+const TEST_FILE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct MacroTestCase {
+    pub name: String,
+    /// Why the case failed, none if it passed
+    pub error: Option<String>,
+    pub duration_ms: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct MacroTestFileReport {
+    /// Relative to the directory the tests were found in
+    pub path: PathBuf,
+    pub cases: Vec<MacroTestCase>,
+    /// Not a success if the file failed to load or threw outside of a case
+    pub exit_status: ExitStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct MacroTestReport {
+    pub files: Vec<MacroTestFileReport>,
+    pub passed: u32,
+    pub failed: u32,
+}
+
+impl MacroTestReport {
+    fn new(files: Vec<MacroTestFileReport>) -> Self {
+        let (failed, passed): (Vec<_>, Vec<_>) = files
+            .iter()
+            .flat_map(|file| file.cases.iter())
+            .partition(|case| case.error.is_some());
+        Self {
+            passed: passed.len() as u32,
+            failed: failed.len() as u32,
+            files,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed == 0 && self.files.iter().all(|file| file.exit_status.is_success())
+    }
+}
+
+/// Where the cases of a test file are reported to
+#[derive(Clone, Default)]
+pub struct MacroTestRecorder(Arc<Mutex<Vec<MacroTestCase>>>);
+
+impl MacroTestRecorder {
+    pub fn record(&self, case: MacroTestCase) {
+        self.0.lock().unwrap().push(case);
+    }
+
+    fn take(&self) -> Vec<MacroTestCase> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// What a test file runs against instead of the real instance ops
+pub struct MacroTestHarness {
+    pub instance: FakeInstance,
+    pub recorder: MacroTestRecorder,
+}
+
+/// An instance with a scriptable state machine, player list and console.
+///
+/// Events go to its own broadcaster, never to the rest of the core.
+pub struct FakeInstance {
+    pub uuid: InstanceUuid,
+    pub name: String,
+    state: State,
+    players: HashSet<MinecraftPlayer>,
+    max_players: u32,
+    /// Commands sent to the console, in order
+    commands: Vec<String>,
+    /// Console output printed in reply to a command
+    responses: HashMap<String, Vec<String>>,
+    event_broadcaster: EventBroadcaster,
+    // keeps the channel open while nothing is listening
+    _event_rx: Receiver<Event>,
+}
+
+impl FakeInstance {
+    pub fn new(uuid: InstanceUuid) -> Self {
+        let (event_broadcaster, event_rx) = EventBroadcaster::new(64);
+        Self {
+            uuid,
+            name: "Test Instance".to_string(),
+            state: State::Stopped,
+            players: HashSet::new(),
+            max_players: 20,
+            commands: Vec::new(),
+            responses: HashMap::new(),
+            event_broadcaster,
+            _event_rx: event_rx,
+        }
+    }
+
+    pub fn event_broadcaster(&self) -> EventBroadcaster {
+        self.event_broadcaster.clone()
+    }
+
+    /// Back to a stopped instance with no players, commands or responses
+    pub fn reset(&mut self) {
+        self.state = State::Stopped;
+        self.players.clear();
+        self.commands.clear();
+        self.responses.clear();
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Moves to `state` without going through the state machine
+    pub fn set_state(&mut self, state: State) {
+        if self.state != state {
+            self.state = state;
+            self.event_broadcaster
+                .send(Event::new_instance_state_transition(
+                    self.uuid.clone(),
+                    self.name.clone(),
+                    state,
+                ));
+        }
+    }
+
+    fn transition(&mut self, action: StateAction) -> Result<(), Error> {
+        if self.state == State::Error {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance is in an error state"),
+            });
+        }
+        let state = self.state.try_new_state(action, None)?;
+        self.set_state(state);
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.transition(StateAction::UserStart)?;
+        self.transition(StateAction::InstanceStart)
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.transition(StateAction::UserStop)?;
+        self.remove_all_players();
+        self.transition(StateAction::InstanceStop)
+    }
+
+    pub fn restart(&mut self) -> Result<(), Error> {
+        self.stop()?;
+        self.start()
+    }
+
+    pub fn kill(&mut self) -> Result<(), Error> {
+        if self.state == State::Stopped {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance is already stopped"),
+            });
+        }
+        self.remove_all_players();
+        self.set_state(State::Stopped);
+        Ok(())
+    }
+
+    /// Records the command, then prints the response set with [`FakeInstance::respond_to`]
+    pub fn send_command(&mut self, command: &str) -> Result<(), Error> {
+        if self.state != State::Running {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance is not running"),
+            });
+        }
+        self.commands.push(command.to_string());
+        if let Some(lines) = self.responses.get(command) {
+            for line in lines {
+                self.emit_output(line.clone());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn respond_to(&mut self, command: String, lines: Vec<String>) {
+        self.responses.insert(command, lines);
+    }
+
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    pub fn clear_commands(&mut self) {
+        self.commands.clear();
+    }
+
+    pub fn emit_output(&self, line: String) {
+        self.event_broadcaster.send(Event::new_instance_output(
+            self.uuid.clone(),
+            self.name.clone(),
+            line,
+        ));
+    }
+
+    pub fn emit_player_message(&self, player: String, message: String) {
+        self.event_broadcaster.send(Event::new_player_message(
+            self.uuid.clone(),
+            self.name.clone(),
+            player,
+            message,
+        ));
+    }
+
+    pub fn emit_system_message(&self, message: String) {
+        self.event_broadcaster.send(Event::new_system_message(
+            self.uuid.clone(),
+            self.name.clone(),
+            message,
+        ));
+    }
+
+    pub fn players(&self) -> HashSet<Player> {
+        self.players
+            .iter()
+            .map(|player| player.clone().into())
+            .collect()
+    }
+
+    pub fn max_players(&self) -> u32 {
+        self.max_players
+    }
+
+    pub fn join(&mut self, player_name: String) {
+        let player = MinecraftPlayer::new(player_name, None);
+        if self.players.insert(player.clone()) {
+            self.emit_player_change(HashSet::from([player.into()]), HashSet::new());
+        }
+    }
+
+    pub fn leave(&mut self, player_name: &str) {
+        let left: HashSet<Player> = self
+            .players
+            .iter()
+            .filter(|player| player.name == player_name)
+            .map(|player| player.clone().into())
+            .collect();
+        if !left.is_empty() {
+            self.players.retain(|player| player.name != player_name);
+            self.emit_player_change(HashSet::new(), left);
+        }
+    }
+
+    fn remove_all_players(&mut self) {
+        if !self.players.is_empty() {
+            let left = self.players();
+            self.players.clear();
+            self.emit_player_change(HashSet::new(), left);
+        }
+    }
+
+    fn emit_player_change(&self, players_joined: HashSet<Player>, players_left: HashSet<Player>) {
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid: self.uuid.clone(),
+                instance_name: self.name.clone(),
+                instance_event_inner: InstanceEventInner::PlayerChange {
+                    player_list: self.players(),
+                    players_joined,
+                    players_left,
+                },
+            }),
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            caused_by: CausedBy::Instance {
+                instance_uuid: self.uuid.clone(),
+            },
+        });
+    }
+}
+
+struct TestWorkerOptionGenerator {
+    instance_uuid: InstanceUuid,
+    recorder: MacroTestRecorder,
+}
+
+impl WorkerOptionGenerator for TestWorkerOptionGenerator {
+    fn generate(&self) -> deno_runtime::worker::WorkerOptions {
+        DefaultWorkerOptionGenerator.generate()
+    }
+
+    fn test_harness(&self) -> Option<MacroTestHarness> {
+        Some(MacroTestHarness {
+            instance: FakeInstance::new(self.instance_uuid.clone()),
+            recorder: self.recorder.clone(),
+        })
+    }
+}
+
+/// `*.test.ts` files under `dir`, skipping hidden directories and dependencies
+pub fn find_test_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("{} is not a directory", dir.display()),
+        });
+    }
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || {
+                let name = entry.file_name().to_string_lossy();
+                !name.starts_with('.') && name != "node_modules"
+            }
+        })
+    {
+        let entry = entry.context("Failed to read test directory")?;
+        if entry.file_type().is_file() && entry.file_name().to_string_lossy().ends_with(".test.ts")
+        {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+impl MacroExecutor {
+    /// An executor with its own in-memory history and events, dropped along with it
+    pub async fn new_for_tests(limits: MacroLimits) -> Result<Self, Error> {
+        let sqlite_pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .context("Failed to create sqlite pool")?;
+        let (tx, _rx) = EventBroadcaster::new(512);
+        let macro_executor = MacroExecutor::new(
+            tx,
+            MacroHistory::new(sqlite_pool).await?,
+            tokio::runtime::Handle::current(),
+        );
+        macro_executor.set_default_limits(limits);
+        Ok(macro_executor)
+    }
+
+    /// Runs every test file under `dir`, one at a time, each against a new [`FakeInstance`]
+    ///
+    /// The tests can read and write `dir` on top of `permissions`.
+    pub async fn run_tests(
+        &self,
+        dir: &Path,
+        permissions: PermissionsOptions,
+    ) -> Result<MacroTestReport, Error> {
+        let permissions = Self::with_dir(permissions, dir);
+        let mut files = Vec::new();
+        for path in find_test_files(dir)? {
+            let instance_uuid = InstanceUuid::default();
+            let recorder = MacroTestRecorder::default();
+            let exit_status = match self
+                .spawn(
+                    path.clone(),
+                    Vec::new(),
+                    CausedBy::System,
+                    Box::new(TestWorkerOptionGenerator {
+                        instance_uuid: instance_uuid.clone(),
+                        recorder: recorder.clone(),
+                    }),
+                    None,
+                    permissions.clone(),
+                    Some(instance_uuid),
+                    None,
+                )
+                .await
+            {
+                Ok(SpawnResult {
+                    macro_pid,
+                    exit_future,
+                    ..
+                }) => match tokio::time::timeout(TEST_FILE_TIMEOUT, exit_future).await {
+                    Ok(Ok(exit_status)) => exit_status,
+                    Ok(Err(e)) => test_file_error(e.to_string()),
+                    Err(_) => {
+                        let _ = self.abort_macro(macro_pid);
+                        test_file_error(format!(
+                            "Timed out after {} seconds",
+                            TEST_FILE_TIMEOUT.as_secs()
+                        ))
+                    }
+                },
+                Err(e) => test_file_error(e.to_string()),
+            };
+            files.push(MacroTestFileReport {
+                path: path.strip_prefix(dir).unwrap_or(&path).to_owned(),
+                cases: recorder.take(),
+                exit_status,
+            });
+        }
+        Ok(MacroTestReport::new(files))
+    }
+}
+
+fn test_file_error(error_msg: String) -> ExitStatus {
+    ExitStatus::Error {
+        time: chrono::Utc::now().timestamp(),
+        error_msg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fake_instance() {
+        let mut instance = FakeInstance::new(InstanceUuid::default());
+        let mut rx = instance.event_broadcaster().subscribe();
+        assert!(instance.send_command("list").is_err());
+        instance.start().unwrap();
+        assert_eq!(instance.state(), State::Running);
+        assert!(instance.start().is_err());
+        for expected in [State::Starting, State::Running] {
+            let event = rx.recv().await.unwrap();
+            assert_eq!(
+                event.event_inner,
+                EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid: instance.uuid.clone(),
+                    instance_name: instance.name.clone(),
+                    instance_event_inner: InstanceEventInner::StateTransition { to: expected },
+                })
+            );
+        }
+
+        instance.respond_to(
+            "list".to_string(),
+            vec!["There are 0 of a max of 20 players online".to_string()],
+        );
+        instance.send_command("list").unwrap();
+        instance.send_command("say hi").unwrap();
+        assert_eq!(instance.commands(), ["list", "say hi"]);
+        match rx.recv().await.unwrap().event_inner {
+            EventInner::InstanceEvent(InstanceEvent {
+                instance_event_inner: InstanceEventInner::InstanceOutput { message },
+                ..
+            }) => assert_eq!(message, "There are 0 of a max of 20 players online"),
+            event => panic!("Unexpected event {event:?}"),
+        }
+
+        instance.join("Steve".to_string());
+        instance.join("Alex".to_string());
+        instance.leave("Steve");
+        assert_eq!(instance.players().len(), 1);
+        instance.stop().unwrap();
+        assert_eq!(instance.state(), State::Stopped);
+        assert!(instance.players().is_empty());
+        assert!(instance.kill().is_err());
+
+        instance.reset();
+        assert!(instance.commands().is_empty());
+    }
+
+    #[test]
+    fn test_find_test_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::create_dir_all(root.join("node_modules/dep")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        for file in [
+            "index.ts",
+            "index.test.ts",
+            "lib/chat.test.ts",
+            "lib/chat.ts",
+            "node_modules/dep/dep.test.ts",
+            ".git/hook.test.ts",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        assert_eq!(
+            find_test_files(root).unwrap(),
+            vec![root.join("index.test.ts"), root.join("lib/chat.test.ts")]
+        );
+        assert!(find_test_files(&root.join("index.ts")).is_err());
+    }
+}
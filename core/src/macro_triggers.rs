@@ -0,0 +1,785 @@
+//! Macros that run on their own when something happens to their instance.
+//!
+//! A macro's triggers are kept in the `MacroTriggers` table, out of reach of anything that can
+//! write to the instance. When one of them fires, the macro is spawned with a
+//! [`MacroTriggerFired`] as JSON for its only argument, on behalf of the user who set the triggers.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    RwLock,
+};
+use tracing::{debug, error, warn};
+use ts_rs::TS;
+
+use crate::{
+    auth::{user::UsersManager, user_id::UserId},
+    error::{Error, ErrorKind},
+    events::{
+        CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner, MacroEvent, MacroEventInner,
+    },
+    macro_executor::{MacroExecutor, MacroPID},
+    prelude::GameInstance,
+    traits::{t_macro::TMacro, t_server::State},
+    types::InstanceUuid,
+};
+
+/// How often the triggers of every macro are read again
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+/// Triggers queued up for a macro, past this they are dropped
+const MAX_QUEUED_TRIGGERS: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum MacroTrigger {
+    PlayerJoin,
+    /// A player's chat message matches `pattern`, a regex
+    ChatMessage {
+        pattern: String,
+    },
+    StateTransition {
+        to: State,
+    },
+    /// The server stopped without being asked to, killing the instance counts too
+    Crash,
+    /// A cron expression with seconds, `0 */5 * * * *` fires every 5 minutes
+    Cron {
+        schedule: String,
+    },
+}
+
+/// What to do when a trigger fires while the macro is still running
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, TS)]
+#[ts(export)]
+pub enum TriggerConcurrency {
+    /// Kill the running macro and start it again, so only one is ever running
+    Single,
+    /// Run it again once the running one exits
+    Queue,
+    /// Ignore the trigger
+    #[default]
+    Drop,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, TS)]
+#[ts(export)]
+pub struct MacroTriggers {
+    #[serde(default)]
+    pub triggers: Vec<MacroTrigger>,
+    #[serde(default)]
+    pub concurrency: TriggerConcurrency,
+    /// Who set the triggers, the macro runs with their permissions.
+    /// Set by the core when the triggers are saved, whatever is sent is ignored.
+    #[serde(default)]
+    pub configured_by: Option<UserId>,
+}
+
+/// The argument a triggered macro is spawned with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct MacroTriggerFired {
+    pub trigger: MacroTrigger,
+    /// The event that fired the trigger, none for cron triggers
+    pub event: Option<Event>,
+    pub time: i64,
+}
+
+impl MacroTriggers {
+    fn compile(&self) -> Result<Vec<(MacroTrigger, Matcher)>, Error> {
+        self.triggers
+            .iter()
+            .map(|trigger| {
+                let matcher = match trigger {
+                    MacroTrigger::PlayerJoin => Matcher::PlayerJoin,
+                    MacroTrigger::ChatMessage { pattern } => {
+                        Matcher::ChatMessage(Regex::new(pattern).map_err(|e| Error {
+                            kind: ErrorKind::BadRequest,
+                            source: eyre!("Invalid chat pattern {pattern}: {e}"),
+                        })?)
+                    }
+                    MacroTrigger::StateTransition { to } => Matcher::StateTransition(*to),
+                    MacroTrigger::Crash => Matcher::Crash,
+                    MacroTrigger::Cron { schedule } => {
+                        Matcher::Cron(cron::Schedule::from_str(schedule).map_err(|e| Error {
+                            kind: ErrorKind::BadRequest,
+                            source: eyre!("Invalid cron schedule {schedule}: {e}"),
+                        })?)
+                    }
+                };
+                Ok((trigger.clone(), matcher))
+            })
+            .collect()
+    }
+}
+
+struct TriggersRow {
+    instance_id: String,
+    macro_name: String,
+    triggers: String,
+    concurrency: String,
+    configured_by: String,
+}
+
+impl TryFrom<TriggersRow> for MacroTriggers {
+    type Error = Error;
+
+    fn try_from(row: TriggersRow) -> Result<Self, Error> {
+        Ok(MacroTriggers {
+            triggers: serde_json::from_str(&row.triggers).context(format!(
+                "Failed to parse triggers of macro {}",
+                row.macro_name
+            ))?,
+            concurrency: serde_json::from_str(&row.concurrency).context(format!(
+                "Failed to parse trigger concurrency of macro {}",
+                row.macro_name
+            ))?,
+            configured_by: Some(UserId::from(row.configured_by)),
+        })
+    }
+}
+
+/// The triggers of every macro, one row per instance and macro
+#[derive(Clone, Debug)]
+pub struct MacroTriggerStore {
+    pool: SqlitePool,
+}
+
+impl MacroTriggerStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, Error> {
+        init_macro_triggers_table(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    /// No triggers if none were set for the macro
+    pub async fn get(
+        &self,
+        instance_uuid: &InstanceUuid,
+        macro_name: &str,
+    ) -> Result<MacroTriggers, Error> {
+        let instance_id = instance_uuid.to_string();
+        match sqlx::query_as!(
+            TriggersRow,
+            r#"
+SELECT instance_id, macro_name, triggers, concurrency, configured_by
+FROM MacroTriggers
+WHERE instance_id = ?1 AND macro_name = ?2"#,
+            instance_id,
+            macro_name,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Failed to fetch macro triggers")?
+        {
+            Some(row) => row.try_into(),
+            None => Ok(MacroTriggers::default()),
+        }
+    }
+
+    /// Replaces the triggers of the macro, the macro will run on behalf of `configured_by`.
+    /// An empty list of triggers removes them
+    pub async fn set(
+        &self,
+        instance_uuid: &InstanceUuid,
+        macro_name: &str,
+        triggers: &MacroTriggers,
+        configured_by: &UserId,
+    ) -> Result<(), Error> {
+        triggers.compile()?;
+        let instance_id = instance_uuid.to_string();
+        if triggers.triggers.is_empty() {
+            sqlx::query!(
+                r#"DELETE FROM MacroTriggers WHERE instance_id = ?1 AND macro_name = ?2"#,
+                instance_id,
+                macro_name,
+            )
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete macro triggers")?;
+            return Ok(());
+        }
+        let trigger_list =
+            serde_json::to_string(&triggers.triggers).context("Failed to serialize triggers")?;
+        let concurrency = serde_json::to_string(&triggers.concurrency)
+            .context("Failed to serialize trigger concurrency")?;
+        let configured_by: &str = configured_by.as_ref();
+        sqlx::query!(
+            r#"
+INSERT INTO MacroTriggers (instance_id, macro_name, triggers, concurrency, configured_by)
+VALUES (?1, ?2, ?3, ?4, ?5)
+ON CONFLICT (instance_id, macro_name)
+DO UPDATE SET triggers = excluded.triggers, concurrency = excluded.concurrency, configured_by = excluded.configured_by"#,
+            instance_id,
+            macro_name,
+            trigger_list,
+            concurrency,
+            configured_by,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to write macro triggers")?;
+        Ok(())
+    }
+
+    /// Every macro that has triggers, with the instance it belongs to
+    pub async fn list(&self) -> Result<Vec<(InstanceUuid, String, MacroTriggers)>, Error> {
+        sqlx::query_as!(
+            TriggersRow,
+            r#"
+SELECT instance_id, macro_name, triggers, concurrency, configured_by
+FROM MacroTriggers"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch macro triggers")?
+        .into_iter()
+        .map(|row| {
+            Ok((
+                InstanceUuid::from(row.instance_id.clone()),
+                row.macro_name.clone(),
+                row.try_into()?,
+            ))
+        })
+        .collect()
+    }
+
+    pub async fn clear_instance(&self, instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        let instance_id = instance_uuid.to_string();
+        sqlx::query!(
+            r#"DELETE FROM MacroTriggers WHERE instance_id = ?1"#,
+            instance_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to delete macro triggers")?;
+        Ok(())
+    }
+}
+
+pub async fn init_macro_triggers_table(pool: &SqlitePool) -> Result<(), Error> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .context("Failed to aquire db connection")?;
+
+    sqlx::query!(
+        r#"
+        CREATE TABLE IF NOT EXISTS MacroTriggers (
+            instance_id         TEXT        NOT NULL,
+            macro_name          TEXT        NOT NULL,
+            triggers            TEXT        NOT NULL,
+            concurrency         TEXT        NOT NULL,
+            configured_by       TEXT        NOT NULL,
+            PRIMARY KEY (instance_id, macro_name)
+        );
+        "#
+    )
+    .execute(&mut connection)
+    .await
+    .context("Failed to create table")?;
+
+    Ok(())
+}
+
+enum Matcher {
+    PlayerJoin,
+    ChatMessage(Regex),
+    StateTransition(State),
+    Crash,
+    Cron(cron::Schedule),
+}
+
+impl Matcher {
+    fn matches_event(&self, event: &InstanceEventInner, crashed: bool) -> bool {
+        match (self, event) {
+            (Matcher::PlayerJoin, InstanceEventInner::PlayerChange { players_joined, .. }) => {
+                !players_joined.is_empty()
+            }
+            (
+                Matcher::ChatMessage(pattern),
+                InstanceEventInner::PlayerMessage { player_message, .. },
+            ) => pattern.is_match(player_message),
+            (Matcher::StateTransition(expected), InstanceEventInner::StateTransition { to }) => {
+                expected == to
+            }
+            (Matcher::Crash, InstanceEventInner::StateTransition { .. }) => crashed,
+            _ => false,
+        }
+    }
+
+    /// Whether the schedule had a time in `(since, until]`
+    fn matches_time(&self, since: &DateTime<Utc>, until: &DateTime<Utc>) -> bool {
+        match self {
+            Matcher::Cron(schedule) => schedule
+                .after(since)
+                .next()
+                .map_or(false, |time| time <= *until),
+            _ => false,
+        }
+    }
+}
+
+/// Stopping without going through `Stopping` first means the server went down on its own
+fn is_crash(from: Option<State>, to: State) -> bool {
+    to == State::Stopped && matches!(from, Some(State::Starting | State::Running))
+}
+
+struct TriggeredMacro {
+    instance_uuid: InstanceUuid,
+    name: String,
+    concurrency: TriggerConcurrency,
+    configured_by: UserId,
+    matchers: Vec<(MacroTrigger, Matcher)>,
+}
+
+/// A trigger that fired and who the macro runs for
+type Firing = (MacroTriggerFired, UserId);
+
+#[derive(Default)]
+struct MacroSlot {
+    running: Option<MacroPID>,
+    queue: VecDeque<Firing>,
+}
+
+struct TriggerRunner {
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    users_manager: Arc<RwLock<UsersManager>>,
+    store: MacroTriggerStore,
+    macro_executor: MacroExecutor,
+    macros: Vec<TriggeredMacro>,
+    slots: HashMap<(InstanceUuid, String), MacroSlot>,
+    instance_states: HashMap<InstanceUuid, State>,
+}
+
+impl TriggerRunner {
+    async fn reload(&mut self) {
+        let stored = match self.store.list().await {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("Failed to load macro triggers: {e}");
+                return;
+            }
+        };
+        let mut macro_lists: HashMap<InstanceUuid, Vec<String>> = HashMap::new();
+        let mut macros = Vec::new();
+        for (instance_uuid, name, triggers) in stored {
+            if !macro_lists.contains_key(&instance_uuid) {
+                let instance = match self.instances.get(&instance_uuid) {
+                    Some(instance) => instance.value().clone(),
+                    None => continue,
+                };
+                // not every instance has macros
+                let names = match instance.get_macro_list().await {
+                    Ok(macro_list) => macro_list.into_iter().map(|entry| entry.name).collect(),
+                    Err(_) => Vec::new(),
+                };
+                macro_lists.insert(instance_uuid.clone(), names);
+            }
+            if !macro_lists[&instance_uuid].contains(&name) {
+                continue;
+            }
+            let configured_by = match triggers.configured_by.clone() {
+                Some(configured_by) => configured_by,
+                None => continue,
+            };
+            match triggers.compile() {
+                Ok(matchers) => macros.push(TriggeredMacro {
+                    instance_uuid,
+                    name,
+                    concurrency: triggers.concurrency,
+                    configured_by,
+                    matchers,
+                }),
+                Err(e) => warn!("Invalid triggers for macro {name}: {e}"),
+            }
+        }
+        self.macros = macros;
+        self.reap_exited().await;
+    }
+
+    async fn handle_instance_event(&mut self, event: &Event, instance_event: &InstanceEvent) {
+        let mut crashed = false;
+        if let InstanceEventInner::StateTransition { to } = instance_event.instance_event_inner {
+            let from = self
+                .instance_states
+                .insert(instance_event.instance_uuid.clone(), to);
+            crashed = is_crash(from, to);
+        }
+        let mut fired = Vec::new();
+        for triggered_macro in &self.macros {
+            if triggered_macro.instance_uuid != instance_event.instance_uuid {
+                continue;
+            }
+            if let Some((trigger, _)) = triggered_macro.matchers.iter().find(|(_, matcher)| {
+                matcher.matches_event(&instance_event.instance_event_inner, crashed)
+            }) {
+                fired.push((
+                    triggered_macro.instance_uuid.clone(),
+                    triggered_macro.name.clone(),
+                    triggered_macro.concurrency,
+                    (
+                        MacroTriggerFired {
+                            trigger: trigger.clone(),
+                            event: Some(event.clone()),
+                            time: chrono::Utc::now().timestamp(),
+                        },
+                        triggered_macro.configured_by.clone(),
+                    ),
+                ));
+            }
+        }
+        for (instance_uuid, name, concurrency, firing) in fired {
+            self.fire(instance_uuid, name, concurrency, firing).await;
+        }
+    }
+
+    async fn handle_tick(&mut self, since: &DateTime<Utc>, until: &DateTime<Utc>) {
+        let mut fired = Vec::new();
+        for triggered_macro in &self.macros {
+            if let Some((trigger, _)) = triggered_macro
+                .matchers
+                .iter()
+                .find(|(_, matcher)| matcher.matches_time(since, until))
+            {
+                fired.push((
+                    triggered_macro.instance_uuid.clone(),
+                    triggered_macro.name.clone(),
+                    triggered_macro.concurrency,
+                    (
+                        MacroTriggerFired {
+                            trigger: trigger.clone(),
+                            event: None,
+                            time: until.timestamp(),
+                        },
+                        triggered_macro.configured_by.clone(),
+                    ),
+                ));
+            }
+        }
+        for (instance_uuid, name, concurrency, firing) in fired {
+            self.fire(instance_uuid, name, concurrency, firing).await;
+        }
+    }
+
+    async fn fire(
+        &mut self,
+        instance_uuid: InstanceUuid,
+        name: String,
+        concurrency: TriggerConcurrency,
+        firing: Firing,
+    ) {
+        let instance = match self.instances.get(&instance_uuid) {
+            Some(instance) => instance.value().clone(),
+            None => return,
+        };
+        let slot = self
+            .slots
+            .entry((instance_uuid.clone(), name.clone()))
+            .or_default();
+        if let Some(running) = slot.running {
+            match concurrency {
+                TriggerConcurrency::Single => {
+                    if let Err(e) = instance.kill_macro(running).await {
+                        warn!("Failed to kill macro {name} for its next trigger: {e}");
+                    }
+                }
+                TriggerConcurrency::Queue => {
+                    if slot.queue.len() < MAX_QUEUED_TRIGGERS {
+                        slot.queue.push_back(firing);
+                    } else {
+                        warn!("Too many triggers queued for macro {name}, dropping trigger");
+                    }
+                    return;
+                }
+                TriggerConcurrency::Drop => {
+                    debug!("Macro {name} is still running, dropping trigger");
+                    return;
+                }
+            }
+        }
+        slot.running = spawn_triggered(&self.users_manager, &instance, &name, &firing).await;
+    }
+
+    async fn handle_macro_stopped(&mut self, macro_pid: MacroPID) {
+        let ((instance_uuid, name), slot) = match self
+            .slots
+            .iter_mut()
+            .find(|(_, slot)| slot.running == Some(macro_pid))
+        {
+            Some(entry) => entry,
+            None => return,
+        };
+        slot.running = None;
+        let firing = match slot.queue.pop_front() {
+            Some(firing) => firing,
+            None => return,
+        };
+        let instance = match self.instances.get(instance_uuid) {
+            Some(instance) => instance.value().clone(),
+            None => {
+                slot.queue.clear();
+                return;
+            }
+        };
+        slot.running = spawn_triggered(&self.users_manager, &instance, name, &firing).await;
+    }
+
+    /// Frees the slots of macros that exited without their `Stopped` event being seen,
+    /// such as when the receiver lagged behind
+    async fn reap_exited(&mut self) {
+        let exited: Vec<MacroPID> = self
+            .slots
+            .values()
+            .filter_map(|slot| slot.running)
+            .filter(|pid| !self.macro_executor.is_running(*pid))
+            .collect();
+        for pid in exited {
+            self.handle_macro_stopped(pid).await;
+        }
+    }
+}
+
+async fn spawn_triggered(
+    users_manager: &RwLock<UsersManager>,
+    instance: &GameInstance,
+    name: &str,
+    (trigger_fired, configured_by): &Firing,
+) -> Option<MacroPID> {
+    let caused_by = match users_manager.read().await.get_user(configured_by) {
+        Some(user) => CausedBy::User {
+            user_id: user.uid,
+            user_name: user.username,
+        },
+        None => {
+            warn!("Not running triggered macro {name}, the user who set its triggers no longer exists");
+            return None;
+        }
+    };
+    let args = match serde_json::to_string(trigger_fired) {
+        Ok(arg) => vec![arg],
+        Err(e) => {
+            error!("Failed to serialize trigger for macro {name}: {e}");
+            return None;
+        }
+    };
+    let configs = match instance.validate_local_config(name, None).await {
+        Ok(configs) if configs.is_empty() => None,
+        Ok(configs) => Some(configs),
+        Err(e) => {
+            warn!("Not running triggered macro {name}, its config is invalid: {e}");
+            return None;
+        }
+    };
+    match instance
+        .run_macro(name, args, configs, caused_by, None)
+        .await
+    {
+        Ok(task) => Some(task.pid),
+        Err(e) => {
+            error!("Failed to run triggered macro {name}: {e}");
+            None
+        }
+    }
+}
+
+/// Runs the macros whose triggers fire, until the event channel closes
+pub async fn run_macro_triggers(
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    users_manager: Arc<RwLock<UsersManager>>,
+    store: MacroTriggerStore,
+    macro_executor: MacroExecutor,
+    mut event_rx: Receiver<Event>,
+) {
+    let mut runner = TriggerRunner {
+        instances,
+        users_manager,
+        store,
+        macro_executor,
+        macros: Vec::new(),
+        slots: HashMap::new(),
+        instance_states: HashMap::new(),
+    };
+    let mut reload_interval = tokio::time::interval(RELOAD_INTERVAL);
+    let mut tick_interval = tokio::time::interval(Duration::from_secs(1));
+    let mut last_tick = Utc::now();
+    loop {
+        tokio::select! {
+            _ = reload_interval.tick() => runner.reload().await,
+            _ = tick_interval.tick() => {
+                let now = Utc::now();
+                runner.handle_tick(&last_tick, &now).await;
+                last_tick = now;
+            }
+            event = event_rx.recv() => match event {
+                Ok(event) => match &event.event_inner {
+                    EventInner::InstanceEvent(instance_event) => {
+                        runner.handle_instance_event(&event, instance_event).await
+                    }
+                    EventInner::MacroEvent(MacroEvent {
+                        macro_pid,
+                        macro_event_inner: MacroEventInner::Stopped { .. },
+                        ..
+                    }) => runner.handle_macro_stopped(*macro_pid).await,
+                    _ => {}
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Macro triggers missed {skipped} events");
+                    runner.reap_exited().await;
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use sqlx::{sqlite::SqliteConnectOptions, Pool};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_macro_triggers() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let pool = Pool::connect_with(
+            SqliteConnectOptions::from_str(&format!(
+                "sqlite://{}/data.db",
+                temp_dir.path().display()
+            ))
+            .unwrap()
+            .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        let store = MacroTriggerStore::new(pool).await.unwrap();
+        let instance_uuid = InstanceUuid::default();
+        let user_id = UserId::default();
+        assert_eq!(
+            store.get(&instance_uuid, "greet").await.unwrap(),
+            MacroTriggers::default()
+        );
+
+        let triggers = MacroTriggers {
+            triggers: vec![
+                MacroTrigger::ChatMessage {
+                    pattern: "^!greet (\\w+)$".to_string(),
+                },
+                MacroTrigger::StateTransition { to: State::Running },
+                MacroTrigger::Crash,
+                MacroTrigger::Cron {
+                    schedule: "0 */5 * * * *".to_string(),
+                },
+            ],
+            concurrency: TriggerConcurrency::Queue,
+            // who set them comes from the requester, not the request
+            configured_by: Some(UserId::default()),
+        };
+        store
+            .set(&instance_uuid, "greet", &triggers, &user_id)
+            .await
+            .unwrap();
+        let stored = store.get(&instance_uuid, "greet").await.unwrap();
+        assert_eq!(stored.triggers, triggers.triggers);
+        assert_eq!(stored.concurrency, triggers.concurrency);
+        assert_eq!(stored.configured_by, Some(user_id.clone()));
+        assert_eq!(store.list().await.unwrap().len(), 1);
+
+        let matchers = triggers.compile().unwrap();
+        let fires = |event: &InstanceEventInner, crashed: bool| {
+            matchers
+                .iter()
+                .filter(|(_, matcher)| matcher.matches_event(event, crashed))
+                .map(|(trigger, _)| trigger.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            fires(
+                &InstanceEventInner::PlayerMessage {
+                    player: "Steve".to_string(),
+                    player_message: "!greet Alex".to_string(),
+                },
+                false
+            ),
+            vec![triggers.triggers[0].clone()]
+        );
+        assert!(fires(
+            &InstanceEventInner::PlayerMessage {
+                player: "Steve".to_string(),
+                player_message: "hello".to_string(),
+            },
+            false
+        )
+        .is_empty());
+        assert!(fires(
+            &InstanceEventInner::PlayerChange {
+                player_list: HashSet::new(),
+                players_joined: HashSet::new(),
+                players_left: HashSet::new(),
+            },
+            false
+        )
+        .is_empty());
+        assert_eq!(
+            fires(
+                &InstanceEventInner::StateTransition { to: State::Stopped },
+                true
+            ),
+            vec![MacroTrigger::Crash]
+        );
+
+        let since = DateTime::parse_from_rfc3339("2026-01-01T00:04:59Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let cron = &matchers[3].1;
+        assert!(cron.matches_time(&since, &(since + chrono::Duration::seconds(1))));
+        assert!(!cron.matches_time(
+            &(since + chrono::Duration::seconds(1)),
+            &(since + chrono::Duration::seconds(2))
+        ));
+
+        assert!(MacroTriggers {
+            triggers: vec![MacroTrigger::Cron {
+                schedule: "every minute".to_string(),
+            }],
+            concurrency: TriggerConcurrency::Drop,
+            configured_by: None,
+        }
+        .compile()
+        .is_err());
+
+        store
+            .set(&instance_uuid, "greet", &MacroTriggers::default(), &user_id)
+            .await
+            .unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        store
+            .set(&instance_uuid, "greet", &triggers, &user_id)
+            .await
+            .unwrap();
+        store.clear_instance(&instance_uuid).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_crash() {
+        assert!(is_crash(Some(State::Running), State::Stopped));
+        assert!(is_crash(Some(State::Starting), State::Stopped));
+        assert!(!is_crash(Some(State::Stopping), State::Stopped));
+        assert!(!is_crash(None, State::Stopped));
+        assert!(!is_crash(Some(State::Running), State::Stopping));
+    }
+}
@@ -6,5 +6,11 @@ use lodestone_core::Args;
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    if let Some(extension) = &args.test_extension {
+        let passed = lodestone_core::test_extension(args.lodestone_path.clone(), extension)
+            .await
+            .unwrap();
+        std::process::exit(if passed { 0 } else { 1 });
+    }
     lodestone_core::run(args).await.unwrap().0.await;
 }
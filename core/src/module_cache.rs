@@ -0,0 +1,378 @@
+//! Remote modules imported by macros, cached on disk so macros start without the network.
+//!
+//! Every macro gets a lockfile next to its main module, `<main>.lock.json`, recording the hash of
+//! each remote module it imported. A module that no longer matches its hash fails to load.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use color_eyre::eyre::{eyre, Context};
+use deno_core::ModuleSpecifier;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    util::rand_alphanumeric,
+};
+
+static OFFLINE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// In offline mode, modules that aren't cached fail to load instead of being fetched
+pub fn set_offline_mode(offline: bool) {
+    OFFLINE_MODE.store(offline, Ordering::Relaxed);
+}
+
+pub fn offline_mode() -> bool {
+    OFFLINE_MODE.load(Ordering::Relaxed)
+}
+
+fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedModuleMetadata {
+    url: String,
+    content_type: String,
+    hash: String,
+    fetch_time: i64,
+}
+
+pub struct CachedModule {
+    pub code: String,
+    pub content_type: String,
+    /// SHA-256 of the code
+    pub hash: String,
+}
+
+#[derive(Clone)]
+pub struct ModuleCache {
+    path: PathBuf,
+    http: reqwest::Client,
+}
+
+impl ModuleCache {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Modules are stored as `<sha256 of url>` with the metadata in `<sha256 of url>.json`
+    fn entry_path(&self, url: &ModuleSpecifier) -> PathBuf {
+        self.path.join(sha256_hex(url.as_str()))
+    }
+
+    async fn get(&self, url: &ModuleSpecifier) -> Result<Option<CachedModule>, Error> {
+        let path = self.entry_path(url);
+        let metadata_path = path.with_extension("json");
+        if !path.exists() || !metadata_path.exists() {
+            return Ok(None);
+        }
+        let metadata: CachedModuleMetadata = serde_json::from_slice(
+            &tokio::fs::read(&metadata_path)
+                .await
+                .context(format!("Failed to read cache metadata of {url}"))?,
+        )
+        .context(format!("Failed to parse cache metadata of {url}"))?;
+        let code = tokio::fs::read_to_string(&path)
+            .await
+            .context(format!("Failed to read cached {url}"))?;
+        Ok(Some(CachedModule {
+            hash: sha256_hex(&code),
+            code,
+            content_type: metadata.content_type,
+        }))
+    }
+
+    async fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let tmp = path.with_extension(format!("tmp-{}", rand_alphanumeric(8)));
+        tokio::fs::write(&tmp, data)
+            .await
+            .context(format!("Failed to write {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .context(format!("Failed to move {}", tmp.display()))?;
+        Ok(())
+    }
+
+    async fn fetch(&self, url: &ModuleSpecifier) -> Result<CachedModule, Error> {
+        let response = self
+            .http
+            .get(url.as_str())
+            .send()
+            .await
+            .context(format!("Failed to fetch module {url}"))?;
+        if !response.status().is_success() {
+            return Err(Error {
+                kind: ErrorKind::External,
+                source: eyre!("Failed to fetch module {url}: {}", response.status()),
+            });
+        }
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|ct| ct.to_str().ok())
+            .ok_or_else(|| Error {
+                kind: ErrorKind::External,
+                source: eyre!("No content-type header for {url}"),
+            })?
+            .to_string();
+        let code = response
+            .text()
+            .await
+            .context(format!("Failed to read module {url}"))?;
+        let module = CachedModule {
+            hash: sha256_hex(&code),
+            code,
+            content_type,
+        };
+
+        tokio::fs::create_dir_all(&self.path)
+            .await
+            .context("Failed to create module cache directory")?;
+        let path = self.entry_path(url);
+        self.write_atomic(&path, module.code.as_bytes()).await?;
+        self.write_atomic(
+            &path.with_extension("json"),
+            &serde_json::to_vec_pretty(&CachedModuleMetadata {
+                url: url.to_string(),
+                content_type: module.content_type.clone(),
+                hash: module.hash.clone(),
+                fetch_time: chrono::Utc::now().timestamp(),
+            })
+            .context("Failed to serialize cache metadata")?,
+        )
+        .await?;
+        Ok(module)
+    }
+
+    /// The cached module, fetched first if it isn't cached unless `offline`
+    pub async fn load(&self, url: &ModuleSpecifier, offline: bool) -> Result<CachedModule, Error> {
+        if let Some(module) = self.get(url).await? {
+            return Ok(module);
+        }
+        if offline {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{url} is not cached and offline mode is on, vendor the macro first"),
+            });
+        }
+        self.fetch(url).await
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LockfileContent {
+    version: u32,
+    /// Hash of every remote module by url
+    remote: BTreeMap<String, String>,
+}
+
+pub struct Lockfile {
+    path: PathBuf,
+    content: LockfileContent,
+}
+
+impl Lockfile {
+    /// `macros/backup.ts` is locked by `macros/backup.lock.json`
+    pub fn path_for(main_module: &Path) -> PathBuf {
+        main_module.with_extension("lock.json")
+    }
+
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let content = if path.exists() {
+            serde_json::from_slice(
+                &std::fs::read(&path).context(format!("Failed to read {}", path.display()))?,
+            )
+            .context(format!("Failed to parse {}", path.display()))?
+        } else {
+            LockfileContent {
+                version: 1,
+                remote: BTreeMap::new(),
+            }
+        };
+        Ok(Self { path, content })
+    }
+
+    /// Fails if the module changed since it was locked, modules seen for the first time are locked
+    pub fn check(&mut self, url: &ModuleSpecifier, hash: &str) -> Result<(), Error> {
+        match self.content.remote.get(url.as_str()) {
+            Some(locked) if locked == hash => Ok(()),
+            Some(locked) => Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!(
+                    "Integrity check failed for {url}, expected {locked} but got {hash}. Delete {} to accept the new version",
+                    self.path.display()
+                ),
+            }),
+            None => {
+                self.content
+                    .remote
+                    .insert(url.to_string(), hash.to_string());
+                self.write()
+            }
+        }
+    }
+
+    fn write(&self) -> Result<(), Error> {
+        std::fs::write(
+            &self.path,
+            serde_json::to_vec_pretty(&self.content).context("Failed to serialize lockfile")?,
+        )
+        .context(format!("Failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct VendorReport {
+    /// Every remote module the macro imports, directly or not
+    pub modules: Vec<String>,
+    pub lockfile: PathBuf,
+}
+
+struct GraphLoader {
+    cache: ModuleCache,
+    lockfile: Rc<RefCell<Lockfile>>,
+    modules: Rc<RefCell<Vec<String>>>,
+}
+
+impl deno_graph::source::Loader for GraphLoader {
+    fn load(
+        &mut self,
+        specifier: &ModuleSpecifier,
+        _is_dynamic: bool,
+    ) -> deno_graph::source::LoadFuture {
+        let specifier = specifier.clone();
+        let cache = self.cache.clone();
+        let lockfile = self.lockfile.clone();
+        let modules = self.modules.clone();
+        async move {
+            match specifier.scheme() {
+                "file" => {
+                    let path = specifier
+                        .to_file_path()
+                        .map_err(|_| deno_core::anyhow::anyhow!("Invalid path {specifier}"))?;
+                    let content = tokio::fs::read_to_string(&path).await?;
+                    Ok(Some(deno_graph::source::LoadResponse::Module {
+                        content: Arc::from(content),
+                        specifier,
+                        maybe_headers: None,
+                    }))
+                }
+                "http" | "https" => {
+                    let module = cache.load(&specifier, offline_mode()).await?;
+                    lockfile.borrow_mut().check(&specifier, &module.hash)?;
+                    modules.borrow_mut().push(specifier.to_string());
+                    Ok(Some(deno_graph::source::LoadResponse::Module {
+                        content: Arc::from(module.code),
+                        specifier,
+                        maybe_headers: Some(HashMap::from([(
+                            "content-type".to_string(),
+                            module.content_type,
+                        )])),
+                    }))
+                }
+                _ => Ok(None),
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Caches every remote module the macro imports and locks their hashes
+pub async fn vendor(cache: &ModuleCache, main_module: &Path) -> Result<VendorReport, Error> {
+    let root = ModuleSpecifier::from_file_path(main_module).map_err(|_| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Invalid macro path {}", main_module.display()),
+    })?;
+    let lockfile_path = Lockfile::path_for(main_module);
+    let lockfile = Rc::new(RefCell::new(Lockfile::load(lockfile_path.clone())?));
+    let modules = Rc::new(RefCell::new(Vec::new()));
+    let mut loader = GraphLoader {
+        cache: cache.clone(),
+        lockfile,
+        modules: modules.clone(),
+    };
+    let mut graph = deno_graph::ModuleGraph::new(deno_graph::GraphKind::All);
+    graph
+        .build(vec![root], &mut loader, Default::default())
+        .await;
+    graph.valid().map_err(|e| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!(
+            "Failed to resolve the imports of {}: {e}",
+            main_module.display()
+        ),
+    })?;
+    let mut modules = modules.take();
+    modules.sort();
+    modules.dedup();
+    Ok(VendorReport {
+        modules,
+        lockfile: lockfile_path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockfile() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let main_module = temp_dir.path().join("backup.ts");
+        let path = Lockfile::path_for(&main_module);
+        assert_eq!(path, temp_dir.path().join("backup.lock.json"));
+
+        let url = ModuleSpecifier::parse("https://deno.land/std/path/mod.ts").unwrap();
+        let mut lockfile = Lockfile::load(path.clone()).unwrap();
+        lockfile.check(&url, "aaaa").unwrap();
+        assert!(path.exists());
+
+        // a new run reads what the last one locked
+        let mut lockfile = Lockfile::load(path).unwrap();
+        lockfile.check(&url, "aaaa").unwrap();
+        assert!(lockfile.check(&url, "bbbb").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_offline_module_cache() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(temp_dir.path().to_owned());
+        let url = ModuleSpecifier::parse("https://example.com/mod.ts").unwrap();
+
+        assert!(cache.load(&url, true).await.is_err());
+
+        let path = cache.entry_path(&url);
+        std::fs::write(&path, "export const a = 1;").unwrap();
+        std::fs::write(
+            path.with_extension("json"),
+            serde_json::to_vec(&CachedModuleMetadata {
+                url: url.to_string(),
+                content_type: "application/typescript".to_string(),
+                hash: sha256_hex("export const a = 1;"),
+                fetch_time: 0,
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        let module = cache.load(&url, true).await.unwrap();
+        assert_eq!(module.code, "export const a = 1;");
+        assert_eq!(module.hash, sha256_hex("export const a = 1;"));
+    }
+}
@@ -4,7 +4,7 @@ use ts_rs::TS;
 use crate::{
     events::{
         CausedBy, Event, EventInner, EventLevel, InstanceEventInner, MacroEventInner,
-        ProgressionEventInner,
+        ProgressionEventInner, UserEventInner,
     },
     types::Snowflake,
 };
@@ -27,7 +27,10 @@ impl From<&Event> for ClientEvent {
                 InstanceEventInner::InstanceWarning { .. } => EventLevel::Warning,
                 _ => EventLevel::Info,
             },
-            EventInner::UserEvent(_) => EventLevel::Info,
+            EventInner::UserEvent(u) => match u.user_event_inner {
+                UserEventInner::LoginFailed { .. } => EventLevel::Warning,
+                _ => EventLevel::Info,
+            },
             EventInner::MacroEvent(m) => match m.macro_event_inner {
                 MacroEventInner::Started => EventLevel::Info,
                 MacroEventInner::Stopped { ref exit_status } => {
@@ -27,6 +27,18 @@ pub fn path_to_stores() -> &'static PathBuf {
     PATH_TO_STORES.get().unwrap()
 }
 
+static PATH_TO_BACKUPS: OnceCell<PathBuf> = OnceCell::new();
+
+pub fn path_to_backups() -> &'static PathBuf {
+    PATH_TO_BACKUPS.get().unwrap()
+}
+
+static PATH_TO_MODULE_CACHE: OnceCell<PathBuf> = OnceCell::new();
+
+pub fn path_to_module_cache() -> &'static PathBuf {
+    PATH_TO_MODULE_CACHE.get().unwrap()
+}
+
 static PATH_TO_GLOBAL_SETTINGS: OnceCell<PathBuf> = OnceCell::new();
 
 pub fn path_to_global_settings() -> &'static PathBuf {
@@ -63,6 +75,8 @@ pub fn init_paths(lodestone_path: PathBuf) {
     let path_to_instances = lodestone_path.join("instances");
     let path_to_binaries = lodestone_path.join("bin");
     let path_to_stores = lodestone_path.join("stores");
+    let path_to_backups = lodestone_path.join("backups");
+    let path_to_module_cache = lodestone_path.join("module_cache");
     let path_to_global_settings = lodestone_path.join("global_settings.json");
     let path_to_users = lodestone_path.join("stores").join("users.json");
     let path_to_tmp = lodestone_path.join("tmp");
@@ -70,6 +84,8 @@ pub fn init_paths(lodestone_path: PathBuf) {
     std::fs::create_dir_all(&path_to_instances).unwrap();
     std::fs::create_dir_all(&path_to_binaries).unwrap();
     std::fs::create_dir_all(&path_to_stores).unwrap();
+    std::fs::create_dir_all(&path_to_backups).unwrap();
+    std::fs::create_dir_all(&path_to_module_cache).unwrap();
     std::fs::create_dir_all(&path_to_tmp).unwrap();
     // std::fs::File::create(&path_to_global_settings).unwrap();
     // std::fs::File::create(&path_to_users).unwrap();
@@ -79,6 +95,8 @@ pub fn init_paths(lodestone_path: PathBuf) {
     let _ = PATH_TO_INSTANCES.set(path_to_instances);
     let _ = PATH_TO_BINARIES.set(path_to_binaries);
     let _ = PATH_TO_STORES.set(path_to_stores);
+    let _ = PATH_TO_BACKUPS.set(path_to_backups);
+    let _ = PATH_TO_MODULE_CACHE.set(path_to_module_cache);
     let _ = PATH_TO_GLOBAL_SETTINGS.set(path_to_global_settings);
     let _ = PATH_TO_USERS.set(path_to_users);
     let _ = PATH_TO_TMP.set(path_to_tmp);
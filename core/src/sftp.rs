@@ -0,0 +1,1093 @@
+//! An embedded SFTP server exposing instance directories.
+//!
+//! Users log in with their password or an API token, and see one directory per instance they
+//! can read files of. Permissions are checked again on every operation, so revoking a token or
+//! permission, or logging the user out everywhere, takes effect immediately.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use russh::{
+    server::{Auth, Msg, Session},
+    Channel, ChannelId, MethodSet,
+};
+use russh_keys::key::KeyPair;
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex, RwLock},
+};
+use tracing::{debug, info};
+
+use crate::{
+    auth::{
+        session::LoginClient,
+        user::{User, UserAction, UsersManager},
+        user_id::UserId,
+        user_secrets::UserSecret,
+    },
+    disk_usage::DiskUsageTracker,
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    events::{new_fs_event, CausedBy, FSOperation, FSTarget},
+    fs_history::{scoped_join_instance_file, FileHistory, HISTORY_DIR_NAME},
+    global_settings::GlobalSettings,
+    handlers::instance_fs::is_path_protected,
+    prelude::{path_to_stores, GameInstance},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+pub const DEFAULT_SFTP_PORT: u16 = 2022;
+
+/// Largest chunk returned by a single read request
+const MAX_READ_LENGTH: u32 = 256 * 1024;
+
+async fn load_or_create_host_key(path: &Path) -> Result<KeyPair, Error> {
+    if path.exists() {
+        let key =
+            russh_keys::load_secret_key(path, None).context("Failed to load SFTP host key")?;
+        return Ok(key);
+    }
+    let key = KeyPair::generate_ed25519().ok_or_else(|| Error {
+        kind: ErrorKind::Internal,
+        source: eyre!("Failed to generate SFTP host key"),
+    })?;
+    let mut pem = Vec::new();
+    russh_keys::encode_pkcs8_pem(&key, &mut pem).context("Failed to encode SFTP host key")?;
+    tokio::fs::write(path, pem)
+        .await
+        .context("Failed to save SFTP host key")?;
+    info!("Generated a new SFTP host key at {}", path.display());
+    Ok(key)
+}
+
+pub async fn run_sftp_server(state: AppState, port: u16) -> Result<(), Error> {
+    let config = russh::server::Config {
+        methods: MethodSet::PASSWORD,
+        auth_rejection_time: Duration::from_secs(3),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        inactivity_timeout: Some(Duration::from_secs(60 * 60)),
+        keys: vec![load_or_create_host_key(&path_to_stores().join("sftp_host_key")).await?],
+        ..Default::default()
+    };
+    let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], port));
+    info!("SFTP server live on {addr}");
+    russh::server::run(Arc::new(config), addr, SftpServer { state })
+        .await
+        .context("SFTP server stopped")?;
+    Ok(())
+}
+
+struct SftpServer {
+    state: AppState,
+}
+
+impl russh::server::Server for SftpServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, peer_addr: Option<SocketAddr>) -> Self::Handler {
+        SshSession {
+            state: self.state.clone(),
+            peer_addr,
+            credential: None,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+struct SshSession {
+    state: AppState,
+    peer_addr: Option<SocketAddr>,
+    credential: Option<Credential>,
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+/// How the connection authenticated, looked up again on every request
+#[derive(Clone)]
+enum Credential {
+    ApiToken(String),
+    /// A password login, which doesn't start a session since the connection holds the user.
+    /// Rotating the user's secret, as logging out everywhere does, ends it.
+    Password {
+        uid: UserId,
+        secret: UserSecret,
+    },
+}
+
+#[async_trait]
+impl russh::server::Handler for SshSession {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        let reject = Auth::Reject {
+            proceed_with_methods: None,
+        };
+        // the password field doubles as a place to put an API token, for users with 2FA
+        if let Some(requester) = self.state.users_manager.read().await.try_auth(password) {
+            if requester.username != user {
+                return Ok(reject);
+            }
+            self.credential = Some(Credential::ApiToken(password.to_string()));
+            return Ok(Auth::Accept);
+        }
+        let login = self
+            .state
+            .users_manager
+            .write()
+            .await
+            .verify_password(
+                user,
+                password,
+                &LoginClient {
+                    ip: self.peer_addr.map(|addr| addr.ip().to_string()),
+                    device: Some("SFTP".to_string()),
+                },
+            )
+            .await;
+        match login {
+            Ok(user) => {
+                self.credential = Some(Credential::Password {
+                    uid: user.uid,
+                    secret: user.secret,
+                });
+                Ok(Auth::Accept)
+            }
+            Err(e) => {
+                debug!("SFTP login for {user} failed: {e}");
+                Ok(reject)
+            }
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let (channel, credential) =
+            match (self.channels.remove(&channel_id), self.credential.clone()) {
+                (Some(channel), Some(credential)) if name == "sftp" => (channel, credential),
+                _ => {
+                    session.channel_failure(channel_id);
+                    return Ok(());
+                }
+            };
+        session.channel_success(channel_id);
+        russh_sftp::server::run(
+            channel.into_stream(),
+            SftpSession::new(&self.state, credential),
+        )
+        .await;
+        Ok(())
+    }
+}
+
+/// An instance directory the user can see, named after the instance
+#[derive(Clone)]
+struct InstanceDir {
+    dir_name: String,
+    uuid: InstanceUuid,
+    name: String,
+    root: PathBuf,
+}
+
+enum ResolvedPath {
+    /// The virtual directory listing the instances
+    Root,
+    Instance {
+        dir: InstanceDir,
+        path: PathBuf,
+    },
+}
+
+enum OpenHandle {
+    File {
+        file: tokio::fs::File,
+        dir: InstanceDir,
+        path: PathBuf,
+        writable: bool,
+        read: bool,
+        bytes_written: u64,
+    },
+    Dir {
+        /// Taken by the first `readdir`, the next one reports the end of the listing
+        entries: Option<Vec<File>>,
+    },
+}
+
+struct SftpSession {
+    users_manager: Arc<RwLock<UsersManager>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    disk_usage: DiskUsageTracker,
+    event_broadcaster: EventBroadcaster,
+    credential: Credential,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+fn ok_status(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn error_status(e: Error) -> StatusCode {
+    debug!("SFTP request failed: {e}");
+    match e.kind {
+        ErrorKind::NotFound => StatusCode::NoSuchFile,
+        ErrorKind::PermissionDenied | ErrorKind::Unauthorized => StatusCode::PermissionDenied,
+        ErrorKind::UnsupportedOperation => StatusCode::OpUnsupported,
+        _ => StatusCode::Failure,
+    }
+}
+
+fn io_status(e: std::io::Error) -> StatusCode {
+    debug!("SFTP request failed: {e}");
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        std::io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+/// Resolves `.` and `..` in an SFTP path, never going above `/`
+fn normalize_path(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components
+}
+
+fn dir_attrs() -> FileAttributes {
+    FileAttributes {
+        permissions: Some(0o40755),
+        ..Default::default()
+    }
+}
+
+/// The `ls -l` style line some clients display instead of the attributes
+fn long_name(file_name: &str, attrs: &FileAttributes) -> String {
+    let permissions = attrs.permissions.unwrap_or(0);
+    let mut mode = String::with_capacity(10);
+    mode.push(if permissions & 0o40000 != 0 { 'd' } else { '-' });
+    for shift in [6, 3, 0] {
+        let bits = (permissions >> shift) & 0o7;
+        mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        mode.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    let mtime = attrs
+        .mtime
+        .and_then(|mtime| chrono::NaiveDateTime::from_timestamp_opt(mtime as i64, 0))
+        .map(|mtime| mtime.format("%b %d %H:%M").to_string())
+        .unwrap_or_else(|| "Jan 01 00:00".to_string());
+    format!(
+        "{mode} 1 lodestone lodestone {:>10} {mtime} {file_name}",
+        attrs.size.unwrap_or(0)
+    )
+}
+
+fn file_entry(file_name: String, attrs: FileAttributes) -> File {
+    File {
+        longname: long_name(&file_name, &attrs),
+        filename: file_name,
+        attrs,
+    }
+}
+
+impl SftpSession {
+    fn new(state: &AppState, credential: Credential) -> Self {
+        Self {
+            users_manager: state.users_manager.clone(),
+            global_settings: state.global_settings.clone(),
+            instances: state.instances.clone(),
+            disk_usage: state.disk_usage.clone(),
+            event_broadcaster: state.event_broadcaster.clone(),
+            credential,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    async fn requester(&self) -> Result<User, StatusCode> {
+        let users_manager = self.users_manager.read().await;
+        match &self.credential {
+            Credential::ApiToken(token) => users_manager.try_auth(token),
+            Credential::Password { uid, secret } => users_manager
+                .get_user(uid)
+                .filter(|user| user.secret == *secret),
+        }
+        .ok_or(StatusCode::PermissionDenied)
+    }
+
+    async fn try_action(&self, requester: &User, action: UserAction) -> Result<(), StatusCode> {
+        requester
+            .try_action(&action, self.global_settings.lock().await.safe_mode())
+            .map_err(error_status)
+    }
+
+    /// Checks the user may write to `path`, the same way the REST handlers do
+    async fn check_write(
+        &self,
+        requester: &User,
+        dir: &InstanceDir,
+        path: &Path,
+    ) -> Result<(), StatusCode> {
+        self.try_action(requester, UserAction::WriteInstanceFile(dir.uuid.clone()))
+            .await?;
+        if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(path) {
+            return Err(StatusCode::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// The instances the user can read files of, docker instances have no local directory
+    async fn instance_dirs(&self, requester: &User) -> Vec<InstanceDir> {
+        let instances: Vec<(InstanceUuid, GameInstance)> = self
+            .state
+            .instances
+            .iter()
+            .filter(|entry| !entry.key().to_string().starts_with("DOCKER-"))
+            .filter(|entry| {
+                requester.can_perform_action(&UserAction::ReadInstanceFile(entry.key().clone()))
+            })
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let mut dirs = Vec::with_capacity(instances.len());
+        for (uuid, instance) in instances {
+            let name = instance.name().await;
+            dirs.push(InstanceDir {
+                dir_name: sanitize_filename::sanitize(&name),
+                uuid,
+                name,
+                root: instance.path().await,
+            });
+        }
+        // instances can share a name, tell them apart by their uuid
+        let mut name_count: HashMap<String, usize> = HashMap::new();
+        for dir in &dirs {
+            *name_count.entry(dir.dir_name.clone()).or_default() += 1;
+        }
+        for dir in dirs.iter_mut() {
+            if name_count[&dir.dir_name] > 1 {
+                dir.dir_name = format!("{} ({})", dir.dir_name, dir.uuid.no_prefix());
+            }
+        }
+        dirs.sort_by(|a, b| a.dir_name.cmp(&b.dir_name));
+        dirs
+    }
+
+    async fn resolve(&self, requester: &User, path: &str) -> Result<ResolvedPath, StatusCode> {
+        let components = normalize_path(path);
+        let (dir_name, relative_path) = match components.split_first() {
+            Some(split) => split,
+            None => return Ok(ResolvedPath::Root),
+        };
+        let dir = self
+            .instance_dirs(requester)
+            .await
+            .into_iter()
+            .find(|dir| dir.dir_name == *dir_name)
+            .ok_or(StatusCode::NoSuchFile)?;
+        let path =
+            scoped_join_instance_file(&dir.root, relative_path.join("/")).map_err(error_status)?;
+        Ok(ResolvedPath::Instance { dir, path })
+    }
+
+    /// Resolves a path inside an instance, the virtual root can't be modified
+    async fn resolve_in_instance(
+        &self,
+        requester: &User,
+        path: &str,
+    ) -> Result<(InstanceDir, PathBuf), StatusCode> {
+        match self.resolve(requester, path).await? {
+            ResolvedPath::Root => Err(StatusCode::PermissionDenied),
+            ResolvedPath::Instance { dir, path } => Ok((dir, path)),
+        }
+    }
+
+    fn insert_handle(&mut self, handle: OpenHandle) -> String {
+        self.next_handle += 1;
+        let id = self.next_handle.to_string();
+        self.handles.insert(id.clone(), handle);
+        id
+    }
+
+    fn caused_by(requester: User) -> CausedBy {
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        }
+    }
+
+    /// Applies the size and permissions of `attrs`, keeping a revision before truncating
+    async fn set_attrs(
+        &self,
+        requester: User,
+        dir: &InstanceDir,
+        path: PathBuf,
+        attrs: FileAttributes,
+    ) -> Result<(), StatusCode> {
+        self.check_write(&requester, dir, &path).await?;
+        if let Some(size) = attrs.size {
+            FileHistory::new(&dir.root)
+                .record(&path, Self::caused_by(requester.clone()))
+                .await
+                .map_err(error_status)?;
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .await
+                .map_err(io_status)?;
+            file.set_len(size).await.map_err(io_status)?;
+        }
+        #[cfg(unix)]
+        if let Some(permissions) = attrs.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(permissions & 0o777))
+                .await
+                .map_err(io_status)?;
+        }
+        // access and modification times are left alone, clients setting them shouldn't fail
+        if attrs.size.is_none() && attrs.permissions.is_none() {
+            return Ok(());
+        }
+        let target = if path.is_dir() {
+            FSTarget::Directory(path)
+        } else {
+            FSTarget::File(path)
+        };
+        self.event_broadcaster.send(new_fs_event(
+            FSOperation::Write,
+            target,
+            Self::caused_by(requester),
+        ));
+        Ok(())
+    }
+
+    async fn stat_path(
+        &self,
+        path: &str,
+        follow_links: bool,
+    ) -> Result<FileAttributes, StatusCode> {
+        let requester = self.requester().await?;
+        match self.resolve(&requester, path).await? {
+            ResolvedPath::Root => Ok(dir_attrs()),
+            ResolvedPath::Instance { dir, path } => {
+                self.try_action(&requester, UserAction::ReadInstanceFile(dir.uuid))
+                    .await?;
+                let metadata = if follow_links {
+                    tokio::fs::metadata(&path).await
+                } else {
+                    tokio::fs::symlink_metadata(&path).await
+                }
+                .map_err(io_status)?;
+                Ok(FileAttributes::from(&metadata))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(
+        &mut self,
+        _version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = format!("/{}", normalize_path(&path).join("/"));
+        Ok(Name {
+            id,
+            files: vec![file_entry(path, dir_attrs())],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        Ok(Attrs {
+            id,
+            attrs: self.stat_path(&path, true).await?,
+        })
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        Ok(Attrs {
+            id,
+            attrs: self.stat_path(&path, false).await?,
+        })
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let attrs = match self.handles.get(&handle) {
+            Some(OpenHandle::File { file, .. }) => {
+                FileAttributes::from(&file.metadata().await.map_err(io_status)?)
+            }
+            Some(OpenHandle::Dir { .. }) => dir_attrs(),
+            None => return Err(StatusCode::Failure),
+        };
+        Ok(Attrs { id, attrs })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let requester = self.requester().await?;
+        let (dir, path) = self.resolve_in_instance(&requester, &path).await?;
+        self.set_attrs(requester, &dir, path, attrs).await?;
+        Ok(ok_status(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let path = match self.handles.get(&handle) {
+            Some(OpenHandle::File { dir, path, .. }) => {
+                format!(
+                    "/{}/{}",
+                    dir.dir_name,
+                    path.strip_prefix(&dir.root)
+                        .map_err(|_| StatusCode::Failure)?
+                        .to_string_lossy()
+                )
+            }
+            _ => return Err(StatusCode::Failure),
+        };
+        self.setstat(id, path, attrs).await
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let requester = self.requester().await?;
+        let (dir, path) = self.resolve_in_instance(&requester, &filename).await?;
+        let writable = pflags.intersects(
+            OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        );
+        if writable {
+            self.check_write(&requester, &dir, &path).await?;
+            // keep the old content around, like writes through the API do
+            FileHistory::new(&dir.root)
+                .record(&path, Self::caused_by(requester))
+                .await
+                .map_err(error_status)?;
+        } else {
+            self.try_action(&requester, UserAction::ReadInstanceFile(dir.uuid.clone()))
+                .await?;
+        }
+        let file = OpenOptions::new()
+            .read(pflags.contains(OpenFlags::READ))
+            .write(pflags.contains(OpenFlags::WRITE))
+            .append(pflags.contains(OpenFlags::APPEND))
+            .truncate(pflags.contains(OpenFlags::TRUNCATE))
+            .create(pflags.contains(OpenFlags::CREATE))
+            .create_new(pflags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE))
+            .open(&path)
+            .await
+            .map_err(io_status)?;
+        let handle = self.insert_handle(OpenHandle::File {
+            file,
+            dir,
+            path,
+            writable,
+            read: false,
+            bytes_written: 0,
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let (file, read) = match self.handles.get_mut(&handle) {
+            Some(OpenHandle::File { file, read, .. }) => (file, read),
+            _ => return Err(StatusCode::Failure),
+        };
+        file.seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(io_status)?;
+        let mut data = vec![0; len.min(MAX_READ_LENGTH) as usize];
+        let n = file.read(&mut data).await.map_err(io_status)?;
+        if n == 0 && len > 0 {
+            return Err(StatusCode::Eof);
+        }
+        data.truncate(n);
+        *read = true;
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let (dir, bytes_written) = match self.handles.get(&handle) {
+            Some(OpenHandle::File {
+                dir,
+                writable: true,
+                bytes_written,
+                ..
+            }) => (dir.clone(), *bytes_written),
+            _ => return Err(StatusCode::PermissionDenied),
+        };
+        // usage is only refreshed once the file is closed, count what was written so far
+        self.disk_usage
+            .check_write(
+                &dir.uuid,
+                &dir.root,
+                &dir.name,
+                bytes_written + data.len() as u64,
+            )
+            .await
+            .map_err(error_status)?;
+        if let Some(OpenHandle::File {
+            file,
+            bytes_written,
+            ..
+        }) = self.handles.get_mut(&handle)
+        {
+            file.seek(std::io::SeekFrom::Start(offset))
+                .await
+                .map_err(io_status)?;
+            file.write_all(&data).await.map_err(io_status)?;
+            *bytes_written += data.len() as u64;
+        }
+        Ok(ok_status(id))
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        let handle = self.handles.remove(&handle).ok_or(StatusCode::Failure)?;
+        if let OpenHandle::File {
+            mut file,
+            path,
+            read,
+            writable,
+            ..
+        } = handle
+        {
+            file.flush().await.map_err(io_status)?;
+            let operation = if writable {
+                FSOperation::Write
+            } else if read {
+                FSOperation::Read
+            } else {
+                return Ok(ok_status(id));
+            };
+            let requester = self.requester().await?;
+            self.event_broadcaster.send(new_fs_event(
+                operation,
+                FSTarget::File(path),
+                Self::caused_by(requester),
+            ));
+        }
+        Ok(ok_status(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let requester = self.requester().await?;
+        let entries = match self.resolve(&requester, &path).await? {
+            ResolvedPath::Root => self
+                .instance_dirs(&requester)
+                .await
+                .into_iter()
+                .map(|dir| file_entry(dir.dir_name, dir_attrs()))
+                .collect(),
+            ResolvedPath::Instance { dir, path } => {
+                self.try_action(&requester, UserAction::ReadInstanceFile(dir.uuid.clone()))
+                    .await?;
+                let history_dir = dir.root.join(HISTORY_DIR_NAME);
+                let mut read_dir = tokio::fs::read_dir(&path).await.map_err(io_status)?;
+                let mut entries = Vec::new();
+                while let Some(entry) = read_dir.next_entry().await.map_err(io_status)? {
+                    if entry.path() == history_dir {
+                        continue;
+                    }
+                    // skip entries that vanished since the listing
+                    if let Ok(metadata) = entry.metadata().await {
+                        entries.push(file_entry(
+                            entry.file_name().to_string_lossy().to_string(),
+                            FileAttributes::from(&metadata),
+                        ));
+                    }
+                }
+                self.event_broadcaster.send(new_fs_event(
+                    FSOperation::Read,
+                    FSTarget::Directory(path),
+                    Self::caused_by(requester),
+                ));
+                entries
+            }
+        };
+        let handle = self.insert_handle(OpenHandle::Dir {
+            entries: Some(entries),
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Dir { entries }) => match entries.take() {
+                Some(files) => Ok(Name { id, files }),
+                None => Err(StatusCode::Eof),
+            },
+            _ => Err(StatusCode::Failure),
+        }
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let requester = self.requester().await?;
+        let (dir, path) = self.resolve_in_instance(&requester, &path).await?;
+        self.try_action(&requester, UserAction::WriteInstanceFile(dir.uuid))
+            .await?;
+        tokio::fs::create_dir(&path).await.map_err(io_status)?;
+        self.event_broadcaster.send(new_fs_event(
+            FSOperation::Create,
+            FSTarget::Directory(path),
+            Self::caused_by(requester),
+        ));
+        Ok(ok_status(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        let requester = self.requester().await?;
+        let (dir, path) = self.resolve_in_instance(&requester, &path).await?;
+        if path == dir.root {
+            return Err(StatusCode::PermissionDenied);
+        }
+        self.check_write(&requester, &dir, &path).await?;
+        // only empty directories can be removed, so there are no protected files to look for
+        tokio::fs::remove_dir(&path).await.map_err(io_status)?;
+        self.event_broadcaster.send(new_fs_event(
+            FSOperation::Delete,
+            FSTarget::Directory(path),
+            Self::caused_by(requester),
+        ));
+        Ok(ok_status(id))
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        let requester = self.requester().await?;
+        let (dir, path) = self.resolve_in_instance(&requester, &filename).await?;
+        self.check_write(&requester, &dir, &path).await?;
+        tokio::fs::remove_file(&path).await.map_err(io_status)?;
+        self.event_broadcaster.send(new_fs_event(
+            FSOperation::Delete,
+            FSTarget::File(path),
+            Self::caused_by(requester),
+        ));
+        Ok(ok_status(id))
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        let requester = self.requester().await?;
+        let (source_dir, source) = self.resolve_in_instance(&requester, &oldpath).await?;
+        let (dest_dir, dest) = self.resolve_in_instance(&requester, &newpath).await?;
+        if source == source_dir.root {
+            return Err(StatusCode::PermissionDenied);
+        }
+        self.check_write(&requester, &source_dir, &source).await?;
+        self.check_write(&requester, &dest_dir, &dest).await?;
+        // SFTP renames never overwrite
+        if tokio::fs::symlink_metadata(&dest).await.is_ok() {
+            return Err(StatusCode::Failure);
+        }
+        if source_dir.uuid != dest_dir.uuid {
+            let size = {
+                let source = source.clone();
+                tokio::task::spawn_blocking(move || crate::util::files_size(&[source]))
+                    .await
+                    .map_err(|_| StatusCode::Failure)?
+            };
+            self.disk_usage
+                .check_write(&dest_dir.uuid, &dest_dir.root, &dest_dir.name, size)
+                .await
+                .map_err(error_status)?;
+        }
+        tokio::fs::rename(&source, &dest).await.map_err(io_status)?;
+        let target = if dest.is_dir() {
+            FSTarget::Directory(dest)
+        } else {
+            FSTarget::File(dest)
+        };
+        self.event_broadcaster.send(new_fs_event(
+            FSOperation::Move { source },
+            target,
+            Self::caused_by(requester),
+        ));
+        Ok(ok_status(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::permission::UserPermission,
+        events::{Event, EventInner, FSEvent},
+        global_settings::GlobalSettingsData,
+    };
+    use tokio::sync::broadcast::Receiver;
+
+    async fn new_session(temp_dir: &Path, user: &User) -> (SftpSession, Receiver<Event>) {
+        let (tx, rx) = EventBroadcaster::new(16);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        users_manager
+            .add_user(user.clone(), CausedBy::System)
+            .await
+            .unwrap();
+        let global_settings = GlobalSettings::new(
+            temp_dir.join("global_settings.json"),
+            tx.clone(),
+            GlobalSettingsData {
+                safe_mode: false,
+                ..Default::default()
+            },
+        );
+        let session = SftpSession {
+            users_manager: Arc::new(RwLock::new(users_manager)),
+            global_settings: Arc::new(Mutex::new(global_settings)),
+            instances: Arc::new(DashMap::new()),
+            disk_usage: DiskUsageTracker::new(temp_dir.join("disk_quotas.json"), tx.clone()),
+            event_broadcaster: tx,
+            credential: Credential::Password {
+                uid: user.uid.clone(),
+                secret: user.secret.clone(),
+            },
+            handles: HashMap::new(),
+            next_handle: 0,
+        };
+        (session, rx)
+    }
+
+    async fn new_instance_dir(temp_dir: &Path) -> InstanceDir {
+        let root = temp_dir.join("survival");
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        tokio::fs::write(root.join("server.properties"), "motd=hello")
+            .await
+            .unwrap();
+        tokio::fs::write(root.join("server.jar"), "jar")
+            .await
+            .unwrap();
+        InstanceDir {
+            dir_name: "survival".to_string(),
+            uuid: InstanceUuid::default(),
+            name: "survival".to_string(),
+            root,
+        }
+    }
+
+    fn truncate() -> FileAttributes {
+        FileAttributes {
+            size: Some(0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert!(normalize_path("/").is_empty());
+        assert!(normalize_path("/../..").is_empty());
+        assert_eq!(
+            normalize_path("/survival/world/../server.properties"),
+            ["survival", "server.properties"]
+        );
+        assert_eq!(normalize_path("survival//./world/"), ["survival", "world"]);
+        assert_eq!(normalize_path("/../survival/../../creative"), ["creative"]);
+    }
+
+    #[tokio::test]
+    async fn test_set_attrs_permission_denied() {
+        let temp_dir = tempdir::TempDir::new("test_sftp").unwrap();
+        let dir = new_instance_dir(temp_dir.path()).await;
+        let properties = dir.root.join("server.properties");
+
+        // reading files doesn't allow changing them
+        let mut permissions = UserPermission::default();
+        permissions.can_read_instance_file.insert(dir.uuid.clone());
+        let reader = User::new("reader".to_string(), "12345", false, false, permissions);
+        let (session, mut rx) = new_session(temp_dir.path(), &reader).await;
+        assert!(matches!(
+            session
+                .set_attrs(reader, &dir, properties.clone(), truncate())
+                .await,
+            Err(StatusCode::PermissionDenied)
+        ));
+        assert_eq!(tokio::fs::read(&properties).await.unwrap(), b"motd=hello");
+        assert!(rx.try_recv().is_err());
+
+        // protected files need global file write on top of instance file write
+        let mut permissions = UserPermission::default();
+        permissions.can_read_instance_file.insert(dir.uuid.clone());
+        permissions.can_write_instance_file.insert(dir.uuid.clone());
+        let writer = User::new("writer".to_string(), "12345", false, false, permissions);
+        let (session, mut rx) = new_session(temp_dir.path(), &writer).await;
+        assert!(matches!(
+            session
+                .set_attrs(
+                    writer.clone(),
+                    &dir,
+                    dir.root.join("server.jar"),
+                    truncate()
+                )
+                .await,
+            Err(StatusCode::PermissionDenied)
+        ));
+        assert_eq!(
+            tokio::fs::read(dir.root.join("server.jar")).await.unwrap(),
+            b"jar"
+        );
+        assert!(rx.try_recv().is_err());
+        session
+            .set_attrs(writer, &dir, properties.clone(), truncate())
+            .await
+            .unwrap();
+        assert!(tokio::fs::read(&properties).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_attrs_records_revision_and_event() {
+        let temp_dir = tempdir::TempDir::new("test_sftp").unwrap();
+        let dir = new_instance_dir(temp_dir.path()).await;
+        let properties = dir.root.join("server.properties");
+        let owner = User::new(
+            "owner".to_string(),
+            "12345",
+            true,
+            false,
+            UserPermission::default(),
+        );
+        let (session, mut rx) = new_session(temp_dir.path(), &owner).await;
+
+        session
+            .set_attrs(owner.clone(), &dir, properties.clone(), truncate())
+            .await
+            .unwrap();
+        assert!(tokio::fs::read(&properties).await.unwrap().is_empty());
+
+        // the truncated content can be restored, like after a write through the API
+        let history = FileHistory::new(&dir.root);
+        let revisions = history.list(&properties).await.unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(
+            history.read(&properties, revisions[0].id).await.unwrap(),
+            b"motd=hello"
+        );
+
+        let event = rx.try_recv().unwrap();
+        match event.event_inner {
+            EventInner::FSEvent(FSEvent { operation, target }) => {
+                assert_eq!(operation, FSOperation::Write);
+                assert_eq!(target, FSTarget::File(properties));
+            }
+            _ => panic!("Expected an FS event"),
+        }
+        assert!(matches!(
+            event.caused_by,
+            CausedBy::User { user_id, .. } if user_id == owner.uid
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_login_ends_on_logout() {
+        let temp_dir = tempdir::TempDir::new("test_sftp").unwrap();
+        let owner = User::new(
+            "owner".to_string(),
+            "12345",
+            true,
+            false,
+            UserPermission::default(),
+        );
+        let (session, _rx) = new_session(temp_dir.path(), &owner).await;
+        assert!(session.requester().await.is_ok());
+
+        // checking the password doesn't start a session
+        let mut users_manager = session.users_manager.write().await;
+        users_manager
+            .verify_password("owner", "12345", &LoginClient::default())
+            .await
+            .unwrap();
+        assert!(users_manager.list_sessions(&owner.uid).unwrap().is_empty());
+        drop(users_manager);
+
+        session
+            .users_manager
+            .write()
+            .await
+            .logout_user(&owner.uid, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(matches!(
+            session.requester().await,
+            Err(StatusCode::PermissionDenied)
+        ));
+    }
+}
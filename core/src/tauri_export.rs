@@ -1,21 +1,30 @@
 use color_eyre::eyre::eyre;
 
 use crate::{
-    auth::{jwt_token::JwtToken, permission::UserPermission, user::User},
+    auth::{jwt_token::JwtToken, permission::UserPermission, session::LoginClient, user::User},
     error::{Error, ErrorKind},
     events::CausedBy,
     AppState,
 };
 
 pub async fn get_owner_jwt(app_state: &AppState) -> Option<JwtToken> {
-    app_state
-        .users_manager
-        .read()
-        .await
+    let mut users_manager = app_state.users_manager.write().await;
+    let owner_uid = users_manager
         .as_ref()
-        .iter()
-        .find(|(_, user)| user.is_owner)
-        .and_then(|(_, user)| user.create_jwt().ok())
+        .values()
+        .find(|user| user.is_owner)?
+        .uid
+        .clone();
+    users_manager
+        .create_session_token(
+            &owner_uid,
+            LoginClient {
+                ip: None,
+                device: Some("Lodestone desktop".to_string()),
+            },
+        )
+        .await
+        .ok()
 }
 
 pub async fn is_owner_account_present(app_state: &AppState) -> bool {
@@ -7,7 +7,10 @@ use ts_rs::TS;
 use crate::{
     error::{Error, ErrorKind},
     events::CausedBy,
-    macro_executor::MacroPID,
+    macro_executor::{MacroDebugOptions, MacroPID},
+    macro_limits::MacroLimits,
+    macro_test::MacroTestReport,
+    module_cache::VendorReport,
     traits::GameInstance,
 };
 
@@ -30,6 +33,9 @@ pub struct TaskEntry {
     pub name: String,
     pub creation_time: i64,
     pub pid: MacroPID,
+    /// Websocket URL of the V8 inspector, only for macros run in debug mode
+    #[serde(default)]
+    pub inspector_url: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
@@ -78,6 +84,7 @@ pub trait TMacro {
         _args: Vec<String>,
         _configs: Option<IndexMap<String, SettingLocalCache>>,
         _caused_by: CausedBy,
+        _debug: Option<MacroDebugOptions>,
     ) -> Result<TaskEntry, Error> {
         Err(Error {
             kind: ErrorKind::UnsupportedOperation,
@@ -119,4 +126,31 @@ pub trait TMacro {
             source: eyre!("This instance does not support running macro"),
         })
     }
+    /// Caches the remote modules the macro imports so it can run in offline mode
+    async fn vendor_macro(&self, _name: &str) -> Result<VendorReport, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support vendoring macro"),
+        })
+    }
+    /// The macro's own limits, not including the global defaults
+    async fn get_macro_limits(&self, _name: &str) -> Result<MacroLimits, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support macro limits"),
+        })
+    }
+    async fn set_macro_limits(&self, _name: &str, _limits: MacroLimits) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support macro limits"),
+        })
+    }
+    /// Runs the `*.test.ts` files of the macro against a fake instance
+    async fn test_macro(&self, _name: &str) -> Result<MacroTestReport, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support testing macro"),
+        })
+    }
 }
@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use crate::error::{Error, ErrorKind};
+use crate::events::CausedBy;
 use crate::implementations::generic::player::GenericPlayer;
 use crate::minecraft::player::MinecraftPlayer;
 use crate::traits::GameInstance;
@@ -63,4 +64,35 @@ pub trait TPlayerManagement {
             source: eyre!("Setting max player count is unsupported for this instance"),
         })
     }
+
+    async fn kick_player(
+        &self,
+        _player_name: &str,
+        _reason: Option<&str>,
+        _caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Kicking players is unsupported for this instance"),
+        })
+    }
+
+    async fn ban_player(
+        &self,
+        _player_name: &str,
+        _reason: Option<&str>,
+        _caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Banning players is unsupported for this instance"),
+        })
+    }
+
+    async fn pardon_player(&self, _player_name: &str, _caused_by: CausedBy) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Pardoning players is unsupported for this instance"),
+        })
+    }
 }
@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use serde_aux::prelude::*;
 use ts_rs::TS;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, TS, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, TS, Copy)]
 #[ts(export)]
 #[serde(into = "String")]
 #[derive(sqlx::Type)]
@@ -22,7 +22,7 @@ pub struct Authentication {
     password: String,
 }
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
 use crate::prelude::path_to_tmp;
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
@@ -196,9 +196,223 @@ pub enum UnzipOption {
     ToDir(PathBuf),
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// File name suffixes of the format, the first one is used for new archives
+    fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            ArchiveFormat::Zip => &[".zip"],
+            ArchiveFormat::Tar => &[".tar"],
+            ArchiveFormat::TarGz => &[".tar.gz", ".tgz", ".gz"],
+            ArchiveFormat::TarXz => &[".tar.xz", ".txz"],
+            ArchiveFormat::TarZst => &[".tar.zst", ".tzst", ".tar.zstd"],
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        self.suffixes()[0]
+    }
+
+    /// Guesses the format from the file name
+    pub fn from_file_name(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        [
+            ArchiveFormat::Zip,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarXz,
+            ArchiveFormat::TarZst,
+            ArchiveFormat::Tar,
+        ]
+        .into_iter()
+        .find(|format| format.suffixes().iter().any(|s| name.ends_with(s)))
+    }
+
+    /// Detects the format from the leading bytes of the file, falling back to its name
+    pub fn detect(path: &Path) -> Result<Self, Error> {
+        let mut header = Vec::with_capacity(512);
+        std::fs::File::open(path)
+            .context(format!("Failed to open file {}", path.display()))?
+            .take(512)
+            .read_to_end(&mut header)
+            .context(format!("Failed to read file {}", path.display()))?;
+        let format = if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(ArchiveFormat::TarXz)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(ArchiveFormat::TarZst)
+        } else if header.get(257..262) == Some(&b"ustar"[..]) {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        };
+        format
+            .or_else(|| Self::from_file_name(path))
+            .ok_or_else(|| eyre!("Unsupported archive format for {}", path.display()).into())
+    }
+}
+
+/// The file name of an archive without its archive suffix, `world.tar.gz` becomes `world`
+fn archive_stem(file: &Path) -> Option<&OsStr> {
+    let name = file.file_name()?.to_str()?;
+    let lowercase = name.to_ascii_lowercase();
+    ArchiveFormat::from_file_name(file)
+        .and_then(|format| {
+            format
+                .suffixes()
+                .iter()
+                .find(|suffix| lowercase.ends_with(*suffix))
+        })
+        .map(|suffix| OsStr::new(&name[..name.len() - suffix.len()]))
+        .filter(|stem| !stem.is_empty())
+        .or_else(|| file.file_stem())
+}
+
+/// Calls `on_progress` with the total number of bytes read after every read
+struct ProgressReader<R, F> {
+    inner: R,
+    read: u64,
+    on_progress: F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        (self.on_progress)(self.read);
+        Ok(read)
+    }
+}
+
+impl<R: std::io::Seek, F> std::io::Seek for ProgressReader<R, F> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Refuses to extract more than `max_size` bytes in total
+fn check_extracted_size(extracted: u64, max_size: Option<u64>) -> Result<(), Error> {
+    match max_size {
+        Some(max_size) if extracted > max_size => Err(Error {
+            kind: ErrorKind::InsufficientStorage,
+            source: eyre!(
+                "Archive extracts to more than the {} it may take up",
+                format_byte(max_size)
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Every entry is joined with `scoped_join_win_safe`, links are skipped since they could point out of `dest`
+fn extract_tar(reader: impl Read, dest: &Path, max_size: Option<u64>) -> Result<(), Error> {
+    let mut archive = Archive::new(reader);
+    let mut extracted = 0_u64;
+    for entry in archive.entries().context("Failed to read archive")? {
+        let mut entry = entry.context("Failed to read archive entry")?;
+        let entry_type = entry.header().entry_type();
+        if !entry_type.is_file() && !entry_type.is_dir() {
+            continue;
+        }
+        // an entry is never read past the size in its header
+        extracted = extracted.saturating_add(entry.size());
+        check_extracted_size(extracted, max_size)?;
+        let path = scoped_join_win_safe(
+            dest,
+            entry.path().context("Archive entry has an invalid path")?,
+        )?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create directory {}", parent.display()))?;
+        }
+        entry
+            .unpack(&path)
+            .context(format!("Failed to extract {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Every entry is joined with `scoped_join_win_safe`
+fn extract_zip(
+    reader: impl Read + std::io::Seek,
+    dest: &Path,
+    max_size: Option<u64>,
+) -> Result<(), Error> {
+    let mut archive = zip::ZipArchive::new(reader).context("Failed to read archive")?;
+    // the declared sizes refuse most archives before anything is written
+    let mut declared = 0_u64;
+    for i in 0..archive.len() {
+        declared = declared.saturating_add(
+            archive
+                .by_index_raw(i)
+                .context("Failed to read archive entry")?
+                .size(),
+        );
+    }
+    check_extracted_size(declared, max_size)?;
+    let mut extracted = 0_u64;
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .context("Failed to read archive entry")?;
+        let name = match file.enclosed_name() {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let path = scoped_join_win_safe(dest, name)?;
+        if file.is_dir() {
+            std::fs::create_dir_all(&path)
+                .context(format!("Failed to create directory {}", path.display()))?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create directory {}", parent.display()))?;
+        }
+        let mut out = std::fs::File::create(&path)
+            .context(format!("Failed to create file {}", path.display()))?;
+        // the declared size can lie, stop one byte past what's left
+        let left = max_size.map_or(u64::MAX, |max_size| max_size.saturating_sub(extracted));
+        extracted += std::io::copy(&mut (&mut file).take(left.saturating_add(1)), &mut out)
+            .context(format!("Failed to extract {}", path.display()))?;
+        check_extracted_size(extracted, max_size)?;
+        #[cfg(unix)]
+        if let Some(mode) = file.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o777))
+                .context(format!("Failed to set permissions of {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
 pub fn unzip_file(
     file: impl AsRef<Path>,
     unzip_option: UnzipOption,
+) -> Result<HashSet<PathBuf>, Error> {
+    unzip_file_with_progress(file, unzip_option, None, |_, _| {})
+}
+
+/// Extracts a zip or tar archive, `on_progress` is called with the bytes of the archive read so far and its size.
+///
+/// Fails without touching the destination if the archive extracts to more than `max_size` bytes
+pub fn unzip_file_with_progress(
+    file: impl AsRef<Path>,
+    unzip_option: UnzipOption,
+    max_size: Option<u64>,
+    mut on_progress: impl FnMut(u64, u64),
 ) -> Result<HashSet<PathBuf>, Error> {
     let file = file.as_ref();
 
@@ -206,21 +420,15 @@ pub fn unzip_file(
         return Err(eyre!("File {} does not exist", file.display()).into());
     }
 
-    let file_extension = file
-        .extension()
-        .ok_or_else(|| eyre!("Failed to get file extension for {}", file.display()))?;
-    if file_extension != "gz" && file_extension != "tgz" && file_extension != "zip" {
-        return Err(eyre!("Unsupported extension for {}", file.display()).into());
-    }
+    let format = ArchiveFormat::detect(file)?;
 
     let parent = file.parent().context(format!(
         "Failed to get parent directory of {}",
         file.display()
     ))?;
 
-    let file_stem = file
-        .file_stem()
-        .context(format!("Failed to get file stem of {}", file.display()))?;
+    let file_stem =
+        archive_stem(file).context(format!("Failed to get file stem of {}", file.display()))?;
 
     let mut dest = match unzip_option {
         UnzipOption::Normal => parent.to_path_buf(),
@@ -240,24 +448,29 @@ pub fn unzip_file(
     )?;
     let temp_dest = temp_dest_dir.path();
 
-    if file_extension == "gz" || file_extension == "tgz" {
-        let tar_gz =
-            std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        archive.set_overwrite(true);
-        archive
-            .unpack(temp_dest)
-            .context(format!("Failed to decompress file {}", file.display()))?;
-    } else if file_extension == "zip" {
-        let zip =
-            std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
-        let mut archive = zip::ZipArchive::new(zip)
-            .context(format!("Failed to decompress file {}", file.display()))?;
-        archive
-            .extract(temp_dest)
-            .context(format!("Failed to decompress file {}", file.display()))?;
+    let archive_file =
+        std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
+    let total = archive_file.metadata().map(|m| m.len()).unwrap_or(0);
+    let reader = ProgressReader {
+        inner: archive_file,
+        read: 0,
+        on_progress: |read: u64| on_progress(read.min(total), total),
+    };
+    match format {
+        ArchiveFormat::Zip => extract_zip(reader, temp_dest, max_size),
+        ArchiveFormat::Tar => extract_tar(reader, temp_dest, max_size),
+        ArchiveFormat::TarGz => extract_tar(GzDecoder::new(reader), temp_dest, max_size),
+        ArchiveFormat::TarXz => extract_tar(xz2::read::XzDecoder::new(reader), temp_dest, max_size),
+        ArchiveFormat::TarZst => extract_tar(
+            zstd::stream::read::Decoder::new(reader).context("Failed to create zstd decoder")?,
+            temp_dest,
+            max_size,
+        ),
     }
+    .map_err(|e| Error {
+        kind: e.kind,
+        source: eyre!("Failed to decompress file {}: {}", file.display(), e.source),
+    })?;
 
     let mut ret: HashSet<PathBuf> = HashSet::new();
 
@@ -302,34 +515,80 @@ pub fn unzip_file(
 pub async fn unzip_file_async(
     file: impl AsRef<Path>,
     unzip_option: UnzipOption,
+) -> Result<HashSet<PathBuf>, Error> {
+    unzip_file_with_progress_async(file, unzip_option, None, |_, _| {}).await
+}
+
+pub async fn unzip_file_with_progress_async(
+    file: impl AsRef<Path>,
+    unzip_option: UnzipOption,
+    max_size: Option<u64>,
+    on_progress: impl FnMut(u64, u64) + Send + 'static,
 ) -> Result<HashSet<PathBuf>, Error> {
     let _file = file.as_ref().to_owned();
-    tokio::task::spawn_blocking(move || unzip_file(_file, unzip_option))
-        .await
-        .context(format!(
-            "Failed to unzip file {} in a blocking task",
-            file.as_ref().display()
-        ))?
+    tokio::task::spawn_blocking(move || {
+        unzip_file_with_progress(_file, unzip_option, max_size, on_progress)
+    })
+    .await
+    .context(format!(
+        "Failed to unzip file {} in a blocking task",
+        file.as_ref().display()
+    ))?
 }
 
-pub fn zip_files(
+/// Total size of the files, including the content of directories
+pub fn files_size(files: &[impl AsRef<Path>]) -> u64 {
+    files
+        .iter()
+        .flat_map(|f| walkdir::WalkDir::new(f.as_ref()).into_iter())
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+fn write_tar<W: Write>(
     files: &[impl AsRef<Path>],
-    dest: impl AsRef<Path>,
-    overwrite_dest: bool,
-) -> Result<PathBuf, Error> {
-    let dest = dest.as_ref();
-    std::fs::create_dir_all(dest.parent().context("Failed to get destination parent")?)
-        .context(format!("Failed to create directory {}", dest.display()))?;
-    let lodestone_tmp = path_to_tmp().clone();
-    std::fs::create_dir_all(&lodestone_tmp).context(format!(
-        "Failed to create temporary directory {}",
-        lodestone_tmp.display()
-    ))?;
-    let tmp_archive = tempfile::NamedTempFile::new_in(lodestone_tmp)
-        .context("Failed to create temporary file for zipping")?;
+    writer: W,
+    on_file_done: &mut impl FnMut(u64),
+) -> Result<W, Error> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    for entry_path in files.iter().map(|f| f.as_ref()) {
+        let parent = entry_path
+            .parent()
+            .context(format!("Failed to get parent for {}", entry_path.display()))?;
+        for child_entry in walkdir::WalkDir::new(entry_path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let child_entry_path = child_entry.path();
+            let child_entry_dest = child_entry_path.strip_prefix(parent).context(format!(
+                "Failed to strip prefix for {}",
+                child_entry_path.display()
+            ))?;
+            builder
+                .append_path_with_name(child_entry_path, child_entry_dest)
+                .context(format!(
+                    "Failed to write {} to archive",
+                    child_entry_path.display()
+                ))?;
+            if child_entry.file_type().is_file() {
+                on_file_done(child_entry.metadata().map(|m| m.len()).unwrap_or(0));
+            }
+        }
+    }
+    builder.into_inner().context("Failed to finish archive")
+}
 
+fn write_zip(
+    files: &[impl AsRef<Path>],
+    archive: &std::fs::File,
+    on_file_done: &mut impl FnMut(u64),
+) -> Result<(), Error> {
     let mut buffer = Vec::new();
-    let mut writer = zip::ZipWriter::new(&tmp_archive);
+    let mut writer = zip::ZipWriter::new(archive);
     let options = zip::write::FileOptions::default().unix_permissions(0o775);
     for entry_path in files.iter().map(|f| f.as_ref()) {
         if entry_path.is_dir() {
@@ -391,6 +650,7 @@ pub fn zip_files(
                         "Failed to write {} to archive",
                         child_entry_path.display()
                     ))?;
+                    on_file_done(buffer.len() as u64);
                     buffer.clear();
                 }
             }
@@ -417,11 +677,84 @@ pub fn zip_files(
                 "Failed to write {} to archive",
                 entry_path.display()
             ))?;
+            on_file_done(buffer.len() as u64);
             buffer.clear();
         }
     }
 
     writer.finish().context("Zip failed")?;
+    Ok(())
+}
+
+pub fn zip_files(
+    files: &[impl AsRef<Path>],
+    dest: impl AsRef<Path>,
+    overwrite_dest: bool,
+) -> Result<PathBuf, Error> {
+    archive_files(files, dest, overwrite_dest, ArchiveFormat::Zip, |_, _| {})
+}
+
+/// Archives files and directories, `on_progress` is called with the bytes archived so far and the total
+pub fn archive_files(
+    files: &[impl AsRef<Path>],
+    dest: impl AsRef<Path>,
+    overwrite_dest: bool,
+    format: ArchiveFormat,
+    mut on_progress: impl FnMut(u64, u64),
+) -> Result<PathBuf, Error> {
+    let dest = dest.as_ref();
+    std::fs::create_dir_all(dest.parent().context("Failed to get destination parent")?)
+        .context(format!("Failed to create directory {}", dest.display()))?;
+    let lodestone_tmp = path_to_tmp().clone();
+    std::fs::create_dir_all(&lodestone_tmp).context(format!(
+        "Failed to create temporary directory {}",
+        lodestone_tmp.display()
+    ))?;
+    let tmp_archive = tempfile::NamedTempFile::new_in(lodestone_tmp)
+        .context("Failed to create temporary file for zipping")?;
+
+    let total = files_size(files);
+    let mut done = 0;
+    let mut on_file_done = |size: u64| {
+        done += size;
+        on_progress(done.min(total), total);
+    };
+    let archive = tmp_archive.as_file();
+    match format {
+        ArchiveFormat::Zip => write_zip(files, archive, &mut on_file_done)?,
+        ArchiveFormat::Tar => {
+            write_tar(files, archive, &mut on_file_done)?;
+        }
+        ArchiveFormat::TarGz => {
+            write_tar(
+                files,
+                flate2::write::GzEncoder::new(archive, flate2::Compression::default()),
+                &mut on_file_done,
+            )?
+            .finish()
+            .context("Failed to finish gzip stream")?;
+        }
+        ArchiveFormat::TarXz => {
+            write_tar(
+                files,
+                xz2::write::XzEncoder::new(archive, 6),
+                &mut on_file_done,
+            )?
+            .finish()
+            .context("Failed to finish xz stream")?;
+        }
+        ArchiveFormat::TarZst => {
+            write_tar(
+                files,
+                zstd::stream::write::Encoder::new(archive, 0)
+                    .context("Failed to create zstd encoder")?,
+                &mut on_file_done,
+            )?
+            .finish()
+            .context("Failed to finish zstd stream")?;
+        }
+    }
+
     let dest = if overwrite_dest {
         dest.into()
     } else {
@@ -440,15 +773,27 @@ pub async fn zip_files_async(
     files: &[impl AsRef<Path>],
     dest: impl AsRef<Path>,
     overwrite_dest: bool,
+) -> Result<PathBuf, Error> {
+    archive_files_async(files, dest, overwrite_dest, ArchiveFormat::Zip, |_, _| {}).await
+}
+
+pub async fn archive_files_async(
+    files: &[impl AsRef<Path>],
+    dest: impl AsRef<Path>,
+    overwrite_dest: bool,
+    format: ArchiveFormat,
+    on_progress: impl FnMut(u64, u64) + Send + 'static,
 ) -> Result<PathBuf, Error> {
     let _files = files
         .iter()
         .map(|f| f.as_ref().to_owned())
         .collect::<Vec<_>>();
     let _dest = dest.as_ref().to_owned();
-    tokio::task::spawn_blocking(move || zip_files(&_files, &_dest, overwrite_dest))
-        .await
-        .context("Failed to spawn blocking task")?
+    tokio::task::spawn_blocking(move || {
+        archive_files(&_files, &_dest, overwrite_dest, format, on_progress)
+    })
+    .await
+    .context("Failed to spawn blocking task")?
 }
 
 pub fn rand_alphanumeric(len: usize) -> String {
@@ -639,8 +984,12 @@ pub fn format_byte(mut bytes: u64) -> String {
 
 #[cfg(test)]
 mod tests {
+    use crate::error::{Error, ErrorKind};
     use crate::prelude::init_paths;
-    use crate::util::{resolve_path_conflict, unzip_file, zip_files, UnzipOption};
+    use crate::util::{
+        archive_files, resolve_path_conflict, unzip_file, unzip_file_with_progress, zip_files,
+        ArchiveFormat, UnzipOption,
+    };
     use std::collections::HashSet;
     use std::io::Read;
     use std::path::PathBuf;
@@ -708,6 +1057,73 @@ mod tests {
         assert!(dest_path.join("sample_1").join("sample.obj").is_file(),);
     }
 
+    #[test]
+    fn test_archive_formats() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let temp_path = temp.path();
+        for format in [
+            ArchiveFormat::Zip,
+            ArchiveFormat::Tar,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarXz,
+            ArchiveFormat::TarZst,
+        ] {
+            let archive = archive_files(
+                &["testdata/zip_test/test1.txt", "testdata/zip_test/test2"],
+                temp_path.join(format!("archive{}", format.extension())),
+                false,
+                format,
+                |_, _| {},
+            )
+            .unwrap();
+            assert_eq!(ArchiveFormat::detect(&archive).unwrap(), format);
+            let dest = temp_path.join(format!("{format:?}"));
+            // over the size it may extract to
+            assert!(matches!(
+                unzip_file_with_progress(
+                    &archive,
+                    UnzipOption::ToDir(dest.clone()),
+                    Some(1),
+                    |_, _| {}
+                ),
+                Err(Error {
+                    kind: ErrorKind::InsufficientStorage,
+                    ..
+                })
+            ));
+            assert!(!dest.exists());
+            unzip_file(&archive, UnzipOption::ToDir(dest.clone())).unwrap();
+            assert!(dest.join("test1.txt").is_file());
+            assert!(dest.join("test2").join("test2").join("test1.txt").is_file());
+        }
+    }
+
+    #[test]
+    fn test_unzip_tar_slip() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let temp_path = temp.path();
+
+        // the tar crate refuses to write `..`, so the name is set by hand
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..11].copy_from_slice(b"../evil.txt");
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &b"evil"[..]).unwrap();
+        let archive = temp_path.join("evil.tar");
+        std::fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        let dest = temp_path.join("dest");
+        unzip_file(&archive, UnzipOption::ToDir(dest.clone())).unwrap();
+        assert!(!temp_path.join("evil.txt").exists());
+        assert!(dest.join("evil.txt").is_file());
+    }
+
     #[test]
     fn test_resolve_path_conflict() {
         let temp_lodestone_path = tempfile::tempdir().unwrap();
@@ -55,6 +55,9 @@ async fn main() {
         is_cli: false,
         is_desktop: true,
         lodestone_path: None,
+        sftp_port: None,
+        sftp: false,
+        test_extension: None,
     })
     .await;
 